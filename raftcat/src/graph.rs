@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, BTreeSet};
+use shipcat_definitions::structs::DepEdge;
+
+use super::ManifestMap;
+
+/// A service in a dependency graph
+#[derive(Serialize, Clone, Debug)]
+pub struct Node {
+    pub name: String,
+    /// Owning team, if the service has a manifest
+    pub team: Option<String>,
+}
+
+/// A dependency of one service on another
+#[derive(Serialize, Clone, Debug)]
+pub struct Edge {
+    /// The dependent service
+    pub from: String,
+    /// The service relied upon
+    pub to: String,
+    #[serde(flatten)]
+    pub dependency: DepEdge,
+}
+
+/// Dependency graph of services built from the cached manifests
+///
+/// Serialized as flat lists of nodes and edges for visualisation tools,
+/// or as graphviz via `Graph::dot`.
+#[derive(Serialize, Default, Debug)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+impl Graph {
+    /// Graph of a set of services and the dependencies between them
+    fn between(names: &BTreeSet<String>, mfs: &ManifestMap) -> Graph {
+        let mut res = Graph::default();
+        for name in names {
+            let mf = mfs.get(name);
+            res.nodes.push(Node {
+                name: name.clone(),
+                team: mf.and_then(|mf| mf.metadata.as_ref()).map(|md| md.team.clone()),
+            });
+            if let Some(mf) = mf {
+                for dep in mf.dependencies.iter().filter(|d| names.contains(&d.name)) {
+                    res.edges.push(Edge {
+                        from: name.clone(),
+                        to: dep.name.clone(),
+                        dependency: DepEdge::new(dep),
+                    });
+                }
+            }
+        }
+        res
+    }
+
+    /// All services in the region and their dependencies
+    ///
+    /// Dependencies without a manifest are included as nodes without a team.
+    pub fn full(mfs: &ManifestMap) -> Graph {
+        let mut names : BTreeSet<String> = mfs.keys().cloned().collect();
+        for mf in mfs.values() {
+            names.extend(mf.dependencies.iter().map(|d| d.name.clone()));
+        }
+        Graph::between(&names, mfs)
+    }
+
+    /// A service and everything it depends on, directly or indirectly
+    pub fn dependencies(service: &str, mfs: &ManifestMap) -> Graph {
+        let names = reachable(service, |name| {
+            mfs.get(name).map(|mf| mf.dependencies.iter().map(|d| d.name.clone()).collect()).unwrap_or_default()
+        });
+        Graph::between(&names, mfs)
+    }
+
+    /// A service and everything that depends on it, directly or indirectly
+    pub fn dependents(service: &str, mfs: &ManifestMap) -> Graph {
+        let mut revdeps : BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (name, mf) in mfs {
+            for dep in &mf.dependencies {
+                revdeps.entry(&dep.name).or_default().push(name.clone());
+            }
+        }
+        let names = reachable(service, |name| revdeps.get(name).cloned().unwrap_or_default());
+        Graph::between(&names, mfs)
+    }
+
+    /// Graphviz representation with edges labelled by protocol and api version
+    pub fn dot(&self) -> String {
+        let mut res = String::from("digraph {\n");
+        for n in &self.nodes {
+            res += &format!("    \"{}\"\n", n.name);
+        }
+        for e in &self.edges {
+            let protocol = format!("{:?}", e.dependency.protocol).to_lowercase();
+            res += &format!("    \"{}\" -> \"{}\" [label=\"{} {}\"]\n", e.from, e.to, protocol, e.dependency.api);
+        }
+        res += "}\n";
+        res
+    }
+}
+
+/// Services reachable from a service (itself included) by following `next`
+fn reachable<F>(service: &str, next: F) -> BTreeSet<String>
+    where F: Fn(&str) -> Vec<String>
+{
+    let mut seen = BTreeSet::new();
+    let mut todo = vec![service.to_string()];
+    while let Some(name) = todo.pop() {
+        if !seen.contains(&name) {
+            todo.extend(next(&name));
+            seen.insert(name);
+        }
+    }
+    seen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Graph;
+    use crate::ManifestMap;
+    use serde_json::json;
+
+    // a -> b -> c, d -> c, and c -> external which has no manifest
+    fn manifests() -> ManifestMap {
+        let mut mfs = ManifestMap::new();
+        for (name, deps) in &[("a", vec!["b"]), ("b", vec!["c"]), ("c", vec!["external"]), ("d", vec!["c"])] {
+            let deps = deps.iter().map(|d| json!({ "name": d, "protocol": "grpc" })).collect::<Vec<_>>();
+            let mf = serde_json::from_value(json!({ "name": name, "dependencies": deps })).unwrap();
+            mfs.insert(name.to_string(), mf);
+        }
+        mfs
+    }
+    fn names(g: &Graph) -> Vec<&str> {
+        g.nodes.iter().map(|n| n.name.as_str()).collect()
+    }
+
+    #[test]
+    fn graph_directions() {
+        let mfs = manifests();
+        let full = Graph::full(&mfs);
+        assert_eq!(names(&full), vec!["a", "b", "c", "d", "external"]);
+        assert_eq!(full.edges.len(), 4);
+
+        let deps = Graph::dependencies("b", &mfs);
+        assert_eq!(names(&deps), vec!["b", "c", "external"]);
+        assert_eq!(deps.edges.len(), 2);
+
+        let revdeps = Graph::dependents("c", &mfs);
+        assert_eq!(names(&revdeps), vec!["a", "b", "c", "d"]);
+        let edges = revdeps.edges.iter().map(|e| (e.from.as_str(), e.to.as_str())).collect::<Vec<_>>();
+        assert_eq!(edges, vec![("a", "b"), ("b", "c"), ("d", "c")]);
+
+        let json = serde_json::to_value(&revdeps).unwrap();
+        assert_eq!(json["edges"][0], json!({ "from": "a", "to": "b", "api": "v1", "contract": null, "protocol": "grpc", "intent": null }));
+        assert!(revdeps.dot().contains("\"a\" -> \"b\" [label=\"grpc v1\"]"));
+    }
+}
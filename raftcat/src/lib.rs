@@ -7,11 +7,19 @@
 pub use failure::Error;
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub use shipcat_definitions::{Manifest, Config, Cluster, Region, Team};
+pub use shipcat_definitions::{Manifest, ManifestStatus, Config, Cluster, Region, Team};
 
 /// A small CLI kubernetes interface
 pub mod kube;
-pub use crate::kube::{ManifestMap, ManifestCache};
+pub use crate::kube::{ManifestMap, StatusMap, ManifestCache, Reflector};
+
+/// Dependency graphs from cached manifests
+pub mod graph;
+pub use crate::graph::Graph;
+
+/// Prometheus metrics
+pub mod metrics;
+pub use crate::metrics::Metrics;
 
 
 mod integrations;
@@ -3,22 +3,22 @@
 use log::{info, warn, error, debug};
 use serde_derive::Serialize;
 use tera::compile_templates;
-use failure::err_msg;
+use failure::{err_msg, format_err};
+use serde::de::DeserializeOwned;
 
-use kubernetes::{
-    client::APIClient,
-    config,
-};
+use shipcat::kube::Client;
 
 use std::{
     collections::BTreeMap,
     env,
     sync::{Arc, Mutex},
+    thread,
     time::{Duration, Instant},
 };
-use chrono::Local;
+use chrono::{Local, Utc};
 
 pub use raftcat::*;
+use shipcat_definitions::math::{ResourceBreakdown, ResourceTotals};
 
 // some slug helpers
 fn team_slug(name: &str) -> String {
@@ -29,10 +29,26 @@ fn find_team(cfg: &Config, slug: &str) -> Option<Team> {
 }
 
 
+fn resource_totals(name: &str, mf: &Manifest) -> Result<ResourceTotals> {
+    mf.compute_resource_totals()
+        .map_err(|e| format_err!("Could not compute resources of {}: {}", name, e))
+}
+
+/// Resource usage of a team, split by service
+#[derive(Serialize, Default)]
+struct TeamResources {
+    totals: ResourceTotals,
+    services: BTreeMap<String, ResourceTotals>,
+    /// Services left out of the totals, with the reason
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    errors: BTreeMap<String, String>,
+}
+
 // ----------------------------------------------------------------------------------
 // Web server interface
 use actix_web::{
     server, App, Path, Responder, HttpRequest, HttpResponse, middleware,
+    middleware::{Middleware, Started, Finished},
     http::{header, Method, StatusCode},
 };
 
@@ -43,41 +59,67 @@ struct AppState {
     pub config: Config,
     pub relics: RelicMap,
     pub sentries: SentryMap,
+    /// Versions running in the region, if they could be loaded
+    versions: Option<VersionMap>,
+    /// Number of manifests without a pinned version
+    unpinned: usize,
     region: String,
     last_update: Instant,
 }
 impl AppState {
-    pub fn new(client: &APIClient) -> Result<Self> {
+    pub fn new(manifests: &Reflector<Manifest>, configs: &Reflector<Config>) -> Result<Self> {
         info!("Loading state from CRDs");
         let rname = env::var("REGION_NAME").expect("Need REGION_NAME evar (kube context)");
-        let state = AppState::init_cache(client)?;
-        let config = kube::get_shipcat_config(client, &rname)?.spec;
+        let config = match configs.data.get(&rname) {
+            Some(crd) => crd.spec.clone(),
+            None => return Err(format_err!("No shipcatconfig found for {}", rname)),
+        };
+        let versions = match version::get_all() {
+            Ok(versions) => {
+                info!("Loaded {} versions", versions.len());
+                Some(versions)
+            }
+            Err(e) => {
+                warn!("Unable to load versions. VERSION_URL set? {}", err_msg(e));
+                None
+            }
+        };
         let mut res = AppState {
-            cache: state,
+            cache: ManifestCache::default(),
             config,
             region: rname,
             relics: BTreeMap::new(),
             sentries: BTreeMap::new(),
+            versions,
+            unpinned: 0,
             last_update: Instant::now(),
         };
+        res.set_manifests(manifests);
         res.update_slow_cache()?;
         Ok(res)
     }
 
-    // Helper for init
-    fn init_cache(client: &APIClient) -> Result<ManifestCache> {
-        info!("Initialising state from CRDs");
-        let mut data = kube::get_shipcat_manifests(client)?;
-        match version::get_all() {
-            Ok(versions) => {
-                info!("Loaded {} versions", versions.len());
-                for (k, mf) in &mut data.manifests {
-                    mf.version = versions.get(k).map(String::clone);
-                }
+    /// Replace the manifests with the latest ones from kube
+    fn set_manifests(&mut self, r: &Reflector<Manifest>) {
+        let mut data = ManifestCache::from_reflector(r);
+        self.unpinned = data.manifests.values().filter(|mf| mf.version.is_none()).count();
+        if let Some(versions) = &self.versions {
+            for (k, mf) in &mut data.manifests {
+                mf.version = versions.get(k).map(String::clone);
             }
-            Err(e) => warn!("Unable to load versions. VERSION_URL set? {}", err_msg(e))
         }
-        Ok(data)
+        self.cache = data;
+        self.last_update = Instant::now();
+    }
+    /// Replace the config with the latest one from kube
+    fn set_config(&mut self, r: &Reflector<Config>) {
+        match r.data.get(&self.region) {
+            Some(crd) => {
+                info!("Loaded config for {}", self.region);
+                self.config = crd.spec.clone();
+            }
+            None => warn!("No shipcatconfig found for {}, keeping the old one", self.region),
+        }
     }
     fn update_slow_cache(&mut self) -> Result<()> {
         let cname = None; // TODO: evar
@@ -108,6 +150,9 @@ impl AppState {
         }
         Ok(None)
     }
+    pub fn get_status(&self, key: &str) -> Option<ManifestStatus> {
+        self.cache.statuses.get(key).cloned()
+    }
     pub fn get_manifests_for(&self, team: &str) -> Result<Vec<String>> {
         let mfs = self.cache.manifests.iter()
             .filter(|(_k, mf)| mf.metadata.clone().unwrap().team == team)
@@ -123,6 +168,53 @@ impl AppState {
         }
         Ok(res)
     }
+    /// Resource usage of all services, split by team and priced if the region has prices
+    pub fn get_resources(&self) -> Result<ResourceBreakdown> {
+        let (_, region) = self.get_cluster_region()?;
+        let mut bd = ResourceBreakdown::new(self.config.teams.clone());
+        for (name, mf) in &self.cache.manifests {
+            if let Some(md) = &mf.metadata {
+                match resource_totals(name, mf) {
+                    Ok(tt) => bd.add(&md.team, &tt),
+                    Err(e) => {
+                        warn!("Skipping from resource totals: {}", e);
+                        bd.errors.insert(name.clone(), e.to_string());
+                    }
+                }
+            }
+        }
+        if let Some(prices) = &region.pricing {
+            bd.price(prices);
+        }
+        Ok(bd.normalise())
+    }
+    /// Resource usage of the services of a team, priced if the region has prices
+    pub fn get_team_resources(&self, team: &str) -> Result<TeamResources> {
+        let (_, region) = self.get_cluster_region()?;
+        let mut res = TeamResources::default();
+        for (name, mf) in &self.cache.manifests {
+            if mf.metadata.as_ref().map(|md| md.team.as_str()) == Some(team) {
+                match resource_totals(name, mf) {
+                    Ok(tt) => {
+                        res.totals.add(&tt);
+                        res.services.insert(name.clone(), tt);
+                    },
+                    Err(e) => {
+                        warn!("Skipping from team resources: {}", e);
+                        res.errors.insert(name.clone(), e.to_string());
+                    }
+                }
+            }
+        }
+        for tt in res.services.values_mut().chain(std::iter::once(&mut res.totals)) {
+            if let Some(prices) = &region.pricing {
+                tt.price(prices);
+            }
+            tt.base.round();
+            tt.extra.round();
+        }
+        Ok(res)
+    }
     pub fn get_cluster_region(&self) -> Result<(Cluster, Region)> {
         let cname = None; // TODO: evar
         let (cluster, region) = self.config.resolve_cluster(&self.region, cname).expect("could not resolve cluster");
@@ -145,6 +237,15 @@ fn get_single_manifest(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
         Ok(HttpResponse::NotFound().finish())
     }
 }
+fn get_manifest_status(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
+    let name = req.match_info().get("name").unwrap();
+    let state = req.state().safe.lock().unwrap();
+    if state.cache.manifests.contains_key(name) {
+        Ok(HttpResponse::Ok().json(state.get_status(name).unwrap_or_default()))
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
 fn get_all_manifests(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
     let mfs = req.state().safe.lock().unwrap().get_manifests()?;
     Ok(HttpResponse::Ok().json(mfs))
@@ -152,12 +253,41 @@ fn get_all_manifests(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
 fn get_resource_usage(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
     let name = req.match_info().get("name").unwrap();
     if let Some(mf) = req.state().safe.lock().unwrap().get_manifest(name)? {
-        let totals = mf.compute_resource_totals().unwrap(); // TODO: use 'failure' in shipcat_definitions
+        let totals = resource_totals(name, &mf)?;
         Ok(HttpResponse::Ok().json(totals))
     } else {
         Ok(HttpResponse::NotFound().finish())
     }
 }
+/// Respond with a graph as json, or as graphviz given `?format=dot`
+fn graph_response(req: &HttpRequest<StateSafe>, graph: Graph) -> HttpResponse {
+    match req.query().get("format").map(String::as_str) {
+        Some("dot") => HttpResponse::Ok().content_type("text/vnd.graphviz").body(graph.dot()),
+        _ => HttpResponse::Ok().json(graph),
+    }
+}
+fn get_graph(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
+    let mfs = req.state().safe.lock().unwrap().get_manifests()?;
+    Ok(graph_response(req, Graph::full(&mfs)))
+}
+fn get_service_graph(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
+    let name = req.match_info().get("name").unwrap();
+    let mfs = req.state().safe.lock().unwrap().get_manifests()?;
+    if mfs.contains_key(name) {
+        Ok(graph_response(req, Graph::dependencies(name, &mfs)))
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+fn get_reverse_graph(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
+    let name = req.match_info().get("name").unwrap();
+    let mfs = req.state().safe.lock().unwrap().get_manifests()?;
+    if mfs.contains_key(name) {
+        Ok(graph_response(req, Graph::dependents(name, &mfs)))
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
 fn get_manifests_for_team(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
     let name = req.match_info().get("name").unwrap();
     let cfg = req.state().safe.lock().unwrap().get_config()?;
@@ -168,6 +298,19 @@ fn get_manifests_for_team(req: &HttpRequest<StateSafe>) -> Result<HttpResponse>
         Ok(HttpResponse::NotFound().finish())
     }
 }
+fn get_team_resources(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
+    let name = req.match_info().get("name").unwrap();
+    let state = req.state().safe.lock().unwrap();
+    if let Some(t) = find_team(&state.config, name) {
+        Ok(HttpResponse::Ok().json(state.get_team_resources(&t.name)?))
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+fn get_all_resources(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
+    let bd = req.state().safe.lock().unwrap().get_resources()?;
+    Ok(HttpResponse::Ok().json(bd))
+}
 fn get_teams(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
     let cfg = req.state().safe.lock().unwrap().get_config()?;
     Ok(HttpResponse::Ok().json(cfg.teams.clone()))
@@ -181,6 +324,7 @@ fn get_service(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
     let revdeps = req.state().safe.lock().unwrap().get_reverse_deps(name).ok();
     let newrelic_link = req.state().safe.lock().unwrap().relics.get(name).map(String::to_owned);
     let sentry_slug = req.state().safe.lock().unwrap().sentries.get(name).map(String::to_owned);
+    let status = req.state().safe.lock().unwrap().get_status(name).unwrap_or_default();
 
     if let Some(mf) = req.state().safe.lock().unwrap().get_manifest(name)?.clone() {
         let pretty = serde_yaml::to_string(&mf)?;
@@ -188,7 +332,8 @@ fn get_service(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
         let pretty_stub = serde_yaml::to_string(&mfstub)?;
 
         let md = mf.metadata.clone().unwrap();
-        let (vlink, version) = if let Some(ver) = mf.version.clone() {
+        // prefer what shipcat last rolled out over the declared version
+        let (vlink, version) = if let Some(ver) = status.appliedVersion.clone().or_else(|| mf.version.clone()) {
             if semver::Version::parse(&ver).is_ok() {
                 let tag = md.version_template(&ver).unwrap_or(ver.to_string());
                 (format!("{}/releases/tag/{}", md.repo, tag), tag)
@@ -250,6 +395,11 @@ fn get_service(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
         }
 
         ctx.insert("revdeps", &revdeps);
+        let last_upgrade = match (&status.state, &status.lastReconcile) {
+            (Some(s), Some(t)) => Some(format!("{:?} at {}", s, t)),
+            _ => None,
+        };
+        ctx.insert("last_upgrade", &last_upgrade);
 
         let date = Local::now();
         let time = date.format("%Y-%m-%d %H:%M:%S").to_string();
@@ -263,6 +413,44 @@ fn get_service(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
     }
 }
 
+fn get_metrics(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
+    let mut out = req.state().metrics.lock().unwrap().render();
+    let state = req.state().safe.lock().unwrap();
+    metrics::family(&mut out, "raftcat_cached_manifests", "gauge",
+        "Manifests in the crd cache", &[(String::new(), state.cache.manifests.len() as f64)]);
+    metrics::family(&mut out, "raftcat_unpinned_services", "gauge",
+        "Services without a pinned version in their manifest", &[(String::new(), state.unpinned as f64)]);
+    let mut teams : BTreeMap<String, usize> = state.config.teams.iter().map(|t| (t.name.clone(), 0)).collect();
+    for mf in state.cache.manifests.values() {
+        if let Some(md) = &mf.metadata {
+            *teams.entry(md.team.clone()).or_insert(0) += 1;
+        }
+    }
+    let teams = teams.iter().map(|(t, n)| (metrics::label("team", t), *n as f64)).collect::<Vec<_>>();
+    metrics::family(&mut out, "raftcat_team_services", "gauge", "Services owned by a team", &teams);
+    Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(out))
+}
+
+/// Middleware recording request latencies by route pattern
+struct RequestMetrics;
+struct RequestStart(Instant);
+
+impl Middleware<StateSafe> for RequestMetrics {
+    fn start(&self, req: &HttpRequest<StateSafe>) -> actix_web::Result<Started> {
+        req.extensions_mut().insert(RequestStart(Instant::now()));
+        Ok(Started::Done)
+    }
+    fn finish(&self, req: &HttpRequest<StateSafe>, _resp: &HttpResponse) -> Finished {
+        if let Some(start) = req.extensions().get::<RequestStart>() {
+            // patterns rather than paths to keep the number of series bounded
+            let route = req.resource().rdef().map(|r| r.pattern().to_string()).unwrap_or_else(|| "other".into());
+            let secs = start.0.elapsed().as_secs_f64();
+            req.state().metrics.lock().unwrap().observe_request(&route, secs);
+        }
+        Finished::Done
+    }
+}
+
 fn health(_: &HttpRequest<StateSafe>) -> HttpResponse {
     HttpResponse::Ok().json("healthy")
 }
@@ -298,28 +486,48 @@ fn index(req: &HttpRequest<StateSafe>) -> Result<HttpResponse> {
 #[derive(Clone)]
 struct StateSafe {
     pub safe: Arc<Mutex<AppState>>,
-    pub client: APIClient,
+    pub client: Client,
     pub template: Arc<Mutex<tera::Tera>>,
+    pub metrics: Arc<Mutex<Metrics>>,
 }
 impl StateSafe {
-    pub fn new(client: APIClient) -> Result<Self> {
+    pub fn new(client: Client, manifests: &Reflector<Manifest>, configs: &Reflector<Config>) -> Result<Self> {
         let t = compile_templates!(concat!("raftcat", "/templates/*"));
-        let state = AppState::new(&client)?;
+        let state = AppState::new(manifests, configs)?;
         Ok(StateSafe {
             client,
             safe: Arc::new(Mutex::new(state)),
             template: Arc::new(Mutex::new(t)),
+            metrics: Arc::new(Mutex::new(Metrics::default())),
         })
     }
-    pub fn watch_manifests(&self) -> Result<()> {
-        let old = self.safe.lock().unwrap().cache.clone();
-        let res = kube::watch_for_shipcat_manifest_updates(
-            &self.client,
-            old
-        )?;
-        // lock to update cache
-        self.safe.lock().unwrap().cache = res;
-        Ok(())
+    /// Continuously poll a reflector, and update the state when it changes
+    fn reflect<T>(&self, mut r: Reflector<T>, update: fn(&mut AppState, &Reflector<T>))
+        where T: DeserializeOwned + Clone + Send + 'static
+    {
+        let client = self.client.clone();
+        let safe = self.safe.clone();
+        let metrics = self.metrics.clone();
+        metrics.lock().unwrap().watch_succeeded(r.resource(), Utc::now().timestamp());
+        thread::spawn(move || {
+            loop {
+                match r.poll(&client) {
+                    Ok(changed) => {
+                        metrics.lock().unwrap().watch_succeeded(r.resource(), Utc::now().timestamp());
+                        if changed {
+                            update(&mut safe.lock().unwrap(), &r);
+                        } else {
+                            debug!("State unchanged");
+                        }
+                    }
+                    Err(e) => {
+                        metrics.lock().unwrap().watch_failed(r.resource());
+                        error!("Failed to refresh {}", e);
+                        thread::sleep(Duration::from_secs(10));
+                    }
+                }
+            }
+        });
     }
 }
 
@@ -328,47 +536,46 @@ fn main() -> Result<()> {
     let dsn = env::var("SENTRY_DSN").expect("Sentry DSN required");
     let _guard = sentry::init(dsn); // must keep _guard in scope
 
-    env::set_var("RUST_LOG", "actix_web=info,raftcat=info,kubernetes=info");
+    env::set_var("RUST_LOG", "actix_web=info,raftcat=info,shipcat=info");
     //env::set_var("RUST_LOG", "actix_web=info,raftcat=debug");
     //env::set_var("RUST_BACKTRACE", "full");
     env_logger::init();
 
-    // Load the config: local kube config prioritised first for local development
+    // Load the config: in cluster service account, or the local kube config for development
     // NB: Only supports a config with client certs locally (e.g. kops setup)
-    let cfg = match env::var("HOME").expect("have HOME dir").as_ref() {
-        "/root" => config::incluster_config(),
-        _ => config::load_kube_config(),
-    }.expect("Failed to load kube config");
-
-    let client = APIClient::new(cfg);
-    let state = StateSafe::new(client)?;
-    let state2 = state.clone();
-    // continuously poll for updates
-    use std::thread;
-    thread::spawn(move || {
-        loop {
-            thread::sleep(Duration::from_secs(10));
-            match state2.watch_manifests() {
-                Ok(_) => debug!("State refreshed"),
-                Err(e) => error!("Failed to refresh {}", e),
-            }
-        }
-    });
+    let client = Client::new().map_err(|e| format_err!("Failed to load kube config: {}", e))?;
+    let namespace = env::var("ENV_NAME").expect("Need ENV_NAME evar (namespace)");
+    let manifests = Reflector::<Manifest>::init(&client, &shipcat::kube::shipcatmanifests_path(&namespace))
+        .map_err(|e| format_err!("{}", e))?;
+    let configs = Reflector::<Config>::init(&client, &shipcat::kube::shipcatconfigs_path(&namespace))
+        .map_err(|e| format_err!("{}", e))?;
+    let state = StateSafe::new(client, &manifests, &configs)?;
+    // continuously watch for updates
+    state.reflect(manifests, AppState::set_manifests);
+    state.reflect(configs, AppState::set_config);
 
     info!("Creating http server");
     let sys = actix::System::new("raftcat");
     server::new(move || {
         App::with_state(state.clone())
-            .middleware(middleware::Logger::default().exclude("/raftcat/health"))
+            .middleware(middleware::Logger::default().exclude("/raftcat/health").exclude("/raftcat/metrics"))
+            .middleware(RequestMetrics)
             .middleware(sentry_actix::SentryMiddleware::new())
             .handler("/raftcat/static", actix_web::fs::StaticFiles::new("./raftcat/static").unwrap())
             .resource("/raftcat/config", |r| r.method(Method::GET).f(get_config))
             .resource("/raftcat/manifests/{name}/resources", |r| r.method(Method::GET).f(get_resource_usage))
+            .resource("/raftcat/manifests/{name}/status", |r| r.method(Method::GET).f(get_manifest_status))
             .resource("/raftcat/manifests/{name}", |r| r.method(Method::GET).f(get_single_manifest))
             .resource("/raftcat/manifests", |r| r.method(Method::GET).f(get_all_manifests))
+            .resource("/raftcat/graph/{name}/reverse", |r| r.method(Method::GET).f(get_reverse_graph))
+            .resource("/raftcat/graph/{name}", |r| r.method(Method::GET).f(get_service_graph))
+            .resource("/raftcat/graph", |r| r.method(Method::GET).f(get_graph))
             .resource("/raftcat/services/{name}", |r| r.method(Method::GET).f(get_service))
+            .resource("/raftcat/teams/{name}/resources", |r| r.method(Method::GET).f(get_team_resources))
             .resource("/raftcat/teams/{name}", |r| r.method(Method::GET).f(get_manifests_for_team))
             .resource("/raftcat/teams", |r| r.method(Method::GET).f(get_teams))
+            .resource("/raftcat/resources", |r| r.method(Method::GET).f(get_all_resources))
+            .resource("/raftcat/metrics", |r| r.method(Method::GET).f(get_metrics))
             .resource("/raftcat/health", |r| r.method(Method::GET).f(health))
             .resource("/raftcat/", |r| r.method(Method::GET).f(index))
         })
@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Upper bounds of the request latency buckets in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Latency histogram of a route
+#[derive(Clone, Default)]
+struct Histogram {
+    /// Observations per bucket in `LATENCY_BUCKETS` (not cumulative)
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+/// Operational metrics of raftcat in the prometheus text format
+///
+/// Watches and requests are recorded as they happen,
+/// while gauges about the cached state are computed when scraped.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    /// Unix time of the last successful watch per resource
+    watches: BTreeMap<String, i64>,
+    /// Failed lists or watches per resource
+    watch_errors: BTreeMap<String, u64>,
+    /// Request latencies per route pattern
+    requests: BTreeMap<String, Histogram>,
+}
+
+impl Metrics {
+    pub fn watch_succeeded(&mut self, resource: &str, timestamp: i64) {
+        self.watches.insert(resource.into(), timestamp);
+        self.watch_errors.entry(resource.into()).or_insert(0);
+    }
+
+    pub fn watch_failed(&mut self, resource: &str) {
+        *self.watch_errors.entry(resource.into()).or_insert(0) += 1;
+    }
+
+    pub fn observe_request(&mut self, route: &str, secs: f64) {
+        self.requests.entry(route.into()).or_default().observe(secs);
+    }
+
+    /// Render the recorded metrics
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let watches = self.watches.iter().map(|(r, t)| (label("resource", r), *t as f64)).collect::<Vec<_>>();
+        family(&mut out, "raftcat_last_watch_success_timestamp_seconds", "gauge",
+            "Unix time of the last successful watch of a crd", &watches);
+        let errors = self.watch_errors.iter().map(|(r, n)| (label("resource", r), *n as f64)).collect::<Vec<_>>();
+        family(&mut out, "raftcat_watch_errors_total", "counter",
+            "Failed lists or watches of a crd", &errors);
+
+        let name = "raftcat_http_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Latency of http requests by route", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (route, h) in &self.requests {
+            let route = label("route", route);
+            let mut cumulative = 0;
+            for (bound, n) in LATENCY_BUCKETS.iter().zip(&h.buckets) {
+                cumulative += n;
+                let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, route, bound, cumulative);
+            }
+            let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, route, h.count);
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, route, h.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, route, h.count);
+        }
+        out
+    }
+}
+
+/// A label pair with the value escaped for the text format
+pub fn label(key: &str, value: &str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("{}=\"{}\"", key, value)
+}
+
+/// Append a metric family with one sample per label set (empty for none)
+pub fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{label, Metrics};
+
+    #[test]
+    fn render_metrics() {
+        let mut m = Metrics::default();
+        m.watch_succeeded("shipcatmanifests", 1_540_000_000);
+        m.watch_failed("shipcatconfigs");
+        m.watch_failed("shipcatconfigs");
+        m.observe_request("/raftcat/manifests/{name}", 0.003);
+        m.observe_request("/raftcat/manifests/{name}", 0.2);
+        m.observe_request("/raftcat/manifests/{name}", 30.0);
+        let out = m.render();
+
+        assert!(out.contains("raftcat_last_watch_success_timestamp_seconds{resource=\"shipcatmanifests\"} 1540000000\n"));
+        assert!(out.contains("raftcat_watch_errors_total{resource=\"shipcatmanifests\"} 0\n"));
+        assert!(out.contains("raftcat_watch_errors_total{resource=\"shipcatconfigs\"} 2\n"));
+        let route = "route=\"/raftcat/manifests/{name}\"";
+        assert!(out.contains(&format!("raftcat_http_request_duration_seconds_bucket{{{},le=\"0.005\"}} 1\n", route)));
+        assert!(out.contains(&format!("raftcat_http_request_duration_seconds_bucket{{{},le=\"0.25\"}} 2\n", route)));
+        assert!(out.contains(&format!("raftcat_http_request_duration_seconds_bucket{{{},le=\"10\"}} 2\n", route)));
+        assert!(out.contains(&format!("raftcat_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 3\n", route)));
+        assert!(out.contains(&format!("raftcat_http_request_duration_seconds_count{{{}}} 3\n", route)));
+
+        assert_eq!(label("team", "A \"B\"\\C"), "team=\"A \\\"B\\\"\\\\C\"");
+    }
+}
@@ -9,6 +9,7 @@ use crate::webhooks::UpgradeState;
 use super::{Result, ResultExt, ErrorKind};
 use super::{AuditWebhook};
 use crate::helm::direct::UpgradeData;
+use crate::product::ProductTag;
 
 /// Payload that gets sent via audit webhook
 #[derive(Serialize, Clone)]
@@ -58,6 +59,11 @@ pub struct AuditDeploymentPayload {
     manifests_revision: String,
     service: String,
     version: String,
+    /// Product the deployment is part of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    product_version: Option<String>,
 }
 
 #[derive(Serialize, Clone)]
@@ -72,9 +78,11 @@ impl AuditDeploymentPayload {
     pub fn new(whc: &BTreeMap<String, String>, ud: &UpgradeData) -> Self {
         let (service, region, version) = (ud.name.clone(), ud.region.clone(), ud.version.clone());
         let manifests_revision = whc["SHIPCAT_AUDIT_REVISION"].clone();
+        let product = ud.product.as_ref().map(|p| p.name.clone());
+        let product_version = ud.product.as_ref().map(|p| p.version.clone());
         Self {
             id: format!("{}-{}-{}-{}", manifests_revision, region, service, version),
-            manifests_revision, region, service, version,
+            manifests_revision, region, service, version, product, product_version,
         }
     }
 }
@@ -102,6 +110,33 @@ impl AuditType for AuditReconciliationPayload {
     }
 }
 
+#[derive(Serialize, Clone)]
+pub struct AuditProductPayload {
+    id: String,
+    region: String,
+    /// Eg Git SHA
+    manifests_revision: String,
+    product: String,
+    product_version: String,
+}
+
+impl AuditProductPayload {
+    pub fn new(whc: &BTreeMap<String, String>, r: &str, tag: &ProductTag) -> Self {
+        let manifests_revision = whc["SHIPCAT_AUDIT_REVISION"].clone();
+        let (region, product, product_version) = (r.to_string(), tag.name.clone(), tag.version.clone());
+        Self {
+            id: format!("{}-{}-{}-{}", manifests_revision, region, product, product_version),
+            manifests_revision, region, product, product_version,
+        }
+    }
+}
+
+impl AuditType for AuditProductPayload {
+    fn get_domain_type(&self) -> String {
+        "product".into()
+    }
+}
+
 pub fn audit_deployment(us: &UpgradeState, ud: &UpgradeData, audcfg: &AuditWebhook, whc: BTreeMap<String, String>) -> Result<()> {
     let ae = AuditEvent::new(&whc, &us, AuditDeploymentPayload::new(&whc, &ud));
     audit(ae, &audcfg)
@@ -112,6 +147,11 @@ pub fn audit_reconciliation(us: &UpgradeState, region: &str, audcfg: &AuditWebho
     audit(ae, &audcfg)
 }
 
+pub fn audit_product(us: &UpgradeState, region: &str, tag: &ProductTag, audcfg: &AuditWebhook, whc: BTreeMap<String, String>) -> Result<()> {
+    let ae = AuditEvent::new(&whc, &us, AuditProductPayload::new(&whc, region, tag));
+    audit(ae, &audcfg)
+}
+
 fn audit<T: Serialize + Clone + AuditType>(ae: AuditEvent<T>, audcfg: &AuditWebhook) -> Result<()> {
     let endpoint = &audcfg.url;
     debug!("event status: {}, url: {:?}", serde_json::to_string(&ae.status)?, endpoint);
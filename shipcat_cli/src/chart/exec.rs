@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use super::Result;
+use super::parse::{self, Node, Pipeline, Command, Arg};
+use super::funcs;
+
+/// Maximum nesting of template calls (guards against recursive defines)
+const MAX_DEPTH: usize = 100;
+
+/// Execution state for a set of templates sharing their defines
+#[derive(Default)]
+pub struct Engine {
+    pub defines: BTreeMap<String, Vec<Node>>,
+}
+
+/// Variables and the dot for the template being executed
+struct Scope {
+    vars: Vec<(String, Value)>,
+    depth: usize,
+}
+
+impl Scope {
+    fn lookup(&self, name: &str) -> Result<Value> {
+        match self.vars.iter().rev().find(|(n, _)| n == name) {
+            Some((_, v)) => Ok(v.clone()),
+            None => bail!("undefined variable: {}", name),
+        }
+    }
+
+    fn assign(&mut self, name: &str, val: Value) -> Result<()> {
+        match self.vars.iter_mut().rev().find(|(n, _)| n == name) {
+            Some(v) => {
+                v.1 = val;
+                Ok(())
+            },
+            None => bail!("undefined variable: {}", name),
+        }
+    }
+}
+
+/// Go template truthiness
+pub fn truth(v: &Value) -> bool {
+    match v {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().map(|f| f != 0.0).unwrap_or(false),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+    }
+}
+
+/// Resolve a field chain on a value
+fn field(v: Value, chain: &[String]) -> Result<Value> {
+    let mut cur = v;
+    for f in chain {
+        cur = match cur {
+            Value::Object(mut o) => o.remove(f).unwrap_or(Value::Null),
+            Value::Null => bail!("nil pointer evaluating interface {{}}.{}", f),
+            other => bail!("can't evaluate field {} in type {}", f, funcs::type_name(&other)),
+        };
+    }
+    Ok(cur)
+}
+
+impl Engine {
+    /// Register the defines of a parsed template
+    pub fn add(&mut self, parsed: &parse::Parsed) {
+        for (k, v) in &parsed.defines {
+            self.defines.insert(k.clone(), v.clone());
+        }
+    }
+
+    /// Register a template file by its path along with its defines
+    ///
+    /// Lets charts `include` whole files (e.g. for config checksums).
+    pub fn add_file(&mut self, name: &str, parsed: &parse::Parsed) {
+        self.add(parsed);
+        self.defines.insert(name.into(), parsed.nodes.clone());
+    }
+
+    /// Execute a list of nodes with a dot
+    pub fn execute(&self, nodes: &[Node], dot: &Value) -> Result<String> {
+        let mut scope = Scope { vars: vec![("$".into(), dot.clone())], depth: 0 };
+        let mut out = String::new();
+        self.walk(nodes, dot, &mut scope, &mut out)?;
+        Ok(out)
+    }
+
+    /// Execute a named define (`include` and `template`)
+    pub fn include(&self, name: &str, dot: &Value, depth: usize) -> Result<String> {
+        if depth > MAX_DEPTH {
+            bail!("exceeded max template depth calling {}", name);
+        }
+        let nodes = self.defines.get(name).ok_or_else(|| format!("no template \"{}\" associated with template", name))?;
+        let mut scope = Scope { vars: vec![("$".into(), dot.clone())], depth: depth + 1 };
+        let mut out = String::new();
+        self.walk(nodes, dot, &mut scope, &mut out)?;
+        Ok(out)
+    }
+
+    /// Render a string as a template (`tpl`)
+    pub fn tpl(&self, src: &str, dot: &Value, depth: usize) -> Result<String> {
+        let parsed = parse::parse(src)?;
+        let mut scope = Scope { vars: vec![("$".into(), dot.clone())], depth: depth + 1 };
+        let mut out = String::new();
+        self.walk(&parsed.nodes, dot, &mut scope, &mut out)?;
+        Ok(out)
+    }
+
+    fn walk(&self, nodes: &[Node], dot: &Value, scope: &mut Scope, out: &mut String) -> Result<()> {
+        // variables declared in a block go out of scope at its end
+        let mark = scope.vars.len();
+        for n in nodes {
+            match n {
+                Node::Text(t) => out.push_str(t),
+                Node::Action(p) => {
+                    let v = self.pipeline(p, dot, scope)?;
+                    if p.decl.is_empty() {
+                        out.push_str(&funcs::print(&v));
+                    }
+                },
+                Node::If(branches, alt) => {
+                    let mut done = false;
+                    for (cond, body) in branches {
+                        if truth(&self.pipeline(cond, dot, scope)?) {
+                            self.walk(body, dot, scope, out)?;
+                            done = true;
+                            break;
+                        }
+                    }
+                    if let (false, Some(alt)) = (done, alt) {
+                        self.walk(alt, dot, scope, out)?;
+                    }
+                },
+                Node::With(p, body, alt) => {
+                    let v = self.pipeline(p, dot, scope)?;
+                    if truth(&v) {
+                        self.walk(body, &v, scope, out)?;
+                    } else if let Some(alt) = alt {
+                        self.walk(alt, dot, scope, out)?;
+                    }
+                },
+                Node::Range(p, body, alt) => {
+                    // declarations on range bind per iteration, not to the pipeline result
+                    let decl = p.decl.clone();
+                    let mut pipe = p.clone();
+                    pipe.decl = vec![];
+                    let v = self.pipeline(&pipe, dot, scope)?;
+                    let items : Vec<(Value, Value)> = match v {
+                        Value::Array(a) => a.into_iter().enumerate().map(|(i, x)| (Value::from(i as i64), x)).collect(),
+                        Value::Object(o) => o.into_iter().map(|(k, x)| (Value::String(k), x)).collect(),
+                        Value::Null => vec![],
+                        Value::Number(n) => {
+                            let n = n.as_i64().unwrap_or(0);
+                            (0..n).map(|i| (Value::from(i), Value::from(i))).collect()
+                        },
+                        other => bail!("range can't iterate over {}", funcs::print(&other)),
+                    };
+                    if items.is_empty() {
+                        if let Some(alt) = alt {
+                            self.walk(alt, dot, scope, out)?;
+                        }
+                    }
+                    for (k, x) in items {
+                        let inner = scope.vars.len();
+                        match decl.len() {
+                            0 => {},
+                            1 => scope.vars.push((decl[0].clone(), x.clone())),
+                            _ => {
+                                scope.vars.push((decl[0].clone(), k));
+                                scope.vars.push((decl[1].clone(), x.clone()));
+                            }
+                        }
+                        self.walk(body, &x, scope, out)?;
+                        scope.vars.truncate(inner);
+                    }
+                },
+                Node::Template(name, p) => {
+                    let arg = match p {
+                        Some(p) => self.pipeline(p, dot, scope)?,
+                        None => Value::Null,
+                    };
+                    out.push_str(&self.include(name, &arg, scope.depth)?);
+                },
+            }
+        }
+        scope.vars.truncate(mark);
+        Ok(())
+    }
+
+    fn pipeline(&self, p: &Pipeline, dot: &Value, scope: &mut Scope) -> Result<Value> {
+        let mut last : Option<Value> = None;
+        for cmd in &p.cmds {
+            last = Some(self.command(cmd, dot, scope, last)?);
+        }
+        let v = last.unwrap_or(Value::Null);
+        if p.assign {
+            for d in &p.decl {
+                scope.assign(d, v.clone())?;
+            }
+        } else {
+            for d in &p.decl {
+                scope.vars.push((d.clone(), v.clone()));
+            }
+        }
+        Ok(v)
+    }
+
+    fn arg(&self, a: &Arg, dot: &Value, scope: &mut Scope) -> Result<Value> {
+        let v = match a {
+            Arg::Field(chain) => field(dot.clone(), chain)?,
+            Arg::Var(name, chain) => field(scope.lookup(name)?, chain)?,
+            Arg::Pipe(p, chain) => {
+                let v = self.pipeline(p, dot, scope)?;
+                field(v, chain)?
+            },
+            Arg::Str(s) => Value::String(s.clone()),
+            Arg::Int(n) => Value::from(*n),
+            Arg::Float(f) => Value::from(*f),
+            Arg::Bool(b) => Value::Bool(*b),
+            Arg::Nil => Value::Null,
+            Arg::Ident(f) => self.call(f, vec![], scope.depth)?, // niladic function
+        };
+        Ok(v)
+    }
+
+    fn command(&self, cmd: &Command, dot: &Value, scope: &mut Scope, piped: Option<Value>) -> Result<Value> {
+        if let Arg::Ident(f) = &cmd.args[0] {
+            let mut args = vec![];
+            for a in &cmd.args[1..] {
+                args.push(self.arg(a, dot, scope)?);
+            }
+            if let Some(p) = piped {
+                args.push(p);
+            }
+            return self.call(f, args, scope.depth);
+        }
+        if cmd.args.len() > 1 || piped.is_some() {
+            bail!("can't give argument to non-function {:?}", cmd.args[0]);
+        }
+        self.arg(&cmd.args[0], dot, scope)
+    }
+
+    fn call(&self, f: &str, args: Vec<Value>, depth: usize) -> Result<Value> {
+        match f {
+            "include" => {
+                let name = funcs::string_arg(&args, 0, f)?;
+                let data = args.get(1).cloned().unwrap_or(Value::Null);
+                Ok(Value::String(self.include(&name, &data, depth)?))
+            },
+            "tpl" => {
+                let src = funcs::string_arg(&args, 0, f)?;
+                let data = args.get(1).cloned().unwrap_or(Value::Null);
+                Ok(Value::String(self.tpl(&src, &data, depth)?))
+            },
+            _ => funcs::call(f, args),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Engine;
+    use super::super::parse::parse;
+    use serde_json::json;
+
+    fn render(engine: &Engine, src: &str, dot: serde_json::Value) -> String {
+        engine.execute(&parse(src).unwrap().nodes, &dot).unwrap()
+    }
+
+    #[test]
+    fn exec_scopes() {
+        let e = Engine::default();
+        let dot = json!({"a": {"b": "x"}, "list": ["p", "q"], "m": {"z": 1, "y": 2}, "empty": []});
+        assert_eq!(render(&e, "{{ with .a }}{{ .b }}{{ else }}none{{ end }}", dot.clone()), "x");
+        assert_eq!(render(&e, "{{ with .missing }}{{ .b }}{{ else }}none{{ end }}", dot.clone()), "none");
+        assert_eq!(render(&e, "{{ range $i, $v := .list }}{{ $i }}={{ $v }},{{ end }}", dot.clone()), "0=p,1=q,");
+        // maps are iterated in key order
+        assert_eq!(render(&e, "{{ range $k, $v := .m }}{{ $k }}{{ $v }}{{ end }}", dot.clone()), "y2z1");
+        assert_eq!(render(&e, "{{ range .empty }}x{{ else }}empty{{ end }}", dot.clone()), "empty");
+        // $ is the root inside blocks, and assignment updates the outer variable
+        assert_eq!(render(&e, "{{ $x := 1 }}{{ with .a }}{{ $x = 2 }}{{ $.a.b }}{{ end }}{{ $x }}", dot.clone()), "x2");
+
+        let err = e.execute(&parse("{{ with .a }}{{ $y := 1 }}{{ end }}{{ $y }}").unwrap().nodes, &dot).unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable: $y");
+        let err = e.execute(&parse("{{ .missing.b }}").unwrap().nodes, &dot).unwrap_err();
+        assert_eq!(err.to_string(), "nil pointer evaluating interface {}.b");
+    }
+
+    #[test]
+    fn exec_includes() {
+        let mut e = Engine::default();
+        let helpers = parse(r#"{{- define "name" -}}{{ .name | upper }}{{- end -}}{{- define "loop" }}{{ include "loop" . }}{{ end -}}"#).unwrap();
+        e.add(&helpers);
+        e.add_file("base/templates/configmap.yaml", &parse("cfg: {{ .name }}").unwrap());
+        let dot = json!({"name": "webapp"});
+
+        assert_eq!(render(&e, r#"{{ template "name" . }}-{{ include "name" . | lower }}"#, dot.clone()), "WEBAPP-webapp");
+        assert_eq!(render(&e, r#"{{ include "base/templates/configmap.yaml" . }}"#, dot.clone()), "cfg: webapp");
+        assert_eq!(render(&e, r#"{{ tpl "{{ .name }}!" . }}"#, dot.clone()), "webapp!");
+
+        let err = e.execute(&parse(r#"{{ include "nope" . }}"#).unwrap().nodes, &dot).unwrap_err();
+        assert_eq!(err.to_string(), "no template \"nope\" associated with template");
+        let err = e.execute(&parse(r#"{{ include "loop" . }}"#).unwrap().nodes, &dot).unwrap_err();
+        assert_eq!(err.to_string(), "exceeded max template depth calling loop");
+    }
+}
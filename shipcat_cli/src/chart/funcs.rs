@@ -0,0 +1,462 @@
+use std::cmp::Ordering;
+
+use serde_json::{Map, Value};
+use sha2::{Sha256, Digest};
+
+use super::Result;
+use super::exec::truth;
+use super::yaml;
+
+/// Go type names used in error messages
+pub fn type_name(v: &Value) -> &'static str {
+    match v {
+        Value::Null => "<nil>",
+        Value::Bool(_) => "bool",
+        Value::Number(n) if n.is_f64() => "float64",
+        Value::Number(_) => "int",
+        Value::String(_) => "string",
+        Value::Array(_) => "[]interface {}",
+        Value::Object(_) => "map[string]interface {}",
+    }
+}
+
+/// Format a float like Go's `%v` (strconv 'g' with shortest precision)
+pub fn go_float(f: f64) -> String {
+    if f.is_nan() {
+        return "NaN".into();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "+Inf".into() } else { "-Inf".into() };
+    }
+    if f == 0.0 {
+        return "0".into();
+    }
+    // shortest round-trip digits via the exponent formatting
+    let e = format!("{:e}", f);
+    let (mantissa, exp) = e.split_at(e.find('e').unwrap());
+    let exp : i32 = exp[1..].parse().unwrap();
+    if !(-4..6).contains(&exp) {
+        // %e style with at least two exponent digits
+        let sign = if exp < 0 { "-" } else { "+" };
+        return format!("{}e{}{:02}", mantissa, sign, exp.abs());
+    }
+    format!("{}", f)
+}
+
+/// Go's `%v` formatting of a value (missing values print as empty)
+pub fn print(v: &Value) -> String {
+    match v {
+        Value::Null => "".into(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                if !n.is_f64() {
+                    return i.to_string();
+                }
+            }
+            if let Some(u) = n.as_u64() {
+                if !n.is_f64() {
+                    return u.to_string();
+                }
+            }
+            go_float(n.as_f64().unwrap_or(0.0))
+        },
+        Value::String(s) => s.clone(),
+        Value::Array(a) => format!("[{}]", a.iter().map(print_nested).collect::<Vec<_>>().join(" ")),
+        Value::Object(o) => format!("map[{}]", o.iter()
+            .map(|(k, v)| format!("{}:{}", k, print_nested(v)))
+            .collect::<Vec<_>>().join(" ")),
+    }
+}
+
+// nil inside collections prints like go does
+fn print_nested(v: &Value) -> String {
+    match v {
+        Value::Null => "<nil>".into(),
+        x => print(x),
+    }
+}
+
+/// Go's `%q` quoting of a string
+pub fn go_quote(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => res.push_str(&format!("\\x{:02x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Go's `json.Marshal` (html characters are escaped)
+pub fn go_json(v: &Value) -> Result<String> {
+    let s = serde_json::to_string(v)?;
+    Ok(s.replace('<', "\\u003c").replace('>', "\\u003e").replace('&', "\\u0026"))
+}
+
+pub fn string_arg(args: &[Value], i: usize, f: &str) -> Result<String> {
+    match args.get(i) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(v) => Ok(print(v)),
+        None => bail!("wrong number of args for {}: want at least {} got {}", f, i + 1, args.len()),
+    }
+}
+
+fn int_arg(args: &[Value], i: usize, f: &str) -> Result<i64> {
+    match args.get(i) {
+        Some(v) => Ok(to_int(v)),
+        None => bail!("wrong number of args for {}: want at least {} got {}", f, i + 1, args.len()),
+    }
+}
+
+fn to_int(v: &Value) -> i64 {
+    match v {
+        Value::Number(n) => n.as_i64().unwrap_or_else(|| n.as_f64().unwrap_or(0.0) as i64),
+        Value::String(s) => s.trim().parse().unwrap_or(0),
+        Value::Bool(true) => 1,
+        _ => 0,
+    }
+}
+
+fn expect_args(args: &[Value], n: usize, f: &str) -> Result<()> {
+    if args.len() != n {
+        bail!("wrong number of args for {}: want {} got {}", f, n, args.len());
+    }
+    Ok(())
+}
+
+/// Sprig's notion of empty
+fn empty(v: &Value) -> bool {
+    !truth(v)
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64()?.partial_cmp(&y.as_f64()?),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    compare(a, b) == Some(Ordering::Equal)
+}
+
+fn indent(n: i64, s: &str) -> String {
+    let pad = " ".repeat(n.max(0) as usize);
+    format!("{}{}", pad, s.replace("\n", &format!("\n{}", pad)))
+}
+
+fn title(s: &str) -> String {
+    let mut res = String::new();
+    let mut prev_letter = false;
+    for c in s.chars() {
+        if !prev_letter {
+            res.extend(c.to_uppercase());
+        } else {
+            res.push(c);
+        }
+        prev_letter = c.is_alphanumeric() || c == '_' || c == '\'';
+    }
+    res
+}
+
+fn trunc(n: i64, s: &str) -> String {
+    let chars : Vec<char> = s.chars().collect();
+    let len = chars.len() as i64;
+    if n < 0 && len + n > 0 {
+        chars[(len + n) as usize..].iter().collect()
+    } else if n >= 0 && len > n {
+        chars[..n as usize].iter().collect()
+    } else {
+        s.to_string()
+    }
+}
+
+/// Go's `fmt.Sprintf` for the verbs used in charts
+fn sprintf(fmt: &str, args: &[Value]) -> String {
+    let mut res = String::new();
+    let mut chars = fmt.chars();
+    let mut i = 0;
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            res.push(c);
+            continue;
+        }
+        let verb = match chars.next() {
+            Some(v) => v,
+            None => {
+                res.push_str("%!(NOVERB)");
+                break;
+            }
+        };
+        if verb == '%' {
+            res.push('%');
+            continue;
+        }
+        let arg = match args.get(i) {
+            Some(a) => a,
+            None => {
+                res.push_str(&format!("%!{}(MISSING)", verb));
+                continue;
+            }
+        };
+        i += 1;
+        match verb {
+            'd' => res.push_str(&to_int(arg).to_string()),
+            'q' => res.push_str(&go_quote(&print(arg))),
+            _ => res.push_str(&print(arg)), // %s and %v
+        }
+    }
+    res
+}
+
+/// Deep merge of a map into another without overriding existing keys
+fn merge_into(dst: &mut Map<String, Value>, src: &Map<String, Value>) {
+    for (k, v) in src {
+        match (dst.get_mut(k), v) {
+            (Some(Value::Object(d)), Value::Object(s)) => merge_into(d, s),
+            (Some(_), _) => {},
+            (None, _) => {
+                dst.insert(k.clone(), v.clone());
+            },
+        }
+    }
+}
+
+/// Call a template function by name
+pub fn call(f: &str, args: Vec<Value>) -> Result<Value> {
+    let res = match f {
+        // comparisons and logic
+        "eq" => {
+            if args.len() < 2 {
+                bail!("missing argument for comparison");
+            }
+            Value::Bool(args[1..].iter().any(|b| equal(&args[0], b)))
+        },
+        "ne" => {
+            expect_args(&args, 2, f)?;
+            Value::Bool(!equal(&args[0], &args[1]))
+        },
+        "lt" | "le" | "gt" | "ge" => {
+            expect_args(&args, 2, f)?;
+            let ord = compare(&args[0], &args[1]).ok_or_else(|| format!("incompatible types for comparison in {}", f))?;
+            Value::Bool(match f {
+                "lt" => ord == Ordering::Less,
+                "le" => ord != Ordering::Greater,
+                "gt" => ord == Ordering::Greater,
+                _ => ord != Ordering::Less,
+            })
+        },
+        "and" => args.iter().find(|a| !truth(a)).or_else(|| args.last()).cloned().unwrap_or(Value::Null),
+        "or" => args.iter().find(|a| truth(a)).or_else(|| args.last()).cloned().unwrap_or(Value::Null),
+        "not" => {
+            expect_args(&args, 1, f)?;
+            Value::Bool(!truth(&args[0]))
+        },
+        "empty" => {
+            expect_args(&args, 1, f)?;
+            Value::Bool(empty(&args[0]))
+        },
+        "default" => {
+            let d = args.first().cloned().unwrap_or(Value::Null);
+            match args.get(1) {
+                Some(v) if !empty(v) => v.clone(),
+                _ => d,
+            }
+        },
+        "coalesce" => args.iter().find(|a| !empty(a)).cloned().unwrap_or(Value::Null),
+        "required" => {
+            expect_args(&args, 2, f)?;
+            if args[1].is_null() || args[1] == Value::String("".into()) {
+                bail!("{}", string_arg(&args, 0, f)?);
+            }
+            args[1].clone()
+        },
+        "fail" => bail!("{}", string_arg(&args, 0, f)?),
+
+        // strings
+        "quote" => Value::String(args.iter().filter(|a| !a.is_null())
+            .map(|a| go_quote(&print(a))).collect::<Vec<_>>().join(" ")),
+        "squote" => Value::String(args.iter().filter(|a| !a.is_null())
+            .map(|a| format!("'{}'", print(a))).collect::<Vec<_>>().join(" ")),
+        "upper" => Value::String(string_arg(&args, 0, f)?.to_uppercase()),
+        "lower" => Value::String(string_arg(&args, 0, f)?.to_lowercase()),
+        "title" => Value::String(title(&string_arg(&args, 0, f)?)),
+        "trim" => Value::String(string_arg(&args, 0, f)?.trim().to_string()),
+        "trimSuffix" => {
+            let (suffix, s) = (string_arg(&args, 0, f)?, string_arg(&args, 1, f)?);
+            Value::String(s.strip_suffix(suffix.as_str()).map(String::from).unwrap_or_else(|| s.clone()))
+        },
+        "trimPrefix" => {
+            let (prefix, s) = (string_arg(&args, 0, f)?, string_arg(&args, 1, f)?);
+            Value::String(s.strip_prefix(prefix.as_str()).map(String::from).unwrap_or_else(|| s.clone()))
+        },
+        "trunc" => Value::String(trunc(int_arg(&args, 0, f)?, &string_arg(&args, 1, f)?)),
+        "replace" => {
+            let (old, new, s) = (string_arg(&args, 0, f)?, string_arg(&args, 1, f)?, string_arg(&args, 2, f)?);
+            Value::String(s.replace(&old, &new))
+        },
+        "contains" => Value::Bool(string_arg(&args, 1, f)?.contains(&string_arg(&args, 0, f)?)),
+        "hasPrefix" => Value::Bool(string_arg(&args, 1, f)?.starts_with(&string_arg(&args, 0, f)?)),
+        "hasSuffix" => Value::Bool(string_arg(&args, 1, f)?.ends_with(&string_arg(&args, 0, f)?)),
+        "indent" => Value::String(indent(int_arg(&args, 0, f)?, &string_arg(&args, 1, f)?)),
+        "nindent" => Value::String(format!("\n{}", indent(int_arg(&args, 0, f)?, &string_arg(&args, 1, f)?))),
+        "printf" => {
+            let fmt = string_arg(&args, 0, f)?;
+            Value::String(sprintf(&fmt, &args[1..]))
+        },
+        "print" => {
+            // operands are separated by spaces when neither side is a string
+            let mut res = String::new();
+            for (i, a) in args.iter().enumerate() {
+                if i > 0 && !a.is_string() && !args[i-1].is_string() {
+                    res.push(' ');
+                }
+                res.push_str(&print(a));
+            }
+            Value::String(res)
+        },
+        "toString" => Value::String(string_arg(&args, 0, f)?),
+        "b64enc" => Value::String(base64::encode(string_arg(&args, 0, f)?.as_bytes())),
+        "sha256sum" => {
+            let mut hasher = Sha256::new();
+            hasher.input(string_arg(&args, 0, f)?.as_bytes());
+            Value::String(format!("{:x}", hasher.result()))
+        },
+        "b64dec" => {
+            let raw = base64::decode(string_arg(&args, 0, f)?.as_bytes()).map_err(|e| e.to_string())?;
+            Value::String(String::from_utf8_lossy(&raw).into())
+        },
+        "join" => {
+            let sep = string_arg(&args, 0, f)?;
+            match args.get(1) {
+                Some(Value::Array(a)) => Value::String(a.iter().map(print).collect::<Vec<_>>().join(&sep)),
+                Some(v) => Value::String(print(v)),
+                None => bail!("wrong number of args for join: want 2 got {}", args.len()),
+            }
+        },
+
+        // numbers
+        "int" | "int64" => Value::from(to_int(args.first().unwrap_or(&Value::Null))),
+        "float64" => Value::from(args.first().and_then(|a| match a {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        }).unwrap_or(0.0)),
+        "add" => Value::from(args.iter().map(to_int).sum::<i64>()),
+        "sub" => Value::from(int_arg(&args, 0, f)? - int_arg(&args, 1, f)?),
+        "mul" => Value::from(args.iter().map(to_int).product::<i64>()),
+        "div" => {
+            let d = int_arg(&args, 1, f)?;
+            if d == 0 {
+                bail!("division by zero");
+            }
+            Value::from(int_arg(&args, 0, f)? / d)
+        },
+
+        // collections
+        "list" => Value::Array(args),
+        "dict" => {
+            let mut m = Map::new();
+            for pair in args.chunks(2) {
+                let k = print(&pair[0]);
+                m.insert(k, pair.get(1).cloned().unwrap_or(Value::Null));
+            }
+            Value::Object(m)
+        },
+        "merge" => {
+            // keys already in the destination win, like sprig's merge
+            let mut dst = match args.first() {
+                Some(Value::Object(o)) => o.clone(),
+                _ => bail!("wrong type for value; expected map[string]interface {{}} in {}", f),
+            };
+            for src in args.iter().skip(1).filter_map(Value::as_object) {
+                merge_into(&mut dst, src);
+            }
+            Value::Object(dst)
+        },
+        "hasKey" => {
+            expect_args(&args, 2, f)?;
+            let k = string_arg(&args, 1, f)?;
+            Value::Bool(args[0].as_object().map(|o| o.contains_key(&k)).unwrap_or(false))
+        },
+        "keys" => {
+            let mut ks : Vec<Value> = args.iter()
+                .filter_map(|a| a.as_object())
+                .flat_map(|o| o.keys().cloned().map(Value::String))
+                .collect();
+            ks.sort_by_key(print);
+            Value::Array(ks)
+        },
+        "len" => {
+            expect_args(&args, 1, f)?;
+            let len = match &args[0] {
+                Value::String(s) => s.len(),
+                Value::Array(a) => a.len(),
+                Value::Object(o) => o.len(),
+                v => bail!("len of type {}", type_name(v)),
+            };
+            Value::from(len as i64)
+        },
+
+        // serialization
+        "toYaml" => {
+            // helm trims the final newline from the marshalled output
+            let y = yaml::to_string(args.first().unwrap_or(&Value::Null));
+            Value::String(y.strip_suffix('\n').unwrap_or(&y).to_string())
+        },
+        "toJson" => Value::String(go_json(args.first().unwrap_or(&Value::Null))?),
+        _ => bail!("function \"{}\" not defined", f),
+    };
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{call, go_float, print};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn go_number_formatting() {
+        assert_eq!(go_float(3.0), "3");
+        assert_eq!(go_float(1.5), "1.5");
+        assert_eq!(go_float(100000.0), "100000");
+        assert_eq!(go_float(1000000.0), "1e+06");
+        assert_eq!(go_float(123456789.0), "1.23456789e+08");
+        assert_eq!(go_float(0.0001), "0.0001");
+        assert_eq!(go_float(0.00001), "1e-05");
+        assert_eq!(print(&Value::from(10)), "10");
+    }
+
+    #[test]
+    fn sprig_functions() {
+        let s = |v: Value| v.as_str().unwrap().to_string();
+        assert_eq!(s(call("sha256sum", vec![json!("")]).unwrap()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(s(call("default", vec![json!("d"), json!("")]).unwrap()), "d");
+        assert_eq!(s(call("default", vec![json!("d"), json!("v")]).unwrap()), "v");
+        assert_eq!(s(call("quote", vec![json!("a\"b")]).unwrap()), "\"a\\\"b\"");
+        assert_eq!(s(call("trunc", vec![json!(3), json!("abcdef")]).unwrap()), "abc");
+        assert_eq!(s(call("printf", vec![json!("%s-%d"), json!("a"), json!(2.0)]).unwrap()), "a-2");
+        assert_eq!(s(call("b64enc", vec![json!("postgres://")]).unwrap()), "cG9zdGdyZXM6Ly8=");
+        assert_eq!(s(call("nindent", vec![json!(2), json!("a\nb")]).unwrap()), "\n  a\n  b");
+
+        let merged = call("merge", vec![json!({"a": 1, "m": {"x": 1}}), json!({"a": 2, "b": 2, "m": {"x": 2, "y": 2}})]).unwrap();
+        assert_eq!(merged, json!({"a": 1, "b": 2, "m": {"x": 1, "y": 2}}));
+        assert_eq!(call("dict", vec![json!("k"), json!(1)]).unwrap(), json!({"k": 1}));
+        assert_eq!(call("eq", vec![json!(1), json!(1.0)]).unwrap(), json!(true));
+
+        assert_eq!(call("nope", vec![]).unwrap_err().to_string(), "function \"nope\" not defined");
+        assert!(call("merge", vec![json!("a")]).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value};
+use serde_yaml;
+
+use super::{Result, ResultExt, Manifest, Config, Region, HelmBackend};
+
+/// Go template parsing
+mod parse;
+/// Go template execution
+mod exec;
+/// Go template builtins and the sprig functions our charts use
+mod funcs;
+/// go-yaml compatible serialization for `toYaml`
+mod yaml;
+
+use self::exec::Engine;
+
+/// Helm 2's install order by kind (used to sort rendered templates)
+const INSTALL_ORDER: [&str; 27] = [
+    "Namespace",
+    "ResourceQuota",
+    "LimitRange",
+    "PodSecurityPolicy",
+    "PodDisruptionBudget",
+    "Secret",
+    "ConfigMap",
+    "StorageClass",
+    "PersistentVolume",
+    "PersistentVolumeClaim",
+    "ServiceAccount",
+    "CustomResourceDefinition",
+    "ClusterRole",
+    "ClusterRoleBinding",
+    "Role",
+    "RoleBinding",
+    "Service",
+    "DaemonSet",
+    "Pod",
+    "ReplicationController",
+    "ReplicaSet",
+    "Deployment",
+    "StatefulSet",
+    "Job",
+    "CronJob",
+    "Ingress",
+    "APIService",
+];
+
+/// A chart loaded from the `charts` directory
+pub struct Chart {
+    /// Name from Chart.yaml
+    pub name: String,
+    /// Chart.yaml with keys capitalised as helm exposes them in `.Chart`
+    pub metadata: Value,
+    /// Default values from values.yaml
+    pub values: Value,
+    /// Template sources keyed by their path relative to `templates`
+    pub templates: BTreeMap<String, String>,
+}
+
+fn read_templates(dir: &Path, prefix: &str, res: &mut BTreeMap<String, String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let pth = entry?.path();
+        let fname = pth.file_name().unwrap().to_string_lossy().to_string();
+        let key = if prefix.is_empty() { fname } else { format!("{}/{}", prefix, fname) };
+        if pth.is_dir() {
+            read_templates(&pth, &key, res)?;
+        } else {
+            res.insert(key, fs::read_to_string(&pth)?);
+        }
+    }
+    Ok(())
+}
+
+/// Convert all numbers to floats like helm's yaml parsing does
+fn normalize(v: Value) -> Value {
+    match v {
+        Value::Number(n) => n.as_f64().map(Value::from).unwrap_or(Value::Null),
+        Value::Array(a) => Value::Array(a.into_iter().map(normalize).collect()),
+        Value::Object(o) => Value::Object(o.into_iter().map(|(k, x)| (k, normalize(x))).collect()),
+        x => x,
+    }
+}
+
+impl Chart {
+    /// Load a chart from `charts/{name}`
+    pub fn load(name: &str) -> Result<Chart> {
+        let dir = Path::new(".").join("charts").join(name);
+        Chart::from_dir(&dir).chain_err(|| format!("Failed to load chart {}", name))
+    }
+
+    fn from_dir(dir: &Path) -> Result<Chart> {
+        let meta : BTreeMap<String, serde_yaml::Value> = serde_yaml::from_str(&fs::read_to_string(dir.join("Chart.yaml"))?)?;
+        let mut metadata = Map::new();
+        for (k, v) in meta {
+            let mut key = k;
+            if let Some(c) = key.get_mut(0..1) {
+                c.make_ascii_uppercase();
+            }
+            metadata.insert(key, normalize(serde_json::to_value(v)?));
+        }
+        let name = metadata.get("Name").and_then(Value::as_str)
+            .ok_or_else(|| format!("Chart.yaml in {} has no name", dir.display()))?
+            .to_string();
+
+        let vpth = dir.join("values.yaml");
+        let values = if vpth.is_file() {
+            let v : serde_yaml::Value = serde_yaml::from_str(&fs::read_to_string(&vpth)?)?;
+            normalize(serde_json::to_value(v)?)
+        } else {
+            Value::Object(Map::new())
+        };
+
+        let mut templates = BTreeMap::new();
+        let tdir = dir.join("templates");
+        if tdir.is_dir() {
+            read_templates(&tdir, "", &mut templates)?;
+        }
+        Ok(Chart { name, metadata: Value::Object(metadata), values, templates })
+    }
+
+    /// Render all templates against the supplied values
+    ///
+    /// Returns rendered content keyed by template path (e.g. `base/templates/service.yaml`).
+    pub fn render(&self, values: Value, release: Value) -> Result<BTreeMap<String, String>> {
+        let vals = coalesce(values, &self.values);
+        let base = format!("{}/templates", self.name);
+        let mut engine = Engine::default();
+        let mut parsed = BTreeMap::new();
+        for (f, src) in &self.templates {
+            let tname = format!("{}/{}", base, f);
+            let p = parse::parse(src).chain_err(|| format!("Failed to parse {}", tname))?;
+            engine.add_file(&tname, &p);
+            parsed.insert(tname, p);
+        }
+        let mut res = BTreeMap::new();
+        for (tname, p) in &parsed {
+            let mut data = Map::new();
+            data.insert("Values".into(), vals.clone());
+            data.insert("Release".into(), release.clone());
+            data.insert("Chart".into(), self.metadata.clone());
+            let mut tpl = Map::new();
+            tpl.insert("Name".into(), Value::String(tname.clone()));
+            tpl.insert("BasePath".into(), Value::String(base.clone()));
+            data.insert("Template".into(), Value::Object(tpl));
+            let out = engine.execute(&p.nodes, &Value::Object(data))
+                .chain_err(|| format!("Failed to render {}", tname))?;
+            res.insert(tname.clone(), out);
+        }
+        Ok(res)
+    }
+}
+
+/// Coalesce chart defaults under user supplied values
+///
+/// User values win, maps are merged, and a null user value removes a chart default.
+fn coalesce(user: Value, defaults: &Value) -> Value {
+    match (user, defaults) {
+        (Value::Object(mut u), Value::Object(d)) => {
+            for (k, dv) in d {
+                let merged = match u.remove(k) {
+                    Some(Value::Null) => None,
+                    Some(uv) => Some(coalesce(uv, dv)),
+                    None => Some(dv.clone()),
+                };
+                if let Some(v) = merged {
+                    u.insert(k.clone(), v);
+                }
+            }
+            Value::Object(u)
+        },
+        (u, _) => u,
+    }
+}
+
+/// Kind of the first document in a rendered template
+fn kind_of(content: &str) -> String {
+    let first = content.split("\n---").next().unwrap_or("");
+    serde_yaml::from_str::<serde_yaml::Value>(first).ok()
+        .and_then(|v| v.get("kind").and_then(|k| k.as_str()).map(String::from))
+        .unwrap_or_default()
+}
+
+/// Format rendered templates like `helm template` does
+///
+/// Partials and NOTES.txt are skipped, and everything else is sorted by install order.
+pub fn format_manifests(rendered: BTreeMap<String, String>) -> String {
+    let mut manifests : Vec<(Option<usize>, String, String, String)> = rendered.into_iter()
+        .filter(|(name, _)| {
+            let base = Path::new(name).file_name().unwrap().to_string_lossy().to_string();
+            !base.starts_with('_') && base != "NOTES.txt"
+        })
+        .map(|(name, content)| {
+            let kind = kind_of(&content);
+            let order = INSTALL_ORDER.iter().position(|k| *k == kind);
+            (order, kind, name, content)
+        })
+        .collect();
+    // known kinds by install order, then unknown kinds by kind, then by name
+    manifests.sort_by(|a, b| {
+        let ka = a.0.unwrap_or(INSTALL_ORDER.len());
+        let kb = b.0.unwrap_or(INSTALL_ORDER.len());
+        ka.cmp(&kb).then_with(|| a.1.cmp(&b.1)).then_with(|| a.2.cmp(&b.2))
+    });
+    let mut out = String::new();
+    for (_, _, name, content) in manifests {
+        out.push_str(&format!("---\n# Source: {}\n{}\n", name, content));
+    }
+    out
+}
+
+/// Release information exposed to templates as `.Release`
+fn release(name: &str, namespace: &str, upgrade: bool, reg: &Region) -> Value {
+    let service = match reg.helm.backend {
+        HelmBackend::Helm2 => "Tiller",
+        HelmBackend::Helm3 => "Helm",
+    };
+    let mut rel = Map::new();
+    rel.insert("Name".into(), Value::String(name.into()));
+    rel.insert("Namespace".into(), Value::String(namespace.into()));
+    rel.insert("Service".into(), Value::String(service.into()));
+    rel.insert("IsInstall".into(), Value::Bool(!upgrade));
+    rel.insert("IsUpgrade".into(), Value::Bool(upgrade));
+    rel.insert("Revision".into(), Value::from(1));
+    Value::Object(rel)
+}
+
+fn render_with(mf: &Manifest, release: Value) -> Result<String> {
+    let chart = Chart::load(mf.chart.as_ref().ok_or_else(|| format!("{} has no chart", mf.name))?)?;
+    let values = normalize(serde_json::to_value(mf)?);
+    let rendered = chart.render(values, release)?;
+    Ok(format_manifests(rendered))
+}
+
+/// Render a completed manifest through its chart without the helm binary
+///
+/// Output is formatted like helm 2's `helm template`.
+pub fn render(mf: &Manifest, reg: &Region) -> Result<String> {
+    // helm 2 does not name releases in `helm template` unless asked to
+    let name = match reg.helm.backend {
+        HelmBackend::Helm2 => "RELEASE-NAME",
+        HelmBackend::Helm3 => mf.name.as_str(),
+    };
+    // offline `helm template` does not know the namespace
+    render_with(mf, release(name, "default", false, reg))
+}
+
+/// Render a completed manifest as an upgrade of its release would
+///
+/// Uses the service name as the release name in the manifest's namespace.
+pub fn render_release(mf: &Manifest, reg: &Region) -> Result<String> {
+    render_with(mf, release(&mf.name, &mf.namespace, true, reg))
+}
+
+/// Analogue of `helm template` using the in-process renderer
+pub fn template(svc: &str, region: &Region, conf: &Config, ver: Option<String>, mock: bool, output: Option<PathBuf>) -> Result<String> {
+    let mut mf = if mock {
+        Manifest::base(svc, conf, region)?.stub(region)?
+    } else {
+        Manifest::base(svc, conf, region)?.complete(region)?
+    };
+    if ver.is_some() {
+        mf.version = ver;
+    }
+    if let Some(v) = &mf.version {
+        region.versioningScheme.verify(v)?;
+    }
+    let tpl = render(&mf, region)?;
+    if let Some(o) = output {
+        let pth = Path::new(".").join(o);
+        info!("Writing template for {} to {}", svc, pth.display());
+        fs::write(&pth, format!("{}\n", tpl))?;
+    } else {
+        println!("{}", tpl);
+    }
+    Ok(tpl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coalesce, format_manifests, Chart};
+    use serde_json::json;
+    use sha2::{Sha256, Digest};
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    #[test]
+    fn coalesce_values() {
+        let user = serde_json::json!({"a": {"b": 1}, "c": null, "e": "user"});
+        let defaults = serde_json::json!({"a": {"b": 2, "d": 3}, "c": "default", "e": "default", "f": true});
+        let res = coalesce(user, &defaults);
+        assert_eq!(res, serde_json::json!({"a": {"b": 1, "d": 3}, "e": "user", "f": true}));
+    }
+
+    #[test]
+    fn install_order() {
+        let mut rendered = BTreeMap::new();
+        rendered.insert("base/templates/_helpers.tpl".to_string(), "".to_string());
+        rendered.insert("base/templates/deployment.yaml".to_string(), "kind: Deployment".to_string());
+        rendered.insert("base/templates/service.yaml".to_string(), "kind: Service".to_string());
+        rendered.insert("base/templates/zz.yaml".to_string(), "kind: Foo".to_string());
+        let out = format_manifests(rendered);
+        assert_eq!(out, "---\n# Source: base/templates/service.yaml\nkind: Service\n\
+---\n# Source: base/templates/deployment.yaml\nkind: Deployment\n\
+---\n# Source: base/templates/zz.yaml\nkind: Foo\n");
+    }
+
+    #[test]
+    fn render_example_chart() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examples/charts/base");
+        let chart = Chart::from_dir(&dir).unwrap();
+        let values = json!({
+            "name": "webapp",
+            "image": "clux/webapp-rs",
+            "version": "0.2.0",
+            "environment": "dev",
+            "region": "minikube",
+            "replicaCount": 1.0,
+            "resources": {"requests": {"cpu": "200m", "memory": "300Mi"}},
+            "health": {"uri": "/health", "wait": 0.0},
+            "httpPort": 8000.0,
+            "configs": {"mount": "/", "files": [{"name": "Rocket.toml.j2", "dest": "Rocket.toml", "value": "[production]"}]},
+            "env": {"plain": {"ROCKET_ENV": "production"}, "secrets": ["DATABASE_URL"]},
+            "secrets": {"DATABASE_URL": "postgres://"},
+        });
+        let release = json!({"Name": "webapp", "Namespace": "apps", "Service": "Tiller"});
+        let rendered = chart.render(values, release).unwrap();
+
+        // files are included by path for the config checksums
+        let checksum = |f: &str| {
+            let mut hasher = Sha256::new();
+            hasher.input(rendered[&format!("base/templates/{}", f)].as_bytes());
+            format!("{:x}", hasher.result())
+        };
+        let deploy = &rendered["base/templates/deployment.yaml"];
+        assert!(deploy.contains(&format!("checksum/config: {}\n", checksum("configmap.yaml"))));
+        assert!(deploy.contains(&format!("checksum/secrets: {}\n", checksum("secrets.yaml"))));
+        // env is merged with the root for the container-env define
+        assert!(deploy.contains("- name: ROCKET_ENV\n          value: \"production\"\n"));
+        assert!(deploy.contains("key: DATABASE_URL\n"));
+        assert!(rendered["base/templates/configmap.yaml"].contains("  Rocket.toml: |-\n    [production]"));
+
+        let out = format_manifests(rendered);
+        assert!(!out.contains("NOTES.txt"));
+        assert!(out.find("kind: Service\n").unwrap() < out.find("kind: Deployment\n").unwrap());
+    }
+}
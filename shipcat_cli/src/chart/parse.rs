@@ -0,0 +1,552 @@
+use std::collections::BTreeMap;
+
+use super::Result;
+
+/// A node in a parsed template
+#[derive(Debug, Clone)]
+pub enum Node {
+    /// Plain text copied to the output
+    Text(String),
+    /// `{{ pipeline }}` printed to the output (unless it declares variables)
+    Action(Pipeline),
+    /// `{{ if }}` with its `else if` branches and an optional `else`
+    If(Vec<(Pipeline, Vec<Node>)>, Option<Vec<Node>>),
+    /// `{{ range }}` with an optional `else`
+    Range(Pipeline, Vec<Node>, Option<Vec<Node>>),
+    /// `{{ with }}` with an optional `else`
+    With(Pipeline, Vec<Node>, Option<Vec<Node>>),
+    /// `{{ template "name" pipeline }}`
+    Template(String, Option<Pipeline>),
+}
+
+/// A pipeline of commands, optionally declaring or assigning variables
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    pub decl: Vec<String>,
+    /// `$x = ..` rather than `$x := ..`
+    pub assign: bool,
+    pub cmds: Vec<Command>,
+}
+
+/// A single command in a pipeline (function call or plain operand)
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub args: Vec<Arg>,
+}
+
+/// Operands in a command
+#[derive(Debug, Clone)]
+pub enum Arg {
+    /// `.Foo.Bar` (empty chain is the dot itself)
+    Field(Vec<String>),
+    /// `$x.Foo` (`$` is the root)
+    Var(String, Vec<String>),
+    /// Function name
+    Ident(String),
+    /// `(pipeline).Foo`
+    Pipe(Box<Pipeline>, Vec<String>),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Nil,
+}
+
+/// A parsed template file along with the templates it defines
+#[derive(Debug, Clone, Default)]
+pub struct Parsed {
+    pub nodes: Vec<Node>,
+    pub defines: BTreeMap<String, Vec<Node>>,
+}
+
+// ----------------------------------------------------------------------------
+// lexing of text and actions
+
+enum Item {
+    Text(String),
+    Action(String),
+}
+
+/// Split a template into text and action items, applying trim markers
+fn lex(src: &str) -> Result<Vec<Item>> {
+    let mut items = vec![];
+    let mut rest = src;
+    let mut trim_next = false;
+    while let Some(start) = rest.find("{{") {
+        let mut text = rest[..start].to_string();
+        if trim_next {
+            text = text.trim_start().to_string();
+        }
+        let after = &rest[start+2..];
+        let trim_prev = after.starts_with("- ") || after.starts_with("-\n") || after.starts_with("-\t");
+        if trim_prev {
+            text = text.trim_end().to_string();
+        }
+        if !text.is_empty() {
+            items.push(Item::Text(text));
+        }
+        let inner_start = if trim_prev { 2 } else { 0 };
+        let inner = &after[inner_start..];
+        // comments can contain }} so find their end first
+        let end = if inner.trim_start().starts_with("/*") {
+            let cend = inner.find("*/").ok_or("unclosed comment")?;
+            cend + inner[cend..].find("}}").ok_or("unclosed comment")?
+        } else {
+            find_action_end(inner)?
+        };
+        let mut body = &inner[..end];
+        trim_next = false;
+        if body.ends_with(" -") || body.ends_with("\n-") || body.ends_with("\t-") {
+            body = &body[..body.len()-1];
+            trim_next = true;
+        }
+        if !body.trim_start().starts_with("/*") {
+            items.push(Item::Action(body.trim().to_string()));
+        }
+        rest = &inner[end+2..];
+    }
+    let mut text = rest.to_string();
+    if trim_next {
+        text = text.trim_start().to_string();
+    }
+    if !text.is_empty() {
+        items.push(Item::Text(text));
+    }
+    Ok(items)
+}
+
+// Find the closing braces of an action, skipping over string literals
+fn find_action_end(s: &str) -> Result<usize> {
+    let b = s.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'"' => {
+                i += 1;
+                while i < b.len() && b[i] != b'"' {
+                    if b[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            },
+            b'`' => {
+                i += 1;
+                while i < b.len() && b[i] != b'`' {
+                    i += 1;
+                }
+            },
+            b'}' if i + 1 < b.len() && b[i+1] == b'}' => return Ok(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    bail!("unclosed action")
+}
+
+// ----------------------------------------------------------------------------
+// tokenizing of action contents
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    /// `.Foo.Bar` or `.`
+    Field(Vec<String>),
+    /// `$x.Foo` or `$`
+    Var(String, Vec<String>),
+    Ident(String),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Pipe,
+    LParen,
+    /// `)` followed by an optional field chain
+    RParen(Vec<String>),
+    Declare,
+    Assign,
+    Comma,
+}
+
+fn field_chain(chars: &[char], i: &mut usize) -> Vec<String> {
+    let mut chain = vec![];
+    while *i < chars.len() && chars[*i] == '.' {
+        *i += 1;
+        let mut name = String::new();
+        while *i < chars.len() && (chars[*i].is_alphanumeric() || chars[*i] == '_') {
+            name.push(chars[*i]);
+            *i += 1;
+        }
+        if !name.is_empty() {
+            chain.push(name);
+        }
+    }
+    chain
+}
+
+fn tokenize(s: &str) -> Result<Vec<Tok>> {
+    let chars : Vec<char> = s.chars().collect();
+    let mut toks = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '|' {
+            toks.push(Tok::Pipe);
+            i += 1;
+        } else if c == '(' {
+            toks.push(Tok::LParen);
+            i += 1;
+        } else if c == ')' {
+            i += 1;
+            toks.push(Tok::RParen(field_chain(&chars, &mut i)));
+        } else if c == ',' {
+            toks.push(Tok::Comma);
+            i += 1;
+        } else if c == ':' && i + 1 < chars.len() && chars[i+1] == '=' {
+            toks.push(Tok::Declare);
+            i += 2;
+        } else if c == '=' {
+            toks.push(Tok::Assign);
+            i += 1;
+        } else if c == '.' && (i + 1 >= chars.len() || !chars[i+1].is_ascii_digit()) {
+            toks.push(Tok::Field(field_chain(&chars, &mut i)));
+        } else if c == '$' {
+            i += 1;
+            let mut name = String::from("$");
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                name.push(chars[i]);
+                i += 1;
+            }
+            toks.push(Tok::Var(name, field_chain(&chars, &mut i)));
+        } else if c == '"' {
+            i += 1;
+            let mut st = String::new();
+            while i < chars.len() && chars[i] != '"' {
+                if chars[i] == '\\' && i + 1 < chars.len() {
+                    i += 1;
+                    st.push(match chars[i] {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        x => x,
+                    });
+                } else {
+                    st.push(chars[i]);
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                bail!("unterminated string in {}", s);
+            }
+            i += 1;
+            toks.push(Tok::Str(st));
+        } else if c == '`' {
+            i += 1;
+            let mut st = String::new();
+            while i < chars.len() && chars[i] != '`' {
+                st.push(chars[i]);
+                i += 1;
+            }
+            if i >= chars.len() {
+                bail!("unterminated raw string in {}", s);
+            }
+            i += 1;
+            toks.push(Tok::Str(st));
+        } else if c.is_ascii_digit() || ((c == '-' || c == '+' || c == '.') && i + 1 < chars.len() && chars[i+1].is_ascii_digit()) {
+            let mut num = String::new();
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || "+-.".contains(chars[i])) {
+                num.push(chars[i]);
+                i += 1;
+            }
+            if let Ok(n) = num.parse::<i64>() {
+                toks.push(Tok::Int(n));
+            } else if let Ok(f) = num.parse::<f64>() {
+                toks.push(Tok::Float(f));
+            } else {
+                bail!("bad number {} in {}", num, s);
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                name.push(chars[i]);
+                i += 1;
+            }
+            toks.push(Tok::Ident(name));
+        } else {
+            bail!("unexpected character '{}' in {}", c, s);
+        }
+    }
+    Ok(toks)
+}
+
+// ----------------------------------------------------------------------------
+// pipeline parsing
+
+struct PipeParser {
+    toks: Vec<Tok>,
+    pos: usize,
+}
+
+impl PipeParser {
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos)
+    }
+
+    fn pipeline(&mut self, top: bool) -> Result<Pipeline> {
+        let mut pipe = Pipeline::default();
+        // variable declarations are only allowed at the start
+        let mut look = self.pos;
+        let mut vars = vec![];
+        while let Some(Tok::Var(v, chain)) = self.toks.get(look) {
+            if !chain.is_empty() {
+                break;
+            }
+            vars.push(v.clone());
+            look += 1;
+            match self.toks.get(look) {
+                Some(Tok::Comma) => look += 1,
+                Some(Tok::Declare) | Some(Tok::Assign) => {
+                    pipe.assign = self.toks[look] == Tok::Assign;
+                    pipe.decl = vars.clone();
+                    self.pos = look + 1;
+                    break;
+                },
+                _ => break,
+            }
+        }
+        loop {
+            let mut args = vec![];
+            loop {
+                let arg = match self.peek().cloned() {
+                    None => break,
+                    Some(Tok::Pipe) => break,
+                    Some(Tok::RParen(_)) if !top => break,
+                    Some(Tok::Field(f)) => Arg::Field(f),
+                    Some(Tok::Var(v, f)) => Arg::Var(v, f),
+                    Some(Tok::Str(s)) => Arg::Str(s),
+                    Some(Tok::Int(n)) => Arg::Int(n),
+                    Some(Tok::Float(n)) => Arg::Float(n),
+                    Some(Tok::Ident(ref id)) if id == "true" => Arg::Bool(true),
+                    Some(Tok::Ident(ref id)) if id == "false" => Arg::Bool(false),
+                    Some(Tok::Ident(ref id)) if id == "nil" => Arg::Nil,
+                    Some(Tok::Ident(id)) => Arg::Ident(id),
+                    Some(Tok::LParen) => {
+                        self.pos += 1;
+                        let inner = self.pipeline(false)?;
+                        match self.peek().cloned() {
+                            Some(Tok::RParen(chain)) => Arg::Pipe(Box::new(inner), chain),
+                            _ => bail!("unclosed parenthesis"),
+                        }
+                    },
+                    Some(t) => bail!("unexpected {:?} in pipeline", t),
+                };
+                self.pos += 1;
+                args.push(arg);
+            }
+            if args.is_empty() {
+                bail!("empty command in pipeline");
+            }
+            pipe.cmds.push(Command { args });
+            match self.peek() {
+                Some(Tok::Pipe) => self.pos += 1,
+                _ => break,
+            }
+        }
+        Ok(pipe)
+    }
+}
+
+fn parse_pipeline(s: &str) -> Result<Pipeline> {
+    let mut p = PipeParser { toks: tokenize(s)?, pos: 0 };
+    let pipe = p.pipeline(true)?;
+    if p.pos != p.toks.len() {
+        bail!("unexpected trailing tokens in {}", s);
+    }
+    Ok(pipe)
+}
+
+// ----------------------------------------------------------------------------
+// structure parsing
+
+/// How a list of nodes was terminated
+enum Term {
+    Eof,
+    End,
+    Else(Option<String>),
+}
+
+struct Parser {
+    items: Vec<Item>,
+    pos: usize,
+    defines: BTreeMap<String, Vec<Node>>,
+}
+
+fn keyword(action: &str) -> (&str, &str) {
+    let action = action.trim();
+    match action.find(char::is_whitespace) {
+        Some(i) => (&action[..i], action[i..].trim()),
+        None => (action, ""),
+    }
+}
+
+fn string_arg(s: &str) -> Result<(String, &str)> {
+    let s = s.trim();
+    let quote = s.chars().next();
+    if quote != Some('"') && quote != Some('`') {
+        bail!("expected a template name in {}", s);
+    }
+    let q = quote.unwrap();
+    let end = s[1..].find(q).ok_or_else(|| format!("unterminated template name in {}", s))? + 1;
+    Ok((s[1..end].to_string(), s[end+1..].trim()))
+}
+
+impl Parser {
+    fn list(&mut self) -> Result<(Vec<Node>, Term)> {
+        let mut nodes = vec![];
+        while self.pos < self.items.len() {
+            let item = match self.items[self.pos] {
+                Item::Text(ref t) => {
+                    nodes.push(Node::Text(t.clone()));
+                    self.pos += 1;
+                    continue;
+                },
+                Item::Action(ref a) => a.clone(),
+            };
+            self.pos += 1;
+            let (kw, rest) = keyword(&item);
+            match kw {
+                "end" => return Ok((nodes, Term::End)),
+                "else" => {
+                    let cond = if rest.is_empty() { None } else { Some(rest.to_string()) };
+                    return Ok((nodes, Term::Else(cond)));
+                },
+                "if" => nodes.push(self.parse_if(rest)?),
+                "range" | "with" => {
+                    let pipe = parse_pipeline(rest)?;
+                    let (body, end) = self.list()?;
+                    let alt = match end {
+                        Term::End => None,
+                        Term::Else(None) => {
+                            let (alt, end) = self.list()?;
+                            if let Term::End = end {} else {
+                                bail!("{} else without end", kw);
+                            }
+                            Some(alt)
+                        },
+                        _ => bail!("unterminated {}", kw),
+                    };
+                    if kw == "range" {
+                        nodes.push(Node::Range(pipe, body, alt));
+                    } else {
+                        nodes.push(Node::With(pipe, body, alt));
+                    }
+                },
+                "define" => {
+                    let (name, _) = string_arg(rest)?;
+                    let (body, end) = self.list()?;
+                    if let Term::End = end {} else {
+                        bail!("unterminated define {}", name);
+                    }
+                    self.defines.insert(name, body);
+                },
+                "template" => {
+                    let (name, pipe) = string_arg(rest)?;
+                    let pipe = if pipe.is_empty() { None } else { Some(parse_pipeline(pipe)?) };
+                    nodes.push(Node::Template(name, pipe));
+                },
+                _ => nodes.push(Node::Action(parse_pipeline(&item)?)),
+            }
+        }
+        Ok((nodes, Term::Eof))
+    }
+
+    fn parse_if(&mut self, cond: &str) -> Result<Node> {
+        let mut branches = vec![];
+        let mut cond = cond.to_string();
+        loop {
+            let pipe = parse_pipeline(&cond)?;
+            let (body, end) = self.list()?;
+            branches.push((pipe, body));
+            match end {
+                Term::End => return Ok(Node::If(branches, None)),
+                Term::Else(None) => {
+                    let (alt, end) = self.list()?;
+                    if let Term::End = end {} else {
+                        bail!("if else without end");
+                    }
+                    return Ok(Node::If(branches, Some(alt)));
+                },
+                Term::Else(Some(c)) => {
+                    let (kw, rest) = keyword(&c);
+                    if kw != "if" {
+                        bail!("unsupported else {}", c);
+                    }
+                    cond = rest.to_string();
+                },
+                Term::Eof => bail!("unterminated if {}", cond),
+            }
+        }
+    }
+}
+
+/// Parse a template source
+pub fn parse(src: &str) -> Result<Parsed> {
+    let mut p = Parser { items: lex(src)?, pos: 0, defines: BTreeMap::new() };
+    let (nodes, end) = p.list()?;
+    match end {
+        Term::Eof => Ok(Parsed { nodes, defines: p.defines }),
+        Term::End => bail!("unexpected end"),
+        Term::Else(_) => bail!("unexpected else"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Arg, Node};
+
+    #[test]
+    fn parse_trim_and_comments() {
+        let p = parse("a  {{- /* a }} comment */ -}}  b {{ .Values.x }}\n").unwrap();
+        match &p.nodes[..] {
+            [Node::Text(a), Node::Text(b), Node::Action(act), Node::Text(nl)] => {
+                assert_eq!(a, "a");
+                assert_eq!(b, "b ");
+                assert_eq!(nl, "\n");
+                match &act.cmds[0].args[..] {
+                    [Arg::Field(chain)] => assert_eq!(chain, &vec!["Values".to_string(), "x".to_string()]),
+                    other => panic!("unexpected args {:?}", other),
+                }
+            },
+            other => panic!("unexpected nodes {:?}", other),
+        }
+        // braces in strings do not end the action
+        let p = parse(r#"{{ "}}" | quote }}"#).unwrap();
+        match &p.nodes[..] {
+            [Node::Action(act)] => {
+                assert_eq!(act.cmds.len(), 2);
+                match &act.cmds[0].args[..] {
+                    [Arg::Str(s)] => assert_eq!(s, "}}"),
+                    other => panic!("unexpected args {:?}", other),
+                }
+            },
+            other => panic!("unexpected nodes {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_control_structures() {
+        let src = r#"{{ define "x" }}X{{ end }}{{ if .a }}1{{ else if .b }}2{{ else }}3{{ end }}{{ range $k, $v := .m }}{{ $k }}{{ end }}"#;
+        let p = parse(src).unwrap();
+        assert!(p.defines.contains_key("x"));
+        match &p.nodes[..] {
+            [Node::If(branches, Some(_)), Node::Range(pipe, _, None)] => {
+                assert_eq!(branches.len(), 2);
+                assert_eq!(pipe.decl, vec!["$k".to_string(), "$v".to_string()]);
+            },
+            other => panic!("unexpected nodes {:?}", other),
+        }
+        assert!(parse("{{ if .a }}").is_err());
+        assert!(parse("{{ end }}").is_err());
+        assert!(parse("{{ /* unclosed }}").is_err());
+    }
+}
@@ -0,0 +1,613 @@
+use std::cmp::Ordering;
+
+use serde_json::Value;
+
+use super::funcs::go_float;
+
+// Yaml output in the style of go-yaml v2 as used by helm's `toYaml`
+//
+// Helm marshals via json first, so integral numbers come out as ints,
+// keys are sorted in go-yaml's natural order, and long scalars are folded at 80 columns.
+
+const BEST_WIDTH: usize = 80;
+const BEST_INDENT: i64 = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Style {
+    Plain,
+    Single,
+    Double,
+    Literal,
+}
+
+struct Emitter {
+    out: String,
+    column: usize,
+    whitespace: bool,
+    indention: bool,
+}
+
+/// Serialize a value to yaml the way helm's `toYaml` does (with a trailing newline)
+pub fn to_string(v: &Value) -> String {
+    let mut e = Emitter { out: String::new(), column: 0, whitespace: true, indention: true };
+    e.node(v, -1, false, false);
+    if !e.out.ends_with('\n') {
+        e.put_break();
+    }
+    e.out
+}
+
+/// go-yaml v2's ordering of map keys (numbers within keys compare numerically)
+fn key_cmp(a: &str, b: &str) -> Ordering {
+    let ar : Vec<char> = a.chars().collect();
+    let br : Vec<char> = b.chars().collect();
+    let mut i = 0;
+    while i < ar.len() && i < br.len() {
+        if ar[i] == br[i] {
+            i += 1;
+            continue;
+        }
+        let al = ar[i].is_alphabetic();
+        let bl = br[i].is_alphabetic();
+        if al && bl {
+            return ar[i].cmp(&br[i]);
+        }
+        if al || bl {
+            return if bl { Ordering::Less } else { Ordering::Greater };
+        }
+        let (mut an, mut bn) = (0i64, 0i64);
+        if ar[i] == '0' || br[i] == '0' {
+            let mut j = i;
+            while j > 0 && ar[j-1].is_ascii_digit() {
+                j -= 1;
+                if ar[j] != '0' {
+                    an = 1;
+                    bn = 1;
+                    break;
+                }
+            }
+        }
+        let mut ai = i;
+        while ai < ar.len() && ar[ai].is_ascii_digit() {
+            an = an.wrapping_mul(10).wrapping_add(i64::from(ar[ai] as u8 - b'0'));
+            ai += 1;
+        }
+        let mut bi = i;
+        while bi < br.len() && br[bi].is_ascii_digit() {
+            bn = bn.wrapping_mul(10).wrapping_add(i64::from(br[bi] as u8 - b'0'));
+            bi += 1;
+        }
+        if an != bn {
+            return an.cmp(&bn);
+        }
+        if ai != bi {
+            return ai.cmp(&bi);
+        }
+        return ar[i].cmp(&br[i]);
+    }
+    ar.len().cmp(&br.len())
+}
+
+/// Whether a plain scalar would be read back as something other than a string
+fn resolves_non_string(s: &str) -> bool {
+    match s {
+        "" | "~" | "null" | "Null" | "NULL" => return true,
+        "y" | "Y" | "yes" | "Yes" | "YES" | "n" | "N" | "no" | "No" | "NO" |
+        "true" | "True" | "TRUE" | "false" | "False" | "FALSE" |
+        "on" | "On" | "ON" | "off" | "Off" | "OFF" => return true,
+        ".inf" | ".Inf" | ".INF" | "+.inf" | "+.Inf" | "+.INF" | "-.inf" | "-.Inf" | "-.INF" |
+        ".nan" | ".NaN" | ".NAN" => return true,
+        _ => {}
+    }
+    let first = s.chars().next().unwrap();
+    if !(first.is_ascii_digit() || "+-.".contains(first)) {
+        return false;
+    }
+    is_int(s) || is_float(s) || is_base60_float(s) || is_timestamp(s)
+}
+
+fn is_int(s: &str) -> bool {
+    let plain = s.replace('_', "");
+    let digits = plain.trim_start_matches(['+', '-']);
+    if digits.is_empty() || plain.len() - digits.len() > 1 {
+        return false;
+    }
+    let (radix, body) = if digits.starts_with("0x") || digits.starts_with("0X") {
+        (16, &digits[2..])
+    } else if digits.starts_with("0b") || digits.starts_with("0B") {
+        (2, &digits[2..])
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (8, &digits[1..])
+    } else {
+        (10, digits)
+    };
+    !body.is_empty() && i64::from_str_radix(body, radix).is_ok()
+}
+
+fn is_float(s: &str) -> bool {
+    // ^[-+]?(\.[0-9]+|[0-9]+(\.[0-9]*)?)([eE][-+]?[0-9]+)?$
+    let b = s.as_bytes();
+    let mut i = 0;
+    if i < b.len() && (b[i] == b'+' || b[i] == b'-') {
+        i += 1;
+    }
+    let int_start = i;
+    while i < b.len() && b[i].is_ascii_digit() {
+        i += 1;
+    }
+    let has_int = i > int_start;
+    if i < b.len() && b[i] == b'.' {
+        i += 1;
+        let frac_start = i;
+        while i < b.len() && b[i].is_ascii_digit() {
+            i += 1;
+        }
+        if !has_int && i == frac_start {
+            return false;
+        }
+    } else if !has_int {
+        return false;
+    }
+    if i < b.len() && (b[i] == b'e' || b[i] == b'E') {
+        i += 1;
+        if i < b.len() && (b[i] == b'+' || b[i] == b'-') {
+            i += 1;
+        }
+        let exp_start = i;
+        while i < b.len() && b[i].is_ascii_digit() {
+            i += 1;
+        }
+        if i == exp_start {
+            return false;
+        }
+    }
+    i == b.len()
+}
+
+fn is_base60_float(s: &str) -> bool {
+    // ^[-+]?[0-9][0-9_]*(?::[0-5]?[0-9])+(?:\.[0-9_]*)?$
+    let s = s.trim_start_matches(['+', '-']);
+    let (main, frac) = match s.find('.') {
+        Some(i) => (&s[..i], Some(&s[i+1..])),
+        None => (s, None),
+    };
+    let mut parts = main.split(':');
+    let head = parts.next().unwrap_or("");
+    if head.is_empty() || !head.starts_with(|c: char| c.is_ascii_digit()) || !head.chars().all(|c| c.is_ascii_digit() || c == '_') {
+        return false;
+    }
+    let rest : Vec<&str> = parts.collect();
+    if rest.is_empty() {
+        return false;
+    }
+    let frac_ok = frac.map(|f| f.chars().all(|c| c.is_ascii_digit() || c == '_')).unwrap_or(true);
+    frac_ok && rest.iter().all(|p| {
+        let b = p.as_bytes();
+        match b.len() {
+            1 => b[0].is_ascii_digit(),
+            2 => (b'0'..=b'5').contains(&b[0]) && b[1].is_ascii_digit(),
+            _ => false,
+        }
+    })
+}
+
+fn is_timestamp(s: &str) -> bool {
+    // date only, or date followed by a time
+    let date_end = s.find(['T', 't', ' ']).unwrap_or(s.len());
+    let date : Vec<&str> = s[..date_end].split('-').collect();
+    let date_ok = date.len() == 3 && date[0].len() == 4
+        && date.iter().all(|p| !p.is_empty() && p.len() <= 4 && p.chars().all(|c| c.is_ascii_digit()))
+        && date[1].len() <= 2 && date[2].len() <= 2;
+    if !date_ok {
+        return false;
+    }
+    if date_end == s.len() {
+        return true;
+    }
+    let time = s[date_end+1..].trim_start();
+    let hms : Vec<&str> = time.splitn(3, ':').collect();
+    hms.len() == 3 && hms[0].chars().all(|c| c.is_ascii_digit()) && !hms[0].is_empty()
+}
+
+struct Analysis {
+    block_plain: bool,
+    single: bool,
+    block: bool,
+}
+
+fn is_printable(c: char) -> bool {
+    let u = c as u32;
+    u == 0x0A || (0x20..=0x7E).contains(&u) || u == 0x85
+        || (0xA0..=0xD7FF).contains(&u) || (0xE000..=0xFFFD).contains(&u) && u != 0xFEFF
+        || (0x10000..=0x10FFFF).contains(&u)
+}
+
+fn analyze(s: &str) -> Analysis {
+    let chars : Vec<char> = s.chars().collect();
+    // flow indicators are irrelevant as flow collections are only used for empty values
+    let mut block_indicators = false;
+    let (mut line_breaks, mut special) = (false, false);
+    let (mut leading_space, mut leading_break, mut trailing_space, mut trailing_break) = (false, false, false, false);
+    let (mut break_space, mut space_break) = (false, false);
+    let (mut prev_space, mut prev_break) = (false, false);
+
+    if s.starts_with("---") || s.starts_with("...") {
+        block_indicators = true;
+    }
+    let mut preceded_by_ws = true;
+    for (i, &c) in chars.iter().enumerate() {
+        let followed_by_ws = i + 1 >= chars.len() || chars[i+1] == ' ' || chars[i+1] == '\t' || chars[i+1] == '\n' || chars[i+1] == '\r';
+        if i == 0 {
+            match c {
+                '#' | ',' | '[' | ']' | '{' | '}' | '&' | '*' | '!' | '|' | '>' | '\'' | '"' | '%' | '@' | '`' => {
+                    block_indicators = true;
+                },
+                '?' | ':' | '-' if followed_by_ws => block_indicators = true,
+                _ => {}
+            }
+        } else {
+            match c {
+                ':' if followed_by_ws => block_indicators = true,
+                '#' if preceded_by_ws => block_indicators = true,
+                _ => {}
+            }
+        }
+        if !is_printable(c) || c == '\u{FEFF}' {
+            special = true;
+        }
+        let is_break = c == '\n' || c == '\r' || c == '\u{85}' || c == '\u{2028}' || c == '\u{2029}';
+        if is_break {
+            line_breaks = true;
+        }
+        if c == ' ' {
+            if i == 0 {
+                leading_space = true;
+            }
+            if i + 1 == chars.len() {
+                trailing_space = true;
+            }
+            if prev_break {
+                break_space = true;
+            }
+            prev_space = true;
+            prev_break = false;
+        } else if is_break {
+            if i == 0 {
+                leading_break = true;
+            }
+            if i + 1 == chars.len() {
+                trailing_break = true;
+            }
+            if prev_space {
+                space_break = true;
+            }
+            prev_space = false;
+            prev_break = true;
+        } else {
+            prev_space = false;
+            prev_break = false;
+        }
+        preceded_by_ws = c == ' ' || c == '\t' || is_break;
+    }
+
+    let mut a = Analysis { block_plain: true, single: true, block: true };
+    if leading_space || leading_break || trailing_space || trailing_break {
+        a.block_plain = false;
+    }
+    if trailing_space {
+        a.block = false;
+    }
+    if break_space {
+        a.block_plain = false;
+        a.single = false;
+    }
+    if space_break || special {
+        a.block_plain = false;
+        a.single = false;
+        a.block = false;
+    }
+    if line_breaks || block_indicators {
+        a.block_plain = false;
+    }
+    a
+}
+
+fn select_style(s: &str, simple_key: bool) -> Style {
+    let a = analyze(s);
+    let mut style = if resolves_non_string(s) {
+        Style::Double
+    } else if s.contains('\n') {
+        Style::Literal
+    } else {
+        Style::Plain
+    };
+    if style == Style::Plain && (!a.block_plain || (s.is_empty() && simple_key)) {
+        style = Style::Single;
+    }
+    if style == Style::Single && !a.single {
+        style = Style::Double;
+    }
+    if style == Style::Literal && (!a.block || simple_key) {
+        style = Style::Double;
+    }
+    style
+}
+
+impl Emitter {
+    fn put(&mut self, c: char) {
+        self.out.push(c);
+        self.column += 1;
+    }
+
+    fn put_break(&mut self) {
+        self.out.push('\n');
+        self.column = 0;
+    }
+
+    fn write_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.put(c);
+        }
+    }
+
+    fn write_indent(&mut self, indent: i64) {
+        let indent = indent.max(0) as usize;
+        if !self.indention || self.column > indent || (self.column == indent && !self.whitespace) {
+            self.put_break();
+        }
+        while self.column < indent {
+            self.put(' ');
+        }
+        self.whitespace = true;
+        self.indention = true;
+    }
+
+    fn write_indicator(&mut self, ind: &str, need_ws: bool, is_ws: bool, is_indention: bool) {
+        if need_ws && !self.whitespace {
+            self.put(' ');
+        }
+        self.write_str(ind);
+        self.whitespace = is_ws;
+        self.indention = self.indention && is_indention;
+    }
+
+    fn increase_indent(indent: i64, flow: bool, indentless: bool) -> i64 {
+        if indent < 0 {
+            if flow { BEST_INDENT } else { 0 }
+        } else if !indentless {
+            indent + BEST_INDENT
+        } else {
+            indent
+        }
+    }
+
+    /// Emit a node whose parent collection is at `indent`
+    fn node(&mut self, v: &Value, indent: i64, mapping_ctx: bool, simple_key: bool) {
+        match v {
+            Value::Object(o) if !o.is_empty() => {
+                let ind = Emitter::increase_indent(indent, false, false);
+                let mut keys : Vec<&String> = o.keys().collect();
+                keys.sort_by(|a, b| key_cmp(a, b));
+                for k in keys {
+                    self.write_indent(ind);
+                    self.scalar(k, ind, true);
+                    self.write_indicator(":", false, false, false);
+                    self.node(&o[k], ind, true, false);
+                }
+            },
+            Value::Array(a) if !a.is_empty() => {
+                let indentless = mapping_ctx && !self.indention;
+                let ind = Emitter::increase_indent(indent, false, indentless);
+                for x in a {
+                    self.write_indent(ind);
+                    self.write_indicator("-", true, false, true);
+                    self.node(x, ind, false, false);
+                }
+            },
+            Value::Object(_) => self.write_indicator("{}", true, false, false),
+            Value::Array(_) => self.write_indicator("[]", true, false, false),
+            Value::Null => self.plain("null"),
+            Value::Bool(b) => self.plain(&b.to_string()),
+            Value::Number(n) => {
+                let s = if let (false, Some(i)) = (n.is_f64(), n.as_i64()) {
+                    i.to_string()
+                } else {
+                    let f = n.as_f64().unwrap_or(0.0);
+                    // helm goes via json where integral floats lose their fraction
+                    if f.fract() == 0.0 && f.abs() < 1e21 {
+                        format!("{}", f as i64)
+                    } else {
+                        match go_float(f).as_ref() {
+                            "+Inf" => ".inf".into(),
+                            "-Inf" => "-.inf".into(),
+                            "NaN" => ".nan".into(),
+                            x => x.to_string(),
+                        }
+                    }
+                };
+                self.plain(&s)
+            },
+            Value::String(s) => self.scalar(s, indent, simple_key),
+        }
+    }
+
+    fn plain(&mut self, s: &str) {
+        if !self.whitespace {
+            self.put(' ');
+        }
+        self.write_str(s);
+        self.whitespace = false;
+        self.indention = false;
+    }
+
+    fn scalar(&mut self, s: &str, parent_indent: i64, simple_key: bool) {
+        let indent = Emitter::increase_indent(parent_indent, true, false);
+        let allow_breaks = !simple_key;
+        match select_style(s, simple_key) {
+            Style::Plain => self.write_plain(s, indent, allow_breaks),
+            Style::Single => self.write_single(s, indent, allow_breaks),
+            Style::Double => self.write_double(s, indent, allow_breaks),
+            Style::Literal => self.write_literal(s, indent),
+        }
+    }
+
+    fn write_plain(&mut self, s: &str, indent: i64, allow_breaks: bool) {
+        if !self.whitespace {
+            self.put(' ');
+        }
+        let chars : Vec<char> = s.chars().collect();
+        let mut spaces = false;
+        for (i, &c) in chars.iter().enumerate() {
+            if c == ' ' {
+                let next_space = chars.get(i+1) == Some(&' ');
+                if allow_breaks && !spaces && self.column > BEST_WIDTH && !next_space {
+                    self.write_indent(indent);
+                } else {
+                    self.put(c);
+                }
+                spaces = true;
+            } else {
+                self.put(c);
+                self.indention = false;
+                spaces = false;
+            }
+        }
+        self.whitespace = false;
+        self.indention = false;
+    }
+
+    fn write_single(&mut self, s: &str, indent: i64, allow_breaks: bool) {
+        self.write_indicator("'", true, false, false);
+        let chars : Vec<char> = s.chars().collect();
+        let mut spaces = false;
+        for (i, &c) in chars.iter().enumerate() {
+            if c == ' ' {
+                let next_space = chars.get(i+1) == Some(&' ');
+                if allow_breaks && !spaces && self.column > BEST_WIDTH && i > 0 && i + 1 < chars.len() && !next_space {
+                    self.write_indent(indent);
+                } else {
+                    self.put(c);
+                }
+                spaces = true;
+            } else {
+                if c == '\'' {
+                    self.put('\'');
+                }
+                self.put(c);
+                self.indention = false;
+                spaces = false;
+            }
+        }
+        self.write_indicator("'", false, false, false);
+    }
+
+    fn write_double(&mut self, s: &str, indent: i64, allow_breaks: bool) {
+        self.write_indicator("\"", true, false, false);
+        let chars : Vec<char> = s.chars().collect();
+        let mut spaces = false;
+        for (i, &c) in chars.iter().enumerate() {
+            let is_break = c == '\n' || c == '\r' || c == '\u{85}' || c == '\u{2028}' || c == '\u{2029}';
+            if !is_printable(c) || c == '\u{FEFF}' || is_break || c == '"' || c == '\\' {
+                self.put('\\');
+                let esc = match c {
+                    '\0' => "0".to_string(),
+                    '\u{7}' => "a".into(),
+                    '\u{8}' => "b".into(),
+                    '\t' => "t".into(),
+                    '\n' => "n".into(),
+                    '\u{b}' => "v".into(),
+                    '\u{c}' => "f".into(),
+                    '\r' => "r".into(),
+                    '\u{1b}' => "e".into(),
+                    '"' => "\"".into(),
+                    '\\' => "\\".into(),
+                    '\u{85}' => "N".into(),
+                    '\u{a0}' => "_".into(),
+                    '\u{2028}' => "L".into(),
+                    '\u{2029}' => "P".into(),
+                    c if (c as u32) <= 0xFF => format!("x{:02X}", c as u32),
+                    c if (c as u32) <= 0xFFFF => format!("u{:04X}", c as u32),
+                    c => format!("U{:08X}", c as u32),
+                };
+                self.write_str(&esc);
+                spaces = false;
+            } else if c == ' ' {
+                if allow_breaks && !spaces && self.column > BEST_WIDTH && i != 0 && i + 1 != chars.len() {
+                    self.write_indent(indent);
+                    if chars.get(i+1) == Some(&' ') {
+                        self.put('\\');
+                    }
+                } else {
+                    self.put(c);
+                }
+                spaces = true;
+            } else {
+                self.put(c);
+                spaces = false;
+            }
+        }
+        self.write_indicator("\"", false, false, false);
+    }
+
+    fn write_literal(&mut self, s: &str, indent: i64) {
+        self.write_indicator("|", true, false, false);
+        // block scalar hints
+        if s.starts_with(' ') || s.starts_with('\n') {
+            self.write_str(&BEST_INDENT.to_string());
+        }
+        if !s.ends_with('\n') {
+            self.write_str("-");
+        } else if s.len() == 1 || s[..s.len()-1].ends_with('\n') {
+            self.write_str("+");
+        }
+        self.put_break();
+        self.indention = true;
+        self.whitespace = true;
+        let mut breaks = true;
+        for c in s.chars() {
+            if c == '\n' {
+                self.put_break();
+                self.indention = true;
+                breaks = true;
+            } else {
+                if breaks {
+                    self.write_indent(indent);
+                    breaks = false;
+                }
+                self.put(c);
+                self.indention = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_string, key_cmp};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn go_yaml_style() {
+        let v = serde_json::json!({
+            "name": "fake-ask",
+            "replicas": 2.0,
+            "ratio": 0.5,
+            "enabled": "true",
+            "empty": {},
+            "ports": [{"name": "http", "port": 80}],
+            "args": ["a", "- b"],
+            "script": "echo hi\necho there\n",
+            "note": "key: value",
+        });
+        let expected = "args:\n- a\n- '- b'\nempty: {}\nenabled: \"true\"\nname: fake-ask\nnote: 'key: value'\n\
+ports:\n- name: http\n  port: 80\nratio: 0.5\nreplicas: 2\nscript: |\n  echo hi\n  echo there\n";
+        assert_eq!(to_string(&v), expected);
+    }
+
+    #[test]
+    fn go_yaml_key_order() {
+        assert_eq!(key_cmp("a10", "a9"), Ordering::Greater);
+        assert_eq!(key_cmp("_x", "Zed"), Ordering::Less);
+        assert_eq!(key_cmp("B", "a"), Ordering::Less);
+    }
+}
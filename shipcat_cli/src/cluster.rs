@@ -1,6 +1,7 @@
 use super::{Config, Region};
 use super::helm::{self, UpgradeMode};
 use super::{Result, Manifest};
+use super::graph;
 use crate::webhooks;
 
 /// Helm upgrade the region (reconcile)
@@ -14,6 +15,28 @@ pub fn helm_reconcile(conf: &Config, region: &Region, n_workers: usize) -> Resul
     mass_helm(conf, region, UpgradeMode::UpgradeInstallWait, n_workers)
 }
 
+/// Helm upgrade the region in dependency order
+///
+/// Upgrades services in waves from the dependency graph, so that a service
+/// is only upgraded once everything it depends on has rolled out.
+/// Services within a wave are upgraded in a threadpool.
+/// Services downstream of a failed upgrade are skipped.
+pub fn helm_reconcile_ordered(conf: &Config, region: &Region, n_workers: usize) -> Result<()> {
+    if let Err(e) = webhooks::ensure_requirements(&region) {
+        warn!("Could not ensure webhook requirements: {}", e);
+    }
+    let graph = graph::build(conf, region)?;
+    let mut waves = vec![];
+    for names in graph::waves(&graph)? {
+        let mut wave = vec![];
+        for svc in names {
+            wave.push(Manifest::base(&svc, conf, region)?);
+        }
+        waves.push(wave);
+    }
+    helm::parallel::reconcile_waves(waves, conf, region, UpgradeMode::UpgradeInstallWait, n_workers)
+}
+
 /// Helm diff the region
 ///
 /// Returns the diffs only from all services across a region.
@@ -41,6 +64,16 @@ pub fn mass_crd(conf: &Config, reg: &Region, n_workers: usize) -> Result<()> {
     crd_reconcile(Manifest::available(&reg.name)?, conf, reg, n_workers)
 }
 
+/// Upgrade all manifest crds in a region to the latest spec version
+///
+/// Needed before a version is removed from `crds::conversion::MANIFEST_VERSIONS`.
+pub fn crd_migrate(region: &Region) -> Result<Vec<String>> {
+    let client = kube::Client::new()?;
+    let converted = kube::migrate_manifest_crds(&client, &region.namespace)?;
+    info!("Migrated {} manifests in {}", converted.len(), region.name);
+    Ok(converted)
+}
+
 use super::kube;
 fn crd_reconcile(svcs: Vec<String>, config: &Config, region: &Region, n_workers: usize) -> Result<()> {
     use threadpool::ThreadPool;
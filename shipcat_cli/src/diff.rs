@@ -0,0 +1,285 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde_json::{self, Value};
+use tempfile::TempDir;
+
+use super::{Result, ResultExt, Config, ConfigType, Manifest};
+
+/// Placeholder for secret values in diffs
+const MASKED: &str = "<masked>";
+
+/// A single difference between two manifests
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Change {
+    /// Field only present in the second manifest
+    Added { path: String, value: Value },
+    /// Field only present in the first manifest
+    Removed { path: String, value: Value },
+    /// Field present in both with different values
+    Changed { path: String, from: Value, to: Value },
+}
+
+impl Change {
+    fn path(&self) -> &str {
+        match self {
+            Change::Added { path, .. } => path,
+            Change::Removed { path, .. } => path,
+            Change::Changed { path, .. } => path,
+        }
+    }
+}
+
+/// Output formats for `shipcat diff`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DiffFormat {
+    Human,
+    Json,
+}
+
+/// Where to read one side of a diff from
+#[derive(Clone, Debug)]
+pub struct DiffSource {
+    /// Region to resolve the manifest for
+    pub region: String,
+    /// Git ref to read manifests from (working tree if unset)
+    pub gitref: Option<String>,
+}
+
+impl DiffSource {
+    fn describe(&self) -> String {
+        match &self.gitref {
+            Some(r) => format!("{}@{}", self.region, r),
+            None => self.region.clone(),
+        }
+    }
+}
+
+/// Replace secret values so they never end up in diff output
+fn mask_secrets(mut v: Value) -> Value {
+    for k in &["secrets", "secretFiles"] {
+        if let Some(secrets) = v.get_mut(*k).and_then(Value::as_object_mut) {
+            for s in secrets.values_mut() {
+                *s = Value::String(MASKED.into());
+            }
+        }
+    }
+    v
+}
+
+/// Key to match array elements by (if every element is an object with a name)
+fn element_key(arr: &[Value]) -> Option<Vec<String>> {
+    arr.iter()
+        .map(|x| x.get("name").and_then(Value::as_str).map(String::from))
+        .collect()
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+fn walk(path: &str, a: &Value, b: &Value, res: &mut Vec<Change>) {
+    match (a, b) {
+        (Value::Object(x), Value::Object(y)) => {
+            for (k, xv) in x {
+                let p = join(path, k);
+                match y.get(k) {
+                    Some(yv) => walk(&p, xv, yv, res),
+                    None => res.push(Change::Removed { path: p, value: xv.clone() }),
+                }
+            }
+            for (k, yv) in y {
+                if !x.contains_key(k) {
+                    res.push(Change::Added { path: join(path, k), value: yv.clone() });
+                }
+            }
+        },
+        (Value::Array(x), Value::Array(y)) => {
+            // named lists (sidecars, workers, cronJobs, ...) are compared by name
+            if let (Some(xk), Some(yk)) = (element_key(x), element_key(y)) {
+                for (i, n) in xk.iter().enumerate() {
+                    let p = format!("{}[{}]", path, n);
+                    match yk.iter().position(|m| m == n) {
+                        Some(j) => walk(&p, &x[i], &y[j], res),
+                        None => res.push(Change::Removed { path: p, value: x[i].clone() }),
+                    }
+                }
+                for (j, n) in yk.iter().enumerate() {
+                    if !xk.contains(n) {
+                        res.push(Change::Added { path: format!("{}[{}]", path, n), value: y[j].clone() });
+                    }
+                }
+            } else {
+                for i in 0..x.len().max(y.len()) {
+                    let p = format!("{}[{}]", path, i);
+                    match (x.get(i), y.get(i)) {
+                        (Some(xv), Some(yv)) => walk(&p, xv, yv, res),
+                        (Some(xv), None) => res.push(Change::Removed { path: p, value: xv.clone() }),
+                        (None, Some(yv)) => res.push(Change::Added { path: p, value: yv.clone() }),
+                        (None, None) => unreachable!(),
+                    }
+                }
+            }
+        },
+        _ => {
+            if a != b {
+                res.push(Change::Changed { path: path.to_string(), from: a.clone(), to: b.clone() });
+            }
+        }
+    }
+}
+
+/// Compare two resolved manifests field by field
+///
+/// Values of `secrets` and `secretFiles` are masked, so only added or removed secrets show up.
+pub fn manifests(a: &Manifest, b: &Manifest) -> Result<Vec<Change>> {
+    let x = mask_secrets(serde_json::to_value(a)?);
+    let y = mask_secrets(serde_json::to_value(b)?);
+    let mut res = vec![];
+    walk("", &x, &y, &mut res);
+    Ok(res)
+}
+
+/// Compact single line representation of a value
+fn short(v: &Value) -> String {
+    serde_json::to_string(v).unwrap_or_else(|_| "?".into())
+}
+
+/// Human readable diff output
+pub fn format_human(changes: &[Change]) -> String {
+    let mut out = String::new();
+    for c in changes {
+        let line = match c {
+            Change::Added { path, value } => format!("+ {}: {}", path, short(value)),
+            Change::Removed { path, value } => format!("- {}: {}", path, short(value)),
+            Change::Changed { path, from, to } => format!("~ {}: {} -> {}", path, short(from), short(to)),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Extract the manifests tree at a git ref into a temporary directory
+///
+/// The directory is removed when the returned guard is dropped.
+fn checkout_ref(gitref: &str) -> Result<TempDir> {
+    let prefix = Command::new("git").args(&["rev-parse", "--show-prefix"]).output()?;
+    if !prefix.status.success() {
+        bail!("shipcat diff --from-ref needs to run inside a git repository");
+    }
+    let prefix = String::from_utf8_lossy(&prefix.stdout).trim().to_string();
+    // archive the manifests directory as of the ref (not necessarily the repo root)
+    let tree = if prefix.is_empty() { gitref.to_string() } else { format!("{}:{}", gitref, prefix) };
+
+    let dir = tempfile::Builder::new().prefix("shipcat-diff-").tempdir()?;
+    let mut archive = Command::new("git").args(&["archive", "--format=tar", &tree])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let untar = Command::new("tar").arg("-x").arg("-C").arg(dir.path())
+        .stdin(archive.stdout.take().unwrap())
+        .status()?;
+    let archived = archive.wait()?;
+    if !archived.success() || !untar.success() {
+        bail!("failed to extract manifests at git ref {}", gitref);
+    }
+    Ok(dir)
+}
+
+/// Working directory change that is undone when dropped
+///
+/// Manifests and config are read relative to the working directory.
+struct CwdGuard {
+    prev: PathBuf,
+}
+
+impl CwdGuard {
+    fn enter(dir: &Path) -> Result<CwdGuard> {
+        let prev = env::current_dir()?;
+        env::set_current_dir(dir)?;
+        Ok(CwdGuard { prev })
+    }
+}
+
+impl Drop for CwdGuard {
+    fn drop(&mut self) {
+        if let Err(e) = env::set_current_dir(&self.prev) {
+            error!("Failed to return to {}: {}", self.prev.display(), e);
+        }
+    }
+}
+
+/// Resolve a stubbed manifest from the current directory
+fn resolve_here(svc: &str, region: &str) -> Result<Manifest> {
+    let (conf, reg) = Config::new(ConfigType::Base, region)?;
+    Ok(Manifest::base(svc, &conf, &reg)?.stub(&reg)?)
+}
+
+/// Resolve a manifest for one side of a diff
+fn resolve(svc: &str, src: &DiffSource) -> Result<Manifest> {
+    let res = if let Some(r) = &src.gitref {
+        let dir = checkout_ref(r)?;
+        // dropped before the checkout is removed
+        let _cwd = CwdGuard::enter(dir.path())?;
+        resolve_here(svc, &src.region)
+    } else {
+        resolve_here(svc, &src.region)
+    };
+    res.chain_err(|| format!("could not resolve {} in {}", svc, src.describe()))
+}
+
+/// Diff a service between two regions and/or git refs
+pub fn diff(svc: &str, from: &DiffSource, to: &DiffSource, fmt: DiffFormat) -> Result<Vec<Change>> {
+    let a = resolve(svc, from)?;
+    let b = resolve(svc, to)?;
+    let mut changes = manifests(&a, &b)?;
+    changes.sort_by(|x, y| x.path().cmp(y.path()));
+    match fmt {
+        DiffFormat::Json => println!("{}", serde_json::to_string_pretty(&changes)?),
+        DiffFormat::Human => {
+            if changes.is_empty() {
+                println!("{}: no differences between {} and {}", svc, from.describe(), to.describe());
+            } else {
+                println!("--- {} ({})", svc, from.describe());
+                println!("+++ {} ({})", svc, to.describe());
+                print!("{}", format_human(&changes));
+            }
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{walk, mask_secrets, Change};
+    use serde_json::json;
+
+    #[test]
+    fn diff_named_lists_and_secrets() {
+        let a = mask_secrets(json!({
+            "env": {"plain": {"A": "1", "B": "2"}},
+            "sidecars": [{"name": "redis", "image": "redis:4"}],
+            "secrets": {"TOKEN": "abc"},
+            "secretFiles": {"cert.pem": "b2xk"},
+        }));
+        let b = mask_secrets(json!({
+            "env": {"plain": {"A": "1", "C": "3"}},
+            "sidecars": [{"name": "statsd"}, {"name": "redis", "image": "redis:5"}],
+            "secrets": {"TOKEN": "def", "OTHER": "x"},
+            "secretFiles": {"cert.pem": "bmV3"},
+        }));
+        let mut res = vec![];
+        walk("", &a, &b, &mut res);
+        assert!(res.contains(&Change::Removed { path: "env.plain.B".into(), value: json!("2") }));
+        assert!(res.contains(&Change::Added { path: "env.plain.C".into(), value: json!("3") }));
+        assert!(res.contains(&Change::Changed {
+            path: "sidecars[redis].image".into(), from: json!("redis:4"), to: json!("redis:5")
+        }));
+        assert!(res.contains(&Change::Added { path: "sidecars[statsd]".into(), value: json!({"name": "statsd"}) }));
+        // secret values never differ in output
+        assert!(res.contains(&Change::Added { path: "secrets.OTHER".into(), value: json!("<masked>") }));
+        assert_eq!(res.len(), 5);
+    }
+}
@@ -6,7 +6,7 @@ use crate::structs::{
     rds::Rds,
     elasticache::ElastiCache,
 };
-use super::{Config, Team, Region};
+use super::{Config, Region};
 use super::{Result, Manifest};
 
 
@@ -229,74 +229,32 @@ pub fn caches(conf: &Config, region: &Region) -> Result<Vec<ElastiCache>> {
 // ----------------------------------------------------------------------------
 
 
-use super::structs::Resources;
-use shipcat_definitions::math::ResourceTotals;
-
-/// Complete breakdown of resource usage in total, and split by team.
-///
-/// Normally this is computed by `Manifest::resources` for a region-wide total.
-/// Looping over all regions is possible in the CLI.
-#[derive(Serialize)]
-pub struct ResourceBreakdown {
-    /// Total totals
-    pub totals: ResourceTotals,
-    /// A partition of totals info teams
-    pub teams: BTreeMap<String, ResourceTotals>,
-}
-
-impl ResourceBreakdown {
-    /// Constructor to ensure all valid teams are filled in
-    pub fn new(tx: Vec<Team>) -> ResourceBreakdown {
-        let mut teams = BTreeMap::new();
-        for t in tx {
-            teams.insert(t.name, ResourceTotals::default());
-        }
-        ResourceBreakdown { teams, totals: ResourceTotals::default() }
-    }
-
-    /// Round all numbers to gigs and full cores (for all teams)
-    pub fn normalise(mut self) -> Self {
-        for tt in &mut self.teams.values_mut() {
-            tt.base.round();
-            tt.extra.round();
-        }
-        self.totals.base.round();
-        self.totals.extra.round();
-        self
-    }
-}
-
+pub use shipcat_definitions::math::ResourceBreakdown;
 
 /// Compute resource usage for all available manifests in a region.
 fn resources_region(conf: &Config, region: &Region) -> Result<ResourceBreakdown> {
     let services = Manifest::available(&region.name)?;
     let mut bd = ResourceBreakdown::new(conf.teams.clone()); // zero for all the things
 
-    let mut sum : Resources<f64> = Default::default();
-    let mut extra : Resources<f64> = Default::default(); // autoscaling limits
-
     for svc in services {
         let mf = Manifest::base(&svc, conf, region)?;
         if let Some(ref md) = mf.metadata {
-            let ResourceTotals { base: sb, extra: se } = mf.compute_resource_totals()?;
-            sum += sb.clone();
-            extra += se.clone();
-            let e = bd.teams.get_mut(&md.team).unwrap(); // exists by ResourceBreakdown::new
-            e.base += sb.clone();
-            e.extra += se.clone();
+            bd.add(&md.team, &mf.compute_resource_totals()?);
         } else {
             bail!("{} service does not have resources specification and metadata", mf.name)
         }
     }
-    bd.totals.base = sum;
-    bd.totals.extra = extra;
     Ok(bd)
 }
 
 
 /// Resource use for a single region
 pub fn resources(conf: &Config, region: &Region) -> Result<()> {
-    let bd = resources_region(&conf, region)?.normalise();
+    let mut bd = resources_region(&conf, region)?;
+    if let Some(prices) = &region.pricing {
+        bd.price(prices);
+    }
+    let bd = bd.normalise();
     println!("{}", serde_json::to_string_pretty(&bd)?);
     Ok(())
 }
@@ -307,13 +265,8 @@ pub fn totalresources(conf: &Config) -> Result<()> {
     for r in conf.list_regions() {
         let reg = conf.get_region(&r)?;
         let res = resources_region(&conf, &reg)?;
-        bd.totals.base += res.totals.base;
-        bd.totals.extra += res.totals.extra;
-        for t in &conf.teams {
-            let rhs = &res.teams[&t.name];
-            let e = bd.teams.get_mut(&t.name).unwrap(); // exists by ResourceBreakdown::new
-            e.base += rhs.base.clone();
-            e.extra += rhs.extra.clone();
+        for (team, rhs) in &res.teams {
+            bd.add(team, rhs);
         }
     }
     bd = bd.normalise();
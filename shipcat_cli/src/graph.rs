@@ -1,10 +1,11 @@
 use petgraph::graph::{DiGraph, NodeIndex};
-use petgraph::dot;
+use petgraph::{algo, dot, Direction};
+use std::collections::BTreeSet;
 use std::fmt::{self, Debug};
 
 use super::{Manifest, Region, Config};
-use super::structs::{Dependency, DependencyProtocol};
-use super::{Result};
+pub use super::structs::DepEdge;
+use super::{Result, ErrorKind};
 
 /// The node type in `CatGraph` representing a `Manifest`
 #[derive(Serialize, Deserialize, Clone)]
@@ -28,26 +29,6 @@ impl Debug for ManifestNode {
     }
 }
 
-/// The edge type in `CatGraph` representing a `Dependency`
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct DepEdge {
-    pub api: String,
-    pub contract: Option<String>,
-    pub protocol: DependencyProtocol,
-    pub intent: Option<String>,
-}
-impl DepEdge {
-    fn new(dep: &Dependency) -> Self {
-        DepEdge {
-            api: dep.api.clone(),
-            contract: dep.contract.clone(),
-            protocol: dep.protocol.clone(),
-            intent: dep.intent.clone(),
-        }
-    }
-}
-
-
 /// Graph of simplified manifests with dependencies as edges
 ///
 /// This is fully serializable because it is created with `petgraph` using the serde
@@ -119,16 +100,39 @@ pub fn generate(service: &str, conf: &Config, reg: &Region, dot: bool) -> Result
 ///
 /// But it would require: TODO: optionally filter edges around node(s)
 pub fn full(dot: bool, conf: &Config, reg: &Region) -> Result<CatGraph> {
+    let graph = build(conf, reg)?;
+    let out = if dot {
+        format!("{:?}", dot::Dot::with_config(&graph, &[dot::Config::EdgeNoLabel]))
+    }
+    else {
+        serde_yaml::to_string(&graph)?
+    };
+    println!("{}", out);
+    Ok(graph)
+}
+
+/// Build the dependency graph of all services in a region without printing it
+///
+/// Dependencies on services that are not in the region are left out.
+pub fn build(conf: &Config, reg: &Region) -> Result<CatGraph> {
     let mut graph : CatGraph = DiGraph::<_, _>::new();
     let services = Manifest::available(&reg.name)?;
-    for svc in services {
+    for svc in &services {
         debug!("Scanning service {:?}", svc);
 
-        let mf = Manifest::base(&svc, conf, reg)?;
-        let node = ManifestNode::new(&mf);
-        let idx = graph.add_node(node);
+        let mf = Manifest::base(svc, conf, reg)?;
+        // node may exist already if an earlier service depended on it
+        let idx = if let Some(id) = nodeidx_from_name(svc, &graph) {
+            id
+        } else {
+            graph.add_node(ManifestNode::new(&mf))
+        };
 
         for dep in &mf.dependencies {
+            if !services.contains(&dep.name) {
+                debug!("Ignoring dependency {} of {} outside {}", dep.name, svc, reg.name);
+                continue;
+            }
             let subidx = if let Some(id) = nodeidx_from_name(&dep.name, &graph) {
                 trace!("Found dependency with existing node: {}", dep.name);
                 id
@@ -136,21 +140,51 @@ pub fn full(dot: bool, conf: &Config, reg: &Region) -> Result<CatGraph> {
                 trace!("Found dependency new in graph: {}", dep.name);
                 let depmf = Manifest::base(&dep.name, conf, reg)?;
                 let depnode = ManifestNode::new(&depmf);
-                let depidx = graph.add_node(depnode);
-                depidx
+                graph.add_node(depnode)
             };
             graph.update_edge(idx, subidx, DepEdge::new(&dep));
         }
     }
+    Ok(graph)
+}
 
-    let out = if dot {
-        format!("{:?}", dot::Dot::with_config(&graph, &[dot::Config::EdgeNoLabel]))
+/// Split a dependency graph into waves of services that can be upgraded together
+///
+/// Every service comes in a later wave than all of its dependencies.
+/// Services within a wave are sorted by name.
+/// Errors with the services involved if the graph has a dependency cycle.
+pub fn waves(graph: &CatGraph) -> Result<Vec<Vec<String>>> {
+    let mut placed = BTreeSet::new();
+    let mut remaining : Vec<NodeIndex> = graph.node_indices().collect();
+    let mut res = vec![];
+    while !remaining.is_empty() {
+        let (ready, blocked) : (Vec<NodeIndex>, Vec<NodeIndex>) = remaining.into_iter().partition(|&idx| {
+            graph.neighbors_directed(idx, Direction::Outgoing).all(|dep| placed.contains(&dep))
+        });
+        if ready.is_empty() {
+            bail!(ErrorKind::DependencyCycle(cycle(graph, &blocked)));
+        }
+        let mut wave : Vec<String> = ready.iter().map(|&idx| graph[idx].name.clone()).collect();
+        wave.sort();
+        placed.extend(ready);
+        res.push(wave);
+        remaining = blocked;
     }
-    else {
-        serde_yaml::to_string(&graph)?
-    };
-    println!("{}", out);
-    Ok(graph)
+    Ok(res)
+}
+
+/// Find the services forming a cycle amongst nodes that could not be placed in a wave
+fn cycle(graph: &CatGraph, blocked: &[NodeIndex]) -> Vec<String> {
+    for scc in algo::tarjan_scc(graph) {
+        let selfref = scc.len() == 1 && graph.find_edge(scc[0], scc[0]).is_some();
+        if (scc.len() > 1 || selfref) && scc.iter().all(|idx| blocked.contains(idx)) {
+            let mut names : Vec<String> = scc.iter().map(|&idx| graph[idx].name.clone()).collect();
+            names.sort();
+            return names;
+        }
+    }
+    // blocked nodes are always downstream of a cycle
+    blocked.iter().map(|&idx| graph[idx].name.clone()).collect()
 }
 
 /// Generate first level reverse dependencies for a service
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::Value;
+use serde_yaml;
+
+use super::{Result, ResultExt, ErrorKind, Region};
+use super::backend::backend;
+use super::direct::UpgradeData;
+use super::kube::{self, Client, ApiResource};
+use super::kube::objects::{ObjectList, MinimalObject};
+
+/// Field manager recorded on everything shipcat applies
+pub const FIELD_MANAGER: &str = "shipcat";
+
+/// Label marking objects applied for a service (used for pruning)
+pub const SERVICE_LABEL: &str = "shipcat.babylontech.co.uk/service";
+
+/// Kinds that are always checked for pruning
+///
+/// Rendered kinds are checked as well, this covers kinds that stopped being rendered.
+const PRUNE_KINDS: [(&str, &str); 10] = [
+    ("v1", "ConfigMap"),
+    ("v1", "Secret"),
+    ("v1", "Service"),
+    ("v1", "ServiceAccount"),
+    ("apps/v1", "Deployment"),
+    ("batch/v1", "Job"),
+    ("batch/v1beta1", "CronJob"),
+    ("autoscaling/v1", "HorizontalPodAutoscaler"),
+    ("policy/v1beta1", "PodDisruptionBudget"),
+    ("extensions/v1beta1", "Ingress"),
+];
+
+/// A rendered kube object
+#[derive(Debug, Clone)]
+pub struct RenderedObject {
+    pub apiVersion: String,
+    pub kind: String,
+    pub name: String,
+    pub data: Value,
+}
+
+/// Split `helm template` output into labelled kube objects
+pub fn parse_rendered(tpl: &str, svc: &str) -> Result<Vec<RenderedObject>> {
+    let mut res = vec![];
+    let mut docs = vec![];
+    let mut current = String::new();
+    for l in tpl.lines() {
+        if l.starts_with("---") {
+            docs.push(current);
+            current = String::new();
+        } else {
+            current.push_str(l);
+            current.push('\n');
+        }
+    }
+    docs.push(current);
+
+    for doc in docs {
+        // skip documents with nothing but comments (e.g. `# Source:` of empty templates)
+        if doc.lines().all(|l| l.trim().is_empty() || l.trim_start().starts_with('#')) {
+            continue;
+        }
+        let mut data : Value = serde_yaml::from_str(&doc)?;
+        if data.is_null() {
+            continue;
+        }
+        let field = |k: &str| data[k].as_str().map(String::from);
+        let apiVersion = field("apiVersion").ok_or_else(|| format!("rendered object without apiVersion in {}", svc))?;
+        let kind = field("kind").ok_or_else(|| format!("rendered object without kind in {}", svc))?;
+        let name = data["metadata"]["name"].as_str().map(String::from)
+            .ok_or_else(|| format!("rendered {} without a name in {}", kind, svc))?;
+        let labels = data["metadata"].as_object_mut()
+            .ok_or_else(|| format!("rendered {} {} has invalid metadata", kind, name))?
+            .entry("labels")
+            .or_insert_with(|| Value::Object(Default::default()));
+        if let Some(lbls) = labels.as_object_mut() {
+            lbls.insert(SERVICE_LABEL.into(), Value::String(svc.into()));
+        }
+        res.push(RenderedObject { apiVersion, kind, name, data });
+    }
+    Ok(res)
+}
+
+/// Render a service's chart with `helm template` for an upgrade
+fn render(data: &UpgradeData, reg: &Region) -> Result<String> {
+    let helm = backend(reg);
+    let mut tplvec = helm.template(&data.name, &format!("charts/{}", data.chart), &data.values);
+    tplvec.extend_from_slice(&["--set".into(), format!("version={}", data.version)]);
+    info!("{}", helm.cmdline(&tplvec));
+    let (tpl, tplerr, success) = helm.output(tplvec.clone())?;
+    if !success {
+        warn!("{} stderr: {}", tplvec.join(" "), tplerr);
+        bail!("helm template failed for {}", data.name);
+    }
+    Ok(tpl)
+}
+
+/// Apply a service without helm's release tracking
+///
+/// Renders the chart with `helm template`, server-side applies every object
+/// under the shipcat field manager, then prunes objects labelled for the service
+/// that are no longer rendered.
+pub fn apply(data: &UpgradeData, reg: &Region) -> Result<()> {
+    let client = Client::new()?;
+    let tpl = render(data, reg)?;
+    let objects = parse_rendered(&tpl, &data.name)?;
+
+    let mut resources : BTreeMap<(String, String), ApiResource> = BTreeMap::new();
+    let mut applied = BTreeSet::new();
+    for o in objects {
+        let key = (o.apiVersion.clone(), o.kind.clone());
+        if !resources.contains_key(&key) {
+            let r = kube::discover(&client, &o.apiVersion, &o.kind)?;
+            resources.insert(key.clone(), r);
+        }
+        let r = &resources[&key];
+        let mut obj = o.data;
+        if r.namespaced {
+            obj["metadata"]["namespace"] = Value::String(data.namespace.clone());
+        }
+        debug!("Applying {} {}", o.kind, o.name);
+        client.apply::<_, MinimalObject>(&r.path(&data.namespace, &o.name), FIELD_MANAGER, &obj)
+            .chain_err(|| ErrorKind::HelmUpgradeFailure(data.name.clone()))?;
+        applied.insert((o.kind, o.name));
+    }
+    info!("Applied {} objects for {}", applied.len(), data.name);
+
+    // prune everything labelled for the service that we did not just apply
+    for (v, k) in PRUNE_KINDS.iter() {
+        let key = (v.to_string(), k.to_string());
+        if !resources.contains_key(&key) {
+            match kube::discover(&client, v, k) {
+                Ok(r) => { resources.insert(key, r); },
+                Err(e) => debug!("Not pruning {} {}: {}", v, k, e),
+            }
+        }
+    }
+    let selector = format!("{}={}", SERVICE_LABEL, data.name);
+    for r in resources.values() {
+        let found : ObjectList<MinimalObject> = client.get(&r.collection_path(&data.namespace), &[("labelSelector", &selector)])?;
+        for o in found.items {
+            if !applied.contains(&(r.kind.clone(), o.metadata.name.clone())) {
+                info!("Pruning {} {} from {}", r.kind, o.metadata.name, data.name);
+                client.delete(&r.path(&data.namespace, &o.metadata.name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rendered, SERVICE_LABEL};
+
+    #[test]
+    fn parse_helm_template() {
+        let tpl = r#"---
+# Source: base/templates/configmap.yaml
+
+---
+# Source: base/templates/deployment.yaml
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: fake-ask
+  labels:
+    app: fake-ask
+spec:
+  replicas: 2
+---
+# Source: base/templates/service.yaml
+apiVersion: v1
+kind: Service
+metadata:
+  name: fake-ask
+"#;
+        let objs = parse_rendered(tpl, "fake-ask").unwrap();
+        assert_eq!(objs.len(), 2);
+        assert_eq!(objs[0].kind, "Deployment");
+        assert_eq!(objs[0].data["metadata"]["labels"]["app"], "fake-ask");
+        assert_eq!(objs[0].data["metadata"]["labels"][SERVICE_LABEL], "fake-ask");
+        assert_eq!(objs[1].apiVersion, "v1");
+        assert_eq!(objs[1].data["metadata"]["labels"][SERVICE_LABEL], "fake-ask");
+    }
+}
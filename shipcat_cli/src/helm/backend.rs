@@ -0,0 +1,125 @@
+use super::{Result, Region, HelmBackend};
+use super::helpers::{hexec, hout};
+
+/// Interface to a helm installation and its release storage
+///
+/// Only the parts that differ between helm versions are required,
+/// the rest of the helm cli is identical enough to be shared.
+pub trait Backend {
+    /// Helm executable to invoke
+    fn binary(&self) -> &str;
+
+    /// Global flags that point helm at the releases of a namespace
+    fn scope(&self, ns: &str) -> Vec<String>;
+
+    /// Arguments for fetching the values of a release as plain yaml
+    fn get_values(&self, name: &str) -> Vec<String>;
+
+    /// Arguments for removing all traces of a release
+    fn purge(&self, name: &str) -> Vec<String>;
+
+    /// Arguments for templating a chart offline
+    fn template(&self, name: &str, chart: &str, hfile: &str) -> Vec<String>;
+
+    /// Whether `helm upgrade --recreate-pods` is available
+    fn supports_recreate(&self) -> bool;
+
+    /// Scope arguments to a namespace
+    fn args(&self, ns: &str, args: Vec<String>) -> Vec<String> {
+        let mut res = self.scope(ns);
+        res.extend(args);
+        res
+    }
+
+    /// Run helm with inherited stdio
+    fn exec(&self, args: Vec<String>) -> Result<()> {
+        hexec(self.binary(), args)
+    }
+
+    /// Run helm and capture (stdout, stderr, success)
+    fn output(&self, args: Vec<String>) -> Result<(String, String, bool)> {
+        hout(self.binary(), args)
+    }
+
+    /// Human readable command line for log messages
+    fn cmdline(&self, args: &[String]) -> String {
+        format!("{} {}", self.binary(), args.join(" "))
+    }
+}
+
+/// Helm 2 with a tiller per namespace
+pub struct Helm2 {
+    binary: String,
+}
+
+impl Backend for Helm2 {
+    fn binary(&self) -> &str {
+        &self.binary
+    }
+    fn scope(&self, ns: &str) -> Vec<String> {
+        vec![format!("--tiller-namespace={}", ns)]
+    }
+    fn get_values(&self, name: &str) -> Vec<String> {
+        vec!["get".into(), "values".into(), name.into()]
+    }
+    fn purge(&self, name: &str) -> Vec<String> {
+        vec!["del".into(), "--purge".into(), name.into()]
+    }
+    fn template(&self, _name: &str, chart: &str, hfile: &str) -> Vec<String> {
+        vec!["template".into(), chart.into(), "-f".into(), hfile.into()]
+    }
+    fn supports_recreate(&self) -> bool {
+        true
+    }
+}
+
+/// Helm 3 storing releases as secrets in the release namespace
+pub struct Helm3 {
+    binary: String,
+}
+
+impl Backend for Helm3 {
+    fn binary(&self) -> &str {
+        &self.binary
+    }
+    fn scope(&self, ns: &str) -> Vec<String> {
+        vec![format!("--namespace={}", ns)]
+    }
+    fn get_values(&self, name: &str) -> Vec<String> {
+        // without an output format helm 3 prefixes the yaml with a header
+        vec!["get".into(), "values".into(), name.into(), "--output=yaml".into()]
+    }
+    fn purge(&self, name: &str) -> Vec<String> {
+        vec!["uninstall".into(), name.into()]
+    }
+    fn template(&self, name: &str, chart: &str, hfile: &str) -> Vec<String> {
+        vec!["template".into(), name.into(), chart.into(), "-f".into(), hfile.into()]
+    }
+    fn supports_recreate(&self) -> bool {
+        false
+    }
+}
+
+/// The helm backend configured for a region
+pub fn backend(reg: &Region) -> Box<dyn Backend> {
+    let binary = reg.helm.binary.clone().unwrap_or_else(|| "helm".into());
+    match reg.helm.backend {
+        HelmBackend::Helm2 => Box::new(Helm2 { binary }),
+        HelmBackend::Helm3 => Box::new(Helm3 { binary }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, Helm2, Helm3};
+
+    #[test]
+    fn backend_scoping() {
+        let h2 = Helm2 { binary: "helm".into() };
+        let h3 = Helm3 { binary: "helm3".into() };
+        let args = vec!["history".to_string(), "svc".to_string()];
+        assert_eq!(h2.args("dev", args.clone()), vec!["--tiller-namespace=dev", "history", "svc"]);
+        assert_eq!(h3.args("dev", args.clone()), vec!["--namespace=dev", "history", "svc"]);
+        assert_eq!(h3.cmdline(&h3.purge("svc")), "helm3 uninstall svc");
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::Duration;
+
+use reqwest;
+use serde_json::{json, Value};
+
+use crate::webhooks::{self, UpgradeState};
+use crate::structs::{Colour, Kong};
+use crate::{kong, KongConfig};
+use super::{Result, ResultExt, ErrorKind, Config, Manifest, Region};
+use super::direct::{self, UpgradeData, UpgradeMode};
+use super::kube::{self, Client};
+use super::kube::objects::MinimalObject;
+
+/// ConfigMap recording the active colour of every blue/green service in a namespace
+pub const COLOUR_CONFIGMAP: &str = "shipcat-colours";
+
+fn configmap_path(ns: &str) -> String {
+    format!("/api/v1/namespaces/{}/configmaps/{}", ns, COLOUR_CONFIGMAP)
+}
+
+/// Active colours recorded in a namespace
+///
+/// Services without an entry are blue.
+pub fn read_colours(client: &Client, ns: &str) -> Result<BTreeMap<String, Colour>> {
+    let cm : Option<Value> = client.get_opt(&configmap_path(ns))?;
+    let mut res = BTreeMap::new();
+    if let Some(data) = cm.as_ref().and_then(|c| c["data"].as_object()) {
+        for (svc, v) in data {
+            let colour = v.as_str().unwrap_or("").parse()
+                .chain_err(|| format!("invalid colour for {} in {}", svc, COLOUR_CONFIGMAP))?;
+            res.insert(svc.clone(), colour);
+        }
+    }
+    Ok(res)
+}
+
+/// Record the active colour of a service
+///
+/// Replaces the ConfigMap with the resourceVersion it was read at,
+/// so concurrent switches of other services are not lost.
+pub fn write_colour(client: &Client, ns: &str, svc: &str, colour: Colour) -> Result<()> {
+    let pth = configmap_path(ns);
+    match client.get_opt::<Value>(&pth)? {
+        Some(mut cm) => {
+            if !cm["data"].is_object() {
+                cm["data"] = json!({});
+            }
+            cm["data"][svc] = Value::String(colour.to_string());
+            client.replace::<_, MinimalObject>(&pth, &cm)?;
+        },
+        None => {
+            let mut cm = json!({
+                "apiVersion": "v1",
+                "kind": "ConfigMap",
+                "metadata": { "name": COLOUR_CONFIGMAP, "namespace": ns },
+                "data": {},
+            });
+            cm["data"][svc] = Value::String(colour.to_string());
+            client.create::<_, MinimalObject>(&format!("/api/v1/namespaces/{}/configmaps", ns), &cm)?;
+        }
+    }
+    Ok(())
+}
+
+/// Active colour of a blue/green service
+pub fn active_colour(client: &Client, mf: &Manifest) -> Result<Colour> {
+    Ok(read_colours(client, &mf.namespace)?.get(&mf.name).cloned().unwrap_or_default())
+}
+
+/// Active colours for all blue/green services in a region
+///
+/// Only talks to kubernetes if the region has blue/green services.
+pub fn active_colours(conf: &Config, region: &Region) -> Result<BTreeMap<String, Colour>> {
+    let mut bluegreen = false;
+    for svc in Manifest::available(&region.name)? {
+        if Manifest::simple(&svc, conf, region)?.blueGreen.is_some() {
+            bluegreen = true;
+            break;
+        }
+    }
+    if !bluegreen {
+        return Ok(BTreeMap::new());
+    }
+    let client = Client::new()?;
+    read_colours(&client, &region.namespace)
+}
+
+/// Point a kong api at a new upstream via the kong admin api
+pub fn switch_kong(kong: &KongConfig, api: &str, upstream: &str) -> Result<()> {
+    let pth = format!("/apis/{}", api);
+    let res = kong::admin_request(kong, reqwest::Method::PATCH, &pth)?
+        .json(&json!({ "upstream_url": upstream }))
+        .send()
+        .chain_err(|| format!("could not switch kong api {} at {}", api, kong::admin_url(kong)))?;
+    if !res.status().is_success() {
+        bail!("kong returned {} when switching {} to {}", res.status(), api, upstream);
+    }
+    Ok(())
+}
+
+/// The manifest of a service as deployed under a colour
+pub fn coloured(mf: &Manifest, colour: Colour) -> Manifest {
+    let mut res = mf.clone();
+    res.name = colour.release_name(&mf.name);
+    res
+}
+
+/// Switch kong to a colour and record it as active
+fn switch(client: &Client, mf: &Manifest, reg: &Region, from: Colour, to: Colour) -> Result<()> {
+    let upstream = |c: Colour| Kong::default_upstream(&c.release_name(&mf.name), &mf.namespace);
+    info!("Switching kong api {} from {} to {}", mf.name, from, to);
+    switch_kong(&reg.kong, &mf.name, &upstream(to))?;
+    if let Err(e) = write_colour(client, &mf.namespace, &mf.name, to) {
+        // kong output would switch back on next reconcile, so undo now
+        warn!("Failed to record {} as {}, switching kong back", mf.name, to);
+        switch_kong(&reg.kong, &mf.name, &upstream(from))?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Deploy a blue/green service to its idle colour and switch traffic to it
+///
+/// The active release is left untouched until the idle one is ready,
+/// so a failure at any point before the switch needs no rollback.
+pub fn bluegreen(mf: &Manifest, ud: &UpgradeData, reg: &Region) -> Result<()> {
+    let client = Client::new()?;
+    let active = active_colour(&client, mf)?;
+    let target = active.other();
+    let idle = coloured(mf, target);
+    info!("{} is {}, deploying {} as {}", mf.name, active, target, idle.name);
+
+    let hfile = format!("{}.helm.gen.yml", idle.name);
+    direct::values(&idle, Some(hfile.clone()))?;
+    let idle_ud = UpgradeData {
+        name: idle.name.clone(),
+        values: hfile.clone(),
+        mode: UpgradeMode::UpgradeInstall,
+        ..ud.clone()
+    };
+    let res = direct::upgrade(&idle_ud, reg);
+    let _ = fs::remove_file(&hfile);
+    res?;
+
+    await_colour(&client, mf, target)?;
+    switch(&client, mf, reg, active, target)
+}
+
+/// Wait for the deployment of a colour to be rolled out
+fn await_colour(client: &Client, mf: &Manifest, colour: Colour) -> Result<()> {
+    let cmf = coloured(mf, colour);
+    let timeout = mf.blueGreen.as_ref().and_then(|bg| bg.readyTimeout).unwrap_or_else(|| cmf.estimate_wait_time());
+    if !kube::track_rollout(client, &cmf, Duration::from_secs(timeout.into()))? {
+        let _ = kube::debug(&cmf);
+        bail!(ErrorKind::UpgradeTimeout(cmf.name.clone(), timeout));
+    }
+    Ok(())
+}
+
+/// Switch a blue/green service back to its previous colour
+///
+/// The previous release is normally still running, so this is immediate,
+/// but traffic only moves once its deployment is confirmed to be rolled out.
+pub fn rollback(mf: &Manifest, ud: &UpgradeData, reg: &Region) -> Result<()> {
+    let client = Client::new()?;
+    let active = active_colour(&client, mf)?;
+    webhooks::upgrade_rollback_event(UpgradeState::RollingBack, ud, reg);
+    let res = await_colour(&client, mf, active.other())
+        .and_then(|_| switch(&client, mf, reg, active, active.other()));
+    match res {
+        Err(e) => {
+            error!("{}", e);
+            webhooks::upgrade_rollback_event(UpgradeState::RollbackFailed, ud, reg);
+            Err(e)
+        },
+        Ok(_) => {
+            webhooks::upgrade_rollback_event(UpgradeState::RolledBack, ud, reg);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::webhooks::{self, UpgradeState};
+use crate::structs::Canary;
+use super::{Result, ResultExt, ErrorKind, Manifest, Region};
+use super::apply::{parse_rendered, FIELD_MANAGER, SERVICE_LABEL};
+use super::direct::UpgradeData;
+use super::kube::{self, Client};
+use super::kube::objects::{Deployment, MinimalObject, ObjectList, Pod};
+use crate::chart;
+
+/// How often to poll the canary while waiting for it
+const POLL_INTERVAL: u64 = 5;
+
+/// Label marking the canary deployment of a service
+///
+/// Used instead of the service label so that pruning and diffs leave the canary alone.
+pub const CANARY_LABEL: &str = "shipcat.babylontech.co.uk/canary";
+
+fn deployment_path(ns: &str, name: &str) -> String {
+    format!("/apis/apps/v1/namespaces/{}/deployments/{}", ns, name)
+}
+
+/// Name of the canary deployment for a service
+pub fn canary_name(mf: &Manifest) -> String {
+    format!("{}-canary", mf.name)
+}
+
+/// Build the canary deployment from the service's rendered chart
+///
+/// Pods keep the labels of the stable deployment so they receive traffic from
+/// the same `Service`, but are additionally labelled with `track: canary`.
+/// The deployment itself carries the `CANARY_LABEL` rather than the service label.
+pub fn canary_deployment(mf: &Manifest, reg: &Region) -> Result<Value> {
+    let tpl = chart::render_release(mf, reg)?;
+    let dep = parse_rendered(&tpl, &mf.name)?.into_iter()
+        .find(|o| o.kind == "Deployment" && o.name == mf.name)
+        .ok_or_else(|| format!("chart for {} does not render a Deployment named {}", mf.name, mf.name))?;
+    let mut data = dep.data;
+    data["metadata"]["name"] = Value::String(canary_name(mf));
+    data["metadata"]["namespace"] = Value::String(mf.namespace.clone());
+    if let Some(lbls) = data["metadata"]["labels"].as_object_mut() {
+        lbls.remove(SERVICE_LABEL);
+        lbls.insert(CANARY_LABEL.into(), Value::String(mf.name.clone()));
+    }
+    let track = Value::String("canary".into());
+    data["metadata"]["labels"]["track"] = track.clone();
+    data["spec"]["selector"]["matchLabels"]["track"] = track.clone();
+    data["spec"]["template"]["metadata"]["labels"]["track"] = track;
+    Ok(data)
+}
+
+/// Set the replica count of a deployment via its scale subresource
+fn scale(client: &Client, ns: &str, name: &str, replicas: u32) -> Result<()> {
+    debug!("Scaling {} to {} replicas", name, replicas);
+    let data = json!({
+        "apiVersion": "autoscaling/v1",
+        "kind": "Scale",
+        "metadata": { "name": name, "namespace": ns },
+        "spec": { "replicas": replicas },
+    });
+    client.replace::<_, MinimalObject>(&format!("{}/scale", deployment_path(ns, name)), &data)?;
+    Ok(())
+}
+
+/// Replicas of the stable deployment before the canary started
+pub fn stable_replicas(client: &Client, mf: &Manifest) -> Result<u32> {
+    let dep : Option<Deployment> = client.get_opt(&deployment_path(&mf.namespace, &mf.name))?;
+    match dep {
+        Some(d) => Ok(d.spec.replicas.unwrap_or(1)),
+        None => bail!("{} has no stable deployment to run a canary against", mf.name),
+    }
+}
+
+/// Check the health gate against the current canary pods
+///
+/// Returns whether all expected canary pods are ready, or an error if the gate failed.
+fn gate(client: &Client, mf: &Manifest, cfg: &Canary, expected: u32) -> Result<bool> {
+    let selector = format!("app={},track=canary", mf.name);
+    let pth = format!("/api/v1/namespaces/{}/pods", mf.namespace);
+    let pods : ObjectList<Pod> = client.get(&pth, &[("labelSelector", &selector)])?;
+    let restarts : u32 = pods.items.iter().map(Pod::restarts).sum();
+    if restarts > cfg.healthGate.maxRestarts {
+        let msg = format!("{} restarts across canary pods (max {})", restarts, cfg.healthGate.maxRestarts);
+        bail!(ErrorKind::RolloutFailure(mf.name.clone(), "CanaryRestarts".into(), msg));
+    }
+    for p in &pods.items {
+        if let Some(w) = p.waiting_for(&["CrashLoopBackOff", "ErrImagePull", "ImagePullBackOff", "CreateContainerConfigError"]) {
+            let msg = format!("canary pod {} is waiting: {}", p.metadata.name, w.message);
+            bail!(ErrorKind::RolloutFailure(mf.name.clone(), w.reason.clone(), msg));
+        }
+    }
+    let ready = pods.items.iter().filter(|p| p.is_healthy()).count() as u32;
+    debug!("{}/{} canary pods of {} ready", ready, expected, mf.name);
+    Ok(ready >= expected)
+}
+
+/// Wait for the canary pods of a step to become ready while checking the health gate
+fn await_step(client: &Client, mf: &Manifest, cfg: &Canary, expected: u32) -> Result<()> {
+    let timeout = cfg.healthGate.readyTimeout.unwrap_or_else(|| mf.estimate_wait_time());
+    let start = Instant::now();
+    while !gate(client, mf, cfg, expected)? {
+        if start.elapsed() > Duration::from_secs(timeout.into()) {
+            bail!(ErrorKind::UpgradeTimeout(canary_name(mf), timeout));
+        }
+        thread::sleep(Duration::from_secs(POLL_INTERVAL));
+    }
+    Ok(())
+}
+
+/// Whether the stable replicas of a service belong to an autoscaler
+///
+/// Replicas are then added next to the stable deployment instead of shifted from it.
+fn autoscaled(mf: &Manifest) -> bool {
+    mf.autoScaling.is_some()
+}
+
+/// Shift replicas from the stable deployment to the canary one step at a time
+///
+/// The canary is left running at the last step's weight with the stable deployment
+/// scaled back up, so that capacity is kept while the stable deployment is upgraded.
+/// Autoscaled services keep their stable replicas, and get the canary on top.
+pub fn run_steps(client: &Client, mf: &Manifest, cfg: &Canary, canary: &Value, total: u32) -> Result<()> {
+    let ns = &mf.namespace;
+    let name = canary_name(mf);
+    let shift = !autoscaled(mf);
+    for (i, step) in cfg.steps.iter().enumerate() {
+        let replicas = if shift {
+            Canary::replicas(step.weight, total)
+        } else {
+            Canary::extra_replicas(step.weight, total)
+        };
+        info!("Canary step {}/{} for {}: {}% ({} of {} replicas)",
+            i + 1, cfg.steps.len(), mf.name, step.weight, replicas, total);
+        let mut obj = canary.clone();
+        obj["spec"]["replicas"] = json!(replicas);
+        client.apply::<_, MinimalObject>(&deployment_path(ns, &name), FIELD_MANAGER, &obj)
+            .chain_err(|| ErrorKind::HelmUpgradeFailure(mf.name.clone()))?;
+        if shift {
+            scale(client, ns, &mf.name, total - replicas)?;
+        }
+        await_step(client, mf, cfg, replicas)?;
+        if step.pause > 0 {
+            info!("Observing canary of {} for {}s", mf.name, step.pause);
+            thread::sleep(Duration::from_secs(step.pause.into()));
+            // pods may have started failing while we waited
+            if !gate(client, mf, cfg, replicas)? {
+                let msg = format!("canary pods of {} stopped being ready", mf.name);
+                bail!(ErrorKind::RolloutFailure(mf.name.clone(), "CanaryUnready".into(), msg));
+            }
+        }
+    }
+    // restore capacity before the stable deployment is upgraded
+    if shift {
+        scale(client, ns, &mf.name, total)?;
+    }
+    Ok(())
+}
+
+/// Remove the canary deployment and restore the stable replica count
+pub fn abort(client: &Client, mf: &Manifest, total: u32) -> Result<()> {
+    if !autoscaled(mf) {
+        scale(client, &mf.namespace, &mf.name, total)?;
+    }
+    cleanup_with(client, mf)?;
+    if !kube::await_rollout_status(mf)? {
+        bail!(ErrorKind::UpgradeTimeout(mf.name.clone(), mf.estimate_wait_time()));
+    }
+    Ok(())
+}
+
+fn cleanup_with(client: &Client, mf: &Manifest) -> Result<()> {
+    let pth = deployment_path(&mf.namespace, &canary_name(mf));
+    if client.get_opt::<MinimalObject>(&pth)?.is_some() {
+        info!("Removing canary deployment of {}", mf.name);
+        client.delete(&pth)?;
+    }
+    Ok(())
+}
+
+/// Remove the canary deployment after the stable deployment has been upgraded
+pub fn cleanup(mf: &Manifest) -> Result<()> {
+    let client = Client::new()?;
+    cleanup_with(&client, mf)
+}
+
+/// Run a canary rollout for a service ahead of its upgrade
+///
+/// Rolls back automatically if the health gate fails at any step,
+/// and fires the rollback webhooks around the rollback.
+pub fn canary(mf: &Manifest, ud: &UpgradeData, reg: &Region) -> Result<()> {
+    let cfg = mf.canary.clone().ok_or_else(|| format!("{} has no canary configuration", mf.name))?;
+    let client = Client::new()?;
+    let total = stable_replicas(&client, mf)?;
+    if total < 2 && !autoscaled(mf) {
+        // shifting the only replica would leave nothing stable running
+        bail!("{} needs at least 2 replicas for a canary, but runs {}", mf.name, total);
+    }
+    let canary = canary_deployment(mf, reg)?;
+    if let Err(e) = run_steps(&client, mf, &cfg, &canary, total) {
+        warn!("Canary of {} failed: {}", mf.name, e);
+        let _ = kube::debug(mf);
+        webhooks::upgrade_rollback_event(UpgradeState::RollingBack, ud, reg);
+        match abort(&client, mf, total) {
+            Ok(_) => webhooks::upgrade_rollback_event(UpgradeState::RolledBack, ud, reg),
+            Err(re) => {
+                error!("Failed to roll back canary of {}: {}", mf.name, re);
+                webhooks::upgrade_rollback_event(UpgradeState::RollbackFailed, ud, reg);
+            }
+        }
+        return Err(e);
+    }
+    info!("Canary of {} passed all {} steps", mf.name, cfg.steps.len());
+    Ok(())
+}
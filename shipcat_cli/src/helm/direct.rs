@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::fmt;
 use std::path::{Path, PathBuf};
@@ -5,12 +6,19 @@ use std::fs::File;
 use std::io::Write;
 
 use serde_yaml;
+use shipcat_definitions::SecretVersion;
 use crate::webhooks::{self, UpgradeState};
+use crate::product::ProductTag;
 use super::kube;
 use super::Metadata;
 use super::{Manifest, Config, Region};
 use super::{Result, ResultExt, ErrorKind};
-use super::helpers::{self, hout, hexec};
+use super::helpers;
+use super::backend::backend;
+use super::apply;
+use super::kubediff;
+use super::canary;
+use super::bluegreen;
 
 /// The different modes we allow `helm upgrade` to run in
 #[derive(PartialEq, Clone, Debug)]
@@ -31,8 +39,12 @@ pub enum UpgradeMode {
     UpgradeInstallWait,
 
     // new modes
-    /// Upgrade and Wait, or Install and don't wait (on first install)
+    /// Server-side apply of the rendered chart without helm release tracking
     Apply,
+    /// Progressive canary ahead of a normal upgrade, rolling back on failed health gates
+    Canary,
+    /// Install the idle colour of a blue/green service and switch kong over to it
+    BlueGreen,
 }
 impl Default for UpgradeMode {
     fn default() -> Self {
@@ -51,6 +63,8 @@ impl fmt::Display for UpgradeMode {
             &UpgradeMode::UpgradeWaitMaybeRollback => write!(f, "upgrade"),
             &UpgradeMode::UpgradeInstallWait => write!(f, "reconcile"),
             &UpgradeMode::Apply => write!(f, "apply"),
+            &UpgradeMode::Canary => write!(f, "canary"),
+            &UpgradeMode::BlueGreen => write!(f, "blue/green"),
         }
     }
 }
@@ -66,6 +80,8 @@ impl UpgradeMode {
             &UpgradeMode::UpgradeWaitMaybeRollback => "upgraded",
             &UpgradeMode::UpgradeInstallWait => "reconciled",
             &UpgradeMode::Apply => "applied",
+            &UpgradeMode::Canary => "upgraded (canary)",
+            &UpgradeMode::BlueGreen => "switched (blue/green)",
         }.into()
     }
 }
@@ -79,22 +95,32 @@ impl UpgradeMode {
 /// TODO: deprecate
 pub fn rollback(reg: &Region, ud: &UpgradeData, mf: &Manifest) -> Result<()> {
     assert!(ud.namespace.len() > 0);
-    let rollbackvec = vec![
-        format!("--tiller-namespace={}", ud.namespace),
+    let helm = backend(reg);
+    let rollbackvec = helm.args(&ud.namespace, vec![
         "rollback".into(),
         ud.name.clone(),
         "0".into(), // magic helm number for previous
-    ];
-    info!("helm {}", rollbackvec.join(" "));
+    ]);
+    info!("{}", helm.cmdline(&rollbackvec));
 
     webhooks::upgrade_rollback_event(UpgradeState::RollingBack, &ud, &reg);
-    match hexec(rollbackvec) {
+    match helm.exec(rollbackvec) {
         Err(e) => {
             error!("{}", e);
             webhooks::upgrade_rollback_event(UpgradeState::RollbackFailed, &ud, &reg);
             Err(e)
         },
         Ok(_) => {
+            // the previous release brings back its rendered secrets along with the image
+            match helpers::deployed_secret_versions(&ud.name, reg) {
+                Ok(ref vs) if !vs.is_empty() => {
+                    let pinned = vs.iter().map(|(k, v)| format!("{}@{}", k, v)).collect::<Vec<_>>();
+                    info!("Restored {} secrets: {}", ud.name, pinned.join(", "));
+                    warn_outdated_secrets(mf, reg, vs);
+                },
+                Ok(_) => {},
+                Err(e) => warn!("Could not read restored secret versions of {}: {}", ud.name, e),
+            }
             let res = kube::await_rollout_status(&mf);
             webhooks::upgrade_rollback_event(UpgradeState::RolledBack, &ud, &reg);
             res?; // propagate errors from rollback check if any
@@ -103,10 +129,47 @@ pub fn rollback(reg: &Region, ud: &UpgradeData, mf: &Manifest) -> Result<()> {
     }
 }
 
+/// Warn when a rolled back release runs secrets that are no longer the latest in vault
+///
+/// The next upgrade reads the latest versions again, unless they are pinned.
+fn warn_outdated_secrets(mf: &Manifest, reg: &Region, restored: &BTreeMap<String, u32>) {
+    match outdated_secrets(mf, reg, restored) {
+        Ok(ref outdated) if !outdated.is_empty() => {
+            let vs = outdated.iter()
+                .map(|(k, (old, new))| format!("{}@{} (latest {})", k, old, new))
+                .collect::<Vec<_>>();
+            warn!("{} was rolled back to outdated secrets: {}", mf.name, vs.join(", "));
+            warn!("The next upgrade of {} deploys the latest secrets unless they are pinned with IN_VAULT@{{version}}", mf.name);
+        },
+        Ok(_) => {},
+        Err(e) => warn!("Could not compare restored secrets of {} against vault: {}", mf.name, e),
+    }
+}
+
+/// Restored secret versions that differ from the latest versions in vault
+fn outdated_secrets(mf: &Manifest, reg: &Region, restored: &BTreeMap<String, u32>) -> Result<BTreeMap<String, (u32, u32)>> {
+    let client = reg.vault.secret_backend()?;
+    let pth = mf.get_vault_path(&reg.vault);
+    let mut outdated = BTreeMap::new();
+    for (k, v) in restored {
+        let (_, latest) = client.read_version(&format!("{}/{}", pth, k), &SecretVersion::Latest)?;
+        if let Some(n) = latest {
+            if n != *v {
+                outdated.insert(k.clone(), (*v, n));
+            }
+        }
+    }
+    Ok(outdated)
+}
+
 /// Rollback entrypoint using a plain service and region
 pub fn rollback_wrapper(svc: &str, conf: &Config, region: &Region) -> Result<()> {
     let base = Manifest::base(svc, &conf, region)?;
     let ud = UpgradeData::from_rollback(&base);
+    if base.blueGreen.is_some() {
+        // previous colour is still running
+        return bluegreen::rollback(&base, &ud, &region);
+    }
     rollback(&region, &ud, &base)
 }
 
@@ -125,7 +188,7 @@ pub struct UpgradeData {
     pub namespace: String,
     /// How long we will let helm wait before upgrading
     pub waittime: u32,
-    /// Precomputed diff via helm diff plugin
+    /// Precomputed diff of the rendered chart against the cluster
     pub diff: String,
     /// Upgrade Mode
     pub mode: UpgradeMode,
@@ -133,6 +196,8 @@ pub struct UpgradeData {
     pub values: String,
     /// Metadata used in slack notifications
     pub metadata: Option<Metadata>,
+    /// Product this upgrade is part of (tags audit events)
+    pub product: Option<ProductTag>,
 }
 
 impl UpgradeData {
@@ -142,19 +207,23 @@ impl UpgradeData {
     /// (DiffOnly can sneak by early due to it not being technically needed)
     ///
     /// Performs basic sanity checks, and populates canonical values that are reused a lot.
-    pub fn new(mf: &Manifest, hfile: &str, mode: UpgradeMode, exists: bool) ->  Result<Option<UpgradeData>> {
-        let helmdiff = if !exists {
-            "".into() // can't diff against what's not there!
+    pub fn new(mf: &Manifest, reg: &Region, hfile: &str, mode: UpgradeMode, exists: bool) ->  Result<Option<UpgradeData>> {
+        let helmdiff = if !exists || mode == UpgradeMode::Apply {
+            // can't diff against what's not there, and apply does not have a helm release
+            "".into()
+        } else if mode == UpgradeMode::DiffOnly {
+            kubediff::diff(mf, reg)?;
+            return Ok(None)
         } else {
-            let hdiff = diff(mf, hfile, DiffMode::Upgrade)?;
-            if mode == UpgradeMode::DiffOnly {
-                return Ok(None)
+            // the diff can miss changes, so it never skips an upgrade
+            match kubediff::diff(mf, reg) {
+                Ok(hdiff) => hdiff,
+                Err(e) => {
+                    // the diff is informational, so it should not block upgrades
+                    warn!("Unable to diff {}: {}", mf.name, e);
+                    "".into()
+                },
             }
-            if hdiff.is_empty() && mode != UpgradeMode::UpgradeRecreateWait {
-                debug!("Not upgrading {} - empty diff", mf.name);
-                return Ok(None)
-            }
-            hdiff
         };
 
         // version + image MUST be set at this point before calling this for upgrade/install purposes
@@ -170,6 +239,7 @@ impl UpgradeData {
             region: mf.region.clone(),
             values: hfile.into(),
             namespace: mf.namespace.clone(),
+            product: None,
             mode, version
         }))
     }
@@ -202,10 +272,10 @@ impl UpgradeData {
     }
 }
 
-pub fn upgrade(data: &UpgradeData) -> Result<()> {
+pub fn upgrade(data: &UpgradeData, reg: &Region) -> Result<()> {
+    let helm = backend(reg);
     // upgrade it using the same command
     let mut upgradevec = vec![
-        format!("--tiller-namespace={}", data.namespace),
         "upgrade".into(),
         data.name.clone(),
         format!("charts/{}", data.chart),
@@ -217,11 +287,14 @@ pub fn upgrade(data: &UpgradeData) -> Result<()> {
 
     // TODO: dedupe
     match data.mode {
-        UpgradeMode::UpgradeWaitMaybeRollback | UpgradeMode::UpgradeWait | UpgradeMode::UpgradeNoWait => {
+        UpgradeMode::UpgradeWaitMaybeRollback | UpgradeMode::UpgradeWait | UpgradeMode::UpgradeNoWait | UpgradeMode::Canary => {
             upgradevec.extend_from_slice(&[
             ]);
         },
         UpgradeMode::UpgradeRecreateWait => {
+            if !helm.supports_recreate() {
+                bail!("{:?} helm backend in {} does not support recreating pods", reg.helm.backend, reg.name);
+            }
             upgradevec.extend_from_slice(&[
                 "--recreate-pods".into(),
             ]);
@@ -236,86 +309,24 @@ pub fn upgrade(data: &UpgradeData) -> Result<()> {
                 "--install".into(),
             ]);
         },
-        // TODO: handle apply correctly (depending on case)
-        ref u => {
-            unimplemented!("Somehow got an uncovered upgrade mode ({:?})", u);
+        UpgradeMode::Apply => {
+            // helm is only used to render
+            return apply::apply(data, reg);
+        },
+        UpgradeMode::DiffOnly | UpgradeMode::BlueGreen => {
+            // diffs never upgrade, and blue/green upgrades its colours via bluegreen::bluegreen
+            bail!("{} can not be upgraded in {} mode", data.name, data.mode);
         }
     }
 
     // CC service contacts on result
-    info!("helm {}", upgradevec.join(" "));
-    hexec(upgradevec).chain_err(||
+    let upgradevec = helm.args(&data.namespace, upgradevec);
+    info!("{}", helm.cmdline(&upgradevec));
+    helm.exec(upgradevec).chain_err(||
         ErrorKind::HelmUpgradeFailure(data.name.clone())
     )
 }
 
-enum DiffMode {
-    Upgrade,
-    //Rollback,
-}
-
-impl fmt::Display for DiffMode {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            &DiffMode::Upgrade => write!(f, "upgrade"),
-            //&DiffMode::Rollback => write!(f, "rollback"),
-        }
-    }
-}
-
-/// helm diff against current running release
-///
-/// Shells out to helm diff, then obfuscates secrets
-fn diff(mf: &Manifest, hfile: &str, dmode: DiffMode) -> Result<String> {
-    let ver = mf.version.clone().unwrap(); // must be set outside
-    let namespace = mf.namespace.clone();
-    let diffvec = vec![
-        format!("--tiller-namespace={}", namespace),
-        "diff".into(),
-        dmode.to_string(),
-        "--no-color".into(),
-        "-q".into(),
-        mf.name.clone(),
-        format!("charts/{}", mf.chart.clone().unwrap()),
-        "-f".into(),
-        hfile.into(),
-        format!("--version={}", ver),
-    ];
-    info!("helm {}", diffvec.join(" "));
-    let (hdiffunobfusc, hdifferr, _) = hout(diffvec.clone())?;
-    let helmdiff = helpers::obfuscate_secrets(
-        hdiffunobfusc,
-        mf.get_secrets()
-    );
-    if !hdifferr.is_empty() {
-        if hdifferr.starts_with(&format!("Error: \"{}\" has no deployed releases", mf.name)) {
-            let cmd = format!("helm --tiller-namespace={} del --purge {}", namespace, mf.name);
-            let reason = "to let you be able to retry the install/reconcile";
-            error!("Previous installs of {} failed, you need to run: \n\t{}\n{}",
-                mf.name, cmd, reason
-            );
-            // TODO: automate above? feels dangerous..
-            // return empty diff to force the error on helms end
-            return Ok(format!("no deployed releases of {} - needs purge", mf.name));
-        }
-        warn!("diff {} stderr: \n{}", mf.name, hdifferr);
-        if ! hdifferr.contains("error copying from local connection to remote stream") &&
-           ! hdifferr.contains("error copying from remote stream to local connection") {
-            bail!("diff plugin for {} returned: {}", mf.name, hdifferr.lines().next().unwrap());
-        }
-    }
-
-    let smalldiff = helpers::diff_format(helmdiff.clone());
-
-    if !helmdiff.is_empty() {
-        debug!("{}", helmdiff); // full diff for logs
-        println!("{}", smalldiff);
-    } else {
-        info!("{} is up to date", mf.name);
-    }
-    Ok(smalldiff)
-}
-
 /// Create helm values file for a service
 ///
 /// Requires a completed manifest (with inlined configs)
@@ -357,14 +368,10 @@ pub fn template(svc: &str, region: &Region, conf: &Config, ver: Option<String>,
     values(&mf, Some(hfile.clone()))?;
 
     // helm template with correct params
-    let tplvec = vec![
-        "template".into(),
-        format!("charts/{}", mf.chart.unwrap()),
-        "-f".into(),
-        hfile.clone(),
-    ];
-    // NB: this call does NOT need --tiller-namespace (offline call)
-    let (tpl, tplerr, success) = hout(tplvec.clone())?;
+    let helm = backend(region);
+    let tplvec = helm.template(svc, &format!("charts/{}", mf.chart.unwrap()), &hfile);
+    // NB: this call does NOT need a namespace scope (offline call)
+    let (tpl, tplerr, success) = helm.output(tplvec.clone())?;
     if !success {
         warn!("{} stderr: {}", tplvec.join(" "), tplerr);
         bail!("helm template failed");
@@ -384,31 +391,31 @@ pub fn template(svc: &str, region: &Region, conf: &Config, ver: Option<String>,
 
 /// Helm history wrapper
 ///
-/// Analogue to `helm history {service}` uses the right tiller namespace
+/// Analogue to `helm history {service}` uses the right release storage
 pub fn history(svc: &str, conf: &Config, region: &Region) -> Result<()> {
     let mf = Manifest::base(svc, &conf, region)?;
-    let histvec = vec![
-        format!("--tiller-namespace={}", mf.namespace),
+    let helm = backend(region);
+    let histvec = helm.args(&mf.namespace, vec![
         "history".into(),
         svc.into(),
-    ];
-    debug!("helm {}", histvec.join(" "));
-    hexec(histvec)?;
+    ]);
+    debug!("{}", helm.cmdline(&histvec));
+    helm.exec(histvec)?;
     Ok(())
 }
 
 /// Helm status wrapper
 ///
-/// Analogue to `helm status {service}` uses the right tiller namespace
+/// Analogue to `helm status {service}` uses the right release storage
 pub fn status(svc: &str, conf: &Config, region: &Region) -> Result<()> {
     let mf = Manifest::base(svc, &conf, region)?;
-    let histvec = vec![
-        format!("--tiller-namespace={}", mf.namespace),
+    let helm = backend(region);
+    let histvec = helm.args(&mf.namespace, vec![
         "status".into(),
         svc.into(),
-    ];
-    debug!("helm {}", histvec.join(" "));
-    hexec(histvec)?;
+    ]);
+    debug!("{}", helm.cmdline(&histvec));
+    helm.exec(histvec)?;
     Ok(())
 }
 
@@ -418,12 +425,16 @@ pub fn handle_upgrade_rollbacks(reg: &Region, u: &UpgradeData, mf: &Manifest) ->
     match u.mode {
         UpgradeMode::UpgradeRecreateWait |
         UpgradeMode::UpgradeInstall |
-        UpgradeMode::UpgradeWaitMaybeRollback => kube::debug(&mf)?,
+        UpgradeMode::UpgradeWaitMaybeRollback |
+        UpgradeMode::Canary => kube::debug(&mf)?,
         _ => {}
     }
-    if u.mode == UpgradeMode::UpgradeWaitMaybeRollback {
+    if u.mode == UpgradeMode::UpgradeWaitMaybeRollback || u.mode == UpgradeMode::Canary {
         rollback(&reg, &u, mf)?;
     }
+    if u.mode == UpgradeMode::Canary {
+        canary::cleanup(mf)?;
+    }
     Ok(())
 }
 
@@ -460,14 +471,26 @@ pub fn upgrade_wrapper(svc: &str, mode: UpgradeMode, region: &Region, conf: &Con
         warn!("No version found in either manifest or passed explicitly");
         bail!("helm install needs an explicit version")
     }
+    // Can't infer a version from helm without a release
+    if mf.version.is_none() && mode == UpgradeMode::Apply {
+        warn!("No version found in either manifest or passed explicitly");
+        bail!("apply without helm needs an explicit version")
+    }
+    if mf.canary.is_none() && mode == UpgradeMode::Canary {
+        bail!("{} has no canary section in its manifest", svc)
+    }
+    if mf.blueGreen.is_none() && mode == UpgradeMode::BlueGreen {
+        bail!("{} has no blueGreen section in its manifest", svc)
+    }
     // assume it exists if we're not doing installs
     // (this is fine atm because upgrade_wrapper is the CLI entrypoint)
-    let exists = mode != UpgradeMode::UpgradeInstall;
+    // blue/green always installs the idle colour, so there is nothing to diff against
+    let exists = mode != UpgradeMode::UpgradeInstall && mode != UpgradeMode::BlueGreen;
     // Other modes can infer in a pinch
 
     // ..but if they already exist on kube, don't block on that..
     if mf.version.is_none() {
-        mf.version = Some(helpers::infer_fallback_version(&svc, &mf.namespace)?);
+        mf.version = Some(helpers::infer_fallback_version(&svc, &region)?);
     };
     // sanity verify what we changed (no-shoehorning in illegal versions in rolling envs)
     region.versioningScheme.verify(&mf.version.clone().unwrap())?;
@@ -477,32 +500,63 @@ pub fn upgrade_wrapper(svc: &str, mode: UpgradeMode, region: &Region, conf: &Con
     values(&mf, Some(hfile.clone()))?;
 
     // Sanity step that gives canonical upgrade data
-    let upgrade_opt = UpgradeData::new(&mf, &hfile, mode, exists)?;
+    let upgrade_opt = UpgradeData::new(&mf, &region, &hfile, mode, exists)?;
     if let Some(ref udata) = upgrade_opt {
         webhooks::upgrade_event(UpgradeState::Pending, &udata, &region);
-        match upgrade(&udata) {
+        if udata.mode == UpgradeMode::BlueGreen {
+            // blue/green tracks its own rollout and leaves the active release alone on failure
+            let res = bluegreen::bluegreen(&mf, &udata, &region);
+            let state = if res.is_ok() { UpgradeState::Completed } else { UpgradeState::Failed };
+            webhooks::upgrade_event(state.clone(), &udata, &region);
+            kube::record_upgrade(&udata, state);
+            let _ = fs::remove_file(&hfile);
+            return res.map(|_| upgrade_opt);
+        }
+        if udata.mode == UpgradeMode::Canary {
+            // canary rolls itself back before the stable deployment is touched
+            if let Err(e) = canary::canary(&mf, &udata, &region) {
+                webhooks::upgrade_event(UpgradeState::Failed, &udata, &region);
+                kube::record_upgrade(&udata, UpgradeState::Failed);
+                return Err(e);
+            }
+        }
+        match upgrade(&udata, &region) {
             Err(e) => {
                 // if it failed here, rollback in job : TODO: FIX kube-deploy-X jobs
                 error!("{} from {}", e, udata.name);
                 // upgrade failed immediately - couldn't create resources
                 webhooks::upgrade_event(UpgradeState::Failed, &udata, &region);
+                kube::record_upgrade(&udata, UpgradeState::Failed);
                 handle_upgrade_rollbacks(&region, &udata, &mf)?; // for now leave it in..
                 return Err(e);
             },
             Ok(_) => {
-                // after helm upgrade / kubectl apply, check rollout status in a loop:
-                if udata.mode == UpgradeMode::UpgradeNoWait || kube::await_rollout_status(&mf)? {
-                    info!("successfully rolled out {}", &udata.name);
-                    webhooks::upgrade_event(UpgradeState::Completed, &udata, &region);
-                }
-                else {
-                    let _ = kube::debug_rollout_status(&mf);
-                    let _ = kube::debug(&mf);
-                    warn!("failed to roll out {}", &udata.name);
-                    webhooks::upgrade_event(UpgradeState::Failed, &udata, &region);
-                    // if it failed here, rollback in job : TODO: FIX kube-deploy-X jobs
-                    handle_upgrade_rollbacks(&region, &udata, &mf)?; // for now leave it in..
-                    return Err(ErrorKind::UpgradeTimeout(mf.name.clone(), mf.estimate_wait_time()).into());
+                // after helm upgrade / kubectl apply, track the rollout until it completes or breaks:
+                let rollout = if udata.mode == UpgradeMode::UpgradeNoWait {
+                    Ok(true)
+                } else {
+                    kube::await_rollout_status(&mf)
+                };
+                match rollout {
+                    Ok(true) => {
+                        info!("successfully rolled out {}", &udata.name);
+                        if udata.mode == UpgradeMode::Canary {
+                            canary::cleanup(&mf)?;
+                        }
+                        webhooks::upgrade_event(UpgradeState::Completed, &udata, &region);
+                        kube::record_upgrade(&udata, UpgradeState::Completed);
+                    },
+                    res => {
+                        let _ = kube::debug_rollout_status(&mf);
+                        let _ = kube::debug(&mf);
+                        warn!("failed to roll out {}", &udata.name);
+                        webhooks::upgrade_event(UpgradeState::Failed, &udata, &region);
+                        kube::record_upgrade(&udata, UpgradeState::Failed);
+                        // if it failed here, rollback in job : TODO: FIX kube-deploy-X jobs
+                        handle_upgrade_rollbacks(&region, &udata, &mf)?; // for now leave it in..
+                        res?; // early rollout failures take precedence over the timeout
+                        return Err(ErrorKind::UpgradeTimeout(mf.name.clone(), mf.estimate_wait_time()).into());
+                    }
                 }
             }
         };
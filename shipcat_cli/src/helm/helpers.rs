@@ -1,7 +1,9 @@
 use serde_yaml;
+use std::collections::BTreeMap;
 
 use regex::Regex;
-use super::{Result};
+use super::{Result, Region};
+use super::backend::{backend, Backend};
 
 
 pub fn diff_format(diff: String) -> String {
@@ -57,41 +59,39 @@ pub fn obfuscate_secrets(input: String, secrets: Vec<String>) -> String {
 
 /// Values parsed from `helm get values {service}`
 ///
-/// This is the completed manifests including templates, but we only need one key
-/// Just parsing this key also makes it more forwards compatible
+/// This is the completed manifests including templates, but we only need a few keys
+/// Just parsing these keys also makes it more forwards compatible
 #[derive(Deserialize)]
 struct HelmVals {
     version: String,
+    #[serde(default)]
+    secretVersions: BTreeMap<String, u32>,
 }
 
-pub fn hexec(args: Vec<String>) -> Result<()> {
+pub fn hexec(bin: &str, args: Vec<String>) -> Result<()> {
     use std::process::Command;
-    debug!("helm {}", args.join(" "));
-    let s = Command::new("helm").args(&args).status()?;
+    debug!("{} {}", bin, args.join(" "));
+    let s = Command::new(bin).args(&args).status()?;
     if !s.success() {
-        bail!("Subprocess failure from helm: {}", s.code().unwrap_or(1001))
+        bail!("Subprocess failure from {}: {}", bin, s.code().unwrap_or(1001))
     }
     Ok(())
 }
-pub fn hout(args: Vec<String>) -> Result<(String, String, bool)> {
+pub fn hout(bin: &str, args: Vec<String>) -> Result<(String, String, bool)> {
     use std::process::Command;
-    debug!("helm {}", args.join(" "));
-    let s = Command::new("helm").args(&args).output()?;
+    debug!("{} {}", bin, args.join(" "));
+    let s = Command::new(bin).args(&args).output()?;
     let out : String = String::from_utf8_lossy(&s.stdout).into();
     let err : String = String::from_utf8_lossy(&s.stderr).into();
     Ok((out, err, s.status.success()))
 }
 
-pub fn infer_fallback_version(service: &str, ns: &str) -> Result<String> {
-    // fetch current version from helm
-    let imgvec = vec![
-        format!("--tiller-namespace={}", ns),
-        "get".into(),
-        "values".into(),
-        service.into(),
-    ];
-    debug!("helm {}", imgvec.join(" "));
-    match hout(imgvec.clone()) {
+fn release_values(service: &str, reg: &Region) -> Result<HelmVals> {
+    let helm = backend(reg);
+    // fetch current values from helm
+    let imgvec = helm.args(&reg.namespace, helm.get_values(service));
+    debug!("{}", helm.cmdline(&imgvec));
+    match helm.output(imgvec.clone()) {
         // got a result from helm + rc was 0:
         Ok((vout, verr, true)) => {
             if !verr.is_empty() {
@@ -100,15 +100,26 @@ pub fn infer_fallback_version(service: &str, ns: &str) -> Result<String> {
             // if we got this far, release was found
             // it should work to parse the HelmVals subset of the values:
             let values : HelmVals = serde_yaml::from_str(&vout.to_owned())?;
-            Ok(values.version)
+            Ok(values)
         },
         _ => {
             // nothing from helm
-            bail!("Service {} not found in in {} tiller", service, ns);
+            bail!("Service {} not found in in {} releases", service, reg.namespace);
         }
     }
 }
 
+pub fn infer_fallback_version(service: &str, reg: &Region) -> Result<String> {
+    Ok(release_values(service, reg)?.version)
+}
+
+/// Secret versions the current release of a service was deployed with
+///
+/// Empty when the region does not use a versioned secret backend.
+pub fn deployed_secret_versions(service: &str, reg: &Region) -> Result<BTreeMap<String, u32>> {
+    Ok(release_values(service, reg)?.secretVersions)
+}
+
 
 #[cfg(test)]
 mod tests {
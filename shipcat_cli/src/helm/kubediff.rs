@@ -0,0 +1,455 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::Value;
+use serde_yaml;
+
+use super::{Result, Manifest, Region};
+use super::apply::{parse_rendered, RenderedObject, SERVICE_LABEL};
+use super::helpers;
+use super::kube::{self, Client, ApiResource};
+use super::kube::objects::ObjectList;
+use crate::chart;
+
+/// Metadata populated by the api server
+const SERVER_METADATA: [&str; 7] = [
+    "uid",
+    "resourceVersion",
+    "generation",
+    "creationTimestamp",
+    "selfLink",
+    "managedFields",
+    "namespace",
+];
+
+/// Annotations managed by controllers or kubectl
+const MANAGED_ANNOTATIONS: [&str; 3] = [
+    "kubectl.kubernetes.io/last-applied-configuration",
+    "deployment.kubernetes.io/revision",
+    "deprecated.daemonset.template.generation",
+];
+
+/// Lines of context around each change
+const CONTEXT: usize = 3;
+
+/// Strip fields that are never part of what we render
+fn strip(obj: &mut Value) {
+    if let Some(o) = obj.as_object_mut() {
+        o.remove("status");
+    }
+    if let Some(md) = obj.get_mut("metadata").and_then(Value::as_object_mut) {
+        for k in SERVER_METADATA.iter() {
+            md.remove(*k);
+        }
+        if let Some(a) = md.get_mut("annotations").and_then(Value::as_object_mut) {
+            for k in MANAGED_ANNOTATIONS.iter() {
+                a.remove(*k);
+            }
+        }
+        // only there when applied without helm
+        if let Some(l) = md.get_mut("labels").and_then(Value::as_object_mut) {
+            l.remove(SERVICE_LABEL);
+        }
+        for k in &["annotations", "labels"] {
+            if md.get(*k).and_then(Value::as_object).map(|m| m.is_empty()).unwrap_or(false) {
+                md.remove(*k);
+            }
+        }
+    }
+}
+
+/// Names of the workloads whose replicas are managed by an autoscaler
+fn autoscaled(mf: &Manifest, rendered: &[RenderedObject]) -> BTreeSet<String> {
+    let mut res : BTreeSet<String> = rendered.iter()
+        .filter(|o| o.kind == "HorizontalPodAutoscaler")
+        .filter_map(|o| o.data["spec"]["scaleTargetRef"]["name"].as_str().map(String::from))
+        .collect();
+    if mf.autoScaling.is_some() {
+        res.insert(mf.name.clone());
+    }
+    res
+}
+
+/// Remove the replica count that an autoscaler is free to change
+fn drop_replicas(obj: &mut Value) {
+    if let Some(spec) = obj.get_mut("spec").and_then(Value::as_object_mut) {
+        spec.remove("replicas");
+    }
+}
+
+/// Fields of any object that the api server defaults
+///
+/// Paths are dot separated, and `*` matches every element of a list.
+const OBJECT_DEFAULTS: [&str; 16] = [
+    "type",
+    "spec.clusterIP",
+    "spec.clusterIPs",
+    "spec.sessionAffinity",
+    "spec.ipFamilies",
+    "spec.ipFamilyPolicy",
+    "spec.internalTrafficPolicy",
+    "spec.ports.*.protocol",
+    "spec.ports.*.targetPort",
+    "spec.revisionHistoryLimit",
+    "spec.progressDeadlineSeconds",
+    "spec.strategy",
+    "spec.concurrencyPolicy",
+    "spec.suspend",
+    "spec.successfulJobsHistoryLimit",
+    "spec.failedJobsHistoryLimit",
+];
+
+/// Pod templates inside workloads
+const POD_TEMPLATES: [&str; 2] = [
+    "spec.template",
+    "spec.jobTemplate.spec.template",
+];
+
+/// Fields of a pod template that the api server defaults
+const POD_DEFAULTS: [&str; 7] = [
+    "metadata.creationTimestamp",
+    "spec.restartPolicy",
+    "spec.dnsPolicy",
+    "spec.schedulerName",
+    "spec.securityContext",
+    "spec.terminationGracePeriodSeconds",
+    "spec.serviceAccount",
+];
+
+/// Fields of a container that the api server defaults
+const CONTAINER_DEFAULTS: [&str; 19] = [
+    "imagePullPolicy",
+    "terminationMessagePath",
+    "terminationMessagePolicy",
+    "ports.*.protocol",
+    "livenessProbe.httpGet.scheme",
+    "livenessProbe.timeoutSeconds",
+    "livenessProbe.periodSeconds",
+    "livenessProbe.successThreshold",
+    "livenessProbe.failureThreshold",
+    "readinessProbe.httpGet.scheme",
+    "readinessProbe.timeoutSeconds",
+    "readinessProbe.periodSeconds",
+    "readinessProbe.successThreshold",
+    "readinessProbe.failureThreshold",
+    "startupProbe.httpGet.scheme",
+    "startupProbe.timeoutSeconds",
+    "startupProbe.periodSeconds",
+    "startupProbe.successThreshold",
+    "startupProbe.failureThreshold",
+];
+
+/// All server defaulted paths, with pod and container fields under every template
+fn default_paths() -> Vec<String> {
+    let mut res : Vec<String> = OBJECT_DEFAULTS.iter().map(|p| p.to_string()).collect();
+    for tpl in POD_TEMPLATES.iter() {
+        res.extend(POD_DEFAULTS.iter().map(|p| format!("{}.{}", tpl, p)));
+        for c in &["containers", "initContainers"] {
+            res.extend(CONTAINER_DEFAULTS.iter().map(|p| format!("{}.spec.{}.*.{}", tpl, c, p)));
+        }
+    }
+    res
+}
+
+/// Remove a live field at a path unless we render it ourselves
+fn prune_path(live: &mut Value, desired: &Value, path: &[&str]) {
+    let (key, rest) = match path.split_first() {
+        Some(x) => x,
+        None => return,
+    };
+    if *key == "*" {
+        if let Some(l) = live.as_array_mut() {
+            for (i, v) in l.iter_mut().enumerate() {
+                prune_path(v, desired.get(i).unwrap_or(&Value::Null), rest);
+            }
+        }
+    } else if rest.is_empty() {
+        if desired.get(*key).is_none() {
+            if let Some(o) = live.as_object_mut() {
+                o.remove(*key);
+            }
+        }
+    } else if let Some(v) = live.get_mut(*key) {
+        prune_path(v, desired.get(*key).unwrap_or(&Value::Null), rest);
+    }
+}
+
+/// Drop fields from a live object that the api server defaulted
+///
+/// Only known defaults are dropped, so fields removed from a chart still show up.
+fn prune_defaults(live: &mut Value, desired: &Value) {
+    for p in default_paths() {
+        let path : Vec<&str> = p.split('.').collect();
+        prune_path(live, desired, &path);
+    }
+    normalise_quantities(live, desired);
+}
+
+/// Use the rendered form of quantities the api returns as strings, like `cpu: 2`
+fn normalise_quantities(live: &mut Value, desired: &Value) {
+    match (live, desired) {
+        (Value::Object(l), Value::Object(d)) => {
+            for (k, v) in l.iter_mut() {
+                if let Some(dv) = d.get(k) {
+                    normalise_quantities(v, dv);
+                }
+            }
+        },
+        (Value::Array(l), Value::Array(d)) => {
+            for (v, dv) in l.iter_mut().zip(d.iter()) {
+                normalise_quantities(v, dv);
+            }
+        },
+        (l, Value::Number(n)) if l.as_str() == Some(&n.to_string()) => {
+            *l = Value::Number(n.clone());
+        },
+        _ => {},
+    }
+}
+
+/// Hide secret data while keeping track of whether it changed
+fn mask_secret_data(live: &mut Value, desired: &mut Value) {
+    for k in &["data", "stringData"] {
+        let old = live.get(*k).and_then(Value::as_object).cloned().unwrap_or_default();
+        if let Some(new) = desired.get_mut(*k).and_then(Value::as_object_mut) {
+            for (key, v) in new.iter_mut() {
+                *v = match old.get(key) {
+                    Some(o) if o == v => Value::String("<masked>".into()),
+                    _ => Value::String("<masked new value>".into()),
+                };
+            }
+        }
+        if let Some(l) = live.get_mut(*k).and_then(Value::as_object_mut) {
+            for v in l.values_mut() {
+                *v = Value::String("<masked>".into());
+            }
+        }
+    }
+}
+
+/// Line based unified diff with hunk headers
+pub fn unified_diff(old: &str, new: &str) -> String {
+    let a : Vec<&str> = old.lines().collect();
+    let b : Vec<&str> = new.lines().collect();
+    let (n, m) = (a.len(), b.len());
+    // longest common subsequence lengths of the suffixes
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i+1][j+1] + 1 } else { lcs[i+1][j].max(lcs[i][j+1]) };
+        }
+    }
+    let mut ops : Vec<(char, &str)> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            ops.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i+1][j] >= lcs[i][j+1]) {
+            ops.push(('-', a[i]));
+            i += 1;
+        } else {
+            ops.push(('+', b[j]));
+            j += 1;
+        }
+    }
+
+    let changes : Vec<usize> = ops.iter().enumerate().filter(|(_, o)| o.0 != ' ').map(|(k, _)| k).collect();
+    let mut out = String::new();
+    let mut h = 0;
+    while h < changes.len() {
+        let start = changes[h].saturating_sub(CONTEXT);
+        let mut last = changes[h];
+        // merge changes whose context would overlap
+        while h + 1 < changes.len() && changes[h+1] - last <= 2 * CONTEXT + 1 {
+            h += 1;
+            last = changes[h];
+        }
+        let end = (last + CONTEXT).min(ops.len() - 1);
+        let count = |ops: &[(char, &str)], skip: char| ops.iter().filter(|o| o.0 != skip).count();
+        let (old_len, new_len) = (count(&ops[start..=end], '+'), count(&ops[start..=end], '-'));
+        let old_start = count(&ops[..start], '+') + if old_len > 0 { 1 } else { 0 };
+        let new_start = count(&ops[..start], '-') + if new_len > 0 { 1 } else { 0 };
+        out.push_str(&format!("@@ -{},{} +{},{} @@\n", old_start, old_len, new_start, new_len));
+        for (c, l) in &ops[start..=end] {
+            out.push_str(&format!("{}{}\n", c, l));
+        }
+        h += 1;
+    }
+    out
+}
+
+fn to_yaml(v: Option<&Value>) -> Result<String> {
+    match v {
+        // without the document marker
+        Some(v) => Ok(serde_yaml::to_string(v)?.trim_start_matches("---\n").to_string()),
+        None => Ok(String::new()),
+    }
+}
+
+/// Diff a single object in the style of helm-diff's headers
+fn object_diff(ns: &str, kind: &str, name: &str, live: Option<&Value>, desired: Option<&Value>) -> Result<Option<String>> {
+    let verb = match (live, desired) {
+        (Some(l), Some(d)) if l == d => return Ok(None),
+        (Some(_), Some(_)) => "has changed",
+        (None, Some(_)) => "has been added",
+        (Some(_), None) => "has been removed",
+        (None, None) => return Ok(None),
+    };
+    let body = unified_diff(&to_yaml(live)?, &to_yaml(desired)?);
+    Ok(Some(format!("{}, {}, {} {}:\n{}", ns, name, kind, verb, body)))
+}
+
+/// Diff the rendered chart of a service against the live objects in the cluster
+///
+/// Returns the diff with secrets obfuscated (empty if everything is up to date).
+pub fn diff_objects(client: &Client, mf: &Manifest, reg: &Region) -> Result<String> {
+    let ns = &mf.namespace;
+    let tpl = chart::render_release(mf, reg)?;
+    let rendered = parse_rendered(&tpl, &mf.name)?;
+    let scaled = autoscaled(mf, &rendered);
+
+    let mut resources : BTreeMap<(String, String), ApiResource> = BTreeMap::new();
+    let mut seen = BTreeSet::new();
+    let mut diffs = vec![];
+    for o in rendered {
+        let key = (o.apiVersion.clone(), o.kind.clone());
+        if !resources.contains_key(&key) {
+            let r = kube::discover(client, &o.apiVersion, &o.kind)?;
+            resources.insert(key.clone(), r);
+        }
+        let mut desired = o.data;
+        strip(&mut desired);
+        let rescaled = scaled.contains(&o.name) && o.kind != "HorizontalPodAutoscaler";
+        if rescaled {
+            drop_replicas(&mut desired);
+        }
+        let mut live : Option<Value> = client.get_opt(&resources[&key].path(ns, &o.name))?;
+        if let Some(l) = live.as_mut() {
+            strip(l);
+            if rescaled {
+                drop_replicas(l);
+            }
+            prune_defaults(l, &desired);
+            if o.kind == "Secret" {
+                mask_secret_data(l, &mut desired);
+            }
+        } else if o.kind == "Secret" {
+            mask_secret_data(&mut Value::Null, &mut desired);
+        }
+        diffs.extend(object_diff(ns, &o.kind, &o.name, live.as_ref(), Some(&desired))?);
+        seen.insert((o.kind, o.name));
+    }
+
+    // objects labelled for the service that are no longer rendered
+    let selector = format!("{}={}", SERVICE_LABEL, mf.name);
+    for r in resources.values() {
+        let found : ObjectList<Value> = client.get(&r.collection_path(ns), &[("labelSelector", &selector)])?;
+        for mut o in found.items {
+            let name = o["metadata"]["name"].as_str().unwrap_or("").to_string();
+            if seen.contains(&(r.kind.clone(), name.clone())) {
+                continue;
+            }
+            strip(&mut o);
+            if r.kind == "Secret" {
+                mask_secret_data(&mut o, &mut Value::Null);
+            }
+            diffs.extend(object_diff(ns, &r.kind, &name, Some(&o), None)?);
+        }
+    }
+    Ok(helpers::obfuscate_secrets(diffs.join(""), mf.get_secrets()))
+}
+
+/// Diff a service against the cluster without helm-diff or tiller
+///
+/// Prints and returns the condensed diff (empty if the service is up to date).
+/// Replica counts are ignored for autoscaled workloads.
+pub fn diff(mf: &Manifest, reg: &Region) -> Result<String> {
+    let client = Client::new()?;
+    info!("Diffing {} against the {} namespace", mf.name, mf.namespace);
+    let fulldiff = diff_objects(&client, mf, reg)?;
+    let smalldiff = helpers::diff_format(fulldiff.clone());
+    if !fulldiff.is_empty() {
+        debug!("{}", fulldiff); // full diff for logs
+        println!("{}", smalldiff);
+    } else {
+        info!("{} is up to date", mf.name);
+    }
+    Ok(smalldiff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unified_diff, prune_defaults, strip, autoscaled, drop_replicas};
+    use super::super::apply::RenderedObject;
+    use super::Manifest;
+    use serde_json::json;
+
+    #[test]
+    fn unified_hunks() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+        let new = "a\nb\nc\nD\ne\nf\ng\nh\ni\nj\nk\nl\n";
+        let expected = "@@ -1,7 +1,7 @@\n a\n b\n c\n-d\n+D\n e\n f\n g\n@@ -9,3 +9,4 @@\n i\n j\n k\n+l\n";
+        assert_eq!(unified_diff(old, new), expected);
+        assert_eq!(unified_diff(old, old), "");
+        assert_eq!(unified_diff("", "a\n"), "@@ -0,0 +1,1 @@\n+a\n");
+    }
+
+    #[test]
+    fn normalise_live_object() {
+        let desired = json!({
+            "metadata": {"name": "svc", "labels": {"app": "svc"}},
+            "spec": {"ports": [{"port": 80}], "resources": {"cpu": 2}}
+        });
+        let mut live = json!({
+            "metadata": {
+                "name": "svc", "uid": "abc", "resourceVersion": "12", "namespace": "dev",
+                "labels": {"app": "svc", "team": "core"},
+                "annotations": {"kubectl.kubernetes.io/last-applied-configuration": "{}"}
+            },
+            "spec": {
+                "clusterIP": "10.0.0.1",
+                "ports": [{"port": 80, "protocol": "TCP"}, {"port": 81, "protocol": "TCP"}],
+                "resources": {"cpu": "2"},
+                "selector": {"app": "svc"}
+            },
+            "status": {"loadBalancer": {}}
+        });
+        strip(&mut live);
+        prune_defaults(&mut live, &desired);
+        // fields removed from the chart are kept
+        assert_eq!(live, json!({
+            "metadata": {"name": "svc", "labels": {"app": "svc", "team": "core"}},
+            "spec": {"ports": [{"port": 80}, {"port": 81}], "resources": {"cpu": 2}, "selector": {"app": "svc"}}
+        }));
+
+        let desired = json!({"spec": {"template": {"spec": {"containers": [{"name": "a"}]}}}});
+        let mut live = json!({"spec": {"template": {"spec": {
+            "restartPolicy": "Always",
+            "nodeSelector": {"pool": "x"},
+            "containers": [{"name": "a", "imagePullPolicy": "Always", "env": [{"name": "A"}]}]
+        }}}});
+        prune_defaults(&mut live, &desired);
+        assert_eq!(live, json!({"spec": {"template": {"spec": {
+            "nodeSelector": {"pool": "x"},
+            "containers": [{"name": "a", "env": [{"name": "A"}]}]
+        }}}}));
+    }
+
+    #[test]
+    fn autoscaled_replicas() {
+        let hpa = RenderedObject {
+            apiVersion: "autoscaling/v2beta1".into(),
+            kind: "HorizontalPodAutoscaler".into(),
+            name: "svc".into(),
+            data: json!({"spec": {"scaleTargetRef": {"kind": "Deployment", "name": "svc-worker"}}}),
+        };
+        let mut mf = Manifest::default();
+        mf.name = "svc".into();
+        assert_eq!(autoscaled(&mf, &[hpa]).into_iter().collect::<Vec<_>>(), vec!["svc-worker".to_string()]);
+
+        let mut deploy = json!({"spec": {"replicas": 2, "template": {}}});
+        drop_replicas(&mut deploy);
+        assert_eq!(deploy, json!({"spec": {"template": {}}}));
+    }
+}
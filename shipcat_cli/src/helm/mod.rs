@@ -1,7 +1,7 @@
 /// Allow normal error handling from structs
 pub use super::{Result, ResultExt, ErrorKind, Error};
 /// Verify trait gets the Config
-pub use super::{Config, Region, VersionScheme, AuditWebhook};
+pub use super::{Config, Region, VersionScheme, AuditWebhook, HelmBackend};
 /// Need basic manifest handling
 pub use super::Manifest;
 
@@ -22,6 +22,22 @@ pub mod direct;
 // Re-exports for main
 pub use self::direct::{history, template, values, status};
 
+/// Helm-less upgrades via server-side apply
+pub mod apply;
+
+/// Diffs of rendered charts against live cluster objects
+pub mod kubediff;
+
+/// Progressive canary rollouts
+pub mod canary;
+
+/// Blue/green releases switched via kong
+pub mod bluegreen;
+
+/// Helm version specific backends
+pub mod backend;
+pub use self::backend::{Backend, backend};
+
 /// Helm related helpers
 pub mod helpers;
 // Commonly used helper
@@ -1,4 +1,5 @@
 use threadpool::ThreadPool;
+use std::collections::BTreeSet;
 use std::sync::mpsc::channel;
 use std::fs;
 
@@ -6,8 +7,11 @@ use super::{Config, Manifest, Region};
 use super::{UpgradeMode, UpgradeData};
 use super::direct;
 use super::helpers;
-use super::kube;
+use super::{bluegreen, canary};
+use super::kube::{self, Client};
+use crate::structs::Colour;
 use crate::webhooks::{self, UpgradeState};
+use crate::product::ProductTag;
 use super::{Result, Error, ErrorKind};
 
 
@@ -19,28 +23,78 @@ use super::{Result, Error, ErrorKind};
 /// and catches any errors.
 /// All operations run to completion and the first error is returned at end if any.
 pub fn reconcile(svcs: Vec<Manifest>, conf: &Config, region: &Region, umode: UpgradeMode, n_workers: usize) -> Result<()> {
-    let n_jobs = svcs.len();
     let pool = ThreadPool::new(n_workers);
-    info!("Starting {} parallel helm jobs using {} workers", n_jobs, n_workers);
+    info!("Starting {} parallel helm jobs using {} workers", svcs.len(), n_workers);
     webhooks::reconcile_event(UpgradeState::Pending, &region);
 
-    let (tx, rx) = channel();
-    for mf in svcs {
-        // satisfying thread safety
-        let mode = umode.clone();
-        let reg = region.clone();
-        let config = conf.clone();
+    if let Err(e) = reconcile_wave(&pool, svcs, conf, region, &umode) {
+        webhooks::reconcile_event(UpgradeState::Failed, &region);
+        return Err(e);
+    }
+    webhooks::reconcile_event(UpgradeState::Completed, &region);
+    Ok(())
+}
 
-        let tx = tx.clone(); // tx channel reused in each thread
-        pool.execute(move || {
-            info!("Running {} for {}", mode, mf.name);
-            let res = reconcile_worker(mf, mode, config, reg);
-            tx.send(res).expect("channel will be there waiting for the pool");
+/// Threaded mass helm operation in dependency order
+///
+/// Every wave is reconciled in parallel like `reconcile`, but a wave only starts
+/// once the previous one has completed. Services in a wave must only depend on
+/// services in earlier waves (see `graph::waves`).
+/// Services depending on a failed service (directly or indirectly) are not reconciled,
+/// but the rest of the waves are. Returns the first non-ignorable error if any.
+pub fn reconcile_waves(waves: Vec<Vec<Manifest>>, conf: &Config, region: &Region, umode: UpgradeMode, n_workers: usize) -> Result<()> {
+    let pool = ThreadPool::new(n_workers);
+    let n_waves = waves.len();
+    info!("Starting {} waves of parallel helm jobs using {} workers", n_waves, n_workers);
+    webhooks::reconcile_event(UpgradeState::Pending, &region);
+
+    // services that failed, or were skipped because of a failure
+    let mut failed = BTreeSet::new();
+    let mut first_err = None;
+    for (i, wave) in waves.into_iter().enumerate() {
+        let (blocked, ready) : (Vec<Manifest>, Vec<Manifest>) = wave.into_iter().partition(|mf| {
+            mf.dependencies.iter().any(|d| failed.contains(&d.name))
         });
+        for mf in blocked {
+            let upstream = mf.dependencies.iter()
+                .filter(|d| failed.contains(&d.name))
+                .map(|d| d.name.clone())
+                .collect::<Vec<_>>();
+            warn!("Not reconciling {} - it depends on failed services: {}", mf.name, upstream.join(", "));
+            failed.insert(mf.name);
+        }
+        if ready.is_empty() {
+            continue;
+        }
+        let names = ready.iter().map(|mf| mf.name.clone()).collect::<Vec<_>>();
+        info!("Reconciling wave {}/{}: {}", i+1, n_waves, names.join(", "));
+        for (svc, r) in run_wave(&pool, ready, conf, region, &umode, None) {
+            match r {
+                Ok(Some(ud)) => debug!("{} {}", ud.mode, ud.name),
+                Ok(None) => {},
+                Err(e) => {
+                    warn!("{} error: {}", umode, e);
+                    if !is_ignorable(&e, region) {
+                        failed.insert(svc);
+                        first_err.get_or_insert(e);
+                    }
+                }
+            }
+        }
     }
+    if let Some(e) = first_err {
+        webhooks::reconcile_event(UpgradeState::Failed, &region);
+        return Err(e);
+    }
+    webhooks::reconcile_event(UpgradeState::Completed, &region);
+    Ok(())
+}
 
-    // wait for threads collect errors
-    let res = rx.iter().take(n_jobs).map(|r| {
+/// Reconcile a set of services in parallel on a pool and wait for all of them
+///
+/// Returns the first non-ignorable error if any.
+fn reconcile_wave(pool: &ThreadPool, svcs: Vec<Manifest>, conf: &Config, region: &Region, umode: &UpgradeMode) -> Result<()> {
+    let res = run_wave(pool, svcs, conf, region, umode, None).into_iter().map(|(_, r)| {
         match &r {
             &Ok(Some(ref ud)) => debug!("{} {}", ud.mode, ud.name),
             &Ok(None) => {},
@@ -51,34 +105,75 @@ pub fn reconcile(svcs: Vec<Manifest>, conf: &Config, region: &Region, umode: Upg
 
     // propagate first non-ignorable error if exists
     for e in res {
-        match e {
-            Error(ErrorKind::MissingRollingVersion(svc),_) => {
-                // This only happens in rolling envs because version is mandatory in other envs
-                warn!("'{}' missing version for {} - please add or install", svc, region.name);
-            },
-            // remaining cases not ignorable
-            _ => {
-                webhooks::reconcile_event(UpgradeState::Failed, &region);
-                return Err(e)
-            },
+        if !is_ignorable(&e, region) {
+            return Err(e);
         }
     }
-    webhooks::reconcile_event(UpgradeState::Completed, &region);
     Ok(())
 }
 
+/// Whether a reconcile error can be ignored
+fn is_ignorable(e: &Error, region: &Region) -> bool {
+    match e {
+        Error(ErrorKind::MissingRollingVersion(svc),_) => {
+            // This only happens in rolling envs because version is mandatory in other envs
+            warn!("'{}' missing version for {} - please add or install", svc, region.name);
+            true
+        },
+        // remaining cases not ignorable
+        _ => false,
+    }
+}
+
+/// Run reconcile workers for a set of services on a pool and collect every result
+///
+/// Results are paired with the service name, in the order the workers finished.
+/// Upgrades are tagged with the product when reconciling as part of one.
+pub fn run_wave(pool: &ThreadPool, svcs: Vec<Manifest>, conf: &Config, region: &Region, umode: &UpgradeMode, product: Option<&ProductTag>) -> Vec<(String, Result<Option<UpgradeData>>)> {
+    let n_jobs = svcs.len();
+    let (tx, rx) = channel();
+    for mf in svcs {
+        // satisfying thread safety
+        let mode = umode.clone();
+        let reg = region.clone();
+        let config = conf.clone();
+        let tag = product.cloned();
+
+        let tx = tx.clone(); // tx channel reused in each thread
+        pool.execute(move || {
+            info!("Running {} for {}", mode, mf.name);
+            let svc = mf.name.clone();
+            let res = reconcile_worker(mf, mode, config, reg, tag);
+            tx.send((svc, res)).expect("channel will be there waiting for the pool");
+        });
+    }
+    // wait for threads
+    rx.iter().take(n_jobs).collect()
+}
+
 
 /// Parallel reconcile worker that reports information sequentially
 ///
 /// This logs errors and upgrade successes individually.
+/// Takes a base manifest, and completes it for the region.
+/// Blue/green services are deployed to their idle colour and switched over,
+/// and canaries run ahead of the upgrade of services that have them.
 /// NB: This can reconcile lock-step upgraded services at the moment.
-fn reconcile_worker(mut mf: Manifest, mode: UpgradeMode, _conf: Config, region: Region) -> Result<Option<UpgradeData>> {
+pub fn reconcile_worker(mut mf: Manifest, mode: UpgradeMode, _conf: Config, region: Region, product: Option<ProductTag>) -> Result<Option<UpgradeData>> {
     mf = mf.complete(&region)?;
     let svc = mf.name.clone();
 
+    // blue/green services have a release per colour, and the active one is running now
+    let active = if mf.blueGreen.is_some() {
+        Some(bluegreen::active_colour(&Client::new()?, &mf)?)
+    } else {
+        None
+    };
+    let release = active.map(|c| c.release_name(&svc)).unwrap_or_else(|| svc.clone());
+
     // get version running now (to limit race condition with deploys)
     // this query also lets us detect if we have to install or simply upgrade
-    let (exists, fallback) = match helpers::infer_fallback_version(&svc, &region.namespace) {
+    let (exists, fallback) = match helpers::infer_fallback_version(&release, &region) {
         Ok(running_ver) => (true, running_ver),
         Err(e) => {
             if let Some(v) = mf.version.clone() {
@@ -107,34 +202,98 @@ fn reconcile_worker(mut mf: Manifest, mode: UpgradeMode, _conf: Config, region:
     let hfile = format!("{}.helm.gen.yml", &svc);
     direct::values(&mf, Some(hfile.clone()))?;
 
-    let upgrade_opt = UpgradeData::new(&mf, &hfile, mode, exists)?;
+    if let Some(colour) = active {
+        let res = bluegreen_worker(&mf, colour, &hfile, mode, exists, &region, product);
+        let _ = fs::remove_file(&hfile);
+        return res;
+    }
+
+    let upgrade_opt = UpgradeData::new(&mf, &region, &hfile, mode, exists)?
+        .map(|ud| UpgradeData { product, ..ud });
     if let Some(ref udata) = upgrade_opt {
         webhooks::upgrade_event(UpgradeState::Pending, &udata, &region);
 
-        // upgrade in given mode, potentially rolling back a failure
-        match direct::upgrade(&udata) {
-            Err(e) => {
-                // upgrade failed immediately - couldn't create resources
-                kube::debug(&mf)?;
-                error!("{} from {}", e, udata.name);
+        // canaries need a stable deployment to shift replicas from
+        let with_canary = mf.canary.is_some() && exists;
+        if with_canary {
+            // canary rolls itself back before the stable deployment is touched
+            if let Err(e) = canary::canary(&mf, udata, &region) {
+                webhooks::upgrade_event(UpgradeState::Failed, &udata, &region);
+                kube::record_upgrade(&udata, UpgradeState::Failed);
                 return Err(e);
             }
-            Ok(_)  => {
-                // after helm upgrade / kubectl apply, check rollout status in a loop:
-                if kube::await_rollout_status(&mf)? {
-                    info!("successfully rolled out {}", &udata.name);
+        }
+        let res = upgrade_worker(&mf, udata, &region);
+        if with_canary {
+            if let Err(e) = canary::cleanup(&mf) {
+                warn!("Failed to remove the canary of {}: {}", mf.name, e);
+            }
+        }
+        res?;
+    }
+    let _ = fs::remove_file(&hfile); // try to remove temporary file
+    Ok(upgrade_opt)
+}
+
+/// Upgrade a service and track its rollout, recording the outcome
+fn upgrade_worker(mf: &Manifest, udata: &UpgradeData, region: &Region) -> Result<()> {
+    // upgrade in given mode, potentially rolling back a failure
+    match direct::upgrade(udata, region) {
+        Err(e) => {
+            // upgrade failed immediately - couldn't create resources
+            kube::record_upgrade(udata, UpgradeState::Failed);
+            let _ = kube::debug(mf);
+            error!("{} from {}", e, udata.name);
+            Err(e)
+        }
+        Ok(_)  => {
+            // after helm upgrade / kubectl apply, track the rollout until it completes or breaks:
+            match kube::await_rollout_status(mf) {
+                Ok(true) => {
+                    info!("successfully rolled out {}", udata.name);
                     // notify about the result directly as they happen
-                    webhooks::upgrade_event(UpgradeState::Completed, &udata, &region);
-                } else {
+                    webhooks::upgrade_event(UpgradeState::Completed, udata, region);
+                    kube::record_upgrade(udata, UpgradeState::Completed);
+                    Ok(())
+                },
+                Ok(false) => {
                     error!("Rollout of {} timed out", mf.name);
-                    kube::debug(&mf)?;
-                    webhooks::upgrade_event(UpgradeState::Failed, &udata, &region);
+                    let _ = kube::debug(mf);
+                    webhooks::upgrade_event(UpgradeState::Failed, udata, region);
+                    kube::record_upgrade(udata, UpgradeState::Failed);
                     // need set this as a reconcile level error
-                    return Err(ErrorKind::UpgradeTimeout(mf.name.clone(), mf.estimate_wait_time()).into());
+                    Err(ErrorKind::UpgradeTimeout(mf.name.clone(), mf.estimate_wait_time()).into())
+                },
+                Err(e) => {
+                    // broken rollouts fail early without waiting out the estimate
+                    error!("Rollout of {} failed: {}", mf.name, e);
+                    let _ = kube::debug(mf);
+                    webhooks::upgrade_event(UpgradeState::Failed, udata, region);
+                    kube::record_upgrade(udata, UpgradeState::Failed);
+                    Err(e)
                 }
             }
         }
     }
-    let _ = fs::remove_file(&hfile); // try to remove temporary file
-    Ok(upgrade_opt)
+}
+
+/// Reconcile a blue/green service from the release of its active colour
+///
+/// Diffs compare against the active colour. Any other mode deploys the idle colour
+/// and switches kong to it, leaving the active release alone on failure.
+fn bluegreen_worker(mf: &Manifest, active: Colour, hfile: &str, mode: UpgradeMode, exists: bool, region: &Region, product: Option<ProductTag>) -> Result<Option<UpgradeData>> {
+    if mode == UpgradeMode::DiffOnly {
+        return UpgradeData::new(&bluegreen::coloured(mf, active), region, hfile, mode, exists);
+    }
+    // the idle colour is always installed, so there is nothing to diff against
+    let udata = match UpgradeData::new(mf, region, hfile, UpgradeMode::BlueGreen, false)? {
+        Some(ud) => UpgradeData { product, ..ud },
+        None => return Ok(None),
+    };
+    webhooks::upgrade_event(UpgradeState::Pending, &udata, region);
+    let res = bluegreen::bluegreen(mf, &udata, region);
+    let state = if res.is_ok() { UpgradeState::Completed } else { UpgradeState::Failed };
+    webhooks::upgrade_event(state.clone(), &udata, region);
+    kube::record_upgrade(&udata, state);
+    res.map(|_| Some(udata))
 }
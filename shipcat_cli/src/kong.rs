@@ -1,11 +1,16 @@
 use std::io::{self, Write};
 use std::collections::BTreeMap;
 
+use reqwest;
+
 use super::{Manifest, Result, Region, Config, KongConfig};
-use super::structs::Kong;
+use super::structs::{Kong, Colour};
 use super::structs::kongfig::{kongfig_apis, kongfig_consumers};
 use super::structs::kongfig::{Api, Consumer, Plugin, Upstream, Certificate};
 
+/// Header authenticating requests to the kong admin api
+pub const ADMIN_TOKEN_HEADER: &str = "Kong-Admin-Token";
+
 /// KongOutput matches the format expected by the Kong Configurator script
 #[derive(Serialize)]
 pub struct KongOutput {
@@ -27,9 +32,12 @@ pub struct KongfigOutput {
 
 impl KongfigOutput {
     pub fn new(data: KongOutput) -> Self {
+        let headers = data.kong.admin_token.iter()
+            .map(|t| format!("{}:{}", ADMIN_TOKEN_HEADER, t))
+            .collect();
         KongfigOutput {
             host: data.kong.clone().config_url,
-            headers: vec![],
+            headers,
             apis: kongfig_apis(data.apis, data.kong.clone()),
             consumers: kongfig_consumers(data.kong.clone()),
             plugins: vec![],
@@ -64,7 +72,11 @@ impl KongCrdOutput {
     }
 }
 
-pub fn generate_kong_output(conf: &Config, region: &Region) -> Result<KongOutput> {
+/// Generate the kong apis for a region
+///
+/// Blue/green services point at the release of their active colour in `colours`
+/// (see `helm::bluegreen::active_colours`).
+pub fn generate_kong_output(conf: &Config, region: &Region, colours: &BTreeMap<String, Colour>) -> Result<KongOutput> {
     let mut apis = BTreeMap::new();
 
     // Generate list of APIs to feed to Kong
@@ -72,8 +84,12 @@ pub fn generate_kong_output(conf: &Config, region: &Region) -> Result<KongOutput
         debug!("Scanning service {:?}", svc);
         let mf = Manifest::simple(&svc, &conf, region)?; // does not need secrets
         debug!("Found service {} in region {}", mf.name, region.name);
-        if let Some(k) = mf.kong {
-           apis.insert(svc, k);
+        if let Some(mut k) = mf.kong {
+            if mf.blueGreen.is_some() {
+                let colour = colours.get(&svc).cloned().unwrap_or_default();
+                k.set_colour(&mf.namespace, colour);
+            }
+            apis.insert(svc, k);
         }
     }
 
@@ -94,7 +110,8 @@ pub enum KongOutputMode {
 
 /// Generate Kong config from a filled in global config
 pub fn output(conf: &Config, region: &Region, mode: KongOutputMode) -> Result<()> {
-    let data = generate_kong_output(conf, &region)?;
+    let colours = super::helm::bluegreen::active_colours(conf, &region)?;
+    let data = generate_kong_output(conf, &region, &colours)?;
     let output = match mode {
         KongOutputMode::Crd => {
             let res = KongCrdOutput::new(&region.name, data);
@@ -115,3 +132,24 @@ pub fn config_url(region: &Region) -> Result<()> {
     println!("{}", region.kong.config_url);
     Ok(())
 }
+
+/// Admin api url for a region's kong
+pub fn admin_url(kong: &KongConfig) -> String {
+    let url = kong.config_url.trim_end_matches('/');
+    if url.contains("://") { url.to_string() } else { format!("https://{}", url) }
+}
+
+/// Request to a path on a region's kong admin api
+///
+/// Authenticated with the region's admin token when it has one (needs a filtered config).
+pub fn admin_request(kong: &KongConfig, method: reqwest::Method, path: &str) -> Result<reqwest::RequestBuilder> {
+    let url = reqwest::Url::parse(&format!("{}{}", admin_url(kong), path))?;
+    let mut req = reqwest::Client::new().request(method, url);
+    if let Some(token) = &kong.admin_token {
+        if token == "IN_VAULT" {
+            bail!("kong admin token for {} has not been read from vault", kong.config_url);
+        }
+        req = req.header(ADMIN_TOKEN_HEADER, token.as_str());
+    }
+    Ok(req)
+}
@@ -0,0 +1,232 @@
+use std::env;
+use std::io::{BufRead, BufReader, Lines};
+use std::marker::PhantomData;
+
+use kubernetes::config::{self, Configuration};
+use reqwest::{RequestBuilder, Response, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{Result, ErrorKind};
+
+/// Error body returned by the kube api on failures
+///
+/// Subset of `meta/v1 Status` that we use to produce structured errors.
+#[derive(Deserialize, Debug, Default)]
+pub struct Status {
+    /// Failure or Success
+    #[serde(default)]
+    pub status: String,
+    /// Human readable description of the status
+    #[serde(default)]
+    pub message: String,
+    /// Machine readable reason (e.g. `NotFound` or `Gone`)
+    #[serde(default)]
+    pub reason: String,
+    /// HTTP status code of the response
+    #[serde(default)]
+    pub code: u16,
+}
+
+/// An event from a kube api watch
+#[derive(Debug)]
+pub enum WatchEvent<T> {
+    Added(T),
+    Modified(T),
+    Deleted(T),
+    /// Watch level failure (e.g. `410 Gone` for an expired resourceVersion)
+    Error(Status),
+}
+
+#[derive(Deserialize)]
+struct RawWatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+/// A blocking stream of watch events
+///
+/// Ends when the api server closes the watch (after `timeoutSeconds`).
+pub struct WatchStream<T> {
+    lines: Lines<BufReader<Response>>,
+    item: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Iterator for WatchStream<T> {
+    type Item = Result<WatchEvent<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.lines.next()? {
+            Ok(l) => l,
+            Err(e) => return Some(Err(e.into())),
+        };
+        if line.trim().is_empty() {
+            return self.next();
+        }
+        Some(parse_watch_event(&line))
+    }
+}
+
+fn parse_watch_event<T: DeserializeOwned>(line: &str) -> Result<WatchEvent<T>> {
+    let raw : RawWatchEvent = serde_json::from_str(line)?;
+    let ev = match raw.kind.as_ref() {
+        "ADDED" => WatchEvent::Added(serde_json::from_value(raw.object)?),
+        "MODIFIED" => WatchEvent::Modified(serde_json::from_value(raw.object)?),
+        "DELETED" => WatchEvent::Deleted(serde_json::from_value(raw.object)?),
+        "ERROR" => WatchEvent::Error(serde_json::from_value(raw.object)?),
+        k => bail!("Unknown watch event type {}", k),
+    };
+    Ok(ev)
+}
+
+/// Server side timeout for watches
+///
+/// Kept below the 30s default read timeout of the reqwest client.
+pub const WATCH_TIMEOUT: u32 = 25;
+
+/// A small typed kubernetes api client
+///
+/// Reuses the kube config loading from the `kubernetes` crate,
+/// but does its own requests so that api errors come back as `KubeApiFailure`.
+#[derive(Clone)]
+pub struct Client {
+    cfg: Configuration,
+}
+
+impl Client {
+    /// Load a client from the environment
+    ///
+    /// Uses the service account when running inside a cluster,
+    /// otherwise the current context in the local kube config.
+    pub fn new() -> Result<Client> {
+        let cfg = if env::var("KUBERNETES_SERVICE_HOST").is_ok() {
+            config::incluster_config()
+        } else {
+            config::load_kube_config()
+        }.map_err(|e| ErrorKind::KubeConfigFailure(e.to_string()))?;
+        Ok(Client { cfg })
+    }
+
+    /// Client for an unauthenticated api server url
+    ///
+    /// Useful against `kubectl proxy` or a mock api server.
+    pub fn from_url(url: &str) -> Client {
+        let cfg = Configuration::new(url.to_string(), reqwest::Client::new());
+        Client { cfg }
+    }
+
+    fn url(&self, path: &str, query: &[(&str, &str)]) -> Result<Url> {
+        let mut url = Url::parse(&format!("{}{}", self.cfg.base_path.trim_end_matches('/'), path))?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        Ok(url)
+    }
+
+    // Convert non-success responses into a KubeApiFailure from the Status body
+    fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let mut res = req.send()?;
+        if !res.status().is_success() {
+            let code = res.status().as_u16();
+            let text = res.text()?;
+            let status = serde_json::from_str::<Status>(&text).unwrap_or_else(|_| Status {
+                message: text.trim().to_string(),
+                code,
+                ..Status::default()
+            });
+            let reason = if status.reason.is_empty() {
+                res.status().canonical_reason().unwrap_or("Unknown").to_string()
+            } else {
+                status.reason
+            };
+            bail!(ErrorKind::KubeApiFailure(code, reason, status.message));
+        }
+        Ok(res)
+    }
+
+    /// GET a typed object or list
+    pub fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        let url = self.url(path, query)?;
+        debug!("GET {}", url);
+        let mut res = self.send(self.cfg.client.get(url))?;
+        Ok(res.json()?)
+    }
+
+    /// GET a typed object, treating a 404 as absence
+    pub fn get_opt<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>> {
+        match self.get(path, &[]) {
+            Ok(o) => Ok(Some(o)),
+            Err(e) => {
+                if let ErrorKind::KubeApiFailure(404, _, _) = e.kind() {
+                    return Ok(None);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Watch a collection for changes
+    ///
+    /// Without a `resourceVersion` in the query the api server first sends
+    /// `Added` events for everything currently matching.
+    pub fn watch<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<WatchStream<T>> {
+        let timeout = WATCH_TIMEOUT.to_string();
+        let mut q = query.to_vec();
+        q.push(("watch", "true"));
+        q.push(("timeoutSeconds", &timeout));
+        let url = self.url(path, &q)?;
+        debug!("WATCH {}", url);
+        let res = self.send(self.cfg.client.get(url))?;
+        Ok(WatchStream { lines: BufReader::new(res).lines(), item: PhantomData })
+    }
+
+    /// GET a plain text resource (e.g. pod logs)
+    pub fn get_text(&self, path: &str, query: &[(&str, &str)]) -> Result<String> {
+        let url = self.url(path, query)?;
+        debug!("GET {}", url);
+        let mut res = self.send(self.cfg.client.get(url))?;
+        Ok(res.text()?)
+    }
+
+    /// POST an object to a collection
+    pub fn create<T: Serialize, U: DeserializeOwned>(&self, path: &str, data: &T) -> Result<U> {
+        let url = self.url(path, &[])?;
+        debug!("POST {}", url);
+        let mut res = self.send(self.cfg.client.post(url).json(data))?;
+        Ok(res.json()?)
+    }
+
+    /// PUT an object in place
+    pub fn replace<T: Serialize, U: DeserializeOwned>(&self, path: &str, data: &T) -> Result<U> {
+        let url = self.url(path, &[])?;
+        debug!("PUT {}", url);
+        let mut res = self.send(self.cfg.client.put(url).json(data))?;
+        Ok(res.json()?)
+    }
+
+    /// Server-side apply an object under a field manager
+    ///
+    /// Creates the object if it does not exist. Conflicting fields owned by
+    /// other managers are taken over as shipcat is the source of truth.
+    pub fn apply<T: Serialize, U: DeserializeOwned>(&self, path: &str, manager: &str, data: &T) -> Result<U> {
+        use reqwest::header::CONTENT_TYPE;
+        let url = self.url(path, &[("fieldManager", manager), ("force", "true")])?;
+        debug!("PATCH {}", url);
+        // json is valid yaml, so it can be sent as an apply patch directly
+        let body = serde_json::to_string(data)?;
+        let req = self.cfg.client.patch(url)
+            .header(CONTENT_TYPE, "application/apply-patch+yaml")
+            .body(body);
+        let mut res = self.send(req)?;
+        Ok(res.json()?)
+    }
+
+    /// DELETE an object
+    pub fn delete(&self, path: &str) -> Result<()> {
+        let url = self.url(path, &[])?;
+        debug!("DELETE {}", url);
+        self.send(self.cfg.client.delete(url))?;
+        Ok(())
+    }
+}
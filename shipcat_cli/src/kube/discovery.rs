@@ -0,0 +1,68 @@
+use super::{Result, Client};
+
+#[derive(Deserialize)]
+struct APIResourceList {
+    #[serde(default)]
+    resources: Vec<APIResource>,
+}
+
+#[derive(Deserialize)]
+struct APIResource {
+    name: String,
+    kind: String,
+    namespaced: bool,
+}
+
+/// Where a kind lives in the kube api
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiResource {
+    /// Group and version (e.g. `apps/v1` or `v1`)
+    pub apiVersion: String,
+    /// Kind of the resource (e.g. `Deployment`)
+    pub kind: String,
+    /// Plural name used in urls (e.g. `deployments`)
+    pub plural: String,
+    /// Whether objects of this kind live in namespaces
+    pub namespaced: bool,
+}
+
+impl ApiResource {
+    /// Url path to the collection of this kind
+    pub fn collection_path(&self, ns: &str) -> String {
+        let base = if self.apiVersion.contains('/') {
+            format!("/apis/{}", self.apiVersion)
+        } else {
+            format!("/api/{}", self.apiVersion) // core group
+        };
+        if self.namespaced {
+            format!("{}/namespaces/{}/{}", base, ns, self.plural)
+        } else {
+            format!("{}/{}", base, self.plural)
+        }
+    }
+
+    /// Url path to a named object of this kind
+    pub fn path(&self, ns: &str, name: &str) -> String {
+        format!("{}/{}", self.collection_path(ns), name)
+    }
+}
+
+/// Look up the resource for a kind via api discovery
+pub fn discover(client: &Client, apiVersion: &str, kind: &str) -> Result<ApiResource> {
+    let pth = if apiVersion.contains('/') {
+        format!("/apis/{}", apiVersion)
+    } else {
+        format!("/api/{}", apiVersion)
+    };
+    let list : APIResourceList = client.get(&pth, &[])?;
+    // subresources like deployments/status share the kind
+    let res = list.resources.into_iter()
+        .find(|r| r.kind == kind && !r.name.contains('/'))
+        .ok_or_else(|| format!("{} is not served under {}", kind, apiVersion))?;
+    Ok(ApiResource {
+        apiVersion: apiVersion.into(),
+        kind: kind.into(),
+        plural: res.name,
+        namespaced: res.namespaced,
+    })
+}
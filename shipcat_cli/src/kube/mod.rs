@@ -0,0 +1,488 @@
+use super::{Result, ErrorKind, Manifest};
+use chrono::{Utc, DateTime};
+use std::collections::HashSet;
+use serde::Serialize;
+use serde_json::Value;
+use shipcat_definitions::{Crd, ManifestStatus, UpgradeState};
+use shipcat_definitions::crds::conversion::{self, latest_manifest_version, MANIFEST_VERSIONS, SPEC_VERSION_ANNOTATION};
+use crate::helm::apply::FIELD_MANAGER;
+use crate::helm::UpgradeData;
+
+/// Kube api client
+mod client;
+pub use self::client::{Client, Status, WatchEvent, WatchStream};
+
+/// Typed subsets of kube api objects
+pub mod objects;
+use self::objects::{ObjectList, MinimalObject, Pod, Event};
+
+/// Api discovery for arbitrary kinds
+mod discovery;
+pub use self::discovery::{ApiResource, discover};
+
+/// Watch based rollout tracking
+mod rollout;
+pub use self::rollout::{rollout_status, track_rollout, await_rollout_status, get_deployment};
+
+/// Watch based caches of custom resources
+mod reflector;
+pub use self::reflector::Reflector;
+
+// Interactive commands still need kubectl for the exec/port-forward streams
+fn kexec(args: Vec<String>) -> Result<()> {
+    use std::process::Command;
+    debug!("kubectl {}", args.join(" "));
+    let s = Command::new("kubectl").args(&args).status()?;
+    if !s.success() {
+        bail!("Subprocess failure from kubectl: {}", s.code().unwrap_or(1001))
+    }
+    Ok(())
+}
+
+/// Minimal kube config for context resolution
+#[derive(Deserialize)]
+struct KubeConfig {
+    #[serde(rename = "current-context", default)]
+    current_context: String,
+}
+
+/// Resolve the current kube context from the kube config
+///
+/// Respects the first file in `KUBECONFIG` if set.
+/// Should only be used from main.
+pub fn current_context() -> Result<String> {
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::PathBuf;
+    let pth = match env::var("KUBECONFIG") {
+        Ok(kc) => PathBuf::from(kc.split(':').next().unwrap_or_default()),
+        Err(_) => dirs::home_dir().ok_or_else(|| "can't find home directory")?.join(".kube").join("config"),
+    };
+    let mut data = String::new();
+    File::open(&pth).and_then(|mut f| f.read_to_string(&mut data)).map_err(|e| {
+        error!("Failed to read kube config from {}", pth.display());
+        e
+    })?;
+    let kc : KubeConfig = serde_yaml::from_str(&data)?;
+    if kc.current_context.is_empty() {
+        bail!("No current-context set in {}", pth.display());
+    }
+    Ok(kc.current_context.trim().to_string())
+}
+
+fn pods_path(mf: &Manifest) -> String {
+    format!("/api/v1/namespaces/{}/pods", mf.namespace)
+}
+
+/// Find all pods belonging to a service
+pub fn get_pods(client: &Client, mf: &Manifest) -> Result<Vec<Pod>> {
+    let selector = format!("app={}", mf.name);
+    let pods : ObjectList<Pod> = client.get(&pods_path(mf), &[("labelSelector", &selector)])?;
+    debug!("Active pods: {:?}", pods.items.iter().map(|p| &p.metadata.name).collect::<Vec<_>>());
+    Ok(pods.items)
+}
+
+/// Return a status table for all pods along with non-running or partially ready pods
+fn get_broken_pods(client: &Client, mf: &Manifest) -> Result<(String, Vec<Pod>)> {
+    let mut table = vec![];
+    let mut bpods = vec![];
+    for p in get_pods(client, mf)? {
+        let (ready, total) = p.ready_count();
+        table.push(format!("{}\t{}/{}\t{}\t{}", p.metadata.name, ready, total, p.status.phase, p.restarts()));
+        if p.status.phase != "Running" {
+            warn!("Found pod not running: {}", p.metadata.name);
+            bpods.push(p);
+        }
+        else if !p.is_healthy() {
+            warn!("Found pod with less than necessary containers healthy: {}", p.metadata.name);
+            bpods.push(p);
+        }
+    }
+    Ok((table.join("\n"), bpods))
+}
+
+/// Debug helper when upgrades fail
+///
+/// Prints log excerpts and events for broken pods.
+/// Typically enough to figure out why upgrades broke.
+pub fn debug(mf: &Manifest) -> Result<()> {
+    let client = Client::new()?;
+    let (podres, pods) = get_broken_pods(&client, &mf)?;
+    if pods.is_empty() {
+        info!("No broken pods found");
+        info!("Pod statuses:\n{}", podres);
+    } else {
+        warn!("Pod statuses:\n{}", podres);
+    }
+    for pod in &pods {
+        let name = &pod.metadata.name;
+        warn!("Debugging non-running pod {}", name);
+        let logpth = format!("{}/{}/log", pods_path(mf), name);
+        match client.get_text(&logpth, &[("container", &mf.name), ("tailLines", "30")]) {
+            Ok(l) => {
+                if l == "" {
+                    warn!("No logs for pod {} found", name);
+                } else {
+                    warn!("Last 30 log lines:");
+                    println!("{}", l);
+                }
+            },
+            Err(e) => {
+                warn!("Failed to get logs from {}: {}", name, e)
+            }
+        }
+    }
+
+    for pod in &pods {
+        let name = &pod.metadata.name;
+        warn!("Listing events for pod {}", name);
+        let evpth = format!("/api/v1/namespaces/{}/events", mf.namespace);
+        let selector = format!("involvedObject.name={}", name);
+        match client.get::<ObjectList<Event>>(&evpth, &[("fieldSelector", &selector)]) {
+            Ok(evs) => {
+                if evs.items.is_empty() {
+                    warn!("Unable to find events for pod {}", name);
+                }
+                for e in evs.items {
+                    println!("{}\t{}\tx{}\t{}", e.kind, e.reason, e.count, e.message.trim());
+                }
+            },
+            Err(e) => {
+                warn!("Failed to list events for {}: {}", name, e)
+            }
+        }
+    }
+    // ignore errors from here atm - it's mostly here as a best effort helper
+    let _ = debug_active_replicasets(&client, mf);
+    Ok(())
+}
+
+
+/// Simplified ReplicaSet struct
+///
+/// Created from the replicasets owned by the main deployment
+#[derive(Debug)]
+pub struct ReplicaSet {
+    /// Name of replicaset
+    pub name: String,
+    /// Available replicas (has been ready for minReadySeconds)
+    pub available: u32,
+    /// Total replicas in set
+    pub total: u32,
+    /// Created timestamp (used for ordering)
+    pub created: DateTime<Utc>,
+}
+
+/// Finds replicasets of the main deployment that still have replicas
+///
+/// This is the old and new replicasets in a rolling upgrade.
+pub fn find_active_replicasets(client: &Client, mf: &Manifest) -> Result<Vec<ReplicaSet>> {
+    let rspth = format!("/apis/apps/v1/namespaces/{}/replicasets", mf.namespace);
+    let selector = format!("app={}", mf.name);
+    let rsl : ObjectList<objects::ReplicaSet> = client.get(&rspth, &[("labelSelector", &selector)])?;
+    let sets = rsl.items.into_iter()
+        .filter(|rs| rs.metadata.ownerReferences.iter().any(|o| o.kind == "Deployment" && o.name == mf.name))
+        .filter(|rs| rs.status.replicas > 0)
+        .map(|rs| ReplicaSet {
+            name: rs.metadata.name,
+            available: rs.status.availableReplicas,
+            total: rs.status.replicas,
+            created: rs.metadata.creationTimestamp.unwrap_or_else(Utc::now),
+        })
+        .collect();
+    Ok(sets)
+}
+
+// Debug status of active replicasets post-upgrade helpful info
+fn debug_active_replicasets(client: &Client, mf: &Manifest) -> Result<()> {
+    let sets = find_active_replicasets(client, mf)?;
+    if sets.len() > 1 {
+        warn!("ReplicaSets: {:?}", sets);
+    }
+    if let Some(latest) = sets.iter().max_by_key(|x| x.created.timestamp()) {
+        info!("Latest {:?}", latest);
+        if latest.available > 0 && latest.available < latest.total {
+            warn!("Some replicas successfully rolled out - maybe a higher timeout would help?");
+        }
+        else if latest.available == 0{
+            warn!("No replicas were rolled out fast enough ({} secs)", mf.estimate_wait_time());
+            warn!("Your application might be crashing, or fail to respond to healthchecks in time");
+            warn!("Current health check is set to {:?}", mf.health);
+        }
+    } else {
+        warn!("No active replicasets found");
+    }
+    Ok(())
+}
+
+/// Print upgrade status of current replicaset rollout
+pub fn debug_rollout_status(mf: &Manifest) -> Result<()> {
+    let client = Client::new()?;
+    let mut sets = find_active_replicasets(&client, mf)?;
+    if sets.len() == 2 {
+        sets.sort_unstable_by(|x,y| x.created.timestamp().cmp(&y.created.timestamp()));
+        let old = sets.first().unwrap();
+        let new = sets.last().unwrap();
+        info!("{} upgrade status: old {}/{} -  new {}/{} ", mf.name,
+            old.available, old.total,
+            new.available, new.total
+        );
+    }
+    Ok(())
+}
+
+
+/// Shell into all pods associated with a service
+///
+/// Optionally specify the arbitrary pod index from kubectl get pods
+pub fn shell(mf: &Manifest, desiredpod: Option<usize>, cmd: Option<Vec<&str>>) -> Result<()> {
+    // TODO: kubectl auth can-i create pods/exec
+    let pods = get_pods(&Client::new()?, &mf)?;
+    let pnr = desiredpod.unwrap_or(0);
+    if let Some(p) = pods.get(pnr).map(|p| &p.metadata.name) {
+        debug!("Shelling into {}", p);
+        //kubectl exec -it $pod sh
+        let mut execargs = vec![
+            "exec".into(),
+            format!("-n={}", mf.namespace),
+            "-it".into(),
+            p.to_string(),
+        ];
+        if let Some(cmdu) = cmd.clone() {
+            for c in cmdu {
+                execargs.push(c.into())
+            }
+        } else {
+            let trybash = vec![
+                "exec".into(),
+                format!("-n={}", mf.namespace),
+                p.to_string(),
+                "which".into(),
+                "bash".into(),
+            ];
+            // kubectl exec $pod which bash
+            // returns a non-zero rc if not found generally
+              let shexe = match kexec(trybash) {
+                Ok(o) => {
+                    debug!("Got {:?}", o);
+                    "bash".into()
+                },
+                Err(e) => {
+                    warn!("No bash in container, falling back to `sh`");
+                    debug!("Error: {}", e);
+                    "sh".into()
+                }
+            };
+            execargs.push(shexe);
+        }
+        kexec(execargs)?;
+    } else {
+        bail!("Pod {} not found for service {}", pnr, &mf.name);
+    }
+    Ok(())
+}
+
+
+/// Port forward a port to localhost
+///
+/// Useful because we have autocomplete on manifest names in shipcat
+pub fn port_forward(mf: &Manifest) -> Result<()> {
+    // TODO: kubectl auth can-i create something?
+    let port = mf.httpPort.unwrap();
+    // first 1024 ports need sudo so avoid that
+    let localport = if port <= 1024 { 7777 } else { port };
+
+    debug!("Port forwarding kube deployment {} to localhost:{}", mf.name, localport);
+    //kubectl port-forward deployment/${name} localport:httpPort
+    let pfargs = vec![
+        format!("-n={}", mf.namespace),
+        "port-forward".into(),
+        format!("deployment/{}", mf.name),
+        format!("{}:{}", port, port)
+    ];
+    kexec(pfargs)?;
+    Ok(())
+}
+
+/// Collection path for the kinds of CRDs we manage
+fn crd_collection_path<T>(crd: &Crd<T>, ns: &str) -> Result<String> {
+    let res = match crd.kind.as_ref() {
+        "CustomResourceDefinition" => format!("/apis/{}/customresourcedefinitions", crd.apiVersion),
+        "ShipcatManifest" => format!("/apis/{}/namespaces/{}/shipcatmanifests", crd.apiVersion, ns),
+        "ShipcatConfig" => format!("/apis/{}/namespaces/{}/shipcatconfigs", crd.apiVersion, ns),
+        k => bail!("Unsupported kind {} for apply", k),
+    };
+    Ok(res)
+}
+
+/// Apply the CRD for any struct that can be turned into a CRD
+///
+/// CRDs itself, Manifest and Config typically.
+/// Creates the object if it does not exist, otherwise replaces it.
+pub fn apply_crd<T: Into<Crd<T>> + Serialize>(name: &str, data: T, ns: &str) -> Result<()> {
+    // Use trait constraint to convert it to a CRD
+    let mut crd : Crd<T> = data.into();
+    let client = Client::new()?;
+
+    let collection = crd_collection_path(&crd, ns)?;
+    let pth = format!("{}/{}", collection, crd.metadata.name);
+    debug!("Applying {} CRD for {}", crd.kind, name);
+    match client.get_opt::<MinimalObject>(&pth)? {
+        Some(existing) => {
+            // replace needs the current resourceVersion for optimistic locking
+            crd.metadata.resourceVersion = existing.metadata.resourceVersion;
+            client.replace::<_, MinimalObject>(&pth, &crd)?;
+            debug!("Replaced {} CRD for {}", crd.kind, name);
+        },
+        None => {
+            client.create::<_, MinimalObject>(&collection, &crd)?;
+            debug!("Created {} CRD for {}", crd.kind, name);
+        }
+    }
+    Ok(())
+}
+
+/// Collection path of ShipcatManifests in a namespace (in the latest version)
+pub fn shipcatmanifests_path(ns: &str) -> String {
+    format!("/apis/babylontech.co.uk/{}/namespaces/{}/shipcatmanifests", latest_manifest_version(), ns)
+}
+
+/// Collection path of ShipcatConfigs in a namespace
+pub fn shipcatconfigs_path(ns: &str) -> String {
+    format!("/apis/babylontech.co.uk/v1/namespaces/{}/shipcatconfigs", ns)
+}
+
+/// Find all ManifestCrds in a given namespace
+///
+/// Allows us to purge manifests that are not in Manifest::available()
+pub fn find_all_manifest_crds(client: &Client, ns: &str) -> Result<Vec<String>> {
+    let res : ObjectList<MinimalObject> = client.get(&shipcatmanifests_path(ns), &[])?;
+    Ok(res.items.into_iter().map(|o| o.metadata.name).collect())
+}
+
+pub fn remove_redundant_manifests(ns: &str, svcs: &[String]) -> Result<Vec<String>> {
+    let client = Client::new()?;
+    let requested: HashSet<_> = svcs.iter().cloned().collect();
+    let found: HashSet<_> = find_all_manifest_crds(&client, ns)?.iter().cloned().collect();
+    debug!("Found manifests: {:?}", found);
+
+    let excess : HashSet<_> = found.difference(&requested).collect();
+    info!("Will remove excess manifests: {:?}", excess);
+    if excess.is_empty() {
+        debug!("No excess manifests found");
+    }
+    for x in &excess {
+        client.delete(&format!("{}/{}", shipcatmanifests_path(ns), x))?;
+    }
+    let exvec = excess.into_iter().cloned().collect();
+    Ok(exvec)
+}
+
+/// Write the status of a ShipcatManifest via its status subresource
+///
+/// The whole status is applied, so fields left out of `status` are removed.
+pub fn update_manifest_status(client: &Client, ns: &str, name: &str, status: &ManifestStatus) -> Result<()> {
+    let pth = format!("{}/{}/status", shipcatmanifests_path(ns), name);
+    let data = serde_json::json!({
+        "apiVersion": format!("babylontech.co.uk/{}", latest_manifest_version()),
+        "kind": "ShipcatManifest",
+        "metadata": { "name": name },
+        "status": status,
+    });
+    client.apply::<_, MinimalObject>(&pth, FIELD_MANAGER, &data)?;
+    debug!("Updated status of {}", name);
+    Ok(())
+}
+
+/// Current status of a ShipcatManifest, or None if the manifest is not in the namespace
+pub fn get_manifest_status(client: &Client, ns: &str, name: &str) -> Result<Option<ManifestStatus>> {
+    let pth = format!("{}/{}", shipcatmanifests_path(ns), name);
+    // spec left untyped so that the status can be read from any spec version
+    let crd : Option<Crd<Value>> = client.get_opt(&pth)?;
+    Ok(crd.map(|c| c.status.unwrap_or_default()))
+}
+
+/// Record the outcome of an upgrade in the deployment history of a ShipcatManifest
+///
+/// Best effort: services without a ShipcatManifest in the namespace are skipped,
+/// and failures are only logged so that they never fail an upgrade.
+pub fn record_upgrade(ud: &UpgradeData, state: UpgradeState) {
+    let record = || -> Result<()> {
+        let client = Client::new()?;
+        let mut status = match get_manifest_status(&client, &ud.namespace, &ud.name)? {
+            Some(s) => s,
+            None => {
+                debug!("No ShipcatManifest for {} in {} - not recording upgrade", ud.name, ud.namespace);
+                return Ok(());
+            }
+        };
+        status.record(&ud.version, state.clone(), &Utc::now().to_rfc3339());
+        update_manifest_status(&client, &ud.namespace, &ud.name, &status)
+    };
+    if let Err(e) = record() {
+        warn!("Failed to record the {:?} upgrade of {}: {}", state, ud.name, e);
+    }
+}
+
+const SHIPCATMANIFESTS_CRD_PATH: &str =
+    "/apis/apiextensions.k8s.io/v1beta1/customresourcedefinitions/shipcatmanifests.babylontech.co.uk";
+
+/// Rewrite all ShipcatManifests in a namespace in the latest spec version
+///
+/// Older specs are upgraded via `crds::conversion`. Every object is replaced
+/// (not just upgraded ones) so that kube stores all of them in the latest version,
+/// after which the older versions are dropped from the CRD's `storedVersions`.
+/// Only the spec and the spec version change, the rest of the metadata is kept.
+/// Returns the names of the manifests that needed a conversion.
+pub fn migrate_manifest_crds(client: &Client, ns: &str) -> Result<Vec<String>> {
+    let latest = latest_manifest_version();
+    let list : ObjectList<Value> = client.get(&shipcatmanifests_path(ns), &[])?;
+    let mut converted = vec![];
+    for mut obj in list.items {
+        let crd : Crd<Value> = serde_json::from_value(obj.clone())?;
+        let name = crd.metadata.name.clone();
+        let from = conversion::spec_version(&crd.metadata, MANIFEST_VERSIONS).to_string();
+        let upgraded = conversion::upgrade_manifest(crd)?;
+        // labels, finalizers and owner references are not part of the typed metadata
+        obj["apiVersion"] = Value::String(upgraded.apiVersion);
+        obj["spec"] = upgraded.spec;
+        obj["metadata"]["annotations"][SPEC_VERSION_ANNOTATION] = Value::String(latest.into());
+        let pth = format!("{}/{}", shipcatmanifests_path(ns), name);
+        client.replace::<_, MinimalObject>(&pth, &obj)?;
+        if from != latest {
+            info!("Migrated {} from {} to {}", name, from, latest);
+            converted.push(name);
+        } else {
+            debug!("Rewrote {} in {}", name, latest);
+        }
+    }
+
+    // everything is stored in the latest version now
+    let status_pth = format!("{}/status", SHIPCATMANIFESTS_CRD_PATH);
+    let mut crd : Value = client.get(SHIPCATMANIFESTS_CRD_PATH, &[])?;
+    let stored = crd["status"]["storedVersions"].clone();
+    if stored != serde_json::json!([latest]) {
+        info!("Setting stored versions of shipcatmanifests from {} to [{}]", stored, latest);
+        crd["status"]["storedVersions"] = serde_json::json!([latest]);
+        client.replace::<_, MinimalObject>(&status_pth, &crd)?;
+    }
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use dirs;
+    use super::current_context;
+
+    #[test]
+    fn validate_ctx() {
+        let kubecfg = dirs::home_dir().unwrap().join(".kube").join("config");
+        // ignoring this test on circleci..
+        if kubecfg.is_file() {
+            let ctx = current_context().unwrap();
+            assert_eq!(ctx, ctx.trim());
+            assert_ne!(ctx, "");
+        }
+    }
+}
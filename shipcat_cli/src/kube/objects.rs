@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use chrono::{Utc, DateTime};
+
+// Limited subsets of kube api objects
+//
+// Only the fields shipcat actually reads are parsed.
+// Everything is defaulted as the api server omits empty fields.
+
+/// Generic list wrapper for kube api list calls
+#[derive(Deserialize, Debug)]
+pub struct ObjectList<T> {
+    #[serde(default)]
+    pub metadata: ListMeta,
+    #[serde(default)]
+    pub items: Vec<T>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ListMeta {
+    #[serde(default)]
+    pub resourceVersion: String,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ObjectMeta {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub resourceVersion: String,
+    #[serde(default)]
+    pub generation: i64,
+    pub creationTimestamp: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default)]
+    pub ownerReferences: Vec<OwnerReference>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OwnerReference {
+    pub kind: String,
+    pub name: String,
+}
+
+/// Any object where only the metadata is of interest
+#[derive(Deserialize, Debug)]
+pub struct MinimalObject {
+    pub metadata: ObjectMeta,
+}
+
+// ----------------------------------------------------------------------------
+
+#[derive(Deserialize, Debug)]
+pub struct Deployment {
+    pub metadata: ObjectMeta,
+    #[serde(default)]
+    pub spec: DeploymentSpec,
+    #[serde(default)]
+    pub status: DeploymentStatus,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DeploymentSpec {
+    /// Desired replicas (kube defaults this to 1)
+    pub replicas: Option<u32>,
+    #[serde(default)]
+    pub template: PodTemplateSpec,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PodTemplateSpec {
+    #[serde(default)]
+    pub metadata: ObjectMeta,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DeploymentStatus {
+    #[serde(default)]
+    pub observedGeneration: i64,
+    #[serde(default)]
+    pub replicas: u32,
+    #[serde(default)]
+    pub updatedReplicas: u32,
+    #[serde(default)]
+    pub readyReplicas: u32,
+    #[serde(default)]
+    pub availableReplicas: u32,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub status: String,
+    #[serde(default)]
+    pub reason: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+// ----------------------------------------------------------------------------
+
+#[derive(Deserialize, Debug)]
+pub struct ReplicaSet {
+    pub metadata: ObjectMeta,
+    #[serde(default)]
+    pub status: ReplicaSetStatus,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ReplicaSetStatus {
+    // `observedGeneration` <- not unique across replicasets so useless
+    /// Currently available replicas (ready for minReadySeconds) from this generation
+    #[serde(default)]
+    pub availableReplicas: u32,
+    /// Currently ready replicas from this generation (weaker than above)
+    #[serde(default)]
+    pub readyReplicas: u32,
+    /// Most recently observed number of replicas
+    #[serde(default)]
+    pub replicas: u32,
+}
+
+// ----------------------------------------------------------------------------
+
+#[derive(Deserialize, Debug)]
+pub struct Pod {
+    pub metadata: ObjectMeta,
+    #[serde(default)]
+    pub status: PodStatus,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PodStatus {
+    #[serde(default)]
+    pub phase: String,
+    #[serde(default)]
+    pub containerStatuses: Vec<ContainerStatus>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContainerStatus {
+    pub name: String,
+    #[serde(default)]
+    pub ready: bool,
+    #[serde(default)]
+    pub restartCount: u32,
+    #[serde(default)]
+    pub state: ContainerState,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ContainerState {
+    pub waiting: Option<ContainerStateWaiting>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContainerStateWaiting {
+    #[serde(default)]
+    pub reason: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl Pod {
+    /// Number of ready containers and total containers
+    pub fn ready_count(&self) -> (usize, usize) {
+        let cs = &self.status.containerStatuses;
+        (cs.iter().filter(|c| c.ready).count(), cs.len())
+    }
+
+    /// Total restarts across all containers
+    pub fn restarts(&self) -> u32 {
+        self.status.containerStatuses.iter().map(|c| c.restartCount).sum()
+    }
+
+    /// First container stuck waiting for one of the given reasons
+    pub fn waiting_for(&self, reasons: &[&str]) -> Option<&ContainerStateWaiting> {
+        self.status.containerStatuses.iter()
+            .filter_map(|c| c.state.waiting.as_ref())
+            .find(|w| reasons.contains(&w.reason.as_ref()))
+    }
+
+    /// Whether the pod is running with all its containers ready
+    pub fn is_healthy(&self) -> bool {
+        let (ready, total) = self.ready_count();
+        self.status.phase == "Running" && ready == total
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+#[derive(Deserialize, Debug)]
+pub struct Event {
+    #[serde(default, rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub reason: String,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub count: u32,
+}
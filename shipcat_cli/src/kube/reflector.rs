@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use serde::de::DeserializeOwned;
+use shipcat_definitions::Crd;
+
+use super::client::{Client, WatchEvent, WatchStream};
+use super::objects::ObjectList;
+use super::{Result, ErrorKind};
+
+/// A local cache of the custom resources of one kind in a namespace
+///
+/// Lists the resources once, then applies watch events from the last seen resource version.
+/// If kube no longer has that version (410 Gone), or a watch fails, the resources are listed again.
+pub struct Reflector<T> {
+    /// Api path of the collection (e.g. from `shipcatmanifests_path`)
+    path: String,
+    /// Resources by name
+    pub data: BTreeMap<String, Crd<T>>,
+    /// Resource version to continue watching from
+    pub version: String, // kube keeps it as a String
+    /// The currently open watch
+    stream: Option<WatchStream<Crd<T>>>,
+    /// Whether the next poll has to list
+    stale: bool,
+}
+
+impl<T> Reflector<T> where T: DeserializeOwned {
+    /// List the resources of a collection
+    pub fn init(client: &Client, path: &str) -> Result<Self> {
+        let mut r = Reflector {
+            path: path.into(),
+            data: BTreeMap::new(),
+            version: "0".into(),
+            stream: None,
+            stale: true,
+        };
+        r.refresh(client)?;
+        Ok(r)
+    }
+
+    /// Plural name of the reflected resource
+    pub fn resource(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// Replace the cache with a full list
+    fn refresh(&mut self, client: &Client) -> Result<()> {
+        self.stream = None;
+        let res : ObjectList<Crd<T>> = client.get(&self.path, &[])?;
+        self.version = res.metadata.resourceVersion;
+        info!("Got {} {} at resource version: {}", res.items.len(), self.resource(), self.version);
+        self.data = res.items.into_iter()
+            .map(|crd| (crd.metadata.name.clone(), crd))
+            .collect();
+        let keys = self.data.keys().cloned().collect::<Vec<_>>().join(", ");
+        debug!("Initialized {} with: {}", self.resource(), keys);
+        self.stale = false;
+        Ok(())
+    }
+
+    /// Apply the next change
+    ///
+    /// Blocks until kube sends an event or the watch times out,
+    /// and returns whether anything changed.
+    pub fn poll(&mut self, client: &Client) -> Result<bool> {
+        if self.stale {
+            self.refresh(client)?;
+            return Ok(true);
+        }
+        if self.stream.is_none() {
+            match client.watch(&self.path, &[("resourceVersion", self.version.as_str())]) {
+                Ok(s) => self.stream = Some(s),
+                Err(e) => {
+                    // kube can also refuse an expired watch outright
+                    self.stale = true;
+                    return Err(e);
+                }
+            }
+        }
+        let ev = match self.stream.as_mut().and_then(|s| s.next()) {
+            Some(Ok(ev)) => ev,
+            Some(Err(e)) => {
+                self.stale = true;
+                return Err(e);
+            },
+            None => {
+                // watch timed out, continue from the same version
+                self.stream = None;
+                return Ok(false);
+            }
+        };
+        let version = match ev {
+            WatchEvent::Added(crd) | WatchEvent::Modified(crd) => {
+                debug!("Updating {} in {}", crd.metadata.name, self.resource());
+                let version = crd.metadata.resourceVersion.clone();
+                self.data.insert(crd.metadata.name.clone(), crd);
+                version
+            },
+            WatchEvent::Deleted(crd) => {
+                info!("Removing {} from {}", crd.metadata.name, self.resource());
+                self.data.remove(&crd.metadata.name);
+                crd.metadata.resourceVersion
+            },
+            WatchEvent::Error(s) => {
+                self.stale = true;
+                if s.code == 410 {
+                    info!("Watch of {} from {} expired: {}", self.resource(), self.version, s.message);
+                    self.refresh(client)?;
+                    return Ok(true);
+                }
+                bail!(ErrorKind::KubeApiFailure(s.code, s.reason, s.message));
+            },
+        };
+        if !version.is_empty() {
+            self.version = version;
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+
+use super::{Result, ErrorKind, Manifest};
+use super::client::{Client, WatchEvent};
+use super::objects::{Deployment, ReplicaSet, Pod};
+
+/// Container waiting reasons that will not resolve themselves during a rollout
+const FATAL_WAITING_REASONS: [&str; 2] = ["CrashLoopBackOff", "ImagePullBackOff"];
+
+/// Annotation kube uses to tie replicasets to a deployment revision
+const REVISION_ANNOTATION: &str = "deployment.kubernetes.io/revision";
+
+fn deployment_path(mf: &Manifest) -> String {
+    format!("/apis/apps/v1/namespaces/{}/deployments/{}", mf.namespace, mf.name)
+}
+
+/// Evaluate the progress of a deployment
+///
+/// Mirrors the logic of `kubectl rollout status` but fails if kube has
+/// given up on the deployment progressing.
+fn deployment_progress(mf: &Manifest, d: &Deployment) -> Result<bool> {
+    let st = &d.status;
+    if d.metadata.generation > st.observedGeneration {
+        debug!("{} waiting for deployment spec update to be observed", mf.name);
+        return Ok(false);
+    }
+    if let Some(c) = st.conditions.iter().find(|c| c.kind == "Progressing") {
+        if c.reason == "ProgressDeadlineExceeded" {
+            bail!(ErrorKind::RolloutFailure(mf.name.clone(), c.reason.clone(), c.message.clone()));
+        }
+    }
+    let desired = d.spec.replicas.unwrap_or(1);
+    if st.updatedReplicas < desired {
+        debug!("{}: {} out of {} new replicas have been updated", mf.name, st.updatedReplicas, desired);
+        return Ok(false);
+    }
+    if st.replicas > st.updatedReplicas {
+        debug!("{}: {} old replicas are pending termination", mf.name, st.replicas - st.updatedReplicas);
+        return Ok(false);
+    }
+    if st.availableReplicas < st.updatedReplicas {
+        debug!("{}: {} of {} updated replicas are available", mf.name, st.availableReplicas, st.updatedReplicas);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Check the rollout status of the main deployment once
+pub fn rollout_status(client: &Client, mf: &Manifest) -> Result<bool> {
+    // TODO: handle more than one deployment
+    let d : Deployment = client.get(&deployment_path(mf), &[])?; // always one deployment with same name
+    deployment_progress(mf, &d)
+}
+
+/// Fetch the main deployment of a service if it exists
+pub fn get_deployment(client: &Client, mf: &Manifest) -> Result<Option<Deployment>> {
+    client.get_opt(&deployment_path(mf))
+}
+
+/// Changes to the objects involved in a rollout
+enum RolloutEvent {
+    Deployment(WatchEvent<Deployment>),
+    ReplicaSet(WatchEvent<ReplicaSet>),
+    Pod(WatchEvent<Pod>),
+    /// A watch ended and is being restarted
+    Resync,
+}
+
+/// Last seen state of the objects involved in a rollout
+#[derive(Default)]
+struct RolloutTracker {
+    deployment: Option<Deployment>,
+    replicasets: BTreeMap<String, ReplicaSet>,
+    pods: BTreeMap<String, Pod>,
+}
+
+impl RolloutTracker {
+    fn apply(&mut self, ev: RolloutEvent) {
+        match ev {
+            RolloutEvent::Deployment(WatchEvent::Added(d)) |
+            RolloutEvent::Deployment(WatchEvent::Modified(d)) => self.deployment = Some(d),
+            RolloutEvent::Deployment(WatchEvent::Deleted(_)) => self.deployment = None,
+            RolloutEvent::ReplicaSet(WatchEvent::Added(rs)) |
+            RolloutEvent::ReplicaSet(WatchEvent::Modified(rs)) => {
+                self.replicasets.insert(rs.metadata.name.clone(), rs);
+            },
+            RolloutEvent::ReplicaSet(WatchEvent::Deleted(rs)) => {
+                self.replicasets.remove(&rs.metadata.name);
+            },
+            RolloutEvent::Pod(WatchEvent::Added(p)) |
+            RolloutEvent::Pod(WatchEvent::Modified(p)) => {
+                self.pods.insert(p.metadata.name.clone(), p);
+            },
+            RolloutEvent::Pod(WatchEvent::Deleted(p)) => {
+                self.pods.remove(&p.metadata.name);
+            },
+            // watchers restart themselves on errors
+            RolloutEvent::Deployment(WatchEvent::Error(s)) |
+            RolloutEvent::ReplicaSet(WatchEvent::Error(s)) |
+            RolloutEvent::Pod(WatchEvent::Error(s)) => debug!("Watch error: {}", s.message),
+            RolloutEvent::Resync => {},
+        }
+    }
+
+    /// Names of the replicasets belonging to the current deployment revision
+    fn current_replicasets(&self, mf: &Manifest) -> Vec<&str> {
+        let rev = match self.deployment.as_ref().and_then(|d| d.metadata.annotations.get(REVISION_ANNOTATION)) {
+            Some(r) => r,
+            None => return vec![],
+        };
+        self.replicasets.values()
+            .filter(|rs| rs.metadata.ownerReferences.iter().any(|o| o.kind == "Deployment" && o.name == mf.name))
+            .filter(|rs| rs.metadata.annotations.get(REVISION_ANNOTATION) == Some(rev))
+            .map(|rs| rs.metadata.name.as_str())
+            .collect()
+    }
+
+    /// Whether the rollout has completed, erroring if it never will
+    fn evaluate(&self, mf: &Manifest) -> Result<bool> {
+        let d = match self.deployment {
+            Some(ref d) => d,
+            None => return Ok(false), // not created yet
+        };
+        // only pods of the new revision can fail the rollout
+        let current = self.current_replicasets(mf);
+        for p in self.pods.values() {
+            if !p.metadata.ownerReferences.iter().any(|o| o.kind == "ReplicaSet" && current.contains(&o.name.as_str())) {
+                continue;
+            }
+            if let Some(w) = p.waiting_for(&FATAL_WAITING_REASONS) {
+                warn!("Pod {} is stuck in {}", p.metadata.name, w.reason);
+                bail!(ErrorKind::RolloutFailure(mf.name.clone(), w.reason.clone(), w.message.clone()));
+            }
+        }
+        deployment_progress(mf, d)
+    }
+}
+
+/// Watch a collection forever, forwarding events until the receiver is gone
+fn watch_into<T, F>(client: Client, path: String, query: Vec<(&'static str, String)>, tx: Sender<RolloutEvent>, wrap: F)
+    where T: DeserializeOwned,
+          F: Fn(WatchEvent<T>) -> RolloutEvent
+{
+    loop {
+        let started = Instant::now();
+        let q : Vec<(&str, &str)> = query.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        match client.watch::<T>(&path, &q) {
+            Ok(stream) => {
+                for ev in stream {
+                    match ev {
+                        Ok(e) => {
+                            if tx.send(wrap(e)).is_err() {
+                                return; // rollout tracking finished
+                            }
+                        },
+                        Err(e) => {
+                            debug!("Dropping watch on {}: {}", path, e);
+                            break;
+                        }
+                    }
+                }
+            },
+            Err(e) => warn!("Failed to watch {}: {}", path, e),
+        }
+        // quiet collections never send events, so check the receiver before restarting
+        if tx.send(RolloutEvent::Resync).is_err() {
+            return;
+        }
+        // avoid hammering the api server if watches end immediately
+        if started.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+/// Track a rollout via watches on the deployment, its replicasets and pods
+///
+/// Returns `Ok(false)` if the rollout did not complete within `timeout`,
+/// and a `RolloutFailure` as soon as the rollout is known to be broken.
+pub fn track_rollout(client: &Client, mf: &Manifest, timeout: Duration) -> Result<bool> {
+    let (tx, rx) = channel();
+    let ns = &mf.namespace;
+    let selector = format!("app={}", mf.name);
+    {
+        let (c, tx) = (client.clone(), tx.clone());
+        let pth = format!("/apis/apps/v1/namespaces/{}/deployments", ns);
+        let q = vec![("fieldSelector", format!("metadata.name={}", mf.name))];
+        thread::spawn(move || watch_into(c, pth, q, tx, RolloutEvent::Deployment));
+    }
+    {
+        let (c, tx) = (client.clone(), tx.clone());
+        let pth = format!("/apis/apps/v1/namespaces/{}/replicasets", ns);
+        let q = vec![("labelSelector", selector.clone())];
+        thread::spawn(move || watch_into(c, pth, q, tx, RolloutEvent::ReplicaSet));
+    }
+    {
+        let c = client.clone();
+        let pth = format!("/api/v1/namespaces/{}/pods", ns);
+        let q = vec![("labelSelector", selector)];
+        thread::spawn(move || watch_into(c, pth, q, tx, RolloutEvent::Pod));
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut tracker = RolloutTracker::default();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok(ev) => {
+                tracker.apply(ev);
+                if tracker.evaluate(mf)? {
+                    return Ok(true);
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => return Ok(false),
+            Err(RecvTimeoutError::Disconnected) => bail!("Lost all watches for {}", mf.name),
+        }
+    }
+}
+
+/// A replacement for helm upgrade's --wait and --timeout
+///
+/// Uses the estimated wait time of the manifest as the upper bound,
+/// but fails as soon as the rollout is known to be broken.
+pub fn await_rollout_status(mf: &Manifest) -> Result<bool> {
+    let client = Client::new()?;
+    let waittime = mf.estimate_wait_time();
+    info!("Waiting up to {}s for deployment {} to rollout", waittime, mf.name);
+    track_rollout(&client, mf, Duration::from_secs(waittime.into()))
+}
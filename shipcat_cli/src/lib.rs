@@ -67,14 +67,34 @@ error_chain! {
             description("slack message send failed")
             display("Failed to send the slack message to '{}' ", &hook)
         }
+        RolloutFailure(svc: String, reason: String, message: String) {
+            description("rollout failed")
+            display("{} rollout failed with {}: {}", &svc, &reason, &message)
+        }
+        ProductUpgradeFailure(product: String, svcs: Vec<String>) {
+            description("product upgrade failed")
+            display("{} failed to upgrade {}", &product, svcs.join(", "))
+        }
+        DependencyCycle(svcs: Vec<String>) {
+            description("dependency cycle between services")
+            display("services {} depend on each other in a cycle", svcs.join(", "))
+        }
+        KubeConfigFailure(msg: String) {
+            description("could not load kube config")
+            display("could not load kube config: {}", &msg)
+        }
+        KubeApiFailure(code: u16, reason: String, message: String) {
+            description("kube api request failed")
+            display("kube api returned {} {}: {}", code, &reason, &message)
+        }
     }
 }
 
 pub use shipcat_definitions::{Manifest, ConfigType};
 pub use shipcat_definitions::structs;
 pub use shipcat_definitions::config::{self, Config, Team};
-pub use shipcat_definitions::region::{Region, VersionScheme, KongConfig, Webhook, AuditWebhook};
-//pub use shipcat_definitions::Product;
+pub use shipcat_definitions::region::{Region, VersionScheme, KongConfig, Webhook, AuditWebhook, HelmBackend};
+pub use shipcat_definitions::Product;
 
 /// Convenience listers
 pub mod list;
@@ -93,12 +113,15 @@ pub mod validate;
 /// gdpr lister
 pub mod gdpr;
 
-/// A small CLI kubernetes interface
+/// A small kubernetes api interface
 pub mod kube;
 
 /// A small CLI helm interface
 pub mod helm;
 
+/// In-process helm chart rendering
+pub mod chart;
+
 /// A small CLI kong config generator interface
 pub mod kong;
 
@@ -117,6 +140,15 @@ pub mod webhooks;
 /// Simple printers
 pub mod show;
 
+/// Semantic manifest diffs between regions and git refs
+pub mod diff;
+
+/// Multi-service product deploys
+pub mod product;
+
+/// Secret drift detection and audits
+pub mod secret;
+
 /// Smart initialiser with safety
 ///
 /// Tricks the library into reading from your manifest location.
@@ -147,6 +147,28 @@ fn main() {
                     .multiple(true)
                     .help("Regions to validate all enabled services for"))
                 .about("Verify existence of secrets for entire regions"))
+            .subcommand(SubCommand::with_name("drift")
+                .arg(Arg::with_name("region")
+                    .short("r")
+                    .long("region")
+                    .takes_value(true)
+                    .help("Region to check running services in"))
+                .arg(Arg::with_name("restart")
+                    .long("restart")
+                    .help("Upgrade services with outdated secrets to restart their pods"))
+                .arg(Arg::with_name("num-jobs")
+                    .short("j")
+                    .long("num-jobs")
+                    .takes_value(true)
+                    .help("Number of worker threads used"))
+                .about("List services running with outdated secrets"))
+            .subcommand(SubCommand::with_name("audit")
+                .arg(Arg::with_name("region")
+                    .short("r")
+                    .long("region")
+                    .takes_value(true)
+                    .help("Region to audit the secrets of"))
+                .about("Report secrets that no manifest uses"))
             .about("Secret interaction"))
 
         .subcommand(SubCommand::with_name("gdpr")
@@ -227,7 +249,9 @@ fn main() {
                     .takes_value(true)
                     .help("Number of worker threads used"))
                 .subcommand(SubCommand::with_name("reconcile")
-                    .about("Reconcile shipcat custom resource definitions with local state")))
+                    .about("Reconcile shipcat custom resource definitions with local state"))
+                .subcommand(SubCommand::with_name("migrate")
+                    .about("Rewrite stored manifest custom resources in the latest version")))
             .subcommand(SubCommand::with_name("helm")
                 .arg(Arg::with_name("num-jobs")
                     .short("j")
@@ -235,6 +259,9 @@ fn main() {
                     .takes_value(true)
                     .help("Number of worker threads used"))
                 .subcommand(SubCommand::with_name("reconcile")
+                    .arg(Arg::with_name("ordered")
+                        .long("ordered")
+                        .help("Upgrade in waves following the dependency graph"))
                     .about("Reconcile kubernetes region configs with local state"))
                 .subcommand(SubCommand::with_name("diff")
                     .about("Diff kubernetes region configs with local state"))))
@@ -308,13 +335,44 @@ fn main() {
               .arg(Arg::with_name("service")
                 .required(true)
                 .help("Service to generate kube yaml for"))
-            .about("Generate kube yaml for a service (through helm)"))
+            .about("Generate kube yaml for a service (without helm)"))
+        .subcommand(SubCommand::with_name("diff")
+              .arg(Arg::with_name("from-region")
+                .long("from-region")
+                .takes_value(true)
+                .help("Region to compare from (defaults to the current context)"))
+              .arg(Arg::with_name("to-region")
+                .long("to-region")
+                .takes_value(true)
+                .help("Region to compare to (defaults to the from region)"))
+              .arg(Arg::with_name("from-ref")
+                .long("from-ref")
+                .takes_value(true)
+                .help("Git ref to read the original manifests from"))
+              .arg(Arg::with_name("json")
+                .long("json")
+                .help("Output the differences as json"))
+              .arg(Arg::with_name("service")
+                .required(true)
+                .help("Service to compare"))
+            .about("Compare the resolved manifest of a service between regions or git refs"))
         .subcommand(SubCommand::with_name("apply")
               .arg(Arg::with_name("tag")
                 .long("tag")
                 .short("t")
                 .takes_value(true)
                 .help("Image version to deploy"))
+              .arg(Arg::with_name("server-side")
+                .long("server-side")
+                .help("Apply the rendered chart directly instead of through a helm release"))
+              .arg(Arg::with_name("canary")
+                .long("canary")
+                .conflicts_with("server-side")
+                .help("Roll out through the canary steps in the manifest before upgrading"))
+              .arg(Arg::with_name("blue-green")
+                .long("blue-green")
+                .conflicts_with_all(&["server-side", "canary"])
+                .help("Install the idle blue/green release and switch kong over to it"))
               .arg(Arg::with_name("service")
                 .required(true)
                 .help("Service to upgrad"))
@@ -336,6 +394,14 @@ fn main() {
             .subcommand(SubCommand::with_name("verify")
                 .about("Verify the parsed config")))
 
+        // schemas
+        .subcommand(SubCommand::with_name("schema")
+            .about("Print the JSON Schema of shipcat.yml or shipcat.conf")
+            .arg(Arg::with_name("kind")
+                .required(true)
+                .possible_values(&["manifest", "config"])
+                .help("Which file to print the schema for")))
+
         // products
         .subcommand(SubCommand::with_name("product")
             .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -359,6 +425,24 @@ fn main() {
                     .required(false)
                     .help("Location name"))
                 .about("Verify product manifests"))
+            .subcommand(SubCommand::with_name("apply")
+                .arg(Arg::with_name("product")
+                    .required(true)
+                    .help("Product name"))
+                .arg(Arg::with_name("region")
+                    .short("r")
+                    .long("region")
+                    .takes_value(true)
+                    .help("Region to apply the product's services in"))
+                .arg(Arg::with_name("rollback")
+                    .long("rollback")
+                    .help("Roll back all upgraded services if any of them fail"))
+                .arg(Arg::with_name("num-jobs")
+                    .short("j")
+                    .long("num-jobs")
+                    .takes_value(true)
+                    .help("Number of worker threads used"))
+                .about("Upgrade all services of a product in dependency order"))
             );
 
     // arg parse
@@ -427,6 +511,9 @@ fn dispatch_commands(args: &ArgMatches) -> Result<()> {
         let (_ , region) = resolve_config(a, ConfigType::Base)?;
         return shipcat::list::services(&region);
     }
+    else if let Some(a) = args.subcommand_matches("schema") {
+        return shipcat::show::schema(a.value_of("kind").unwrap());
+    }
     //if let Some(a) = args.subcommand_matches("list-products") {
     //    let l = a.value_of("location").unwrap().into();
     //    return shipcat::list::products(&conf, l);
@@ -477,19 +564,26 @@ fn dispatch_commands(args: &ArgMatches) -> Result<()> {
         }
     }
     // product
-    else if let Some(_a) = args.subcommand_matches("product") {
-        // TODO: handle more like the other commands
-        unimplemented!();
-/*        if let Some(b) = a.subcommand_matches("verify") {
+    else if let Some(a) = args.subcommand_matches("product") {
+        if let Some(b) = a.subcommand_matches("apply") {
+            let product = b.value_of("product").unwrap();
+            // this absolutely needs secrets..
+            let (conf, region) = resolve_config(b, ConfigType::Filtered)?;
+            assert!(conf.has_secrets()); // sanity on cluster disruptive commands
+            let jobs = b.value_of("num-jobs").unwrap_or("8").parse().unwrap();
+            return shipcat::product::apply(product, &conf, &region, b.is_present("rollback"), jobs).map(void);
+        }
+        let conf = Config::read()?;
+        if let Some(b) = a.subcommand_matches("verify") {
             let location = b.value_of("location");
             let products  = b.values_of("products").unwrap().map(String::from).collect::<Vec<_>>();
-            return shipcat::product::validate(products, &conf, location.map(String::from));
+            return Ok(shipcat_definitions::product::validate(products, &conf, location.map(String::from))?);
         }
         else if let Some(b) = a.subcommand_matches("show") {
             let product  = b.value_of("product").map(String::from);
             let location = b.value_of("location");
-            return shipcat::product::show(product, &conf, location.unwrap());
-        }*/
+            return Ok(shipcat_definitions::product::show(product, &conf, location.unwrap())?);
+        }
     }
     else if let Some(a) = args.subcommand_matches("config") {
         if let Some(_) = a.subcommand_matches("crd") {
@@ -514,6 +608,16 @@ fn dispatch_commands(args: &ArgMatches) -> Result<()> {
     // helpers that can work without a kube region, but will shell out to kubectl if not passed
     // TODO: remove this
     else if let Some(a) = args.subcommand_matches("secret") {
+        if let Some(b) = a.subcommand_matches("drift") {
+            let (conf, region) = resolve_config(b, ConfigType::Filtered)?;
+            assert!(conf.has_secrets()); // sanity on cluster disruptive commands
+            let jobs = b.value_of("num-jobs").unwrap_or("8").parse().unwrap();
+            return shipcat::secret::drift(&conf, &region, b.is_present("restart"), jobs).map(void);
+        }
+        if let Some(b) = a.subcommand_matches("audit") {
+            let (conf, region) = resolve_config(b, ConfigType::Base)?;
+            return shipcat::secret::audit(&conf, &region).map(void);
+        }
         let rawconf = Config::read()?;
         if let Some(b) = a.subcommand_matches("verify-region") {
             let regions = b.values_of("regions").unwrap().map(String::from).collect::<Vec<_>>();
@@ -572,9 +676,25 @@ fn dispatch_commands(args: &ArgMatches) -> Result<()> {
         let (conf, region) = resolve_config(a, ss)?;
 
         let mock = !a.is_present("secrets");
-        return shipcat::helm::direct::template(&svc,
+        return shipcat::chart::template(&svc,
                 &region, &conf, None, mock, None).map(void);
     }
+    else if let Some(a) = args.subcommand_matches("diff") {
+        let svc = a.value_of("service").map(String::from).unwrap();
+        let from_region = match a.value_of("from-region") {
+            Some(r) => r.to_string(),
+            None => kube::current_context()?,
+        };
+        let to_region = a.value_of("to-region").map(String::from).unwrap_or_else(|| from_region.clone());
+        let from_ref = a.value_of("from-ref").map(String::from);
+        if from_region == to_region && from_ref.is_none() {
+            return Err("Nothing to compare - specify a --to-region or a --from-ref".into());
+        }
+        let from = shipcat::diff::DiffSource { region: from_region, gitref: from_ref };
+        let to = shipcat::diff::DiffSource { region: to_region, gitref: None };
+        let fmt = if a.is_present("json") { shipcat::diff::DiffFormat::Json } else { shipcat::diff::DiffFormat::Human };
+        return shipcat::diff::diff(&svc, &from, &to, fmt).map(void);
+    }
     else if let Some(a) = args.subcommand_matches("crd") {
         let svc = a.value_of("service").map(String::from).unwrap();
 
@@ -611,7 +731,15 @@ fn dispatch_commands(args: &ArgMatches) -> Result<()> {
         let svc = a.value_of("service").map(String::from).unwrap();
         // this absolutely needs secrets..
         let (conf, region) = resolve_config(a, ConfigType::Filtered)?;
-        let umode = shipcat::helm::UpgradeMode::UpgradeInstall;
+        let umode = if a.is_present("server-side") {
+            shipcat::helm::UpgradeMode::Apply
+        } else if a.is_present("canary") {
+            shipcat::helm::UpgradeMode::Canary
+        } else if a.is_present("blue-green") {
+            shipcat::helm::UpgradeMode::BlueGreen
+        } else {
+            shipcat::helm::UpgradeMode::UpgradeInstall
+        };
         let ver = a.value_of("tag").map(String::from); // needed for some subcommands
         assert!(conf.has_secrets()); // sanity on cluster disruptive commands
         return shipcat::helm::direct::upgrade_wrapper(&svc,
@@ -690,6 +818,9 @@ fn dispatch_commands(args: &ArgMatches) -> Result<()> {
             if let Some(_) = b.subcommand_matches("reconcile") {
                 return shipcat::cluster::mass_crd(&conf, &region, jobs);
             }
+            if let Some(_) = b.subcommand_matches("migrate") {
+                return shipcat::cluster::crd_migrate(&region).map(void);
+            }
         }
         if let Some(b) = a.subcommand_matches("helm") {
             // absolutely need secrets for helm reconcile
@@ -700,7 +831,10 @@ fn dispatch_commands(args: &ArgMatches) -> Result<()> {
             if let Some(_) = b.subcommand_matches("diff") {
                 return shipcat::cluster::helm_diff(&conf, &region, jobs);
             }
-            else if let Some(_) = b.subcommand_matches("reconcile") {
+            else if let Some(c) = b.subcommand_matches("reconcile") {
+                if c.is_present("ordered") {
+                    return shipcat::cluster::helm_reconcile_ordered(&conf, &region, jobs);
+                }
                 return shipcat::cluster::helm_reconcile(&conf, &region, jobs);
             }
         }
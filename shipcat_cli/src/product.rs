@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use threadpool::ThreadPool;
+
+use shipcat_definitions::Product;
+
+use super::{Config, Region, Manifest};
+use super::{Result, ErrorKind};
+use super::graph;
+use super::helm::{self, UpgradeMode, UpgradeData};
+use crate::structs::Colour;
+use crate::webhooks::{self, UpgradeState};
+
+/// Product name and version that upgrades of its services are tagged with
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ProductTag {
+    pub name: String,
+    pub version: String,
+}
+
+/// What happened to a service during a product apply
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ServiceResult {
+    /// Upgraded or installed
+    Upgraded,
+    /// Already up to date
+    Unchanged,
+    /// Upgrade failed
+    Failed,
+    /// Not attempted because an earlier wave failed
+    Skipped,
+    /// Rolled back after a failure in the product
+    RolledBack,
+    /// Rollback after a failure in the product failed
+    RollbackFailed,
+}
+
+/// Aggregated result of a product apply
+#[derive(Serialize, Clone, Debug)]
+pub struct ProductReport {
+    pub product: String,
+    pub version: String,
+    pub region: String,
+    pub services: BTreeMap<String, ServiceResult>,
+}
+
+impl ProductReport {
+    fn with(&self, res: &ServiceResult) -> Vec<String> {
+        self.services.iter().filter(|(_, r)| *r == res).map(|(s, _)| s.clone()).collect()
+    }
+}
+
+/// Read and verify a product for the location served by a region
+///
+/// Uses the first location of the region that the product is active in.
+pub fn resolve(product: &str, conf: &Config, region: &Region) -> Result<Product> {
+    let basic = Product::basic(product, conf)?;
+    let location = region.locations.iter().find(|l| basic.locations.contains(l))
+        .ok_or_else(|| format!("Product {} is not active in any location served by {}", product, region.name))?;
+    let pdc = Product::completed(product, conf, location)?;
+    pdc.verify(conf)?;
+    Ok(pdc)
+}
+
+/// Services of a product in a region, in waves of dependency order
+///
+/// Ordering follows the full dependency graph of the region,
+/// so indirect dependencies through other services are respected.
+pub fn waves(pdc: &Product, conf: &Config, region: &Region) -> Result<Vec<Vec<String>>> {
+    let svcs : Vec<String> = pdc.services.iter()
+        .filter(|s| s.region == region.name)
+        .map(|s| s.name.clone())
+        .collect();
+    if svcs.is_empty() {
+        bail!("Product {} has no services in {}", pdc.name, region.name);
+    }
+    let available = Manifest::available(&region.name)?;
+    for svc in &svcs {
+        if !available.contains(svc) {
+            bail!("Product {} lists {} which is not deployed to {}", pdc.name, svc, region.name);
+        }
+    }
+    let graph = graph::build(conf, region)?;
+    let res = graph::waves(&graph)?.into_iter()
+        .map(|w| w.into_iter().filter(|s| svcs.contains(s)).collect::<Vec<_>>())
+        .filter(|w| !w.is_empty())
+        .collect();
+    Ok(res)
+}
+
+/// Upgrade all services of a product in a region
+///
+/// Services are upgraded in dependency order, in parallel within each wave.
+/// Later waves are skipped once an upgrade fails, and if `rollback` is set,
+/// every service upgraded by this apply is rolled back to its previous release.
+/// Prints the aggregated result and errors with the failed services, if any.
+pub fn apply(product: &str, conf: &Config, region: &Region, rollback: bool, n_workers: usize) -> Result<ProductReport> {
+    let pdc = resolve(product, conf, region)?;
+    let tag = ProductTag {
+        name: pdc.name.clone(),
+        version: pdc.version.clone().ok_or_else(|| format!("Product {} has no version", pdc.name))?,
+    };
+    let mut waves_mf = vec![];
+    for wave in waves(&pdc, conf, region)? {
+        let mut mfs = vec![];
+        for svc in wave {
+            mfs.push(Manifest::base(&svc, conf, region)?);
+        }
+        waves_mf.push(mfs);
+    }
+    let order : Vec<String> = waves_mf.iter().flatten().map(|mf| mf.name.clone()).collect();
+
+    // only services with a release before the apply can be rolled back
+    let mut existed = BTreeMap::new();
+    for svc in &order {
+        existed.insert(svc.clone(), helm::infer_fallback_version(svc, region).is_ok());
+    }
+    // blue/green services are only rolled back if the apply switched their colour
+    let colours = helm::bluegreen::active_colours(conf, region)?;
+
+    let mut report = ProductReport {
+        product: tag.name.clone(),
+        version: tag.version.clone(),
+        region: region.name.clone(),
+        services: BTreeMap::new(),
+    };
+    webhooks::product_event(UpgradeState::Pending, &tag, region);
+
+    let pool = ThreadPool::new(n_workers);
+    let n_waves = waves_mf.len();
+    let mut failed = false;
+    for (i, wave) in waves_mf.into_iter().enumerate() {
+        if failed {
+            for mf in wave {
+                report.services.insert(mf.name, ServiceResult::Skipped);
+            }
+            continue;
+        }
+        let names = wave.iter().map(|mf| mf.name.clone()).collect::<Vec<_>>();
+        info!("Applying {} wave {}/{}: {}", tag.name, i+1, n_waves, names.join(", "));
+        let umode = UpgradeMode::UpgradeInstallWait;
+        for (svc, res) in helm::parallel::run_wave(&pool, wave, conf, region, &umode, Some(&tag)) {
+            let outcome = match res {
+                Ok(Some(_)) => ServiceResult::Upgraded,
+                Ok(None) => ServiceResult::Unchanged,
+                Err(e) => {
+                    error!("Failed to upgrade {}: {}", svc, e);
+                    failed = true;
+                    ServiceResult::Failed
+                }
+            };
+            report.services.insert(svc, outcome);
+        }
+    }
+
+    let failures = report.with(&ServiceResult::Failed);
+    if failures.is_empty() {
+        webhooks::product_event(UpgradeState::Completed, &tag, region);
+    } else {
+        webhooks::product_event(UpgradeState::Failed, &tag, region);
+        if rollback {
+            webhooks::product_event(UpgradeState::RollingBack, &tag, region);
+            rollback_all(&mut report, &order, &existed, &colours, conf, region, &tag)?;
+            let state = if report.with(&ServiceResult::RollbackFailed).is_empty() {
+                UpgradeState::RolledBack
+            } else {
+                UpgradeState::RollbackFailed
+            };
+            webhooks::product_event(state, &tag, region);
+        }
+    }
+
+    println!("{}", serde_yaml::to_string(&report)?);
+    if !failures.is_empty() {
+        bail!(ErrorKind::ProductUpgradeFailure(tag.name, failures));
+    }
+    Ok(report)
+}
+
+/// Roll back every service touched by a failed product apply
+///
+/// Goes through services in reverse dependency order, so dependents are
+/// rolled back before what they depend on. Services installed by the apply
+/// have no previous release and are left alone. Blue/green services are
+/// switched back only if their active colour changed since `colours`.
+fn rollback_all(report: &mut ProductReport, order: &[String], existed: &BTreeMap<String, bool>, colours: &BTreeMap<String, Colour>, conf: &Config, region: &Region, tag: &ProductTag) -> Result<()> {
+    let current = helm::bluegreen::active_colours(conf, region)?;
+    for svc in order.iter().rev() {
+        match report.services.get(svc) {
+            Some(ServiceResult::Upgraded) | Some(ServiceResult::Failed) => {},
+            _ => continue,
+        }
+        let mf = Manifest::base(svc, conf, region)?;
+        let ud = UpgradeData { product: Some(tag.clone()), ..UpgradeData::from_rollback(&mf) };
+        let res = if mf.blueGreen.is_some() {
+            let before = colours.get(svc).cloned().unwrap_or_default();
+            if current.get(svc).cloned().unwrap_or_default() == before {
+                // a failed blue/green upgrade leaves the active colour alone
+                info!("Not rolling back {} - still running {}", svc, before);
+                continue;
+            }
+            helm::bluegreen::rollback(&mf, &ud, region)
+        } else {
+            if !existed[svc] {
+                warn!("Not rolling back {} - it was installed by this apply", svc);
+                continue;
+            }
+            helm::direct::rollback(region, &ud, &mf)
+        };
+        let outcome = match res {
+            Ok(_) => ServiceResult::RolledBack,
+            Err(e) => {
+                error!("Failed to roll back {}: {}", svc, e);
+                ServiceResult::RollbackFailed
+            }
+        };
+        report.services.insert(svc.clone(), outcome);
+    }
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use shipcat_definitions::SecretBackend;
+
+use super::{Config, Region, Manifest};
+use super::{Result, ResultExt};
+use super::helm::{self, UpgradeMode};
+use super::kube::{self, Client};
+use super::kube::objects::Deployment;
+
+/// Pod template annotation that charts should set to `.Values.secretsChecksum`
+pub const SECRETS_CHECKSUM_ANNOTATION: &str = "shipcat.babylontech.co.uk/secrets-checksum";
+
+/// How the secrets of a running service compare to the secret backend
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum DriftState {
+    /// Pods run with the current secrets
+    InSync,
+    /// Secrets changed since the pods were started
+    Drifted,
+    /// The deployment has no checksum annotation to compare against
+    Untracked,
+    /// No deployment found for the service
+    NotRunning,
+    /// The service could not be checked (see `DriftReport::errors`)
+    Errored,
+}
+
+/// Secret drift of all services in a region
+#[derive(Serialize, Clone, Debug)]
+pub struct DriftReport {
+    pub region: String,
+    pub services: BTreeMap<String, DriftState>,
+    /// Why services could not be checked
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<String, String>,
+}
+
+impl DriftReport {
+    /// Services that need a restart to pick up their secrets
+    pub fn drifted(&self) -> Vec<String> {
+        self.services.iter()
+            .filter(|(_, s)| **s == DriftState::Drifted)
+            .map(|(svc, _)| svc.clone())
+            .collect()
+    }
+}
+
+/// Compare the expected secrets checksum with the one on a running deployment
+pub fn drift_state(expected: Option<&str>, running: Option<&Deployment>) -> DriftState {
+    let d = match running {
+        Some(d) => d,
+        None => return DriftState::NotRunning,
+    };
+    let annotation = d.spec.template.metadata.annotations.get(SECRETS_CHECKSUM_ANNOTATION);
+    match (expected, annotation) {
+        (None, None) => DriftState::InSync,
+        (Some(e), Some(r)) if e == r => DriftState::InSync,
+        (Some(_), None) => DriftState::Untracked,
+        // changed, or all secrets were removed
+        _ => DriftState::Drifted,
+    }
+}
+
+fn service_drift(client: &Client, conf: &Config, region: &Region, svc: &str) -> Result<DriftState> {
+    let mf = Manifest::base(svc, conf, region)?.complete(region)
+        .chain_err(|| format!("Could not resolve secrets for {}", svc))?;
+    let running = kube::get_deployment(client, &mf)?;
+    Ok(drift_state(mf.secretsChecksum.as_deref(), running.as_ref()))
+}
+
+/// Compare current secrets of every service in a region against running deployments
+///
+/// Reads all secrets from the region's secret backend.
+/// Services that cannot be checked are reported as errored rather than failing the report.
+pub fn drift_report(client: &Client, conf: &Config, region: &Region) -> Result<DriftReport> {
+    let mut services = BTreeMap::new();
+    let mut errors = BTreeMap::new();
+    for svc in Manifest::available(&region.name)? {
+        let state = match service_drift(client, conf, region, &svc) {
+            Ok(s) => s,
+            Err(e) => {
+                let msg = e.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(": ");
+                warn!("Could not check secret drift of {}: {}", svc, msg);
+                errors.insert(svc.clone(), msg);
+                DriftState::Errored
+            }
+        };
+        debug!("{} secrets are {:?}", svc, state);
+        services.insert(svc, state);
+    }
+    Ok(DriftReport { region: region.name.clone(), services, errors })
+}
+
+/// List services running with outdated secrets
+///
+/// Prints the report, and if `restart` is set, upgrades the drifted services
+/// so that their pods restart with the current secrets.
+pub fn drift(conf: &Config, region: &Region, restart: bool, n_workers: usize) -> Result<DriftReport> {
+    let client = Client::new()?;
+    let report = drift_report(&client, conf, region)?;
+    println!("{}", serde_yaml::to_string(&report)?);
+
+    let untracked = report.services.values().filter(|s| **s == DriftState::Untracked).count();
+    if untracked > 0 {
+        warn!("{} services have no {} annotation - their charts need to set it", untracked, SECRETS_CHECKSUM_ANNOTATION);
+    }
+    let drifted = report.drifted();
+    if restart && !drifted.is_empty() {
+        info!("Restarting {} services with outdated secrets: {}", drifted.len(), drifted.join(", "));
+        let mut svcs = vec![];
+        for svc in &drifted {
+            svcs.push(Manifest::base(svc, conf, region)?);
+        }
+        helm::parallel::reconcile(svcs, conf, region, UpgradeMode::UpgradeWait, n_workers)?;
+    }
+    if !report.errors.is_empty() {
+        let failed = report.errors.keys().cloned().collect::<Vec<_>>();
+        bail!("Could not check secret drift of {} services: {}", failed.len(), failed.join(", "));
+    }
+    Ok(report)
+}
+
+/// Folders in a region's secret folder that are not tied to a service
+const REGION_FOLDERS: [&str; 2] = ["kong", "shipcat"];
+
+/// Secrets a service reads from its secret folder
+#[derive(Clone, Debug)]
+pub struct SecretUsage {
+    pub service: String,
+    /// Full path of the folder (e.g. `dev-uk/fake-ask`)
+    pub folder: String,
+    /// Referenced secret names
+    pub keys: BTreeSet<String>,
+    /// Whether the service is enabled in the region
+    pub active: bool,
+}
+
+/// Secrets in a region that nothing uses
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SecretAudit {
+    pub region: String,
+    /// Keys that no manifest references, by folder
+    pub unreferenced: BTreeMap<String, Vec<String>>,
+    /// Keys only referenced by services that are disabled or not in the region, by folder
+    pub inactive: BTreeMap<String, Vec<String>>,
+    /// Folders without a service in `services/`
+    pub orphaned: Vec<String>,
+}
+
+impl SecretAudit {
+    pub fn is_clean(&self) -> bool {
+        self.unreferenced.is_empty() && self.inactive.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+/// Secret usage of every service in the manifests, as seen from a region
+///
+/// Services that are not in the region are read without region overrides.
+pub fn secret_usage(conf: &Config, region: &Region) -> Result<Vec<SecretUsage>> {
+    let mut res = vec![];
+    for svc in Manifest::all()? {
+        let blank = Manifest::blank(&svc)?;
+        let deployed = blank.regions.contains(&region.name);
+        let mf = if deployed { Manifest::base(&svc, conf, region)? } else { blank };
+        res.push(SecretUsage {
+            folder: mf.get_vault_path(&region.vault),
+            keys: mf.vault_secret_names(),
+            active: deployed && !mf.disabled && !mf.external,
+            service: svc,
+        });
+    }
+    Ok(res)
+}
+
+/// Cross reference the secret folders of a region with how services use them
+pub fn audit_usage(client: &dyn SecretBackend, region: &Region, usage: &[SecretUsage]) -> Result<SecretAudit> {
+    let root = &region.vault.folder;
+    let mut audit = SecretAudit { region: region.name.clone(), ..SecretAudit::default() };
+    for f in client.folders(root)? {
+        let folder = format!("{}/{}", root, f);
+        let users = usage.iter().filter(|u| u.folder == folder).collect::<Vec<_>>();
+        if users.is_empty() {
+            if !REGION_FOLDERS.contains(&f.as_str()) {
+                audit.orphaned.push(folder);
+            }
+            continue;
+        }
+        let (mut unreferenced, mut inactive) = (vec![], vec![]);
+        for key in client.list(&folder)? {
+            if users.iter().any(|u| u.active && u.keys.contains(&key)) {
+                continue;
+            }
+            if users.iter().any(|u| u.keys.contains(&key)) {
+                inactive.push(key);
+            } else {
+                unreferenced.push(key);
+            }
+        }
+        if !unreferenced.is_empty() {
+            audit.unreferenced.insert(folder.clone(), unreferenced);
+        }
+        if !inactive.is_empty() {
+            audit.inactive.insert(folder, inactive);
+        }
+    }
+    Ok(audit)
+}
+
+/// Report secrets in a region that no enabled service uses
+///
+/// The reverse of `validate::secret_presence`: lists every service folder
+/// in the region's secret backend and prints unused keys and folders.
+pub fn audit(conf: &Config, region: &Region) -> Result<SecretAudit> {
+    let client = region.vault.secret_backend()?;
+    let usage = secret_usage(conf, region)?;
+    let audit = audit_usage(client.as_ref(), region, &usage)?;
+    println!("{}", serde_yaml::to_string(&audit)?);
+    if !audit.is_clean() {
+        warn!("{} has {} unused secret folders and unused keys in {} folders", region.name,
+            audit.orphaned.len(), audit.unreferenced.len() + audit.inactive.len());
+    }
+    Ok(audit)
+}
@@ -24,3 +24,17 @@ pub fn manifest_crd(svc: &str, conf: &Config, reg: &Region) -> Result<()> {
     println!("{}", serde_yaml::to_string(&crd)?);
     Ok(())
 }
+
+use shipcat_definitions::schema;
+/// Print the JSON Schema of a manifest or the config
+///
+/// Editors can use this to validate and complete `shipcat.yml` / `shipcat.conf`.
+pub fn schema(kind: &str) -> Result<()> {
+    let schema = match kind {
+        "manifest" => schema::manifest(),
+        "config" => schema::config(),
+        _ => bail!("No schema for {}", kind),
+    };
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
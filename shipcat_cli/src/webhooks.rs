@@ -5,25 +5,11 @@ use crate::{
     Result
 };
 use crate::helm::{UpgradeData, UpgradeMode};
+use crate::product::ProductTag;
 use super::{Region, Webhook};
 
 /// The different states an upgrade can be in
-#[derive(Serialize, PartialEq, Clone)]
-#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-pub enum UpgradeState {
-    /// Before action
-    Pending,
-    /// No errors
-    Completed,
-    /// Errors
-    Failed,
-    // Before revert
-    RollingBack,
-    // After revert
-    RolledBack,
-    // Fail to revert
-    RollbackFailed,
-}
+pub use shipcat_definitions::UpgradeState;
 
 pub fn ensure_requirements(reg: &Region) -> Result<()> {
     if let Some(whs) = &reg.webhooks {
@@ -53,6 +39,25 @@ pub fn reconcile_event(us: UpgradeState, reg: &Region) {
     }
 }
 
+/// Throw product events to configured webhooks - warning on delivery errors
+///
+/// Http errors are NOT propagated from here
+pub fn product_event(us: UpgradeState, tag: &ProductTag, reg: &Region) {
+    if let Some(whs) = &reg.webhooks {
+        for wh in whs {
+            if let Ok(whc) = wh.get_configuration() {
+                if let Err(e) = match wh {
+                    Webhook::Audit(h) => {
+                        audit::audit_product(&us, &reg.name, tag, &h, whc)
+                    }
+                } {
+                    warn!("Failed to notify about product event: {}", e)
+                }
+            }
+        }
+    }
+}
+
 /// Throw events to configured webhooks - warning on delivery errors
 ///
 /// Http errors are NOT propagated from here
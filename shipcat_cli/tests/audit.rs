@@ -16,6 +16,7 @@ use crate::shipcat::audit;
 use crate::shipcat::{AuditWebhook};
 use crate::shipcat::helm::direct::UpgradeData;
 use crate::shipcat::webhooks;
+use crate::shipcat::product::ProductTag;
 
 #[test]
 fn audit_does_audit_deployment() {
@@ -75,3 +76,23 @@ fn audit_reconciliation_has_type() {
     let ae = audit::AuditEvent::new(&whc, &webhooks::UpgradeState::Completed, arp);
     assert_eq!(ae.domain_type, "reconciliation");
 }
+
+#[test]
+fn audit_deployment_tagged_with_product() {
+    let mut whc: BTreeMap<String, String> = BTreeMap::default();
+    whc.insert("SHIPCAT_AUDIT_REVISION".into(), "egrevision".into());
+
+    let mut ud = UpgradeData{
+        name: "svc".into(),
+        version: "v1".into(),
+        region: "r1".into(),
+        ..Default::default()
+    };
+    let untagged = serde_json::to_value(audit::AuditDeploymentPayload::new(&whc, &ud)).unwrap();
+    assert!(untagged.get("product").is_none());
+
+    ud.product = Some(ProductTag { name: "triage".into(), version: "1.2.4".into() });
+    let tagged = serde_json::to_value(audit::AuditDeploymentPayload::new(&whc, &ud)).unwrap();
+    assert_eq!(tagged["product"], "triage");
+    assert_eq!(tagged["product_version"], "1.2.4");
+}
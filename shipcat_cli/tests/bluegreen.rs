@@ -0,0 +1,93 @@
+#![warn(rust_2018_idioms)]
+
+use mockito;
+use shipcat;
+
+use crate::mockito::{mock, Matcher};
+
+use crate::shipcat::structs::Colour;
+use crate::shipcat::helm::bluegreen;
+use crate::shipcat::kube::Client;
+use crate::shipcat::KongConfig;
+
+#[test]
+fn bluegreen_colours_roundtrip() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let _cm = mock("GET", "/api/v1/namespaces/bg/configmaps/shipcat-colours")
+        .with_status(200)
+        .with_body(r#"{
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {"name": "shipcat-colours", "namespace": "bg", "resourceVersion": "12"},
+            "data": {"fake-ask": "green"}
+        }"#)
+        .create();
+    let colours = bluegreen::read_colours(&client, "bg").unwrap();
+    assert_eq!(colours["fake-ask"], Colour::Green);
+    assert!(colours.get("fake-storage").is_none());
+
+    // other services and the resourceVersion are kept when recording a switch
+    let put = mock("PUT", "/api/v1/namespaces/bg/configmaps/shipcat-colours")
+        .match_body(Matcher::Json(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {"name": "shipcat-colours", "namespace": "bg", "resourceVersion": "12"},
+            "data": {"fake-ask": "green", "fake-storage": "green"}
+        })))
+        .with_status(200)
+        .with_body(r#"{"metadata": {"name": "shipcat-colours"}}"#)
+        .expect(1)
+        .create();
+    bluegreen::write_colour(&client, "bg", "fake-storage", Colour::Green).unwrap();
+    put.assert();
+}
+
+#[test]
+fn bluegreen_colours_created() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let _cm = mock("GET", "/api/v1/namespaces/bgnew/configmaps/shipcat-colours")
+        .with_status(404)
+        .with_body(r#"{"kind": "Status", "status": "Failure", "reason": "NotFound", "message": "not found", "code": 404}"#)
+        .create();
+    assert!(bluegreen::read_colours(&client, "bgnew").unwrap().is_empty());
+
+    let post = mock("POST", "/api/v1/namespaces/bgnew/configmaps")
+        .match_body(Matcher::Json(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {"name": "shipcat-colours", "namespace": "bgnew"},
+            "data": {"fake-ask": "green"}
+        })))
+        .with_status(201)
+        .with_body(r#"{"metadata": {"name": "shipcat-colours"}}"#)
+        .expect(1)
+        .create();
+    bluegreen::write_colour(&client, "bgnew", "fake-ask", Colour::Green).unwrap();
+    post.assert();
+}
+
+#[test]
+fn bluegreen_kong_switch() {
+    let mut kong = KongConfig::default();
+    kong.config_url = mockito::SERVER_URL.into();
+    kong.admin_token = Some("admintoken".into());
+    let patch = mock("PATCH", "/apis/fake-ask")
+        .match_header("kong-admin-token", "admintoken")
+        .match_body(Matcher::Json(serde_json::json!({
+            "upstream_url": "http://fake-ask-green.dev.svc.cluster.local"
+        })))
+        .with_status(200)
+        .expect(1)
+        .create();
+    bluegreen::switch_kong(&kong, "fake-ask", "http://fake-ask-green.dev.svc.cluster.local").unwrap();
+    patch.assert();
+
+    let _missing = mock("PATCH", "/apis/nope")
+        .with_status(404)
+        .create();
+    assert!(bluegreen::switch_kong(&kong, "nope", "http://nope.dev.svc.cluster.local").is_err());
+
+    // secrets have to be filled in before talking to kong
+    kong.admin_token = Some("IN_VAULT".into());
+    assert!(bluegreen::switch_kong(&kong, "fake-ask", "http://fake-ask-green.dev.svc.cluster.local").is_err());
+}
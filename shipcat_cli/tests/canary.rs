@@ -0,0 +1,125 @@
+#![warn(rust_2018_idioms)]
+
+use mockito;
+use shipcat;
+
+use crate::mockito::mock;
+use serde_json::json;
+
+use crate::shipcat::{Manifest, ErrorKind};
+use crate::shipcat::structs::canary::{Canary, CanaryStep, CanaryGate};
+use crate::shipcat::structs::autoscaling::AutoScaling;
+use crate::shipcat::helm::canary;
+use crate::shipcat::kube::Client;
+
+fn mf(name: &str) -> Manifest {
+    Manifest {
+        name: name.into(),
+        namespace: "dev".into(),
+        ..Default::default()
+    }
+}
+
+fn config(max_restarts: u32) -> Canary {
+    Canary {
+        steps: vec![
+            CanaryStep { weight: 25, pause: 0 },
+            CanaryStep { weight: 50, pause: 0 },
+        ],
+        healthGate: CanaryGate { maxRestarts: max_restarts, readyTimeout: Some(0) },
+    }
+}
+
+fn pods(n: u32, restarts: u32) -> String {
+    let items : Vec<_> = (0..n).map(|i| json!({
+        "metadata": { "name": format!("pod-{}", i) },
+        "status": {
+            "phase": "Running",
+            "containerStatuses": [{ "name": "app", "ready": true, "restartCount": restarts }]
+        }
+    })).collect();
+    json!({ "items": items }).to_string()
+}
+
+#[test]
+fn canary_steps_shift_replicas() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let svc = mf("canary-ok");
+    let dep = json!({"apiVersion": "apps/v1", "kind": "Deployment", "metadata": {"name": "canary-ok-canary"}});
+
+    let apply = mock("PATCH", "/apis/apps/v1/namespaces/dev/deployments/canary-ok-canary?fieldManager=shipcat&force=true")
+        .with_status(200)
+        .with_body(r#"{"metadata": {"name": "canary-ok-canary"}}"#)
+        .expect(2)
+        .create();
+    let scale = mock("PUT", "/apis/apps/v1/namespaces/dev/deployments/canary-ok/scale")
+        .with_status(200)
+        .with_body(r#"{"metadata": {"name": "canary-ok"}}"#)
+        .expect(3) // once per step, then restored
+        .create();
+    let _pods = mock("GET", "/api/v1/namespaces/dev/pods?labelSelector=app%3Dcanary-ok%2Ctrack%3Dcanary")
+        .with_status(200)
+        .with_body(pods(2, 0))
+        .create();
+
+    canary::run_steps(&client, &svc, &config(0), &dep, 4).unwrap();
+    apply.assert();
+    scale.assert();
+}
+
+#[test]
+fn canary_leaves_autoscaled_replicas_alone() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let mut svc = mf("canary-hpa");
+    svc.autoScaling = Some(AutoScaling { minReplicas: 2, maxReplicas: 4, metrics: vec![] });
+    let dep = json!({"apiVersion": "apps/v1", "kind": "Deployment", "metadata": {"name": "canary-hpa-canary"}});
+
+    let apply = mock("PATCH", "/apis/apps/v1/namespaces/dev/deployments/canary-hpa-canary?fieldManager=shipcat&force=true")
+        .with_status(200)
+        .with_body(r#"{"metadata": {"name": "canary-hpa-canary"}}"#)
+        .expect(2)
+        .create();
+    let scale = mock("PUT", "/apis/apps/v1/namespaces/dev/deployments/canary-hpa/scale")
+        .with_status(200)
+        .with_body(r#"{"metadata": {"name": "canary-hpa"}}"#)
+        .expect(0) // the autoscaler owns the stable replicas
+        .create();
+    let _pods = mock("GET", "/api/v1/namespaces/dev/pods?labelSelector=app%3Dcanary-hpa%2Ctrack%3Dcanary")
+        .with_status(200)
+        .with_body(pods(1, 0))
+        .create();
+
+    // a single stable replica is fine when the canary runs next to it
+    canary::run_steps(&client, &svc, &config(0), &dep, 1).unwrap();
+    apply.assert();
+    scale.assert();
+}
+
+#[test]
+fn canary_health_gate_fails() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let svc = mf("canary-bad");
+    let dep = json!({"apiVersion": "apps/v1", "kind": "Deployment", "metadata": {"name": "canary-bad-canary"}});
+
+    let _apply = mock("PATCH", "/apis/apps/v1/namespaces/dev/deployments/canary-bad-canary?fieldManager=shipcat&force=true")
+        .with_status(200)
+        .with_body(r#"{"metadata": {"name": "canary-bad-canary"}}"#)
+        .create();
+    let _scale = mock("PUT", "/apis/apps/v1/namespaces/dev/deployments/canary-bad/scale")
+        .with_status(200)
+        .with_body(r#"{"metadata": {"name": "canary-bad"}}"#)
+        .create();
+    let _pods = mock("GET", "/api/v1/namespaces/dev/pods?labelSelector=app%3Dcanary-bad%2Ctrack%3Dcanary")
+        .with_status(200)
+        .with_body(pods(1, 3))
+        .create();
+
+    let err = canary::run_steps(&client, &svc, &config(1), &dep, 4).unwrap_err();
+    match err.kind() {
+        ErrorKind::RolloutFailure(svc, reason, _) => {
+            assert_eq!(svc, "canary-bad");
+            assert_eq!(reason, "CanaryRestarts");
+        },
+        e => panic!("unexpected error {}", e),
+    }
+}
@@ -0,0 +1,26 @@
+mod common;
+use crate::common::setup;
+use std::fs;
+use shipcat_definitions::{Manifest, Config, ConfigType};
+use shipcat::chart;
+
+fn render_matches(svc: &str) {
+    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let mf = Manifest::base(svc, &conf, &reg).unwrap().stub(&reg).unwrap();
+    let tpl = chart::render(&mf, &reg).unwrap();
+    // output of `helm template charts/base -f values.yml` with helm 2
+    let expected = fs::read_to_string(format!("rendered/{}.yml", svc)).unwrap();
+    assert_eq!(tpl, expected);
+}
+
+#[test]
+fn chart_render_fake_ask() {
+    setup();
+    render_matches("fake-ask");
+}
+
+#[test]
+fn chart_render_fake_storage() {
+    setup();
+    render_matches("fake-storage");
+}
@@ -39,8 +39,23 @@ pub fn setup() {
     });
 }
 
-use shipcat_definitions::{Config, Manifest}; // Product
+use shipcat_definitions::{Config, Manifest, Product, Region, MockVault};
 use shipcat_definitions::ConfigType;
+use shipcat_definitions::vault::KvVersion;
+
+/// Point a region at a mock vault seeded from `secrets/{region}.yml`
+///
+/// Keep the returned server alive for as long as secrets are read.
+#[allow(dead_code)] // not every test binary reads secrets
+pub fn mock_vault(reg: &mut Region) -> MockVault {
+    if env::var("VAULT_TOKEN").is_err() {
+        env::set_var("VAULT_TOKEN", "mock-vault-token");
+    }
+    let pth = Path::new("secrets").join(format!("{}.yml", reg.name));
+    let vault = MockVault::from_file(&pth, KvVersion::V1).unwrap();
+    reg.vault.url = vault.url();
+    vault
+}
 
 #[test]
 fn config_test() {
@@ -66,7 +81,8 @@ fn config_cr_settings_test() {
 #[test]
 fn config_defaults_test() {
     setup();
-    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let _vault = mock_vault(&mut reg);
 
     // -- Slack channels --
 
@@ -83,7 +99,7 @@ fn config_defaults_test() {
     assert_eq!(*metadata.notifications.unwrap(), "#dev-platform-notif-override");
 }
 
-/*#[test]
+#[test]
 fn product_test() {
     setup();
     let conf = Config::read().unwrap();
@@ -91,7 +107,6 @@ fn product_test() {
     let res = p.verify(&conf);
     assert!(res.is_ok(), "verified product");
 }
-*/
 
 use shipcat::get;
 #[test]
@@ -142,7 +157,8 @@ fn get_codeowners() {
 #[test]
 fn manifest_test() {
     setup();
-    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let _vault = mock_vault(&mut reg);
     let mfread = Manifest::base("fake-storage", &conf, &reg);
     assert!(mfread.is_ok());
     let mfbase = mfread.unwrap();
@@ -163,7 +179,8 @@ fn manifest_test() {
 #[test]
 fn templating_test() {
     setup();
-    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let _vault = mock_vault(&mut reg);
     let mf = Manifest::base("fake-ask", &conf, &reg).unwrap().complete(&reg).unwrap();
 
     // verify templating
@@ -202,8 +219,8 @@ fn templating_test() {
     // verify secrets
     let sec = mf.secrets;
     assert_eq!(sec["CLIENT_SECRET"], "FAKEASKSECRET".to_string()); // via reg.kong consumers
-    assert_eq!(sec["FAKE_SECRET"], "hello".to_string()); // NB: ACTUALLY IN_VAULT
-    assert_eq!(sec["FAKE_NUMBER"], "-2".to_string()); // NB: ACTUALLY IN_VAULT
+    assert_eq!(sec["FAKE_SECRET"], "hello".to_string()); // NB: IN_VAULT (mocked)
+    assert_eq!(sec["FAKE_NUMBER"], "-2".to_string()); // NB: IN_VAULT (mocked)
 
     let configs = mf.configs.clone().unwrap();
     let configini = configs.files[0].clone();
@@ -0,0 +1,41 @@
+mod common;
+use crate::common::setup;
+use serde_json::json;
+use shipcat_definitions::{Manifest, Config, ConfigType};
+use shipcat::diff::{self, Change};
+
+#[test]
+fn diff_manifest_overrides() {
+    setup();
+    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let mf = Manifest::base("fake-ask", &conf, &reg).unwrap().stub(&reg).unwrap();
+    assert!(diff::manifests(&mf, &mf).unwrap().is_empty());
+
+    let mut changed = mf.clone();
+    changed.replicaCount = Some(3);
+    changed.env.plain.insert("NEW_VAR".into(), "yes".into());
+    changed.env.plain.remove("JAVA_OPTS");
+    for v in changed.secrets.values_mut() {
+        *v = "rotated".into();
+    }
+    let res = diff::manifests(&mf, &changed).unwrap();
+    assert!(res.contains(&Change::Changed { path: "replicaCount".into(), from: json!(2), to: json!(3) }));
+    assert!(res.contains(&Change::Added { path: "env.plain.NEW_VAR".into(), value: json!("yes") }));
+    assert!(res.contains(&Change::Removed {
+        path: "env.plain.JAVA_OPTS".into(),
+        value: json!("-Xms256m -Xmx2048m")
+    }));
+    // secret values are masked and never show up as changes
+    assert_eq!(res.len(), 3);
+}
+
+#[test]
+fn diff_named_lists() {
+    setup();
+    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let mf = Manifest::base("fake-ask", &conf, &reg).unwrap().stub(&reg).unwrap();
+    let mut changed = mf.clone();
+    changed.workers[0].replicaCount = 3;
+    let res = diff::manifests(&mf, &changed).unwrap();
+    assert_eq!(res, vec![Change::Changed { path: "workers[worker].replicaCount".into(), from: json!(2), to: json!(3) }]);
+}
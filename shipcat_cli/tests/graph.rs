@@ -1,7 +1,9 @@
 mod common;
 use crate::common::setup;
 use shipcat_definitions::{Config, ConfigType};
-use shipcat::graph::{generate, nodeidx_from_name};
+use shipcat::graph::{generate, nodeidx_from_name, build, waves, CatGraph, ManifestNode, DepEdge};
+use shipcat::ErrorKind;
+use shipcat_definitions::structs::DependencyProtocol;
 
 #[test]
 fn graph_generate() {
@@ -20,3 +22,33 @@ fn graph_generate() {
     println!("edge: {:?}", edge);
     assert_eq!(edge.intent, Some("testing graph module".into()));
 }
+
+
+#[test]
+fn graph_waves() {
+    setup();
+    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let graph = build(&conf, &reg).unwrap();
+    assert_eq!(graph.node_count(), 2); // no duplicate node for fake-storage
+    let order = waves(&graph).unwrap();
+    assert_eq!(order, vec![vec!["fake-storage".to_string()], vec!["fake-ask".to_string()]]);
+}
+
+#[test]
+fn graph_waves_cycle() {
+    let mut graph = CatGraph::new();
+    let edge = || DepEdge { api: "x".into(), contract: None, protocol: DependencyProtocol::Http, intent: None };
+    let a = graph.add_node(ManifestNode { name: "a".into() });
+    let b = graph.add_node(ManifestNode { name: "b".into() });
+    let c = graph.add_node(ManifestNode { name: "c".into() });
+    let d = graph.add_node(ManifestNode { name: "d".into() });
+    graph.add_edge(a, b, edge());
+    graph.add_edge(b, c, edge());
+    graph.add_edge(c, b, edge());
+    graph.add_edge(b, d, edge());
+
+    match waves(&graph).unwrap_err().kind() {
+        ErrorKind::DependencyCycle(svcs) => assert_eq!(svcs, &vec!["b".to_string(), "c".to_string()]),
+        e => panic!("unexpected error {}", e),
+    }
+}
@@ -1,7 +1,9 @@
 mod common;
 use crate::common::setup;
 
+use std::collections::BTreeMap;
 use shipcat::kong::{KongfigOutput, generate_kong_output};
+use shipcat_definitions::structs::Colour;
 use shipcat_definitions::structs::kongfig::{ConsumerCredentials, PluginBase, ApiPlugin};
 use shipcat_definitions::Config;
 use shipcat_definitions::ConfigType;
@@ -30,7 +32,7 @@ macro_rules! assert_plugin_removed {
 fn kong_test() {
     setup();
     let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
-    let kongrs = generate_kong_output(&conf, &reg).unwrap();
+    let kongrs = generate_kong_output(&conf, &reg, &BTreeMap::new()).unwrap();
     let output = KongfigOutput::new(kongrs);
 
     assert_eq!(output.host, "admin.dev.something.domain.com");
@@ -84,3 +86,17 @@ fn kong_test() {
 
     assert_plugin_removed!("Jwt", &api.plugins[3], ApiPlugin::Jwt);
 }
+
+#[test]
+fn kong_bluegreen_upstream() {
+    setup();
+    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let mut colours = BTreeMap::new();
+    colours.insert("fake-ask".to_string(), Colour::Green);
+    let output = KongfigOutput::new(generate_kong_output(&conf, &reg, &colours).unwrap());
+    assert_eq!(output.apis[0].attributes.upstream_url, "http://fake-ask-green.dev.svc.cluster.local");
+
+    colours.insert("fake-ask".to_string(), Colour::Blue);
+    let output = KongfigOutput::new(generate_kong_output(&conf, &reg, &colours).unwrap());
+    assert_eq!(output.apis[0].attributes.upstream_url, "http://fake-ask.dev.svc.cluster.local");
+}
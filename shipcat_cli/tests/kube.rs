@@ -0,0 +1,290 @@
+#![warn(rust_2018_idioms)]
+
+use mockito;
+use shipcat;
+
+use crate::mockito::mock;
+
+use crate::shipcat::{Manifest, ErrorKind};
+use crate::shipcat::kube::{self, Client};
+
+use std::time::Duration;
+
+fn mf(name: &str) -> Manifest {
+    Manifest {
+        name: name.into(),
+        namespace: "dev".into(),
+        ..Default::default()
+    }
+}
+
+fn deployment(generation: i64, observed: i64, updated: u32, total: u32, available: u32) -> String {
+    format!(r#"{{
+        "metadata": {{ "name": "svc", "generation": {} }},
+        "spec": {{ "replicas": 2 }},
+        "status": {{
+            "observedGeneration": {},
+            "updatedReplicas": {},
+            "replicas": {},
+            "availableReplicas": {},
+            "conditions": [{{ "type": "Progressing", "status": "True", "reason": "NewReplicaSetAvailable" }}]
+        }}
+    }}"#, generation, observed, updated, total, available)
+}
+
+#[test]
+fn rollout_status_complete() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let _m = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/rollout-done")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(deployment(3, 3, 2, 2, 2))
+        .create();
+    assert!(kube::rollout_status(&client, &mf("rollout-done")).unwrap());
+}
+
+#[test]
+fn rollout_status_incomplete() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    // old replicas still terminating
+    let _m1 = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/rollout-old")
+        .with_status(200)
+        .with_body(deployment(3, 3, 2, 3, 2))
+        .create();
+    assert!(!kube::rollout_status(&client, &mf("rollout-old")).unwrap());
+
+    // new generation not yet observed
+    let _m2 = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/rollout-gen")
+        .with_status(200)
+        .with_body(deployment(4, 3, 2, 2, 2))
+        .create();
+    assert!(!kube::rollout_status(&client, &mf("rollout-gen")).unwrap());
+}
+
+#[test]
+fn rollout_status_deadline_exceeded() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let _m = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/rollout-stuck")
+        .with_status(200)
+        .with_body(r#"{
+            "metadata": { "name": "rollout-stuck", "generation": 2 },
+            "status": {
+                "observedGeneration": 2,
+                "conditions": [{ "type": "Progressing", "status": "False", "reason": "ProgressDeadlineExceeded" }]
+            }
+        }"#)
+        .create();
+    assert!(kube::rollout_status(&client, &mf("rollout-stuck")).is_err());
+}
+
+#[test]
+fn api_errors_are_structured() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let _m = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/missing")
+        .with_status(404)
+        .with_body(r#"{
+            "kind": "Status",
+            "status": "Failure",
+            "message": "deployments.apps \"missing\" not found",
+            "reason": "NotFound",
+            "code": 404
+        }"#)
+        .create();
+    let err = kube::rollout_status(&client, &mf("missing")).unwrap_err();
+    match err.kind() {
+        ErrorKind::KubeApiFailure(code, reason, msg) => {
+            assert_eq!(*code, 404);
+            assert_eq!(reason, "NotFound");
+            assert!(msg.contains("not found"));
+        }
+        k => panic!("unexpected error kind {:?}", k),
+    }
+}
+
+#[test]
+fn pods_and_replicasets() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let _m1 = mock("GET", "/api/v1/namespaces/dev/pods?labelSelector=app%3Dpodsvc")
+        .with_status(200)
+        .with_body(r#"{
+            "metadata": { "resourceVersion": "10" },
+            "items": [
+                { "metadata": { "name": "podsvc-1" }, "status": { "phase": "Running",
+                  "containerStatuses": [{ "name": "podsvc", "ready": true, "restartCount": 0 }] } },
+                { "metadata": { "name": "podsvc-2" }, "status": { "phase": "Running",
+                  "containerStatuses": [{ "name": "podsvc", "ready": false, "restartCount": 4,
+                    "state": { "waiting": { "reason": "CrashLoopBackOff" } } }] } }
+            ]
+        }"#)
+        .create();
+    let pods = kube::get_pods(&client, &mf("podsvc")).unwrap();
+    assert_eq!(pods.len(), 2);
+    assert!(pods[0].is_healthy());
+    assert!(!pods[1].is_healthy());
+    assert_eq!(pods[1].restarts(), 4);
+
+    let _m2 = mock("GET", "/apis/apps/v1/namespaces/dev/replicasets?labelSelector=app%3Dpodsvc")
+        .with_status(200)
+        .with_body(r#"{
+            "items": [
+                { "metadata": { "name": "podsvc-new", "creationTimestamp": "2018-11-01T12:00:00Z",
+                    "ownerReferences": [{ "kind": "Deployment", "name": "podsvc" }] },
+                  "status": { "replicas": 1, "availableReplicas": 0 } },
+                { "metadata": { "name": "podsvc-old", "creationTimestamp": "2018-10-01T12:00:00Z",
+                    "ownerReferences": [{ "kind": "Deployment", "name": "podsvc" }] },
+                  "status": { "replicas": 0 } },
+                { "metadata": { "name": "podsvc-worker-abc",
+                    "ownerReferences": [{ "kind": "Deployment", "name": "podsvc-worker" }] },
+                  "status": { "replicas": 1 } }
+            ]
+        }"#)
+        .create();
+    let sets = kube::find_active_replicasets(&client, &mf("podsvc")).unwrap();
+    assert_eq!(sets.len(), 1);
+    assert_eq!(sets[0].name, "podsvc-new");
+    assert_eq!(sets[0].total, 1);
+}
+
+#[test]
+fn track_rollout_completes() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let _m = mock("GET", "/apis/apps/v1/namespaces/dev/deployments?fieldSelector=metadata.name%3Dwatch-ok&watch=true&timeoutSeconds=25")
+        .with_status(200)
+        .with_body(format!("{{\"type\": \"ADDED\", \"object\": {}}}\n", deployment(2, 2, 2, 2, 2).replace("\n", "")))
+        .create();
+    let res = kube::track_rollout(&client, &mf("watch-ok"), Duration::from_secs(10));
+    assert!(res.unwrap());
+}
+
+#[test]
+fn track_rollout_fails_early() {
+    let client = Client::from_url(mockito::SERVER_URL);
+    let rev = r#""annotations": { "deployment.kubernetes.io/revision": "2" }"#;
+    let _m1 = mock("GET", "/apis/apps/v1/namespaces/dev/deployments?fieldSelector=metadata.name%3Dwatch-crash&watch=true&timeoutSeconds=25")
+        .with_status(200)
+        .with_body(format!(r#"{{"type": "MODIFIED", "object": {{ "metadata": {{ "name": "watch-crash", "generation": 2, {} }}, "status": {{ "observedGeneration": 2, "replicas": 2, "updatedReplicas": 1 }} }} }}
+"#, rev))
+        .create();
+    let _m2 = mock("GET", "/apis/apps/v1/namespaces/dev/replicasets?labelSelector=app%3Dwatch-crash&watch=true&timeoutSeconds=25")
+        .with_status(200)
+        .with_body(format!(r#"{{"type": "ADDED", "object": {{ "metadata": {{ "name": "watch-crash-2", {}, "ownerReferences": [{{ "kind": "Deployment", "name": "watch-crash" }}] }} }} }}
+"#, rev))
+        .create();
+    let _m3 = mock("GET", "/api/v1/namespaces/dev/pods?labelSelector=app%3Dwatch-crash&watch=true&timeoutSeconds=25")
+        .with_status(200)
+        .with_body(r#"{"type": "ADDED", "object": { "metadata": { "name": "watch-crash-2-abc", "ownerReferences": [{ "kind": "ReplicaSet", "name": "watch-crash-2" }] }, "status": { "phase": "Running", "containerStatuses": [{ "name": "watch-crash", "ready": false, "state": { "waiting": { "reason": "CrashLoopBackOff", "message": "back-off restarting" } } }] } } }
+"#)
+        .create();
+    let err = kube::track_rollout(&client, &mf("watch-crash"), Duration::from_secs(10)).unwrap_err();
+    match err.kind() {
+        ErrorKind::RolloutFailure(svc, reason, _) => {
+            assert_eq!(svc, "watch-crash");
+            assert_eq!(reason, "CrashLoopBackOff");
+        }
+        k => panic!("unexpected error kind {:?}", k),
+    }
+}
+
+#[test]
+fn migrate_manifest_crds() {
+    use crate::mockito::Matcher;
+    let client = Client::from_url(mockito::SERVER_URL);
+    // written before spec versions were recorded
+    let _list = mock("GET", "/apis/babylontech.co.uk/v1/namespaces/dev/shipcatmanifests")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{
+            "apiVersion": "babylontech.co.uk/v1",
+            "kind": "ShipcatManifestList",
+            "metadata": { "resourceVersion": "12" },
+            "items": [{
+                "apiVersion": "babylontech.co.uk/v1",
+                "kind": "ShipcatManifest",
+                "metadata": {
+                    "name": "fake-ask",
+                    "resourceVersion": "11",
+                    "labels": { "team": "devops" },
+                    "finalizers": ["shipcat"],
+                    "ownerReferences": [{ "kind": "ShipcatConfig", "name": "dev-uk", "uid": "c0ff33" }]
+                },
+                "spec": { "name": "fake-ask", "image": "quay.io/babylonhealth/fake-ask" }
+            }]
+        }"#)
+        .create();
+    let put = mock("PUT", "/apis/babylontech.co.uk/v1/namespaces/dev/shipcatmanifests/fake-ask")
+        .match_body(Matcher::Regex(concat!(
+            r#""annotations":\{"shipcat.babylontech.co.uk/spec-version":"v1"\},"finalizers":\["shipcat"\],"#,
+            r#""labels":\{"team":"devops"\},"name":"fake-ask","ownerReferences":\[.*\],"resourceVersion":"11""#
+        ).into()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{ "metadata": { "name": "fake-ask" } }"#)
+        .expect(1)
+        .create();
+    let _crd = mock("GET", "/apis/apiextensions.k8s.io/v1beta1/customresourcedefinitions/shipcatmanifests.babylontech.co.uk")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{ "metadata": { "name": "shipcatmanifests.babylontech.co.uk" }, "status": { "storedVersions": ["v1"] } }"#)
+        .create();
+
+    // v1 is the only version so far, so nothing needs converting
+    let converted = kube::migrate_manifest_crds(&client, "dev").unwrap();
+    assert!(converted.is_empty());
+    put.assert();
+}
+
+#[test]
+fn update_manifest_status() {
+    use crate::mockito::Matcher;
+    use shipcat_definitions::ManifestStatus;
+    let client = Client::from_url(mockito::SERVER_URL);
+    let patch = mock("PATCH", "/apis/babylontech.co.uk/v1/namespaces/dev/shipcatmanifests/fake-ask/status?fieldManager=shipcat&force=true")
+        .match_header("content-type", "application/apply-patch+yaml")
+        .match_body(Matcher::Regex(r#""status":\{[^}]*"observedGeneration":3"#.into()))
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{ "metadata": { "name": "fake-ask" } }"#)
+        .expect(1)
+        .create();
+    let status = ManifestStatus {
+        observedGeneration: Some(3),
+        appliedVersion: Some("1.2.3".into()),
+        ..ManifestStatus::default()
+    };
+    kube::update_manifest_status(&client, "dev", "fake-ask", &status).unwrap();
+    patch.assert();
+}
+
+#[test]
+fn get_manifest_status() {
+    use shipcat_definitions::UpgradeState;
+    let client = Client::from_url(mockito::SERVER_URL);
+    let _crd = mock("GET", "/apis/babylontech.co.uk/v1/namespaces/dev/shipcatmanifests/fake-storage")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{
+            "apiVersion": "babylontech.co.uk/v1",
+            "kind": "ShipcatManifest",
+            "metadata": { "name": "fake-storage", "generation": 2 },
+            "spec": { "name": "fake-storage" },
+            "status": {
+                "appliedVersion": "1.0.0",
+                "state": "FAILED",
+                "history": [
+                    { "version": "1.0.0", "state": "COMPLETED", "time": "2019-01-01T00:00:00+00:00" },
+                    { "version": "1.1.0", "state": "FAILED", "time": "2019-01-02T00:00:00+00:00" }
+                ]
+            }
+        }"#)
+        .create();
+    let _missing = mock("GET", "/apis/babylontech.co.uk/v1/namespaces/dev/shipcatmanifests/fake-missing")
+        .with_status(404)
+        .with_body(r#"{ "status": "Failure", "reason": "NotFound", "code": 404 }"#)
+        .create();
+
+    let status = kube::get_manifest_status(&client, "dev", "fake-storage").unwrap().unwrap();
+    assert_eq!(status.appliedVersion, Some("1.0.0".into()));
+    assert_eq!(status.state, Some(UpgradeState::Failed));
+    assert_eq!(status.history.len(), 2);
+    assert!(kube::get_manifest_status(&client, "dev", "fake-missing").unwrap().is_none());
+}
@@ -0,0 +1,133 @@
+mod common;
+use crate::common::setup;
+
+use mockito::mock;
+use shipcat_definitions::{Manifest, Config, ConfigType};
+use shipcat::kube::Client;
+use shipcat::helm::kubediff;
+
+fn metadata(name: &str, extra: &str) -> String {
+    format!(r#"{{
+        "name": "{}",
+        "namespace": "dev",
+        "uid": "2b5ef1c9",
+        "resourceVersion": "4242",
+        "creationTimestamp": "2019-03-01T10:00:00Z",
+        "labels": {{
+            "app": "fake-ask",
+            "chart": "base-0.1.0",
+            "heritage": "Tiller"{}
+        }}
+    }}"#, name, extra)
+}
+
+fn live_service(name: &str) -> String {
+    format!(r#"{{
+        "apiVersion": "v1",
+        "kind": "Service",
+        "metadata": {},
+        "spec": {{
+            "type": "ClusterIP",
+            "clusterIP": "100.64.12.3",
+            "sessionAffinity": "None",
+            "ports": [{{ "port": 80, "targetPort": 8080, "protocol": "TCP", "name": "http" }}],
+            "selector": {{ "app": "fake-ask" }}
+        }},
+        "status": {{ "loadBalancer": {{}} }}
+    }}"#, metadata(name, r#", "shipcat.babylontech.co.uk/service": "fake-ask""#))
+}
+
+fn live_deployment(version: &str) -> String {
+    format!(r#"{{
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": {},
+        "spec": {{
+            "replicas": 2,
+            "revisionHistoryLimit": 10,
+            "selector": {{ "matchLabels": {{ "app": "fake-ask" }} }},
+            "template": {{
+                "metadata": {{ "labels": {{ "app": "fake-ask" }} }},
+                "spec": {{
+                    "restartPolicy": "Always",
+                    "nodeSelector": {{ "pool": "legacy" }},
+                    "containers": [{{
+                        "name": "fake-ask",
+                        "image": "quay.io/babylonhealth/fake-ask:{}",
+                        "imagePullPolicy": "IfNotPresent",
+                        "ports": [{{ "name": "http", "containerPort": 8080, "protocol": "TCP" }}],
+                        "readinessProbe": {{
+                            "httpGet": {{ "path": "/health", "port": "http", "scheme": "HTTP" }},
+                            "initialDelaySeconds": 30,
+                            "periodSeconds": 10
+                        }},
+                        "resources": {{
+                            "limits": {{ "cpu": "2", "memory": "2Gi" }},
+                            "requests": {{ "cpu": "250m", "memory": "1Gi" }}
+                        }}
+                    }}]
+                }}
+            }}
+        }},
+        "status": {{ "replicas": 2, "availableReplicas": 2 }}
+    }}"#, metadata("fake-ask", r#", "custom-metrics": "true""#), version)
+}
+
+#[test]
+fn kubediff_against_live_objects() {
+    setup();
+    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let mf = Manifest::base("fake-ask", &conf, &reg).unwrap().stub(&reg).unwrap();
+    let client = Client::from_url(mockito::SERVER_URL);
+
+    let _core = mock("GET", "/api/v1")
+        .with_status(200)
+        .with_body(r#"{"resources": [{"name": "services", "kind": "Service", "namespaced": true}]}"#)
+        .create();
+    let _apps = mock("GET", "/apis/apps/v1")
+        .with_status(200)
+        .with_body(r#"{"resources": [
+            {"name": "deployments", "kind": "Deployment", "namespaced": true},
+            {"name": "deployments/status", "kind": "Deployment", "namespaced": true}
+        ]}"#)
+        .create();
+    let _svc = mock("GET", "/api/v1/namespaces/dev/services/fake-ask")
+        .with_status(200)
+        .with_body(live_service("fake-ask"))
+        .create();
+    let _deploy = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/fake-ask")
+        .with_status(200)
+        .with_body(live_deployment("1.5.0"))
+        .create();
+    // a service left over from an older version of the chart
+    let _svcs = mock("GET", "/api/v1/namespaces/dev/services?labelSelector=shipcat.babylontech.co.uk%2Fservice%3Dfake-ask")
+        .with_status(200)
+        .with_body(format!(r#"{{"items": [{}, {}]}}"#, live_service("fake-ask"), live_service("fake-ask-old")))
+        .create();
+    let _deploys = mock("GET", "/apis/apps/v1/namespaces/dev/deployments?labelSelector=shipcat.babylontech.co.uk%2Fservice%3Dfake-ask")
+        .with_status(200)
+        .with_body(format!(r#"{{"items": [{}]}}"#, live_deployment("1.5.0")))
+        .create();
+
+    let diff = kubediff::diff_objects(&client, &mf, &reg).unwrap();
+    println!("{}", diff);
+    // server populated fields and defaults are ignored
+    assert!(!diff.contains("dev, fake-ask, Service"));
+    assert!(!diff.contains("status"));
+    assert!(!diff.contains("imagePullPolicy"));
+
+    // the image changed in the deployment
+    assert!(diff.contains("dev, fake-ask, Deployment has changed:"));
+    let changes : Vec<&str> = diff.lines()
+        .filter(|l| l.starts_with('-') || l.starts_with('+'))
+        .filter(|l| !l.starts_with("---"))
+        .collect();
+    assert!(changes.iter().any(|l| l.starts_with('-') && l.contains("fake-ask:1.5.0")));
+    assert!(changes.iter().any(|l| l.starts_with('+') && l.contains("fake-ask:1.6.0")));
+    // fields dropped from the chart are removals, not defaults
+    assert!(changes.iter().any(|l| l.starts_with('-') && l.contains("nodeSelector")));
+
+    // objects no longer rendered show up as removed
+    assert!(diff.contains("dev, fake-ask-old, Service has been removed:"));
+    assert!(changes.iter().any(|l| l.starts_with('-') && l.contains("name: fake-ask-old")));
+}
@@ -0,0 +1,28 @@
+mod common;
+use crate::common::setup;
+use shipcat_definitions::{Config, ConfigType};
+use shipcat::product::{resolve, waves};
+
+#[test]
+fn product_resolves_for_region() {
+    setup();
+    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let pdc = resolve("triage", &conf, &reg).unwrap();
+    assert_eq!(pdc.location, "uk"); // first location of dev-uk the product is in
+    assert_eq!(pdc.version, Some("1.2.4".into())); // from the uk override
+
+    // fake-ask depends on fake-storage
+    let order = waves(&pdc, &conf, &reg).unwrap();
+    assert_eq!(order, vec![vec!["fake-storage".to_string()], vec!["fake-ask".to_string()]]);
+}
+
+#[test]
+fn product_without_region_services() {
+    setup();
+    let (conf, reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let mut pdc = resolve("triage", &conf, &reg).unwrap();
+    for s in &mut pdc.services {
+        s.region = "dev-global".into();
+    }
+    assert!(waves(&pdc, &conf, &reg).is_err());
+}
@@ -0,0 +1,239 @@
+mod common;
+use crate::common::{setup, mock_vault};
+use mockito::mock;
+use shipcat_definitions::{Config, ConfigType, Manifest, SecretSource, SecretVersion, Vault};
+use shipcat_definitions::secrets::FileEncryption;
+use shipcat_definitions::vault::KvVersion;
+use shipcat::kube::Client;
+use shipcat::secret::{self, DriftState, SecretUsage, SECRETS_CHECKSUM_ANNOTATION};
+
+fn kv2_secret(value: &str, version: u32) -> String {
+    format!(r#"{{"data": {{"data": {{"value": "{}"}}, "metadata": {{"version": {}}}}}}}"#, value, version)
+}
+
+#[test]
+fn file_backend_completes_manifest() {
+    setup();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    reg.vault.backend = SecretSource::File {
+        path: "secrets/dev-uk.yml".into(),
+        encryption: FileEncryption::None,
+    };
+    let base = Manifest::base("fake-ask", &conf, &reg).unwrap();
+    base.verify_secrets_exist(&reg.vault).unwrap();
+    let mf = base.complete(&reg).unwrap();
+    assert_eq!(mf.secrets["FAKE_SECRET"], "hello");
+    assert_eq!(mf.secrets["FAKE_NUMBER"], "-2");
+}
+
+#[test]
+fn file_backend_is_loaded_once() {
+    setup();
+    let (_conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let pth = dir.path().join("secrets.yml");
+    std::fs::copy("secrets/dev-uk.yml", &pth).unwrap();
+    reg.vault.backend = SecretSource::File {
+        path: pth.to_string_lossy().into(),
+        encryption: FileEncryption::None,
+    };
+    assert_eq!(reg.vault.secret_backend().unwrap().read("dev-uk/test-shipcat/FAKE_SECRET").unwrap(), "hello");
+    // clones of the region (as in parallel upgrades) reuse the loaded file
+    std::fs::remove_file(&pth).unwrap();
+    let client = reg.clone().vault.secret_backend().unwrap();
+    assert_eq!(client.read("dev-uk/test-shipcat/FAKE_SECRET").unwrap(), "hello");
+}
+
+#[test]
+fn mock_vault_completes_manifest() {
+    setup();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    let _vault = mock_vault(&mut reg);
+    let base = Manifest::base("fake-ask", &conf, &reg).unwrap();
+    base.verify_secrets_exist(&reg.vault).unwrap();
+    let mf = base.clone().complete(&reg).unwrap();
+    assert_eq!(mf.secrets["FAKE_SECRET"], "hello");
+    assert_eq!(mf.secrets["FAKE_NUMBER"], "-2");
+    assert!(mf.secretVersions.is_empty()); // kv v1
+
+    // missing secrets are caught by both verify and complete
+    let mut broken = base;
+    broken.env.plain.insert("NOT_IN_VAULT".into(), "IN_VAULT".into());
+    assert!(broken.verify_secrets_exist(&reg.vault).is_err());
+    assert!(broken.complete(&reg).is_err());
+}
+
+#[test]
+fn env_backend_completes_manifest() {
+    setup();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    reg.vault.backend = SecretSource::Env { prefix: "SHIPCAT_ENVTEST_".into() };
+    let base = Manifest::base("fake-ask", &conf, &reg).unwrap();
+    assert!(base.clone().complete(&reg).is_err()); // nothing set yet
+
+    std::env::set_var("SHIPCAT_ENVTEST_DEV_UK_TEST_SHIPCAT_FAKE_SECRET", "hello");
+    std::env::set_var("SHIPCAT_ENVTEST_DEV_UK_TEST_SHIPCAT_FAKE_NUMBER", "-2");
+    let mf = base.complete(&reg).unwrap();
+    assert_eq!(mf.secrets["FAKE_SECRET"], "hello");
+}
+
+#[test]
+fn stubs_need_no_secrets() {
+    setup();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    reg.vault.backend = SecretSource::File {
+        path: "secrets/does-not-exist.yml".into(),
+        encryption: FileEncryption::None,
+    };
+    let mf = Manifest::base("fake-ask", &conf, &reg).unwrap().stub(&reg).unwrap();
+    assert_eq!(mf.secrets["FAKE_SECRET"], "aGVsbG8gd29ybGQ=");
+}
+
+#[test]
+fn vault_kv2_records_versions() {
+    setup();
+    std::env::set_var("VAULT_TOKEN", "kv2-test");
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    reg.vault.url = mockito::SERVER_URL.into();
+    reg.vault.kvVersion = KvVersion::V2;
+
+    let _secret = mock("GET", "/v1/secret/data/dev-uk/test-shipcat/FAKE_SECRET")
+        .match_header("X-Vault-Token", "kv2-test")
+        .with_status(200)
+        .with_body(kv2_secret("hello", 4))
+        .create();
+    let _number = mock("GET", "/v1/secret/data/dev-uk/test-shipcat/FAKE_NUMBER")
+        .with_status(200)
+        .with_body(r#"{"data": {"data": {"value": -2}, "metadata": {"version": 1}}}"#)
+        .create();
+    let _list = mock("GET", "/v1/secret/metadata/dev-uk/test-shipcat?list=true")
+        .with_status(200)
+        .with_body(r#"{"data": {"keys": ["FAKE_NUMBER", "FAKE_SECRET", "fake-file", "sub/"]}}"#)
+        .create();
+
+    let base = Manifest::base("fake-ask", &conf, &reg).unwrap();
+    base.verify_secrets_exist(&reg.vault).unwrap();
+    let mf = base.complete(&reg).unwrap();
+    assert_eq!(mf.secrets["FAKE_SECRET"], "hello");
+    assert_eq!(mf.secrets["FAKE_NUMBER"], "-2");
+    assert_eq!(mf.secretVersions["FAKE_SECRET"], 4);
+    assert_eq!(mf.secretVersions["FAKE_NUMBER"], 1);
+
+    // versions end up in the helm values
+    let values = serde_yaml::to_string(&mf).unwrap();
+    assert!(values.contains("secretVersions"));
+}
+
+#[test]
+fn vault_kv2_pinned_read() {
+    setup();
+    std::env::set_var("VAULT_TOKEN", "kv2-test");
+    let (_, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    reg.vault.url = mockito::SERVER_URL.into();
+    reg.vault.kvVersion = KvVersion::V2;
+
+    let _pinned = mock("GET", "/v1/secret/data/dev-uk/pinned/KEY?version=2")
+        .with_status(200)
+        .with_body(kv2_secret("old", 2))
+        .expect(1)
+        .create();
+    let client = Vault::regional(&reg.vault).unwrap();
+    let (value, version) = client.read_version("dev-uk/pinned/KEY", &SecretVersion::Pinned(2)).unwrap();
+    assert_eq!(value, "old");
+    assert_eq!(version, Some(2));
+    _pinned.assert();
+
+    // kv v1 has no history to pin against
+    reg.vault.kvVersion = KvVersion::V1;
+    let client = Vault::regional(&reg.vault).unwrap();
+    assert!(client.read_version("dev-uk/pinned/KEY", &SecretVersion::Pinned(2)).is_err());
+}
+
+fn deployment(checksum: Option<&str>) -> String {
+    let annotations = match checksum {
+        Some(c) => format!(r#"{{"{}": "{}"}}"#, SECRETS_CHECKSUM_ANNOTATION, c),
+        None => "{}".into(),
+    };
+    format!(r#"{{"metadata": {{"name": "x"}}, "spec": {{"template": {{"metadata": {{"annotations": {}}}}}}}}}"#, annotations)
+}
+
+#[test]
+fn secret_drift() {
+    setup();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    reg.vault.backend = SecretSource::File {
+        path: "secrets/dev-uk.yml".into(),
+        encryption: FileEncryption::None,
+    };
+    let mf = Manifest::base("fake-ask", &conf, &reg).unwrap().complete(&reg).unwrap();
+    let checksum = mf.secretsChecksum.clone().unwrap();
+    // hash covers every secret value
+    let mut changed = mf.clone();
+    changed.secrets.insert("FAKE_SECRET".into(), "rotated".into());
+    assert_ne!(changed.secrets_checksum().unwrap(), checksum);
+
+    let client = Client::from_url(mockito::SERVER_URL);
+    let stale = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/fake-ask")
+        .with_status(200)
+        .with_body(deployment(Some("stale")))
+        .create();
+    // fake-storage has no secrets
+    let storage = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/fake-storage")
+        .with_status(200)
+        .with_body(deployment(None))
+        .create();
+    let report = secret::drift_report(&client, &conf, &reg).unwrap();
+    assert_eq!(report.services["fake-ask"], DriftState::Drifted);
+    assert_eq!(report.services["fake-storage"], DriftState::InSync);
+    assert_eq!(report.drifted(), vec!["fake-ask".to_string()]);
+    drop(stale);
+
+    let _current = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/fake-ask")
+        .with_status(200)
+        .with_body(deployment(Some(&checksum)))
+        .create();
+    let report = secret::drift_report(&client, &conf, &reg).unwrap();
+    assert_eq!(report.services["fake-ask"], DriftState::InSync);
+    assert!(report.drifted().is_empty());
+    assert!(report.errors.is_empty());
+    drop(storage);
+
+    // one failing service does not hide the others
+    let _broken = mock("GET", "/apis/apps/v1/namespaces/dev/deployments/fake-storage")
+        .with_status(500)
+        .with_body(r#"{"kind": "Status", "status": "Failure", "message": "etcd timeout", "code": 500}"#)
+        .create();
+    let report = secret::drift_report(&client, &conf, &reg).unwrap();
+    assert_eq!(report.services["fake-ask"], DriftState::InSync);
+    assert_eq!(report.services["fake-storage"], DriftState::Errored);
+    assert!(report.errors.contains_key("fake-storage"));
+}
+
+#[test]
+fn secret_audit() {
+    setup();
+    let (conf, mut reg) = Config::new(ConfigType::Base, "dev-uk").unwrap();
+    reg.vault.backend = SecretSource::File {
+        path: "secrets/dev-uk.yml".into(),
+        encryption: FileEncryption::None,
+    };
+    let mut usage = secret::secret_usage(&conf, &reg).unwrap();
+    let ask = usage.iter().find(|u| u.service == "fake-ask").unwrap();
+    assert_eq!(ask.folder, "dev-uk/test-shipcat");
+    assert!(ask.active);
+    assert!(ask.keys.contains("FAKE_NUMBER")); // from sidecar
+    usage.push(SecretUsage {
+        service: "fake-old".into(),
+        folder: "dev-uk/fake-old".into(),
+        keys: vec!["OLD_TOKEN".to_string()].into_iter().collect(),
+        active: false,
+    });
+
+    let client = reg.vault.secret_backend().unwrap();
+    let audit = secret::audit_usage(client.as_ref(), &reg, &usage).unwrap();
+    assert_eq!(audit.unreferenced["dev-uk/test-shipcat"], vec!["UNUSED".to_string(), "fake-file".to_string()]);
+    assert_eq!(audit.inactive["dev-uk/fake-old"], vec!["OLD_TOKEN".to_string()]);
+    // region folders like kong are not reported
+    assert_eq!(audit.orphaned, vec!["dev-uk/deleted-service".to_string()]);
+    assert!(!audit.is_clean());
+}
@@ -18,7 +18,7 @@ use crate::region::Region;
 // ----------------------------------------------------------------------------------
 
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct ManifestDefaults {
     /// Image prefix string
@@ -43,7 +43,7 @@ impl Default for ManifestDefaults {
 
 
 /// Kubernetes cluster information
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Cluster {
     /// Name of the cluster
@@ -54,7 +54,7 @@ pub struct Cluster {
     pub regions: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Team {
     /// Team name
     pub name: String,
@@ -69,7 +69,7 @@ pub struct Team {
     pub notifications: Option<SlackChannel>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Location {
     /// Location name
@@ -82,7 +82,7 @@ pub struct Location {
     pub local_region: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct GithubParameters {
     /// Location name
@@ -90,7 +90,7 @@ pub struct GithubParameters {
 }
 
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct SlackParameters {
     /// Location name
@@ -102,7 +102,7 @@ pub struct SlackParameters {
 
 
 /// Main manifest, serializable from shipcat.yml
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     /// Global defaults for the manifests
@@ -136,6 +136,7 @@ pub struct Config {
     pub teams: Vec<Team>,
 
     /// Shipcat version pin
+    #[schemars(with = "String")]
     pub version: Version,
 
     // Internal state of the config
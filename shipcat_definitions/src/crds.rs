@@ -1,8 +1,14 @@
 use std::collections::BTreeMap;
+use serde_json::Value;
 use crate::config::{Config};
+use crate::schema;
 
 use super::{Manifest};
-use crate::states::{ManifestType};
+use crate::states::{ManifestType, UpgradeState};
+
+/// Upgrades of stored custom resources between spec versions
+pub mod conversion;
+use self::conversion::{MANIFEST_VERSIONS, SPEC_VERSION_ANNOTATION};
 
 const KUBE_API_VERSION: &str = "apiextensions.k8s.io/v1beta1";
 const DOMAIN: &str = "babylontech.co.uk";
@@ -17,6 +23,11 @@ pub struct Crd<T> {
     pub kind: String,
     pub metadata: Metadata,
     pub spec: T,
+    /// Deployment status (only `ShipcatManifest` objects have one)
+    ///
+    /// Kube ignores it on writes to the main resource.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<ManifestStatus>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -25,9 +36,11 @@ pub struct Metadata {
     pub name: String,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub annotations: BTreeMap<String, String>,
-    // TODO: generation?
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub resourceVersion: String,
+    /// Sequence number of spec changes, set by kube
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<i64>,
 }
 
 /// Literal CRD - eg for creating definitions against kube api
@@ -37,8 +50,41 @@ pub struct CrdSpec {
     pub version: String,
     pub scope: String,
     pub names: CrdNames,
+    /// Served versions when there is more than one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<CrdVersion>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub additionalPrinterColumns: Option<Vec<CrdAdditionalPrinterColumns>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validation: Option<CrdValidation>,
+    /// Whether fields outside the validation schema are kept
+    ///
+    /// Set to false with a structural schema to have kube prune unknown fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserveUnknownFields: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subresources: Option<CrdSubresources>,
+}
+
+/// Subresources kube serves for custom resources
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CrdSubresources {
+    /// Enables `/status`
+    ///
+    /// Status is then ignored on writes to the main resource (and the spec on `/status`),
+    /// and only spec changes bump `metadata.generation`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<Value>,
+}
+
+/// A version of a CRD that kube serves
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CrdVersion {
+    pub name: String,
+    pub served: bool,
+    pub storage: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<CrdValidation>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default)]
@@ -57,21 +103,82 @@ pub struct CrdAdditionalPrinterColumns {
     pub JSONPath: String,
 }
 
+/// Schema the kube api validates custom resources against
+///
+/// The schema is structural, so invalid specs are rejected on apply.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CrdValidation {
+    pub openAPIV3Schema: Value,
+}
+
+impl CrdValidation {
+    /// Validation of custom resources with a spec of type `T`
+    fn spec<T: schemars::JsonSchema>() -> CrdValidation {
+        CrdValidation {
+            openAPIV3Schema: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "spec": schema::openapi::<T>(),
+                },
+            }),
+        }
+    }
+
+    /// Also validate a status of type `S`
+    fn with_status<S: schemars::JsonSchema>(mut self) -> CrdValidation {
+        self.openAPIV3Schema["properties"]["status"] = schema::openapi::<S>();
+        self
+    }
+
+    /// Validation that accepts any spec
+    fn unvalidated() -> CrdValidation {
+        CrdValidation {
+            openAPIV3Schema: serde_json::json!({
+                "type": "object",
+                "x-kubernetes-preserve-unknown-fields": true,
+            }),
+        }
+    }
+}
+
+impl CrdSpec {
+    /// Serve `versions` (oldest first), storing and validating the latest
+    ///
+    /// Older versions are served without validation so that stored objects
+    /// stay readable until `shipcat cluster crd migrate` has upgraded them.
+    fn serve(&mut self, versions: &[&str], validation: CrdValidation) {
+        let latest = versions[versions.len() - 1];
+        self.version = latest.into();
+        if versions.len() == 1 {
+            // kube wants a top level schema when all versions share it
+            self.validation = Some(validation);
+            return;
+        }
+        // kube prefers the first listed version, so list newest first
+        self.versions = versions.iter().rev().map(|v| CrdVersion {
+            name: v.to_string(),
+            served: true,
+            storage: *v == latest,
+            schema: Some(if *v == latest { validation.clone() } else { CrdValidation::unvalidated() }),
+        }).collect();
+    }
+}
+
 pub fn gen_all_crds() -> Vec<CrdSpec> {
-    let shipcatConfig = CrdSpec{
+    let mut shipcatConfig = CrdSpec{
         group: DOMAIN.into(),
-        version: VERSION.into(),
         scope: "Namespaced".into(),
         names: CrdNames{
             plural: "shipcatconfigs".into(),
             singular: "shipcatconfig".into(),
             kind: SHIPCATCONFIG_KIND.into(),
         },
+        preserveUnknownFields: Some(false),
         ..CrdSpec::default()
     };
-    let shipcatManifest = CrdSpec{
+    shipcatConfig.serve(&[VERSION], CrdValidation::spec::<Config>());
+    let mut shipcatManifest = CrdSpec{
         group: DOMAIN.into(),
-        version: VERSION.into(),
         scope: "Namespaced".into(),
         names: CrdNames{
             plural: "shipcatmanifests".into(),
@@ -84,9 +191,34 @@ pub fn gen_all_crds() -> Vec<CrdSpec> {
                 apcType: "string".into(),
                 description: "The URI where the service is available through kong".into(),
                 JSONPath: ".spec.kong.uris".into(),
-            }
+            },
+            CrdAdditionalPrinterColumns{
+                name: "Version".into(),
+                apcType: "string".into(),
+                description: "The version shipcat last rolled out".into(),
+                JSONPath: ".status.appliedVersion".into(),
+            },
+            CrdAdditionalPrinterColumns{
+                name: "State".into(),
+                apcType: "string".into(),
+                description: "Outcome of the last upgrade".into(),
+                JSONPath: ".status.state".into(),
+            },
+            CrdAdditionalPrinterColumns{
+                name: "Upgraded".into(),
+                apcType: "date".into(),
+                description: "When the service was last upgraded".into(),
+                JSONPath: ".status.lastReconcile".into(),
+            },
         ]),
+        preserveUnknownFields: Some(false),
+        subresources: Some(CrdSubresources {
+            status: Some(serde_json::json!({})),
+        }),
+        ..CrdSpec::default()
     };
+    let validation = CrdValidation::spec::<Manifest>().with_status::<ManifestStatus>();
+    shipcatManifest.serve(MANIFEST_VERSIONS, validation);
     vec![shipcatConfig, shipcatManifest]
 }
 
@@ -100,6 +232,7 @@ impl From<CrdSpec> for Crd<CrdSpec> {
                 ..Metadata::default()
             },
             spec: cs,
+            status: None,
         }
     }
 }
@@ -109,14 +242,88 @@ impl From<Manifest> for Crd<Manifest> {
         // we assume the manifest has all it needs to fill in the pieces
         // but no secrets!
         assert_eq!(mf.kind, ManifestType::Base);
+        let version = conversion::latest_manifest_version();
+        let mut annotations = BTreeMap::new();
+        annotations.insert(SPEC_VERSION_ANNOTATION.to_string(), version.to_string());
         Crd {
-            apiVersion: format!("{}/{}", DOMAIN, VERSION),
+            apiVersion: format!("{}/{}", DOMAIN, version),
             kind: SHIPCATMANIFEST_KIND.into(),
             metadata: Metadata {
                 name: mf.name.clone(),
+                annotations,
                 ..Metadata::default()
             },
             spec: mf,
+            status: None,
+        }
+    }
+}
+
+impl Manifest {
+    /// Restore the base state of a manifest read from a `ShipcatManifest`
+    ///
+    /// The kind is not serialized, and only defaults to `Base` with the crd backing.
+    pub fn into_base(mut self) -> Manifest {
+        self.kind = ManifestType::Base;
+        self
+    }
+}
+
+/// Deployments kept in the status of a `ShipcatManifest`
+pub const DEPLOY_HISTORY_LIMIT: usize = 10;
+
+/// Deployment status of a `ShipcatManifest`
+///
+/// Written through the status subresource by shipcat after upgrades,
+/// and by the shipcat operator after reconciling a changed spec.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+pub struct ManifestStatus {
+    /// Generation of the spec that the operator last reconciled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observedGeneration: Option<i64>,
+    /// When the last upgrade finished (RFC 3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lastReconcile: Option<String>,
+    /// Version rolled out by the last successful upgrade
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub appliedVersion: Option<String>,
+    /// Outcome of the last upgrade
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<UpgradeState>,
+    /// Error from the last reconcile if it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Recent upgrades, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<DeployRecord>,
+}
+
+/// An upgrade in the deployment history of a service
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DeployRecord {
+    /// Version that was rolled out
+    pub version: String,
+    /// Outcome of the upgrade
+    pub state: UpgradeState,
+    /// When the upgrade finished (RFC 3339)
+    pub time: String,
+}
+
+impl ManifestStatus {
+    /// Record the outcome of an upgrade
+    ///
+    /// Only the last `DEPLOY_HISTORY_LIMIT` upgrades are kept.
+    pub fn record(&mut self, version: &str, state: UpgradeState, time: &str) {
+        if state == UpgradeState::Completed {
+            self.appliedVersion = Some(version.into());
+            self.error = None;
+        }
+        self.lastReconcile = Some(time.into());
+        self.state = Some(state.clone());
+        self.history.push(DeployRecord { version: version.into(), state, time: time.into() });
+        if self.history.len() > DEPLOY_HISTORY_LIMIT {
+            let excess = self.history.len() - DEPLOY_HISTORY_LIMIT;
+            self.history.drain(..excess);
         }
     }
 }
@@ -141,6 +348,7 @@ impl From<Config> for Crd<Config> {
                 name: rname, ..Metadata::default()
             },
             spec: conf,
+            status: None,
         }
     }
 }
@@ -174,3 +382,63 @@ pub struct CrdEvent<T> {
     pub kind: CrdEventType,
     pub object: Crd<T>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CrdSpec, CrdValidation, ManifestStatus, DEPLOY_HISTORY_LIMIT, gen_all_crds};
+    use crate::states::UpgradeState;
+
+    #[test]
+    fn crd_versions() {
+        let validation = CrdValidation { openAPIV3Schema: serde_json::json!({ "type": "object" }) };
+        let mut cs = CrdSpec::default();
+        cs.serve(&["v1"], validation.clone());
+        assert_eq!(cs.version, "v1");
+        assert!(cs.versions.is_empty());
+        assert!(cs.validation.is_some());
+
+        let mut cs = CrdSpec::default();
+        cs.serve(&["v1", "v2"], validation);
+        assert_eq!(cs.version, "v2");
+        assert!(cs.validation.is_none());
+        let names = cs.versions.iter().map(|v| v.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["v2", "v1"]);
+        assert!(cs.versions[0].storage && !cs.versions[1].storage);
+        assert!(cs.versions.iter().all(|v| v.served));
+        let old = &cs.versions[1].schema.as_ref().unwrap().openAPIV3Schema;
+        assert_eq!(old["x-kubernetes-preserve-unknown-fields"], serde_json::json!(true));
+
+        assert!(gen_all_crds().iter().all(|c| c.version == "v1"));
+    }
+
+    #[test]
+    fn manifest_status_subresource() {
+        let crds = gen_all_crds();
+        let mf = crds.iter().find(|c| c.names.kind == "ShipcatManifest").unwrap();
+        assert!(mf.subresources.as_ref().and_then(|s| s.status.as_ref()).is_some());
+        let schema = &mf.validation.as_ref().unwrap().openAPIV3Schema;
+        let status = &schema["properties"]["status"]["properties"];
+        assert_eq!(status["observedGeneration"]["type"], serde_json::json!("integer"));
+        let conf = crds.iter().find(|c| c.names.kind == "ShipcatConfig").unwrap();
+        assert!(conf.subresources.is_none());
+    }
+
+    #[test]
+    fn deploy_history() {
+        let mut status = ManifestStatus::default();
+        status.record("1.0.0", UpgradeState::Completed, "2019-01-01T00:00:00+00:00");
+        status.record("1.1.0", UpgradeState::Failed, "2019-01-02T00:00:00+00:00");
+        assert_eq!(status.appliedVersion.as_deref(), Some("1.0.0"));
+        assert_eq!(status.state, Some(UpgradeState::Failed));
+        assert_eq!(status.lastReconcile.as_deref(), Some("2019-01-02T00:00:00+00:00"));
+
+        for i in 0..DEPLOY_HISTORY_LIMIT {
+            status.record(&format!("2.{}.0", i), UpgradeState::Completed, "2019-01-03T00:00:00+00:00");
+        }
+        assert_eq!(status.history.len(), DEPLOY_HISTORY_LIMIT);
+        assert_eq!(status.history[0].version, "2.0.0");
+        assert_eq!(status.appliedVersion.as_deref(), Some("2.9.0"));
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["history"][0]["state"], serde_json::json!("COMPLETED"));
+    }
+}
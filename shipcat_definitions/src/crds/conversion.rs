@@ -0,0 +1,135 @@
+use serde_json::Value;
+
+use super::{Crd, Metadata, DOMAIN};
+use crate::{Result, ResultExt};
+
+/// Annotation recording which spec version a custom resource was written with
+///
+/// Kube only relabels `apiVersion` when serving stored objects in another version,
+/// so the spec itself has to say what shape it is in.
+pub const SPEC_VERSION_ANNOTATION: &str = "shipcat.babylontech.co.uk/spec-version";
+
+/// Served versions of the `ShipcatManifest` spec, oldest first
+///
+/// The last one is stored and written by shipcat.
+/// When `Manifest` changes in a way that breaks stored specs, add a version
+/// here along with a `Conversion` from the previous one in `MANIFEST_CONVERSIONS`.
+pub const MANIFEST_VERSIONS: &[&str] = &["v1"];
+
+/// Upgrades of `ShipcatManifest` specs between consecutive versions
+pub const MANIFEST_CONVERSIONS: &[Conversion] = &[];
+
+/// A breaking change between two versions of a spec
+pub struct Conversion {
+    /// Version the conversion reads
+    pub from: &'static str,
+    /// Version the conversion writes
+    pub to: &'static str,
+    /// Rewrite a spec in the `from` format into the `to` format
+    pub convert: fn(Value) -> Result<Value>,
+}
+
+/// Latest version of the `ShipcatManifest` spec
+pub fn latest_manifest_version() -> &'static str {
+    MANIFEST_VERSIONS[MANIFEST_VERSIONS.len() - 1]
+}
+
+/// Spec version a custom resource was written with
+///
+/// Resources written before versioning have no annotation and are of the first version.
+pub fn spec_version<'a>(md: &'a Metadata, versions: &[&'a str]) -> &'a str {
+    md.annotations.get(SPEC_VERSION_ANNOTATION).map(String::as_str).unwrap_or(versions[0])
+}
+
+/// Upgrade a spec from version `from` to the latest of `versions`
+///
+/// Conversions are applied one version at a time.
+pub fn upgrade(spec: Value, from: &str, versions: &[&str], conversions: &[Conversion]) -> Result<Value> {
+    if !versions.contains(&from) {
+        bail!("Unknown spec version {}", from);
+    }
+    let latest = versions[versions.len() - 1];
+    let mut spec = spec;
+    let mut current = from;
+    while current != latest {
+        let conv = match conversions.iter().find(|c| c.from == current) {
+            Some(c) => c,
+            None => bail!("No conversion from spec version {} to {}", current, latest),
+        };
+        debug!("Converting spec from {} to {}", conv.from, conv.to);
+        spec = (conv.convert)(spec).chain_err(|| format!("Failed to convert spec from {} to {}", conv.from, conv.to))?;
+        current = conv.to;
+    }
+    Ok(spec)
+}
+
+/// Upgrade a stored `ShipcatManifest` to the latest spec version
+///
+/// The spec stays untyped as it is read straight from kube.
+pub fn upgrade_manifest(crd: Crd<Value>) -> Result<Crd<Value>> {
+    let from = spec_version(&crd.metadata, MANIFEST_VERSIONS).to_string();
+    let latest = latest_manifest_version();
+    let mut metadata = crd.metadata;
+    let spec = upgrade(crd.spec, &from, MANIFEST_VERSIONS, MANIFEST_CONVERSIONS)
+        .chain_err(|| format!("Could not upgrade the {} manifest", metadata.name))?;
+    metadata.annotations.insert(SPEC_VERSION_ANNOTATION.into(), latest.into());
+    Ok(Crd {
+        apiVersion: format!("{}/{}", DOMAIN, latest),
+        kind: crd.kind,
+        metadata,
+        spec,
+        status: crd.status,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spec_version, upgrade, Conversion, SPEC_VERSION_ANNOTATION};
+    use crate::crds::Metadata;
+    use crate::Result;
+    use serde_json::{json, Value};
+
+    // v2 renamed `image` to `repository`, v3 nested it under `container`
+    fn v1_to_v2(mut spec: Value) -> Result<Value> {
+        let obj = spec.as_object_mut().ok_or("spec is not an object")?;
+        if let Some(img) = obj.remove("image") {
+            obj.insert("repository".into(), img);
+        }
+        Ok(spec)
+    }
+    fn v2_to_v3(mut spec: Value) -> Result<Value> {
+        let obj = spec.as_object_mut().ok_or("spec is not an object")?;
+        let repo = obj.remove("repository").unwrap_or(Value::Null);
+        obj.insert("container".into(), json!({ "repository": repo }));
+        Ok(spec)
+    }
+    const VERSIONS: &[&str] = &["v1", "v2", "v3"];
+    const CONVERSIONS: &[Conversion] = &[
+        Conversion { from: "v1", to: "v2", convert: v1_to_v2 },
+        Conversion { from: "v2", to: "v3", convert: v2_to_v3 },
+    ];
+
+    #[test]
+    fn upgrade_chains_conversions() {
+        let spec = json!({ "name": "fake-ask", "image": "quay.io/babylonhealth/fake-ask" });
+        let res = upgrade(spec, "v1", VERSIONS, CONVERSIONS).unwrap();
+        assert_eq!(res, json!({ "name": "fake-ask", "container": { "repository": "quay.io/babylonhealth/fake-ask" } }));
+
+        let spec = json!({ "name": "fake-ask", "repository": "quay.io/babylonhealth/fake-ask" });
+        assert_eq!(upgrade(spec, "v2", VERSIONS, CONVERSIONS).unwrap()["container"]["repository"],
+            json!("quay.io/babylonhealth/fake-ask"));
+
+        let latest = json!({ "name": "fake-ask" });
+        assert_eq!(upgrade(latest.clone(), "v3", VERSIONS, CONVERSIONS).unwrap(), latest);
+        assert!(upgrade(latest.clone(), "v0", VERSIONS, CONVERSIONS).is_err());
+        assert!(upgrade(latest, "v1", VERSIONS, &CONVERSIONS[1..]).is_err());
+    }
+
+    #[test]
+    fn spec_version_defaults_to_first() {
+        let mut md = Metadata::default();
+        assert_eq!(spec_version(&md, VERSIONS), "v1");
+        md.annotations.insert(SPEC_VERSION_ANNOTATION.into(), "v2".into());
+        assert_eq!(spec_version(&md, VERSIONS), "v2");
+    }
+}
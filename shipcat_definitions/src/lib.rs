@@ -4,6 +4,7 @@
 #![warn(rust_2018_idioms)]
 
 #[macro_use] extern crate serde_derive;
+#[macro_use] extern crate schemars;
 #[macro_use] extern crate log;
 
 /// The backing for manifests must come from the filesystem or the CRD
@@ -74,7 +75,7 @@ error_chain! {
 
 /// Config with regional data
 pub mod region;
-pub use crate::region::{Region, VaultConfig, VersionScheme, KongConfig};
+pub use crate::region::{Region, VaultConfig, VersionScheme, KongConfig, HelmBackend, HelmConfig, PricingConfig};
 /// Master config with cross-region data
 pub mod config;
 pub use crate::config::{Config, Cluster, Team, ManifestDefaults};
@@ -86,13 +87,16 @@ pub mod structs;
 pub mod manifest;
 pub use crate::manifest::Manifest;
 
+/// JSON Schemas for manifests and the config
+pub mod schema;
+
 /// Crd wrappers
-mod crds;
-pub use crate::crds::{Crd, CrdList, CrdEvent, CrdEventType, gen_all_crds};
+pub mod crds;
+pub use crate::crds::{Crd, CrdList, CrdEvent, CrdEventType, ManifestStatus, DeployRecord, gen_all_crds};
 
 /// Internal classifications and states
 mod states;
-pub use crate::states::{ConfigType};
+pub use crate::states::{ConfigType, UpgradeState};
 
 /// File backing
 #[cfg(feature = "filesystem")]
@@ -109,9 +113,22 @@ pub mod math;
 /// Used for small app configs that are inlined in the completed manifests.
 pub mod template;
 
-//pub mod product;
-//pub use product::Product;
+/// Product manifests grouping services across regions
+#[cfg(feature = "filesystem")]
+pub mod product;
+#[cfg(feature = "filesystem")]
+pub use crate::product::Product;
 
 /// A Hashicorp Vault HTTP client using `reqwest`
 pub mod vault;
 pub use crate::vault::Vault;
+
+/// Pluggable secret backends (vault, secret files, environment variables)
+pub mod secrets;
+pub use crate::secrets::{SecretBackend, SecretSource, SecretVersion};
+
+/// An in-process vault stand-in for tests
+#[cfg(any(test, feature = "mock"))]
+pub mod mockvault;
+#[cfg(any(test, feature = "mock"))]
+pub use crate::mockvault::MockVault;
@@ -1,4 +1,4 @@
-use crate::vault::Vault;
+use crate::secrets::{SecretBackend, SecretVersion};
 use std::collections::{BTreeMap, BTreeSet};
 use regex::Regex;
 
@@ -19,6 +19,8 @@ use super::structs::{
     {CronJob, Sidecar, EnvVars},
     {Gate, Kafka, Kong, Rbac},
     RollingUpdate,
+    Canary,
+    BlueGreen,
     autoscaling::AutoScaling,
     tolerations::Tolerations,
     LifeCycle,
@@ -29,7 +31,7 @@ use super::structs::{
 };
 
 /// Main manifest, serializable from shipcat.yml or the shipcat CRD.
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Manifest {
     // ------------------------------------------------------------------------
@@ -241,6 +243,7 @@ pub struct Manifest {
     ///
     /// These have a few special convenience behaviours:
     /// "IN_VAULT" values is replaced with value from vault/secret/folder/service/KEY
+    /// "IN_VAULT@3" values pin version 3 of the secret (vault KV v2 only)
     /// One off `tera` templates are calculated with a limited template context
     ///
     /// IN_VAULT secrets will all be put in a single kubernetes `Secret` object.
@@ -254,6 +257,9 @@ pub struct Manifest {
     ///   # vault lookup:
     ///   DATABASE_URL: IN_VAULT
     ///
+    ///   # pinned vault lookup:
+    ///   API_KEY: IN_VAULT@3
+    ///
     ///   # templated evars:
     ///   INTERNAL_AUTH_URL: "{{ base_urls.services }}/auth/internal"
     ///   AUTH_ID: "{{ kong.consumers['webapp'].oauth_client_id }}"
@@ -283,7 +289,7 @@ pub struct Manifest {
     /// ```yaml
     /// secretFiles:
     ///   webapp-ssl-keystore: IN_VAULT
-    ///   webapp-ssl-truststore: IN_VAULT
+    ///   webapp-ssl-truststore: IN_VAULT@2
     /// ```
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub secretFiles: BTreeMap<String, String>,
@@ -473,6 +479,39 @@ pub struct Manifest {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub rollingUpdate: Option<RollingUpdate>,
 
+    /// Progressive canary rollout parameters
+    ///
+    /// Used by `shipcat apply --canary` to shift replicas to the new version step by step.
+    /// Pods of the canary must pass the health gate after every step,
+    /// otherwise the canary is removed and the stable `Deployment` is restored.
+    ///
+    /// ```yaml
+    /// canary:
+    ///   steps:
+    ///   - weight: 10
+    ///     pause: 120
+    ///   - weight: 50
+    ///     pause: 300
+    ///   healthGate:
+    ///     maxRestarts: 0
+    /// ```
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canary: Option<Canary>,
+
+    /// Blue/green deployment parameters
+    ///
+    /// Used by `shipcat apply --blue-green` to install the new version as a second
+    /// full release (`{name}-green` alternating with `{name}`) and switch the
+    /// kong api's `upstream_url` over to it once it is ready.
+    /// The previous release is left running, so switching back is the rollback.
+    ///
+    /// ```yaml
+    /// blueGreen:
+    ///   readyTimeout: 600
+    /// ```
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blueGreen: Option<BlueGreen>,
+
     /// `HorizontalPodAutoScaler` parameters for kubernetes
     ///
     /// Passed all parameters directly onto the `spec` of a kube HPA.
@@ -743,6 +782,25 @@ pub struct Manifest {
     #[serde(default, skip_deserializing, skip_serializing_if = "BTreeMap::is_empty")]
     pub secrets: BTreeMap<String, String>,
 
+    /// Versions of the vault secrets read into `secrets` and `secretFiles`
+    ///
+    /// Only filled in by versioned backends (vault KV v2).
+    /// Stored with the helm values, so every release records the secret
+    /// versions it was deployed with.
+    ///
+    /// This is an internal property that is exposed as an output only.
+    #[serde(default, skip_deserializing, skip_serializing_if = "BTreeMap::is_empty")]
+    pub secretVersions: BTreeMap<String, u32>,
+
+    /// Content hash of all resolved `secrets` and `secretFiles`
+    ///
+    /// Charts should put this in a pod template annotation so that pods restart
+    /// when a secret changes (see `shipcat secret drift`).
+    ///
+    /// This is an internal property that is exposed as an output only.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub secretsChecksum: Option<String>,
+
     /// Internal kind of the manifest
     ///
     /// A manifest goes through different stages of serialization, templating,
@@ -862,6 +920,20 @@ impl Manifest {
         if let Some(ref ru) = &self.rollingUpdate {
             ru.verify(self.replicaCount.unwrap())?;
         }
+        if let Some(ref c) = &self.canary {
+            c.verify()?;
+            if self.autoScaling.is_some() {
+                bail!("canary cannot be used with autoScaling as replicas are shifted manually");
+            }
+        }
+        if self.blueGreen.is_some() {
+            if self.kong.is_none() {
+                bail!("blueGreen needs a kong api to switch between releases");
+            }
+            if self.canary.is_some() {
+                bail!("blueGreen and canary cannot be used together");
+            }
+        }
 
         self.env.verify()?;
 
@@ -916,7 +988,8 @@ impl Manifest {
         Ok(())
     }
 
-    fn get_vault_path(&self, vc: &VaultConfig) -> String {
+    /// Secret folder of the service, `{region folder}/{service}` unless overridden by `vault`
+    pub fn get_vault_path(&self, vc: &VaultConfig) -> String {
         // some services use keys from other services
         let (svc, reg) = if let Some(ref vopts) = self.vault {
             (vopts.name.clone(), vopts.region.clone().unwrap_or_else(|| vc.folder.clone()))
@@ -926,6 +999,23 @@ impl Manifest {
         format!("{}/{}", reg, svc)
     }
 
+    /// Names of all secrets read from the service's secret folder
+    ///
+    /// Covers `IN_VAULT` env vars of all containers, workers and cron jobs, and `secretFiles`.
+    pub fn vault_secret_names(&self) -> BTreeSet<String> {
+        let mut envs = vec![&self.env];
+        envs.extend(self.sidecars.iter().map(|s| &s.env));
+        envs.extend(self.workers.iter().map(|w| &w.env));
+        envs.extend(self.cronJobs.iter().map(|c| &c.env));
+        let files = self.secretFiles.iter();
+        envs.into_iter()
+            .flat_map(|e| e.plain.iter())
+            .chain(files)
+            .filter(|(_, v)| SecretVersion::is_placeholder(v))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
     // Get EnvVars for all containers, workers etc. for this Manifest.
     pub fn get_env_vars(&mut self) -> Vec<&mut EnvVars> {
         let mut envs = Vec::new();
@@ -942,19 +1032,22 @@ impl Manifest {
         envs
     }
 
-    /// Populate placeholder fields with secrets from vault
+    /// Populate placeholder fields with secrets from the region's secret backend
     ///
-    /// This will use the HTTP api of Vault using the configuration parameters
+    /// This will typically use the HTTP api of Vault using the configuration parameters
     /// in the `Config`.
-    pub fn secrets(&mut self, client: &Vault, vc: &VaultConfig) -> Result<()> {
+    pub fn secrets(&mut self, client: &dyn SecretBackend, vc: &VaultConfig) -> Result<()> {
         let pth = self.get_vault_path(vc);
         debug!("Injecting secrets from vault {} ({:?})", pth, client.mode());
 
-        let mut vault_secrets = BTreeSet::new();
+        let mut vault_secrets = BTreeMap::new();
         let mut template_secrets = BTreeMap::new();
         for e in &mut self.get_env_vars() {
-            for k in e.vault_secrets() {
-                vault_secrets.insert(k.to_string());
+            for (k, v) in e.vault_secrets()? {
+                let original = vault_secrets.insert(k.to_string(), v.clone());
+                if original.iter().any(|x| x != &v) {
+                    bail!("Secret {} can not be pinned to different versions", k);
+                }
             }
             for (k, v) in e.template_secrets() {
                 let original = template_secrets.insert(k.to_string(), v.to_string());
@@ -964,33 +1057,62 @@ impl Manifest {
             }
         }
 
-        let template_keys = template_secrets.keys().map(|x| x.to_string()).collect();
-        if let Some(k) = vault_secrets.intersection(&template_keys).next() {
+        if let Some(k) = vault_secrets.keys().find(|k| template_secrets.contains_key(*k)) {
             bail!("Secret {} can not be both templated and fetched from vault", k);
         }
 
         // Lookup values for each secret in vault.
-        for k in vault_secrets {
+        for (k, version) in vault_secrets {
             let vkey = format!("{}/{}", pth, k);
-            self.secrets.insert(k.to_string(), client.read(&vkey)?);
+            let (value, read) = client.read_version(&vkey, &version)?;
+            self.secrets.insert(k.to_string(), value);
+            if let Some(n) = read {
+                self.secretVersions.insert(k.to_string(), n);
+            }
         }
 
         self.secrets.append(&mut template_secrets);
 
         // do the same for secret secrets
         for (k, v) in &mut self.secretFiles {
-            if v == "IN_VAULT" {
+            if let Some(version) = SecretVersion::from_placeholder(v)? {
                 let vkey = format!("{}/{}", pth, k);
-                *v = client.read(&vkey)?;
+                let (value, read) = client.read_version(&vkey, &version)?;
+                *v = value;
+                if let Some(n) = read {
+                    self.secretVersions.insert(k.to_string(), n);
+                }
             }
             // sanity check; secretFiles are assumed base64 verify we can decode
             if base64::decode(v).is_err() {
                 bail!("Secret {} is not base64 encoded", k);
             }
         }
+        self.secretsChecksum = self.secrets_checksum();
         Ok(())
     }
 
+    /// Hash of the resolved secrets
+    ///
+    /// A sha256 over all names and values of `secrets` and `secretFiles`.
+    /// None when the manifest uses no secrets.
+    pub fn secrets_checksum(&self) -> Option<String> {
+        use sha2::{Sha256, Digest};
+        if self.secrets.is_empty() && self.secretFiles.is_empty() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        let files = self.secretFiles.iter().map(|(k, v)| (format!("file:{}", k), v));
+        for (k, v) in self.secrets.iter().map(|(k, v)| (k.clone(), v)).chain(files) {
+            // NUL separated so that no two different maps hash the same
+            hasher.input(k.as_bytes());
+            hasher.input(b"\0");
+            hasher.input(v.as_bytes());
+            hasher.input(b"\0");
+        }
+        Some(format!("{:x}", hasher.result()))
+    }
+
     /// Get a list of raw secrets (without associated keys)
     ///
     /// Useful for obfuscation mechanisms so it knows what to obfuscate.
@@ -1006,33 +1128,24 @@ impl Manifest {
             .plain
             .clone()
             .into_iter()
-            .filter(|(_, v)| v == "IN_VAULT")
+            .filter(|(_, v)| SecretVersion::is_placeholder(v))
             .map(|(k, _)| k)
             .collect::<Vec<_>>();
         let files = self.secretFiles.clone().into_iter()
-            .filter(|(_,v)| v == "IN_VAULT")
+            .filter(|(_,v)| SecretVersion::is_placeholder(v))
             .map(|(k, _)| k)
             .collect::<Vec<_>>();
         if keys.is_empty() && files.is_empty() {
             return Ok(()); // no point trying to cross reference
         }
 
-        // what we have
-        let v = Vault::regional(vc)?; // only listing anyway
+        // compare with what we have
+        let v = vc.secret_backend()?;
         let secpth = self.get_vault_path(vc);
-        let found = v.list(&secpth)?; // can fail if folder is empty
-        debug!("Found secrets {:?} for {}", found, self.name);
-
-        // compare
-        for k in keys {
-            if !found.contains(&k) {
-                bail!("Secret {} not found in vault {} for {}", k, secpth, self.name);
-            }
-        }
-        for k in files {
-            if !found.contains(&k) {
-                bail!("Secret file {} not found in vault {} for {}", k, secpth, self.name);
-            }
+        let expected = [keys, files.clone()].concat();
+        if let Some(k) = v.missing(&secpth, &expected)?.first() { // vault list can fail if folder is empty
+            let kind = if files.contains(k) { "Secret file" } else { "Secret" };
+            bail!("{} {} not found in {} for {}", kind, k, secpth, self.name);
         }
         Ok(())
     }
@@ -1,16 +1,104 @@
+use std::collections::BTreeMap;
+
 use super::structs::Resources;
 use super::structs::rollingupdate::{RollingUpdate};
-use super::{Result, Manifest};
+use super::region::PricingConfig;
+use super::{Result, Manifest, Team};
 
 /// Total resource usage for a Manifest
 ///
 /// Accounting for workers, replicas, sidecars, and autoscaling policies for these.
-#[derive(Serialize, Default)]
+#[derive(Serialize, Default, Clone)]
 pub struct ResourceTotals {
     /// Sum of basic resource structs (ignoring autoscaling limits)
     pub base: Resources<f64>,
     /// Autoscaling Ceilings on top of required
     pub extra: Resources<f64>,
+    /// Monthly cost of the requested resources, if priced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<ResourceCost>,
+}
+
+/// Monthly cost estimate of resource requests
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct ResourceCost {
+    /// Cost of the base requests
+    pub base: f64,
+    /// Extra cost if autoscaling to the ceilings
+    pub extra: f64,
+}
+
+impl ResourceTotals {
+    /// Add the usage of another service (cost is not summed, price the sum instead)
+    pub fn add(&mut self, rhs: &ResourceTotals) {
+        self.base += rhs.base.clone();
+        self.extra += rhs.extra.clone();
+    }
+
+    /// Estimate the monthly cost of the requests from region prices
+    ///
+    /// Memory is expected in bytes, so this must happen before rounding.
+    pub fn price(&mut self, prices: &PricingConfig) {
+        let gib = 1024.0 * 1024.0 * 1024.0;
+        let cost = |r: &Resources<f64>| {
+            let monthly = r.requests.cpu * prices.cpu + r.requests.memory / gib * prices.memory;
+            (monthly * 100.0).round() / 100.0
+        };
+        self.cost = Some(ResourceCost { base: cost(&self.base), extra: cost(&self.extra) });
+    }
+}
+
+/// Complete breakdown of resource usage in total, and split by team.
+///
+/// Normally this is computed by `Manifest::resources` for a region-wide total.
+/// Looping over all regions is possible in the CLI.
+#[derive(Serialize)]
+pub struct ResourceBreakdown {
+    /// Total totals
+    pub totals: ResourceTotals,
+    /// A partition of totals info teams
+    pub teams: BTreeMap<String, ResourceTotals>,
+    /// Services left out of the totals, with the reason
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<String, String>,
+}
+
+impl ResourceBreakdown {
+    /// Constructor to ensure all valid teams are filled in
+    pub fn new(tx: Vec<Team>) -> ResourceBreakdown {
+        let mut teams = BTreeMap::new();
+        for t in tx {
+            teams.insert(t.name, ResourceTotals::default());
+        }
+        ResourceBreakdown { teams, totals: ResourceTotals::default(), errors: BTreeMap::new() }
+    }
+
+    /// Add the usage of a service owned by a team
+    pub fn add(&mut self, team: &str, rhs: &ResourceTotals) {
+        self.totals.add(rhs);
+        self.teams.entry(team.to_string()).or_default().add(rhs);
+    }
+
+    /// Estimate monthly costs for all teams from region prices
+    ///
+    /// Must happen before `normalise` as memory is priced per GiB.
+    pub fn price(&mut self, prices: &PricingConfig) {
+        for tt in &mut self.teams.values_mut() {
+            tt.price(prices);
+        }
+        self.totals.price(prices);
+    }
+
+    /// Round all numbers to gigs and full cores (for all teams)
+    pub fn normalise(mut self) -> Self {
+        for tt in &mut self.teams.values_mut() {
+            tt.base.round();
+            tt.extra.round();
+        }
+        self.totals.base.round();
+        self.totals.extra.round();
+        self
+    }
 }
 
 /// Calculations done based on values in manifests
@@ -104,7 +192,7 @@ impl Manifest {
             }
 
         }
-        Ok(ResourceTotals { base, extra })
+        Ok(ResourceTotals { base, extra, cost: None })
     }
 
 }
@@ -113,7 +201,9 @@ impl Manifest {
 #[cfg(test)]
 mod tests {
     use crate::structs::HealthCheck;
-    use super::{Manifest};
+    use crate::structs::{Resources, ResourceRequest, ResourceLimit};
+    use crate::region::PricingConfig;
+    use super::{Manifest, ResourceTotals, ResourceCost};
 
     #[test]
     fn mf_wait_time_check() {
@@ -153,4 +243,17 @@ mod tests {
         assert_eq!(mf.estimate_wait_time(), 990); // lots of leeway here just in case
 
     }
+
+    #[test]
+    fn resource_pricing() {
+        let gib = 1024.0 * 1024.0 * 1024.0;
+        let res = |cpu: f64, memory: f64| Resources {
+            requests: ResourceRequest { cpu, memory },
+            limits: ResourceLimit { cpu: cpu * 2.0, memory: memory * 2.0 },
+        };
+        let mut tt = ResourceTotals { base: res(2.0, 4.0 * gib), extra: res(0.5, 0.5 * gib), cost: None };
+        tt.price(&PricingConfig { cpu: 20.0, memory: 2.5 });
+        // limits are not priced
+        assert_eq!(tt.cost, Some(ResourceCost { base: 50.0, extra: 11.25 }));
+    }
 }
@@ -163,6 +163,12 @@ impl Manifest {
         if mf.rollingUpdate.is_some() {
             self.rollingUpdate = mf.rollingUpdate;
         }
+        if mf.canary.is_some() {
+            self.canary = mf.canary;
+        }
+        if mf.blueGreen.is_some() {
+            self.blueGreen = mf.blueGreen;
+        }
         if mf.autoScaling.is_some() {
             self.autoScaling = mf.autoScaling;
         }
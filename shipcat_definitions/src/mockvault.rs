@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use super::Result;
+use crate::secrets::{SecretFile, FileEncryption};
+use crate::vault::KvVersion;
+
+/// Token handed out by kubernetes logins to the mock vault
+pub const MOCK_TOKEN: &str = "mock-vault-token";
+
+/// An in-process stand-in for the vault HTTP api
+///
+/// Serves the read and list endpoints shipcat uses (KV v1 or v2) on a random
+/// local port, so that manifests can be completed in tests without a real vault.
+/// Kubernetes logins with any non-empty service account token get `MOCK_TOKEN`.
+/// Secrets are seeded from yaml in the format of the file secret backend:
+///
+/// ```yaml
+/// dev-uk:
+///   test-shipcat:
+///     FAKE_SECRET: hello
+/// ```
+///
+/// Every secret is at version 1 in KV v2 mode.
+/// Requests without an `X-Vault-Token` header are rejected like vault does.
+/// The server stops when dropped.
+pub struct MockVault {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+}
+
+impl MockVault {
+    /// Start a server for a yaml fixture
+    pub fn from_file(pth: &Path, kv: KvVersion) -> Result<MockVault> {
+        let sf = SecretFile::load(pth, &FileEncryption::None)?;
+        MockVault::start((*sf.secrets).clone(), kv)
+    }
+
+    /// Start a server for secrets keyed by their full paths (e.g. `dev-uk/svc/KEY`)
+    pub fn start(secrets: BTreeMap<String, String>, kv: KvVersion) -> Result<MockVault> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(s) = stream {
+                    if let Err(e) = handle(s, &secrets, &kv) {
+                        warn!("mock vault failed to respond: {}", e);
+                    }
+                }
+            }
+        });
+        debug!("Started mock vault on {}", addr);
+        Ok(MockVault { addr, stop })
+    }
+
+    /// Url to use as the vault url of a region
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+impl Drop for MockVault {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it can see the flag
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn handle(stream: TcpStream, secrets: &BTreeMap<String, String>, kv: &KvVersion) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut authed = false;
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let header = line.to_lowercase();
+        if header.starts_with("x-vault-token:") {
+            authed = true;
+        }
+        if let Some(len) = header.strip_prefix("content-length:") {
+            length = len.trim().parse()?;
+        }
+    }
+    let mut data = vec![0; length];
+    reader.read_exact(&mut data)?;
+    let target = request.split_whitespace().nth(1).unwrap_or("").to_string();
+    debug!("mock vault {}", request.trim());
+    let (status, body) = if request.starts_with("POST ") && target == "/v1/auth/kubernetes/login" {
+        login(&data)
+    } else if !request.starts_with("GET ") {
+        (405, r#"{"errors":[]}"#.to_string())
+    } else if !authed {
+        (403, r#"{"errors":["permission denied"]}"#.to_string())
+    } else {
+        respond(&target, secrets, kv)
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Status and body for a kubernetes login
+fn login(data: &[u8]) -> (u16, String) {
+    let req : serde_json::Value = serde_json::from_slice(data).unwrap_or_default();
+    let given = |k: &str| req[k].as_str().map(|v| !v.is_empty()).unwrap_or(false);
+    if !given("role") || !given("jwt") {
+        return (400, r#"{"errors":["missing role or jwt"]}"#.to_string());
+    }
+    let body = serde_json::json!({ "auth": { "client_token": MOCK_TOKEN, "lease_duration": 3600 } });
+    (200, body.to_string())
+}
+
+/// Status and body for a GET on the vault api
+fn respond(target: &str, secrets: &BTreeMap<String, String>, kv: &KvVersion) -> (u16, String) {
+    let not_found = (404, r#"{"errors":[]}"#.to_string());
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p, q),
+        None => (target, ""),
+    };
+    let params : BTreeMap<&str, &str> = query.split('&').filter_map(|p| p.split_once('=')).collect();
+    let path = match path.strip_prefix("/v1/secret/") {
+        Some(p) => p,
+        None => return not_found,
+    };
+    let (path, listing) = match (kv, params.get("list")) {
+        (KvVersion::V1, list) => (Some(path), list == Some(&"true")),
+        (KvVersion::V2, Some(&"true")) => (path.strip_prefix("metadata/"), true),
+        (KvVersion::V2, _) => (path.strip_prefix("data/"), false),
+    };
+    let path = match path {
+        Some(p) => p.trim_end_matches('/'),
+        None => return not_found,
+    };
+
+    if listing {
+        let pfx = format!("{}/", path);
+        let keys : BTreeSet<String> = secrets.keys()
+            .filter_map(|k| k.strip_prefix(&pfx))
+            .map(|k| match k.split_once('/') {
+                Some((folder, _)) => format!("{}/", folder),
+                None => k.to_string(),
+            })
+            .collect();
+        if keys.is_empty() {
+            return not_found;
+        }
+        return (200, serde_json::json!({ "data": { "keys": keys } }).to_string());
+    }
+
+    let value = match secrets.get(path) {
+        Some(v) => v,
+        None => return not_found,
+    };
+    let body = match kv {
+        KvVersion::V1 => serde_json::json!({ "data": { "value": value }, "lease_duration": 2_764_800 }),
+        KvVersion::V2 => {
+            if matches!(params.get("version"), Some(v) if *v != "1") {
+                return not_found;
+            }
+            serde_json::json!({ "data": { "data": { "value": value }, "metadata": { "version": 1 } } })
+        }
+    };
+    (200, body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::respond;
+    use crate::vault::KvVersion;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn mock_vault_routes() {
+        let mut secrets = BTreeMap::new();
+        secrets.insert("dev-uk/svc/KEY".to_string(), "hi".to_string());
+        secrets.insert("dev-uk/svc/sub/OTHER".to_string(), "x".to_string());
+
+        let (status, body) = respond("/v1/secret/dev-uk/svc/KEY", &secrets, &KvVersion::V1);
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""value":"hi""#));
+        let (_, body) = respond("/v1/secret/dev-uk/svc?list=true", &secrets, &KvVersion::V1);
+        assert_eq!(body, r#"{"data":{"keys":["KEY","sub/"]}}"#);
+        assert_eq!(respond("/v1/secret/dev-uk/svc/MISSING", &secrets, &KvVersion::V1).0, 404);
+        assert_eq!(respond("/v1/secret/dev-uk/empty?list=true", &secrets, &KvVersion::V1).0, 404);
+
+        let (status, body) = respond("/v1/secret/data/dev-uk/svc/KEY?version=1", &secrets, &KvVersion::V2);
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""version":1"#));
+        assert_eq!(respond("/v1/secret/data/dev-uk/svc/KEY?version=2", &secrets, &KvVersion::V2).0, 404);
+        assert_eq!(respond("/v1/secret/dev-uk/svc/KEY", &secrets, &KvVersion::V2).0, 404);
+        let (_, body) = respond("/v1/secret/metadata/dev-uk/svc?list=true", &secrets, &KvVersion::V2);
+        assert_eq!(body, r#"{"data":{"keys":["KEY","sub/"]}}"#);
+    }
+}
@@ -1,7 +1,7 @@
 // This file describes how product manifests and environment overrides are merged.
 
 use serde_yaml;
-use std::path::PathBuf;
+use std::path::Path;
 use std::io::prelude::*;
 use std::fs::File;
 
@@ -12,7 +12,7 @@ impl Product {
     /// Add implicit defaults to self after merging in location overrides
     pub fn post_merge_implicits(&mut self, _conf: &Config, location: Option<String>) -> Result<()> {
         if let Some(l) = location {
-            self.location = l.clone();
+            self.location = l;
         }
         Ok(())
     }
@@ -26,12 +26,12 @@ impl Product {
     /// Merge defaults from partial override file
     ///
     /// Copies keys from environment files into the current product struct by default.
-    pub fn merge(&mut self, pth: &PathBuf) -> Result<()> {
+    pub fn merge(&mut self, pth: &Path) -> Result<()> {
         trace!("Merging {}", pth.display());
         if !pth.exists() {
             bail!("Defaults file {} does not exist", pth.display())
         }
-        let mut f = File::open(&pth)?;
+        let mut f = File::open(pth)?;
         let mut data = String::new();
         f.read_to_string(&mut data)?;
         // Because Product has most things implementing Default via serde
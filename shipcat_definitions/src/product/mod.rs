@@ -3,13 +3,14 @@
 /// Allow normal error handling from structs
 pub use super::{Result, ErrorKind, Error};
 
-pub use super::config::{Config, VaultConfig};
+pub use super::config::Config;
 
 /// Products needs some structs
 pub use super::structs;
 
 
 /// main module
+#[allow(clippy::module_inception)]
 pub mod product;
 
 // Exports
@@ -1,10 +1,10 @@
 use serde_yaml;
 use regex::Regex;
-//use walkdir::WalkDir;
+use walkdir::WalkDir;
 
 use std::io::prelude::*;
 use std::fs::File;
-use std::path::{PathBuf, Path};
+use std::path::Path;
 
 use super::{Result, Config};
 
@@ -15,8 +15,9 @@ use super::structs::Contact;
 // -- dependent structs used by Product (currently just inlined here)
 
 /// Product owner
+///
+/// NB: `deny_unknown_fields` cannot be combined with `flatten`
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(deny_unknown_fields)]
 pub struct Owner {
     /// Contact details (flattened into Owner)
     #[serde(flatten)]
@@ -111,7 +112,7 @@ impl Product {
         let pdcdir = Path::new(".").join("products");
         let pdcts = WalkDir::new(&pdcdir)
             .min_depth(1)
-            .min_depth(1)
+            .max_depth(1)
             .into_iter()
             .filter_map(|e| e.ok())
             .filter(|e| e.file_type().is_dir());
@@ -125,10 +126,14 @@ impl Product {
             let svcname = svccomp.as_os_str().to_str().unwrap();
             xs.push(svcname.into());
         }
+        xs.sort();
         Ok(xs)
     }
 
-    fn basic(product: &str, conf: &Config) -> Result<Product> {
+    /// Product manifest without location overrides
+    ///
+    /// Enough to find the locations a product is active in.
+    pub fn basic(product: &str, conf: &Config) -> Result<Product> {
         let pth = Path::new(".").join("products").join(product);
         if !pth.exists() {
             bail!("Product folder {} does not exist", pth.display())
@@ -140,7 +145,7 @@ impl Product {
     }
 
     /// Read a Product file in an arbitrary path
-    fn read_from(pwd: &PathBuf) -> Result<Product> {
+    fn read_from(pwd: &Path) -> Result<Product> {
         let mpath = pwd.join("product.yml");
         trace!("Using product manifest in {}", mpath.display());
         if !mpath.exists() {
@@ -185,7 +190,7 @@ impl Product {
     ///
     /// Assumes the product has been populated with `implicits`
     pub fn verify(&self, conf: &Config) -> Result<()> {
-        assert!(self.location != ""); // needs to have been set by implicits!
+        assert!(!self.location.is_empty()); // needs to have been set by implicits!
         // limit to 50 characters, alphanumeric, dashes for sanity.
         // 63 is kube dns limit (13 char suffix buffer)
         let re = Regex::new(r"^[0-9a-z\-]{1,50}$").unwrap();
@@ -198,14 +203,14 @@ impl Product {
 
 
         if let Some(ref md) = self.owner {
-            md.verify(&conf)?;
+            md.verify(conf)?;
         } else {
             bail!("Missing owner for {}", self.name);
         }
 
         // vectorised entries
         for d in &self.services {
-            d.verify(&conf, &self.location)?;
+            d.verify(conf, &self.location)?;
         }
 
         // version
@@ -216,8 +221,8 @@ impl Product {
         // JIRA
         if let Some(ticket) = &self.jira {
             let pattern = r"^[A-Z]+-[0-9]+$";
-            let re = Regex::new(&pattern).unwrap();
-            if !re.is_match(&ticket) {
+            let re = Regex::new(pattern).unwrap();
+            if !re.is_match(ticket) {
                 bail!("Jira ticket '{}' does not match expected format: {}", ticket, pattern);
             }
         } else {
@@ -227,7 +232,7 @@ impl Product {
         // locations
         for l in &self.locations {
             // 1) is it a valid location name?
-            if conf.locations.get(l).is_none() {
+            if !conf.locations.contains_key(l) {
                 bail!("Unsupported location {} without entry in config", l);
             }
         }
@@ -271,6 +276,6 @@ pub fn show(product: Option<String>, conf: &Config, location: &str) -> Result<()
         }
         serde_yaml::to_string(&px)?
     };
-    print!("{}\n", encoded);
+    println!("{}", encoded);
     Ok(())
 }
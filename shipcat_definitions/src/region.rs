@@ -1,13 +1,16 @@
 use crate::structs::kong::Kong;
 use std::collections::BTreeMap;
 use std::env;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use semver::Version;
 
 use url::Url;
 use uuid::Uuid;
 
-use super::Vault;
+use crate::vault::{Vault, KvVersion};
+use crate::secrets::{SecretBackend, SecretSource, SecretFile, EnvSecrets, Mocked};
 #[allow(unused_imports)]
 use super::{Result, Error, ErrorKind};
 use super::ConfigType;
@@ -16,7 +19,7 @@ use super::ConfigType;
 ///
 /// This is valdiated strictly using `shipcat validate` when versions are found in manifests.
 /// Otherwise, it's validated on upgrade time (via `shipcat apply`) when it's passed.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub enum VersionScheme {
     /// Version must be valid semver (no leading v)
     ///
@@ -55,8 +58,39 @@ impl VersionScheme {
     }
 }
 
+/// Helm release storage used in a region
+///
+/// Regions can be migrated between these one at a time.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub enum HelmBackend {
+    /// Helm 2 with releases managed by a tiller in the region namespace
+    Helm2,
+    /// Helm 3 with releases stored as secrets in the region namespace (no tiller)
+    Helm3,
+}
+
+impl Default for HelmBackend {
+    fn default() -> HelmBackend {
+        HelmBackend::Helm2
+    }
+}
+
+/// Helm configuration for a region
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HelmConfig {
+    /// Helm version and release storage to use
+    #[serde(default)]
+    pub backend: HelmBackend,
+    /// Helm executable to call if it is not `helm` on the PATH
+    ///
+    /// Useful when both helm versions are installed side by side.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<String>,
+}
+
 /// Vault configuration for a region
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[cfg_attr(test, derive(Default))]
 #[serde(deny_unknown_fields)]
 pub struct VaultConfig {
@@ -66,6 +100,49 @@ pub struct VaultConfig {
     ///
     /// Typically, the name of the region to disambiguate.
     pub folder: String,
+    /// Where secrets are read from
+    ///
+    /// Defaults to vault at `url`. Secret paths are the same for all backends.
+    #[serde(default)]
+    pub backend: SecretSource,
+    /// Version of the vault KV engine mounted at secret/
+    ///
+    /// KV v2 allows pinning secret versions with `IN_VAULT@{version}`.
+    #[serde(default)]
+    pub kvVersion: KvVersion,
+    /// The secret file once loaded
+    ///
+    /// Shared between clones of the region, so the file is only decrypted once.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub(crate) file: Arc<Mutex<Option<SecretFile>>>,
+}
+
+impl VaultConfig {
+    /// Secret backend for the region
+    pub fn secret_backend(&self) -> Result<Box<dyn SecretBackend>> {
+        let backend : Box<dyn SecretBackend> = match &self.backend {
+            SecretSource::Vault => Box::new(Vault::regional(self)?),
+            SecretSource::File { path, encryption } => {
+                let mut file = self.file.lock().unwrap();
+                if file.is_none() {
+                    *file = Some(SecretFile::load(Path::new(path), encryption)?);
+                }
+                Box::new(file.clone().unwrap())
+            },
+            SecretSource::Env { prefix } => Box::new(EnvSecrets::new(prefix)),
+        };
+        Ok(backend)
+    }
+
+    /// Secret backend returning dummy values for the region
+    pub fn mocked_backend(&self) -> Result<Box<dyn SecretBackend>> {
+        let backend : Box<dyn SecretBackend> = match &self.backend {
+            SecretSource::Vault => Box::new(Vault::mocked(self)?),
+            _ => Box::new(Mocked),
+        };
+        Ok(backend)
+    }
 }
 
 //#[derive(Serialize, Deserialize, Clone, Default)]
@@ -78,7 +155,7 @@ pub struct VaultConfig {
 //}
 
 /// Kafka configuration for a region
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct KafkaConfig {
     /// Broker urls in "hostname:port" format.
@@ -88,7 +165,7 @@ pub struct KafkaConfig {
 }
 
 /// Webhook types that shipcat might trigger after actions
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(tag = "name", deny_unknown_fields, rename_all = "snake_case")]
 pub enum Webhook {
     /// Audit webhook details
@@ -96,18 +173,19 @@ pub enum Webhook {
 }
 
 /// Where / how to send audited events
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct AuditWebhook {
     /// Endpoint
     #[serde(with = "url_serde")]
+    #[schemars(with = "String")]
     pub url: Url,
     /// Credential
     pub token: String,
 }
 
 /// Configure how CRs will be deployed on a region
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct CRSettings {
     #[serde(rename = "config")]
@@ -117,7 +195,7 @@ pub struct CRSettings {
 // ----------------------------------------------------------------------------------
 
 /// Kong configuration for a region
-#[derive(Serialize, Deserialize, Clone, Default)] // TODO: better Default impl
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)] // TODO: better Default impl
 #[serde(deny_unknown_fields)]
 pub struct KongConfig {
     /// Base URL to use (e.g. uk.dev.babylontech.co.uk)
@@ -127,6 +205,11 @@ pub struct KongConfig {
     /// Kong token expiration time (in seconds)
     pub kong_token_expiration: u32,
     pub oauth_provision_key: String,
+    /// Token for the admin api at `config_url`
+    ///
+    /// Sent as the `Kong-Admin-Token` header. Usually `IN_VAULT` (read from `{region}/kong/admin_token`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
     /// TCP logging options
     pub tcp_log: KongTcpLogConfig,
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -142,7 +225,7 @@ pub struct KongConfig {
 }
 
 /// StatusCake configuration for a region
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct StatuscakeConfig {
     /// Contact Group that will be used if tests go down
@@ -154,7 +237,7 @@ pub struct StatuscakeConfig {
 }
 
 /// Logz.io configuration for a region
-#[derive(Serialize, Deserialize, Clone, Default)] // TODO: better Default impl
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)] // TODO: better Default impl
 #[serde(deny_unknown_fields)]
 pub struct LogzIoConfig {
     /// Base URL to use (e.g. https://app-eu.logz.io/#/dashboard/kibana/dashboard)
@@ -164,7 +247,7 @@ pub struct LogzIoConfig {
 }
 
 /// Grafana details for a region
-#[derive(Serialize, Deserialize, Clone, Default)] // TODO: better Default impl
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)] // TODO: better Default impl
 #[serde(deny_unknown_fields)]
 pub struct GrafanaConfig {
     /// Base URL to use (e.g. https://dev-grafana.ops.babylontech.co.uk)
@@ -174,20 +257,32 @@ pub struct GrafanaConfig {
 }
 
 /// Sentry details for a region
-#[derive(Serialize, Deserialize, Clone, Default)] // TODO: better Default impl
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)] // TODO: better Default impl
 #[serde(deny_unknown_fields)]
 pub struct SentryConfig {
     /// Base URL to use (e.g. https://dev-uk-sentry.ops.babylontech.co.uk)
     pub url: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Resource prices for a region
+///
+/// Used to estimate monthly costs of the resources requested by services.
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PricingConfig {
+    /// Monthly price of a cpu core
+    pub cpu: f64,
+    /// Monthly price of a GiB of memory
+    pub memory: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct KongAnonymousConsumers {
     pub anonymous: BTreeMap<String, String>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct KongOauthConsumer {
     pub oauth_client_id: String,
@@ -195,7 +290,7 @@ pub struct KongOauthConsumer {
     pub username: String
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct KongJwtConsumer {
     pub issuer: String,
@@ -203,7 +298,7 @@ pub struct KongJwtConsumer {
 }
 
 impl KongConfig {
-    fn secrets(&mut self, vault: &Vault, region: &str) -> Result<()> {
+    fn secrets(&mut self, vault: &dyn SecretBackend, region: &str) -> Result<()> {
         for (svc, data) in &mut self.consumers {
             if data.oauth_client_id == "IN_VAULT" {
                 let vkey = format!("{}/kong/consumers/{}_oauth_client_id", region, svc);
@@ -218,9 +313,21 @@ impl KongConfig {
             let vkey = format!("{}/kong/oauth_provision_key", region);
             self.oauth_provision_key = vault.read(&vkey)?;
         }
+        if let Some(token) = &mut self.admin_token {
+            if token == "IN_VAULT" {
+                let vkey = format!("{}/kong/admin_token", region);
+                *token = vault.read(&vkey)?;
+            }
+        }
         Ok(())
     }
-    fn verify_secrets_exist(&self, vault: &Vault, region: &str) -> Result<()> {
+    fn verify_secrets_exist(&self, vault: &dyn SecretBackend, region: &str) -> Result<()> {
+        if self.admin_token.as_ref().map(String::as_str) == Some("IN_VAULT") {
+            let secpth = format!("{}/kong", region);
+            if let Some(v) = vault.missing(&secpth, &["admin_token".to_string()])?.first() {
+                bail!("Kong secret {} not found in {} secrets", v, region);
+            }
+        }
         let mut expected = vec![];
         for (svc, data) in &self.consumers {
             if data.oauth_client_id == "IN_VAULT" {
@@ -234,18 +341,14 @@ impl KongConfig {
             return Ok(()); // no point trying to cross reference
         }
         let secpth = format!("{}/kong/consumers", region);
-        let found = vault.list(&secpth)?;
-        debug!("Found kong secrets {:?} for {}", found, region);
-        for v in expected {
-            if !found.contains(&v) {
-                bail!("Kong secret {} not found in {} vault", v, region);
-            }
+        if let Some(v) = vault.missing(&secpth, &expected)?.first() {
+            bail!("Kong secret {} not found in {} secrets", v, region);
         }
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct KongTcpLogConfig {
     pub enabled: bool,
@@ -260,7 +363,7 @@ impl KongConfig {
 }
 
 impl Webhook {
-    fn secrets(&mut self, vault: &Vault, region: &str) -> Result<()> {
+    fn secrets(&mut self, vault: &dyn SecretBackend, region: &str) -> Result<()> {
         match self {
             Webhook::Audit(h) => {
                 if h.token == "IN_VAULT" {
@@ -272,7 +375,7 @@ impl Webhook {
         Ok(())
     }
 
-    fn verify_secrets_exist(&self, vault: &Vault, region: &str) -> Result<()> {
+    fn verify_secrets_exist(&self, vault: &dyn SecretBackend, region: &str) -> Result<()> {
         match self {
             Webhook::Audit(_h) => {
                 let vkey = format!("{}/shipcat/WEBHOOK_AUDIT_TOKEN", region);
@@ -377,7 +480,7 @@ mod test_webhooks {
 ///
 /// Either it's a pure kubernetes context with a namespace and a cluster,
 /// or it's an abstract concept with many associated real kubernetes contexts.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[cfg_attr(test, derive(Default))]
 #[serde(deny_unknown_fields)]
 pub struct Region {
@@ -389,6 +492,9 @@ pub struct Region {
     pub environment: String,
     /// Versioning scheme
     pub versioningScheme: VersionScheme,
+    /// Helm backend used for releases in the region
+    #[serde(default)]
+    pub helm: HelmConfig,
 
     /// Important base urls that can be templated in evars
     #[serde(default)]
@@ -417,6 +523,9 @@ pub struct Region {
     pub grafana: Option<GrafanaConfig>,
     /// Sentry URL for the region
     pub sentry: Option<SentryConfig>,
+    /// Resource prices for cost estimates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pricing: Option<PricingConfig>,
     /// List of locations the region serves
     #[serde(default)]
     pub locations: Vec<String>,
@@ -429,11 +538,11 @@ pub struct Region {
 impl Region {
     // Internal secret populator for Config::new
     pub fn secrets(&mut self) -> Result<()> {
-        let v = Vault::regional(&self.vault)?;
-        self.kong.secrets(&v, &self.name)?;
+        let v = self.vault.secret_backend()?;
+        self.kong.secrets(v.as_ref(), &self.name)?;
         if let Some(ref mut whs) = &mut self.webhooks {
             for wh in whs.iter_mut() {
-                wh.secrets(&v, &self.name)?;
+                wh.secrets(v.as_ref(), &self.name)?;
             }
         }
         Ok(())
@@ -441,12 +550,12 @@ impl Region {
 
     // Entry point for region verifier
     pub fn verify_secrets_exist(&self) -> Result<()> {
-        let v = Vault::regional(&self.vault)?;
+        let v = self.vault.secret_backend()?;
         debug!("Validating kong secrets for {}", self.name);
-        self.kong.verify_secrets_exist(&v, &self.name)?;
+        self.kong.verify_secrets_exist(v.as_ref(), &self.name)?;
         if let Some(whs) = &self.webhooks {
             for wh in whs.iter() {
-                wh.verify_secrets_exist(&v, &self.name)?;
+                wh.verify_secrets_exist(v.as_ref(), &self.name)?;
             }
         }
         Ok(())
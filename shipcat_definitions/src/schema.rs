@@ -0,0 +1,224 @@
+use std::collections::BTreeSet;
+
+use schemars::JsonSchema;
+use schemars::gen::SchemaSettings;
+use schemars::schema::RootSchema;
+use serde_json::{Map, Value};
+
+use super::{Config, Manifest};
+
+/// JSON Schema for `shipcat.yml`
+///
+/// Descriptions come from the doc comments on the `Manifest` structs.
+pub fn manifest() -> RootSchema {
+    schema_for!(Manifest)
+}
+
+/// JSON Schema for `shipcat.conf`
+pub fn config() -> RootSchema {
+    schema_for!(Config)
+}
+
+/// Where the openapi generator puts its definitions
+const OPENAPI_DEFINITIONS: &str = "#/components/schemas/";
+
+/// Structural OpenAPI v3 schema of a type as it is serialized into a CRD
+///
+/// Kubernetes does not resolve `$ref`, so every definition is inlined,
+/// keywords that kube refuses in CRD validation are dropped,
+/// and unions are collapsed so that every node has a single type.
+pub fn openapi<T: JsonSchema>() -> Value {
+    let root = SchemaSettings::openapi3().into_generator().into_root_schema_for::<T>();
+    let mut defs = Map::new();
+    for (name, schema) in root.definitions {
+        defs.insert(name, serde_json::to_value(schema).expect("schemas serialize"));
+    }
+    // evars are serialized as a struct rather than the map they are read from
+    if defs.contains_key("EnvVars") {
+        defs.insert("EnvVars".into(), serialized_env_vars());
+    }
+    let mut schema = serde_json::to_value(root.schema).expect("schemas serialize");
+    inline(&mut schema, &defs);
+    schema
+}
+
+/// How `EnvVars` look after `Manifest::secrets` partitioned them
+fn serialized_env_vars() -> Value {
+    serde_json::json!({
+        "description": "Environment variables to inject, split into plain values and secret names",
+        "type": "object",
+        "properties": {
+            "plain": { "type": "object", "additionalProperties": { "type": "string" } },
+            "secrets": { "type": "array", "items": { "type": "string" } },
+        },
+    })
+}
+
+/// Replace a reference with its definition
+fn resolve(schema: &mut Value, defs: &Map<String, Value>) {
+    let def = schema.get("$ref")
+        .and_then(Value::as_str)
+        .and_then(|r| r.strip_prefix(OPENAPI_DEFINITIONS))
+        .and_then(|name| defs.get(name))
+        .cloned();
+    if let Some(def) = def {
+        *schema = def;
+    }
+}
+
+/// Inline all references and reduce a schema to what kube calls structural
+///
+/// The manifest structs are not recursive, so this terminates.
+fn inline(schema: &mut Value, defs: &Map<String, Value>) {
+    resolve(schema, defs);
+    let obj = match schema.as_object_mut() {
+        Some(obj) => obj,
+        None => return,
+    };
+    // documented fields wrap their reference in a single allOf
+    if matches!(obj.get("allOf"), Some(Value::Array(xs)) if xs.len() == 1) {
+        if let Some(Value::Array(mut xs)) = obj.remove("allOf") {
+            let mut inner = xs.remove(0);
+            resolve(&mut inner, defs);
+            if let Value::Object(inner) = inner {
+                for (k, v) in inner {
+                    obj.entry(k).or_insert(v);
+                }
+            }
+        }
+    }
+    // defaults need structural schemas, and set uniqueness is quadratic
+    obj.remove("default");
+    obj.remove("uniqueItems");
+    // kube treats these as mutually exclusive
+    if obj.contains_key("properties") && obj.get("additionalProperties") == Some(&Value::Bool(false)) {
+        obj.remove("additionalProperties");
+    }
+
+    if let Some(Value::Object(props)) = obj.get_mut("properties") {
+        for v in props.values_mut() {
+            inline(v, defs);
+        }
+    }
+    for key in &["items", "additionalProperties", "allOf", "anyOf", "oneOf"] {
+        match obj.get_mut(*key) {
+            Some(Value::Array(xs)) => xs.iter_mut().for_each(|x| inline(x, defs)),
+            Some(v) => inline(v, defs),
+            None => {}
+        }
+    }
+    merge_union(obj);
+}
+
+/// Collapse a union into a single node, as structural schemas need one type per node
+///
+/// Unions of objects (data carrying enums) become an object with the properties
+/// of every variant, and unions of one scalar type (documented unit enums) a single
+/// enum. String or integer unions are marked as kube's `IntOrString`, and anything
+/// else is left for shipcat to validate.
+fn merge_union(obj: &mut Map<String, Value>) {
+    let variants = match obj.remove("oneOf").or_else(|| obj.remove("anyOf")) {
+        Some(Value::Array(xs)) => xs,
+        _ => return,
+    };
+    let types = variants.iter()
+        .map(|v| v.get("type").and_then(Value::as_str))
+        .collect::<Option<BTreeSet<_>>>()
+        .unwrap_or_default();
+    match types.into_iter().collect::<Vec<_>>().as_slice() {
+        ["object"] => {
+            let mut props = Map::new();
+            for v in &variants {
+                if let Some(Value::Object(ps)) = v.get("properties") {
+                    for (k, p) in ps {
+                        props.entry(k.clone()).or_insert_with(|| p.clone());
+                    }
+                }
+            }
+            obj.insert("type".into(), "object".into());
+            obj.insert("properties".into(), Value::Object(props));
+        }
+        [scalar] => {
+            if variants.iter().all(|v| v.get("enum").is_some()) {
+                let values = variants.iter()
+                    .filter_map(|v| v["enum"].as_array())
+                    .flatten()
+                    .cloned()
+                    .collect();
+                obj.insert("enum".into(), Value::Array(values));
+            }
+            obj.insert("type".into(), scalar.to_string().into());
+        }
+        ["integer", "string"] => {
+            obj.insert("x-kubernetes-int-or-string".into(), true.into());
+        }
+        _ => {
+            obj.insert("x-kubernetes-preserve-unknown-fields".into(), true.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{manifest, openapi};
+    use crate::{Config, Manifest};
+    use serde_json::{json, Value};
+
+    /// Every node has a single type (or a kube extension instead)
+    fn assert_structural(schema: &Value, path: &str) {
+        for key in &["$ref", "allOf", "anyOf", "oneOf"] {
+            assert!(schema.get(key).is_none(), "{} has {}", path, key);
+        }
+        let flagged = schema.get("x-kubernetes-int-or-string").is_some()
+            || schema.get("x-kubernetes-preserve-unknown-fields").is_some();
+        assert!(flagged || schema.get("type").is_some(), "{} has no type", path);
+        if let Some(props) = schema.get("properties").and_then(Value::as_object) {
+            for (k, v) in props {
+                assert_structural(v, &format!("{}.{}", path, k));
+            }
+        }
+        for key in &["items", "additionalProperties"] {
+            if let Some(v) = schema.get(key).filter(|v| v.is_object()) {
+                assert_structural(v, &format!("{}[]", path));
+            }
+        }
+    }
+
+    #[test]
+    fn manifest_schema() {
+        let schema = serde_json::to_value(manifest()).unwrap();
+        let engines = &schema["definitions"]["RdsEngine"]["enum"];
+        assert_eq!(engines, &json!(["postgres", "mysql"]));
+        let classes = schema["definitions"]["InstanceClass"]["enum"].as_array().unwrap();
+        assert!(classes.contains(&json!("db.t3.small")));
+        let name = &schema["properties"]["name"]["description"];
+        assert!(name.as_str().unwrap().starts_with("Name of the service"));
+        let env = &schema["definitions"]["EnvVars"];
+        assert_eq!(env["additionalProperties"]["type"], json!("string"));
+    }
+
+    #[test]
+    fn manifest_openapi_is_inlined() {
+        let schema = openapi::<Manifest>();
+        assert!(!schema.to_string().contains("$ref"));
+        let env = &schema["properties"]["env"];
+        assert_eq!(env["properties"]["plain"]["type"], json!("object"));
+        assert!(env["description"].as_str().unwrap().starts_with("Environment variables"));
+        let engine = &schema["properties"]["database"]["properties"]["engine"];
+        assert_eq!(engine["enum"], json!(["postgres", "mysql"]));
+        // deny_unknown_fields does not survive next to properties
+        assert!(schema.get("additionalProperties").is_none());
+    }
+
+    #[test]
+    fn crd_schemas_are_structural() {
+        let mf = openapi::<Manifest>();
+        assert_structural(&mf, "spec");
+        assert_structural(&openapi::<Config>(), "spec");
+
+        let surge = &mf["properties"]["rollingUpdate"]["properties"]["maxSurge"];
+        assert_eq!(surge["x-kubernetes-int-or-string"], json!(true));
+        let scheme = &openapi::<Config>()["properties"]["regions"]["items"]["properties"]["versioningScheme"];
+        assert_eq!(scheme["enum"], json!(["Semver", "GitShaOrSemver"]));
+    }
+}
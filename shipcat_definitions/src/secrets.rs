@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+use super::{Result, ErrorKind, ResultExt};
+use crate::vault::{Vault, Mode, MOCKED_SECRET};
+
+/// A store that `IN_VAULT` secrets can be read from
+///
+/// Keys are slash separated paths, like `{folder}/{service}/{KEY}`.
+pub trait SecretBackend {
+    /// Read a single secret
+    fn read(&self, key: &str) -> Result<String>;
+
+    /// Read a secret at a version, returning the version that was read
+    ///
+    /// Backends without versioned secrets return no version,
+    /// and can only read the latest value.
+    fn read_version(&self, key: &str, version: &SecretVersion) -> Result<(String, Option<u32>)> {
+        match version {
+            SecretVersion::Latest => Ok((self.read(key)?, None)),
+            SecretVersion::Pinned(n) => bail!("{} is pinned to version {}, but the secret backend is not versioned", key, n),
+        }
+    }
+
+    /// List the secret names directly under a path
+    fn list(&self, path: &str) -> Result<Vec<String>>;
+
+    /// List the sub folders directly under a path
+    fn folders(&self, path: &str) -> Result<Vec<String>> {
+        bail!("The secret backend can not list folders under {}", path)
+    }
+
+    /// Whether the backend returns real secrets or dummies
+    fn mode(&self) -> Mode {
+        Mode::Standard
+    }
+
+    /// The names out of `keys` that do not exist under a path
+    ///
+    /// Lists the path once by default.
+    fn missing(&self, path: &str, keys: &[String]) -> Result<Vec<String>> {
+        let found = self.list(path)?;
+        Ok(keys.iter().filter(|k| !found.contains(k)).cloned().collect())
+    }
+}
+
+impl SecretBackend for Vault {
+    fn read(&self, key: &str) -> Result<String> {
+        Vault::read(self, key)
+    }
+    fn read_version(&self, key: &str, version: &SecretVersion) -> Result<(String, Option<u32>)> {
+        Vault::read_version(self, key, version)
+    }
+    fn list(&self, path: &str) -> Result<Vec<String>> {
+        Vault::list(self, path)
+    }
+    fn folders(&self, path: &str) -> Result<Vec<String>> {
+        Vault::list_folders(self, path)
+    }
+    fn mode(&self) -> Mode {
+        Vault::mode(self)
+    }
+}
+
+/// The version of a secret that a placeholder asks for
+///
+/// Placeholders are either `IN_VAULT` for the latest version,
+/// or `IN_VAULT@3` to pin version 3 (requires a versioned backend like vault KV v2).
+#[derive(Clone, Debug, PartialEq)]
+pub enum SecretVersion {
+    Latest,
+    Pinned(u32),
+}
+
+impl SecretVersion {
+    /// Whether a value is meant as a secret placeholder
+    pub fn is_placeholder(value: &str) -> bool {
+        value == "IN_VAULT" || value.starts_with("IN_VAULT@")
+    }
+
+    /// Parse a secret placeholder
+    ///
+    /// Returns `None` for plain values, and errors on invalid versions.
+    pub fn from_placeholder(value: &str) -> Result<Option<SecretVersion>> {
+        if value == "IN_VAULT" {
+            return Ok(Some(SecretVersion::Latest));
+        }
+        if let Some(v) = value.strip_prefix("IN_VAULT@") {
+            return match v.parse::<u32>() {
+                Ok(n) if n > 0 => Ok(Some(SecretVersion::Pinned(n))),
+                _ => bail!("Invalid secret version in {} - versions are positive integers", value),
+            };
+        }
+        Ok(None)
+    }
+}
+
+/// Where the secrets of a region are read from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    /// Hashicorp Vault KV at the configured url
+    Vault,
+    /// A local yaml file, optionally encrypted
+    File {
+        /// Path relative to the manifests directory
+        path: String,
+        /// How the file is encrypted
+        #[serde(default)]
+        encryption: FileEncryption,
+    },
+    /// Environment variables named after the secret paths
+    Env {
+        /// Prefix of all variable names
+        #[serde(default = "default_env_prefix")]
+        prefix: String,
+    },
+}
+
+impl Default for SecretSource {
+    fn default() -> SecretSource {
+        SecretSource::Vault
+    }
+}
+
+fn default_env_prefix() -> String {
+    "SHIPCAT_SECRET_".into()
+}
+
+/// Encryption of a secret file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FileEncryption {
+    /// Plaintext yaml
+    None,
+    /// Decrypted with `sops -d`, using whatever keys sops is configured with
+    Sops,
+    /// Decrypted with `age -d` using the identity file in `SHIPCAT_AGE_IDENTITY`
+    Age,
+}
+
+impl Default for FileEncryption {
+    fn default() -> FileEncryption {
+        FileEncryption::None
+    }
+}
+
+/// Dummy backend returning the same value for every secret
+///
+/// Used for stubbed manifests, so they never need access to real secrets.
+pub struct Mocked;
+
+impl SecretBackend for Mocked {
+    fn read(&self, _key: &str) -> Result<String> {
+        Ok(MOCKED_SECRET.into())
+    }
+    fn read_version(&self, _key: &str, _version: &SecretVersion) -> Result<(String, Option<u32>)> {
+        Ok((MOCKED_SECRET.into(), None))
+    }
+    fn list(&self, _path: &str) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+    fn folders(&self, _path: &str) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+    fn mode(&self) -> Mode {
+        Mode::Mocked
+    }
+}
+
+/// Secrets from a local yaml file
+///
+/// The file is either a flat map of secret paths to values,
+/// or nested maps with one level per path segment:
+///
+/// ```yaml
+/// dev-uk:
+///   test-shipcat:
+///     FAKE_SECRET: hello
+///     FAKE_NUMBER: -2
+/// ```
+///
+/// Encrypted files are decrypted in memory when loaded.
+/// Clones share the decrypted secrets.
+#[derive(Clone)]
+pub struct SecretFile {
+    pub(crate) secrets: Arc<BTreeMap<String, String>>,
+}
+
+impl SecretFile {
+    /// Read and decrypt a secret file
+    pub fn load(pth: &Path, enc: &FileEncryption) -> Result<SecretFile> {
+        if !pth.is_file() {
+            bail!("Secret file {} does not exist", pth.display());
+        }
+        let file = pth.to_string_lossy().to_string();
+        let data = match enc {
+            FileEncryption::None => fs::read_to_string(pth)?,
+            FileEncryption::Sops => decrypt("sops", &["-d", &file])?,
+            FileEncryption::Age => {
+                let identity = env::var("SHIPCAT_AGE_IDENTITY")
+                    .chain_err(|| "SHIPCAT_AGE_IDENTITY must point to an age identity file")?;
+                decrypt("age", &["-d", "-i", &identity, &file])?
+            }
+        };
+        SecretFile::from_yaml(&data).chain_err(|| format!("Invalid secret file {}", pth.display()))
+    }
+
+    /// Parse secrets from plaintext yaml
+    pub fn from_yaml(data: &str) -> Result<SecretFile> {
+        let mut secrets = BTreeMap::new();
+        if data.trim().is_empty() {
+            return Ok(SecretFile { secrets: Arc::new(secrets) });
+        }
+        let root : serde_yaml::Value = serde_yaml::from_str(data)?;
+        flatten("", &root, &mut secrets)?;
+        Ok(SecretFile { secrets: Arc::new(secrets) })
+    }
+}
+
+fn flatten(prefix: &str, value: &serde_yaml::Value, res: &mut BTreeMap<String, String>) -> Result<()> {
+    use serde_yaml::Value;
+    match value {
+        Value::Mapping(m) => {
+            for (k, v) in m {
+                let key = match k {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    _ => bail!("Secret names must be strings (under '{}')", prefix),
+                };
+                let pth = if prefix.is_empty() { key } else { format!("{}/{}", prefix, key) };
+                flatten(&pth, v, res)?;
+            }
+        },
+        Value::String(s) => {
+            res.insert(prefix.to_string(), s.clone());
+        },
+        // integers coerced like in vault
+        Value::Number(n) => {
+            res.insert(prefix.to_string(), n.to_string());
+        },
+        _ => bail!("Secret {} must be a string or a number", prefix),
+    }
+    Ok(())
+}
+
+fn decrypt(bin: &str, args: &[&str]) -> Result<String> {
+    debug!("{} {}", bin, args.join(" "));
+    let s = Command::new(bin).args(args).output()
+        .chain_err(|| format!("Could not run {}", bin))?;
+    if !s.status.success() {
+        bail!("{} failed to decrypt secrets: {}", bin, String::from_utf8_lossy(&s.stderr));
+    }
+    String::from_utf8(s.stdout).chain_err(|| format!("{} output is not utf8", bin))
+}
+
+impl SecretBackend for SecretFile {
+    fn read(&self, key: &str) -> Result<String> {
+        self.secrets.get(key).cloned()
+            .ok_or_else(|| ErrorKind::SecretNotAccessible(key.into()).into())
+    }
+    fn list(&self, path: &str) -> Result<Vec<String>> {
+        let pfx = format!("{}/", path);
+        Ok(self.secrets.keys()
+            .filter_map(|k| k.strip_prefix(&pfx))
+            .filter(|k| !k.contains('/')) // skip sub folders
+            .map(String::from)
+            .collect())
+    }
+    fn folders(&self, path: &str) -> Result<Vec<String>> {
+        let pfx = format!("{}/", path);
+        let res : BTreeSet<String> = self.secrets.keys()
+            .filter_map(|k| k.strip_prefix(&pfx))
+            .filter_map(|k| k.split_once('/'))
+            .map(|(folder, _)| folder.to_string())
+            .collect();
+        Ok(res.into_iter().collect())
+    }
+}
+
+/// Secrets from environment variables
+///
+/// A secret path maps to a variable by upper casing it, replacing everything
+/// that is not alphanumeric with underscores, and adding a prefix.
+/// E.g. `dev-uk/fake-ask/FAKE_SECRET` is read from `SHIPCAT_SECRET_DEV_UK_FAKE_ASK_FAKE_SECRET`.
+pub struct EnvSecrets {
+    prefix: String,
+}
+
+impl EnvSecrets {
+    pub fn new(prefix: &str) -> EnvSecrets {
+        EnvSecrets { prefix: prefix.into() }
+    }
+
+    /// Variable name for a secret path
+    pub fn var_name(&self, key: &str) -> String {
+        let name : String = key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        format!("{}{}", self.prefix, name)
+    }
+}
+
+impl SecretBackend for EnvSecrets {
+    fn read(&self, key: &str) -> Result<String> {
+        let var = self.var_name(key);
+        env::var(&var).chain_err(|| ErrorKind::SecretNotAccessible(format!("{} (from ${})", key, var)))
+    }
+
+    /// Variable names cannot be mapped back to secret names,
+    /// so this lists the remainder of the variable names under the path.
+    fn list(&self, path: &str) -> Result<Vec<String>> {
+        let pfx = format!("{}_", self.var_name(path));
+        Ok(env::vars()
+            .filter_map(|(k, _)| k.strip_prefix(&pfx).map(String::from))
+            .filter(|k| !k.is_empty())
+            .collect())
+    }
+
+    fn missing(&self, path: &str, keys: &[String]) -> Result<Vec<String>> {
+        Ok(keys.iter()
+            .filter(|k| env::var(self.var_name(&format!("{}/{}", path, k))).is_err())
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SecretBackend, SecretFile, EnvSecrets, SecretVersion};
+    use std::env;
+
+    #[test]
+    fn secret_file_paths() {
+        let sf = SecretFile::from_yaml(r#"
+dev-uk:
+  test-shipcat:
+    FAKE_SECRET: hello
+    FAKE_NUMBER: -2
+    nested:
+      deeper: x
+dev-uk/kong/oauth_provision_key: flat
+"#).unwrap();
+        assert_eq!(sf.read("dev-uk/test-shipcat/FAKE_SECRET").unwrap(), "hello");
+        assert_eq!(sf.read("dev-uk/test-shipcat/FAKE_NUMBER").unwrap(), "-2");
+        assert_eq!(sf.read("dev-uk/kong/oauth_provision_key").unwrap(), "flat");
+        assert!(sf.read("dev-uk/test-shipcat/nested").is_err());
+
+        let mut keys = sf.list("dev-uk/test-shipcat").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["FAKE_NUMBER".to_string(), "FAKE_SECRET".to_string()]);
+        let expected = vec!["FAKE_SECRET".to_string(), "OTHER".to_string()];
+        assert_eq!(sf.missing("dev-uk/test-shipcat", &expected).unwrap(), vec!["OTHER".to_string()]);
+        assert_eq!(sf.folders("dev-uk").unwrap(), vec!["kong".to_string(), "test-shipcat".to_string()]);
+        assert_eq!(sf.folders("dev-uk/test-shipcat").unwrap(), vec!["nested".to_string()]);
+
+        assert!(SecretFile::from_yaml("dev-uk: [1, 2]").is_err());
+        assert!(SecretFile::from_yaml("").unwrap().list("dev-uk").unwrap().is_empty());
+    }
+
+    #[test]
+    fn secret_placeholders() {
+        assert_eq!(SecretVersion::from_placeholder("IN_VAULT").unwrap(), Some(SecretVersion::Latest));
+        assert_eq!(SecretVersion::from_placeholder("IN_VAULT@3").unwrap(), Some(SecretVersion::Pinned(3)));
+        assert_eq!(SecretVersion::from_placeholder("plain").unwrap(), None);
+        assert!(SecretVersion::from_placeholder("IN_VAULT@").is_err());
+        assert!(SecretVersion::from_placeholder("IN_VAULT@0").is_err());
+        assert!(SecretVersion::from_placeholder("IN_VAULT@latest").is_err());
+        assert!(SecretVersion::is_placeholder("IN_VAULT@x"));
+        assert!(!SecretVersion::is_placeholder("IN_VAULTS"));
+
+        // unversioned backends can only read the latest secret
+        let sf = SecretFile::from_yaml("dev-uk/svc/KEY: hi").unwrap();
+        assert_eq!(sf.read_version("dev-uk/svc/KEY", &SecretVersion::Latest).unwrap(), ("hi".to_string(), None));
+        assert!(sf.read_version("dev-uk/svc/KEY", &SecretVersion::Pinned(1)).is_err());
+    }
+
+    #[test]
+    fn env_secret_names() {
+        let es = EnvSecrets::new("SHIPCAT_TEST_SECRET_");
+        assert_eq!(es.var_name("dev-uk/fake-ask/FAKE_SECRET"), "SHIPCAT_TEST_SECRET_DEV_UK_FAKE_ASK_FAKE_SECRET");
+        env::set_var("SHIPCAT_TEST_SECRET_DEV_UK_FAKE_ASK_FAKE_SECRET", "hello");
+        assert_eq!(es.read("dev-uk/fake-ask/FAKE_SECRET").unwrap(), "hello");
+        assert!(es.read("dev-uk/fake-ask/MISSING").is_err());
+        let expected = vec!["FAKE_SECRET".to_string(), "fake-file".to_string()];
+        assert_eq!(es.missing("dev-uk/fake-ask", &expected).unwrap(), vec!["fake-file".to_string()]);
+    }
+}
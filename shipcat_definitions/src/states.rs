@@ -1,11 +1,10 @@
 use super::{Result, Manifest, Region};
-use super::vault::Vault;
 
 
 /// Various states a manifest can exist in depending on resolution.
 ///
 /// This only matters within shipcat and is used to optimize speed of accessors.
-#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, JsonSchema)]
 pub enum ManifestType {
     /// A completed manifest
     ///
@@ -83,8 +82,8 @@ impl Manifest {
     fn upgrade(mut self, reg: &Region, kind: ManifestType) -> Result<Self> {
         assert_eq!(self.kind, ManifestType::Base); // sanity
         let v = match kind {
-            ManifestType::Completed => Vault::regional(&reg.vault)?,
-            ManifestType::Stubbed => Vault::mocked(&reg.vault)?,
+            ManifestType::Completed => reg.vault.secret_backend()?,
+            ManifestType::Stubbed => reg.vault.mocked_backend()?,
             _ => bail!("Can only upgrade a Base manifest to Completed or Stubbed"),
         };
         // replace one-off templates in evar strings with values
@@ -92,7 +91,7 @@ impl Manifest {
         // secrets may be injected at this step from the Region
         self.template_evars(reg)?;
         // secrets before configs (.j2 template files use raw secret values)
-        self.secrets(&v, &reg.vault)?;
+        self.secrets(v.as_ref(), &reg.vault)?;
 
         // templates last
         self.template_configs(reg)?;
@@ -115,7 +114,7 @@ impl Manifest {
 /// Various states a Config can exist in depending on resolution.
 ///
 /// Within shipcat, this is used to optimize speed of accessors.
-#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, JsonSchema)]
 pub enum ConfigType {
     /// A filtered config for a specific region, with resolved secrets
     Filtered,
@@ -145,3 +144,21 @@ impl Default for ConfigType {
     #[cfg(not(feature = "filesystem"))]
     fn default() -> Self { ConfigType::Base }
 }
+
+/// The different states an upgrade can be in
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum UpgradeState {
+    /// Before action
+    Pending,
+    /// No errors
+    Completed,
+    /// Errors
+    Failed,
+    // Before revert
+    RollingBack,
+    // After revert
+    RolledBack,
+    // Fail to revert
+    RollbackFailed,
+}
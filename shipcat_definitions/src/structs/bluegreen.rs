@@ -0,0 +1,87 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::Result;
+
+/// The two releases of a blue/green service
+///
+/// Blue is the release named after the service, green is `{service}-green`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Colour {
+    Blue,
+    Green,
+}
+
+impl Default for Colour {
+    fn default() -> Colour {
+        Colour::Blue
+    }
+}
+
+impl fmt::Display for Colour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Colour::Blue => write!(f, "blue"),
+            Colour::Green => write!(f, "green"),
+        }
+    }
+}
+
+impl FromStr for Colour {
+    type Err = crate::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "blue" => Ok(Colour::Blue),
+            "green" => Ok(Colour::Green),
+            _ => bail!("unknown colour {}", s),
+        }
+    }
+}
+
+impl Colour {
+    /// The colour that is not this one
+    pub fn other(self) -> Colour {
+        match self {
+            Colour::Blue => Colour::Green,
+            Colour::Green => Colour::Blue,
+        }
+    }
+
+    /// Name of the release (and its kube objects) for this colour
+    pub fn release_name(self, svc: &str) -> String {
+        match self {
+            Colour::Blue => svc.to_string(),
+            Colour::Green => format!("{}-green", svc),
+        }
+    }
+}
+
+/// Blue/green deployment parameters
+///
+/// Every upgrade installs a full release of the idle colour next to the active one,
+/// and switches the kong api over to it once it is ready.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BlueGreen {
+    /// How long to wait for the idle release to become ready in seconds
+    ///
+    /// Defaults to the estimated wait time of a normal upgrade.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readyTimeout: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Colour;
+
+    #[test]
+    fn colour_releases() {
+        assert_eq!(Colour::default(), Colour::Blue);
+        assert_eq!(Colour::Blue.other(), Colour::Green);
+        assert_eq!(Colour::Green.release_name("fake-ask"), "fake-ask-green");
+        assert_eq!(Colour::Blue.release_name("fake-ask"), "fake-ask");
+        assert_eq!("green".parse::<Colour>().unwrap(), Colour::Green);
+        assert!("red".parse::<Colour>().is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use super::Result;
+
+/// A single step of a canary rollout
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryStep {
+    /// Percentage of replicas running the new version during this step
+    pub weight: u32,
+    /// How long to observe the canary in seconds before the next step
+    #[serde(default)]
+    pub pause: u32,
+}
+
+/// Health requirements canary pods must meet between steps
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryGate {
+    /// Maximum container restarts tolerated across all canary pods
+    #[serde(default)]
+    pub maxRestarts: u32,
+    /// How long to wait for canary pods to become ready in seconds
+    ///
+    /// Defaults to the estimated wait time of a normal upgrade.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readyTimeout: Option<u32>,
+}
+
+/// Progressive canary rollout parameters
+///
+/// A canary `Deployment` running the new version is created next to the stable one,
+/// and replicas are shifted to it step by step. The health gate is checked
+/// after every step, and the canary is removed again if it fails.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Canary {
+    /// Steps with increasing weights
+    pub steps: Vec<CanaryStep>,
+    /// Health gate for canary pods
+    #[serde(default)]
+    pub healthGate: CanaryGate,
+}
+
+impl Canary {
+    pub fn verify(&self) -> Result<()> {
+        if self.steps.is_empty() {
+            bail!("canary needs at least one step");
+        }
+        let mut prev = 0;
+        for s in &self.steps {
+            if s.weight <= prev {
+                bail!("canary step weights must be increasing (got {} after {})", s.weight, prev);
+            }
+            if s.weight >= 100 {
+                bail!("canary step weights must be below 100 (full promotion is implicit)");
+            }
+            if s.pause > 3600 {
+                bail!("canary pause of {}s is more than an hour", s.pause);
+            }
+            prev = s.weight;
+        }
+        Ok(())
+    }
+
+    /// Number of canary replicas for a step out of a total
+    ///
+    /// Always at least one, and always leaves one stable replica when possible.
+    /// Shifting needs a total of at least two replicas.
+    pub fn replicas(weight: u32, total: u32) -> u32 {
+        let n = ((total * weight) as f64 / 100.0).ceil() as u32;
+        std::cmp::max(1, std::cmp::min(n, total.saturating_sub(1)))
+    }
+
+    /// Number of canary replicas for a step run next to all stable replicas
+    ///
+    /// Used for autoscaled services, whose stable replicas belong to the autoscaler.
+    /// Always at least one, so the canary gets a smaller share than its weight.
+    pub fn extra_replicas(weight: u32, total: u32) -> u32 {
+        let n = ((total * weight) as f64 / 100.0).ceil() as u32;
+        std::cmp::max(1, n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Canary, CanaryStep, CanaryGate};
+
+    #[test]
+    fn canary_verify_and_replicas() {
+        let step = |weight, pause| CanaryStep { weight, pause };
+        let c = Canary { steps: vec![step(10, 60), step(50, 0)], healthGate: CanaryGate::default() };
+        assert!(c.verify().is_ok());
+        let unordered = Canary { steps: vec![step(50, 0), step(10, 0)], healthGate: CanaryGate::default() };
+        assert!(unordered.verify().is_err());
+        let full = Canary { steps: vec![step(100, 0)], healthGate: CanaryGate::default() };
+        assert!(full.verify().is_err());
+        assert!(Canary { steps: vec![], healthGate: CanaryGate::default() }.verify().is_err());
+
+        assert_eq!(Canary::replicas(10, 10), 1);
+        assert_eq!(Canary::replicas(10, 4), 1);
+        assert_eq!(Canary::replicas(50, 4), 2);
+        assert_eq!(Canary::replicas(90, 4), 3);
+        assert_eq!(Canary::replicas(50, 1), 1);
+        assert_eq!(Canary::extra_replicas(10, 1), 1);
+        assert_eq!(Canary::extra_replicas(50, 4), 2);
+        assert_eq!(Canary::extra_replicas(90, 4), 4);
+    }
+}
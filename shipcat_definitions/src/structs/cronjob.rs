@@ -4,7 +4,7 @@ use crate::structs::resources::Resources;
 use super::EnvVars;
 use super::Result;
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct CronJobVolumeClaim {
     /// The cron job name
@@ -12,7 +12,7 @@ pub struct CronJobVolumeClaim {
     pub mountPath: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct CronJob {
     /// The cron job name
@@ -4,7 +4,7 @@ use super::Result;
 /// Supported dependency protocols
 ///
 /// Forces lowercase values of this enum to be used
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum DependencyProtocol {
     /// HTTP REST dependency
@@ -25,7 +25,7 @@ impl Default for DependencyProtocol {
 }
 
 /// Dependency of a service
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Dependency {
     /// Name of service relied upon (used to goto dependent manifest)
@@ -60,3 +60,24 @@ impl Dependency {
         Ok(())
     }
 }
+
+/// A dependency as an edge in a dependency graph
+///
+/// The dependent service and the service relied upon are the ends of the edge.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DepEdge {
+    pub api: String,
+    pub contract: Option<String>,
+    pub protocol: DependencyProtocol,
+    pub intent: Option<String>,
+}
+impl DepEdge {
+    pub fn new(dep: &Dependency) -> Self {
+        DepEdge {
+            api: dep.api.clone(),
+            contract: dep.contract.clone(),
+            protocol: dep.protocol.clone(),
+            intent: dep.intent.clone(),
+        }
+    }
+}
@@ -9,7 +9,7 @@ use super::Metadata;
 ///
 /// Subset of the official [AWS ElastiCache node type list](https://aws.amazon.com/elasticache/pricing/).
 /// Only current generation (m5 + r5) + along with cheap t2 nodes
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
 pub enum NodeType {
     // Cheap t2 nodes
     #[serde(rename = "cache.t2.micro")]
@@ -47,7 +47,7 @@ pub enum NodeType {
 /// - [aws elasticache cluster replication](https://docs.aws.amazon.com/AmazonElastiCache/latest/red-ug/Replication.Redis-RedisCluster.html)
 /// - [aws elasticache cluster replication groups](https://docs.aws.amazon.com/AmazonElastiCache/latest/red-ug/Replication.CreatingReplGroup.ExistingCluster.html)
 /// - [terraform aws_elasticache_replication_group](https://www.terraform.io/docs/providers/aws/r/elasticache_replication_group.html)
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ElastiCache {
     /// Name of service (filled from manifest name)
     #[serde(skip_deserializing)]
@@ -2,10 +2,13 @@ use super::Result;
 use std::collections::{BTreeMap, BTreeSet};
 use std::mem;
 
+use crate::secrets::SecretVersion;
+
 /// Environment variables to inject
 ///
 /// These have a few special convenience behaviours:
 /// "IN_VAULT" values is replaced with value from vault/secret/folder/service/KEY
+/// "IN_VAULT@3" values pin version 3 of the secret (vault KV v2 only)
 /// One off `tera` templates are calculated with a limited template context
 ///
 /// IN_VAULT secrets will all be put in a single kubernetes `Secret` object.
@@ -19,6 +22,9 @@ use std::mem;
 ///   # vault lookup:
 ///   DATABASE_URL: IN_VAULT
 ///
+///   # pinned vault lookup:
+///   API_KEY: IN_VAULT@3
+///
 ///   # templated evars:
 ///   INTERNAL_AUTH_URL: "{{ base_urls.services }}/auth/internal"
 ///   AUTH_ID: "{{ kong.consumers['webapp'].oauth_client_id }}"
@@ -48,8 +54,8 @@ pub struct EnvVars {
 
 
 impl EnvVars {
-    fn is_vault_secret(value: &String) -> bool {
-        value == "IN_VAULT"
+    fn is_vault_secret(value: &str) -> bool {
+        SecretVersion::is_placeholder(value)
     }
 
     fn template_secret_value(value: &String) -> Option<String> {
@@ -67,23 +73,27 @@ impl EnvVars {
                 bail!("Env vars need to be uppercase, found: {}", k);
             }
         }
+        for v in self.plain.values() {
+            SecretVersion::from_placeholder(v)?;
+        }
         Ok(())
     }
 
-    // Remove variables with a value "IN_VAULT", mark them as a secret and return them.
-    pub fn vault_secrets(&mut self) -> BTreeSet<String> {
+    // Remove variables with a value "IN_VAULT", mark them as a secret and return them with their versions.
+    pub fn vault_secrets(&mut self) -> Result<BTreeMap<String, SecretVersion>> {
         let mut plain = BTreeMap::new();
-        let mut vs = BTreeSet::new();
+        let mut vs = BTreeMap::new();
         for (k, v) in self.plain.iter() {
-            if EnvVars::is_vault_secret(&v) {
-                vs.insert(k.to_string());
+            if EnvVars::is_vault_secret(v) {
+                let version = SecretVersion::from_placeholder(v)?.expect("placeholder is a vault secret");
+                vs.insert(k.to_string(), version);
                 self.secrets.insert(k.to_string());
             } else {
                 plain.insert(k.to_string(), v.to_string());
             }
         }
         mem::replace(&mut self.plain, plain);
-        vs
+        Ok(vs)
     }
 
     // Remove secrets generated from templates from the plain variables, mark them as a secret and return them.
@@ -106,6 +116,26 @@ impl EnvVars {
     }
 }
 
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+
+/// Manifests write evars as a plain map
+///
+/// CRDs hold the serialized struct instead (see `schema::openapi`).
+impl JsonSchema for EnvVars {
+    fn schema_name() -> String {
+        "EnvVars".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = <BTreeMap<String, String>>::json_schema(gen).into_object();
+        schema.metadata().description = Some("Environment variables to inject\n\n\
+            Values of `IN_VAULT` (or `IN_VAULT@{version}`) are read from vault.".into());
+        schema.into()
+    }
+}
+
 #[cfg(feature = "filesystem")]
 use serde::de::{Deserialize, Deserializer};
 
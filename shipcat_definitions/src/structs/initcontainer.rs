@@ -1,7 +1,7 @@
 use regex::Regex;
 use super::Result;
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct InitContainer {
     pub name: String,
     pub image: String,
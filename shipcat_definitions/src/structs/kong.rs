@@ -1,9 +1,10 @@
 use super::{Result, Region};
+use super::bluegreen::Colour;
 use std::ops::Not;
 use std::collections::BTreeMap;
 
 /// Kong setup for a service
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Kong {
     /// Auto-populated name of service
@@ -152,7 +153,7 @@ impl Kong {
         }
         // Generate upstream_url for an in-kubernetes service
         if self.upstream_url.is_empty() {
-          self.upstream_url = format!("http://{}.{}.svc.cluster.local", self.name, reg.namespace);
+          self.upstream_url = Kong::default_upstream(&self.name, &reg.namespace);
         }
 
         if tophosts.is_empty() {
@@ -165,6 +166,20 @@ impl Kong {
         }
     }
 
+    /// Upstream url of an in-kubernetes service
+    pub fn default_upstream(svc: &str, namespace: &str) -> String {
+        format!("http://{}.{}.svc.cluster.local", svc, namespace)
+    }
+
+    /// Point a generated upstream_url at the release of a blue/green colour
+    ///
+    /// Explicitly configured upstreams are left alone.
+    pub fn set_colour(&mut self, namespace: &str, colour: Colour) {
+        if self.upstream_url == Kong::default_upstream(&self.name, namespace) {
+            self.upstream_url = Kong::default_upstream(&colour.release_name(&self.name), namespace);
+        }
+    }
+
     /// Merge in fields from an override, if they're set
     pub fn merge(&mut self, other: Kong) {
         if let Some(cors) = other.cors {
@@ -179,7 +194,7 @@ impl Kong {
 fn preserve_host_default() -> bool { true }
 
 /// Cors plugin data
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Cors {
     pub credentials: bool,
@@ -193,7 +208,7 @@ pub struct Cors {
 }
 
 /// Babylon Auth Header plugin data
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct BabylonAuthHeader {
     pub auth_service: String,
@@ -230,7 +245,7 @@ impl Kong {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 pub enum Authentication {
     None,
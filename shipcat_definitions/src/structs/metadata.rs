@@ -5,7 +5,7 @@ use super::Result;
 use crate::config::{Team, SlackParameters};
 
 /// Contact data
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Contact {
     /// Free text name
     pub name: String,
@@ -40,7 +40,7 @@ impl Contact {
 }
 
 /// Slack channel verifier
-#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug, JsonSchema)]
 pub struct SlackChannel(String);
 impl SlackChannel {
     pub fn new(chan: &str) -> Self {
@@ -76,7 +76,7 @@ impl DerefMut for SlackChannel {
 }
 
 /// Metadata for a service
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[cfg_attr(test, derive(Default))]
 #[serde(deny_unknown_fields)]
 pub struct Metadata {
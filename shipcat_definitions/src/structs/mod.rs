@@ -10,7 +10,7 @@ pub use super::Manifest;
 // Structs that exist in the manifest
 
 mod dependency;
-pub use self::dependency::{Dependency, DependencyProtocol};
+pub use self::dependency::{Dependency, DependencyProtocol, DepEdge};
 
 mod worker;
 pub use self::worker::Worker;
@@ -47,7 +47,7 @@ pub use self::env::EnvVars;
 // translations - these are typically inlined in templates as yaml
 /// Kubernetes resource structs
 mod resources;
-pub use self::resources::Resources;
+pub use self::resources::{Resources, ResourceRequest, ResourceLimit};
 pub use self::resources::parse_memory;
 /// Kubernetes volumes
 pub mod volume;
@@ -64,6 +64,12 @@ pub use self::probes::Probe;
 /// Kubernetes rolling-update settings
 pub mod rollingupdate;
 pub use self::rollingupdate::RollingUpdate;
+/// Progressive canary rollouts
+pub mod canary;
+pub use self::canary::Canary;
+/// Blue/green releases
+pub mod bluegreen;
+pub use self::bluegreen::{BlueGreen, Colour};
 /// Kubernetes horizontal pod autoscaler
 pub mod autoscaling;
 /// Kuberneter tolerations
@@ -1,6 +1,6 @@
 use super::Result;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum PortProtocol {
     Tcp,
@@ -13,7 +13,7 @@ impl Default for PortProtocol {
 }
 
 /// Port to open on a container
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct Port {
     /// Name of the port
     pub name: String,
@@ -4,7 +4,7 @@ use super::Result;
 ///
 /// Designed for services which requires escalated privileges
 /// Used to generate roles and role bindings in kubernetes
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Rbac {
     /// API groups containing resources (defined below)
@@ -15,7 +15,7 @@ pub struct Rbac {
     pub verbs: Vec<AllowedVerbs>
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum AllowedApiGroups {
     #[serde(rename = "")]
@@ -26,7 +26,7 @@ pub enum AllowedApiGroups {
     Babylontech,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum AllowedResources {
     Deployments,
@@ -52,7 +52,7 @@ pub enum AllowedResources {
 
 /// We don't allow eg Delete or other operations for security reasons (least privilege).
 /// More operations can be added if required but due diligence would be sane.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum AllowedVerbs {
     List,
@@ -4,7 +4,7 @@ use super::Metadata;
 /// Supported RDS engines
 ///
 /// Subset of the official [AWS RDS database engines](https://aws.amazon.com/rds/).
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum RdsEngine {
     Postgres,
@@ -15,7 +15,7 @@ pub enum RdsEngine {
 ///
 /// Subset of the official [AWS RDS instance type list](https://aws.amazon.com/rds/instance-types/).
 /// Current gen (m5 + t3) along with older m4 + t2.
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
 pub enum InstanceClass {
     // Burstable T2 instances for compat
     #[serde(rename = "db.t2.micro")]
@@ -61,7 +61,7 @@ pub enum InstanceClass {
 /// Simplified input for configuring a database for your service.
 /// Based loosely on the inputs from
 /// [terraform aws_db_instance](https://www.terraform.io/docs/providers/aws/r/db_instance.html).
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Rds {
     /// Name of service (filled from manifest name)
     #[serde(skip_deserializing)]
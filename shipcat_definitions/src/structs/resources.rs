@@ -10,7 +10,7 @@ use super::Result;
 // implemented to be a bit more useful, as well as some to convert between them.
 
 /// Kubernetes resource requests
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct ResourceRequest<T> {
     /// CPU request string
@@ -21,7 +21,7 @@ pub struct ResourceRequest<T> {
 }
 
 /// Kubernetes resource limits
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct ResourceLimit<T> {
     /// CPU limit string
@@ -34,7 +34,7 @@ pub struct ResourceLimit<T> {
 /// Kubernetes resources
 ///
 /// This can be inlined straight into a container spec at the moment
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Resources<T> {
     /// Resource requests for k8s
@@ -4,7 +4,7 @@ use super::Result;
 /// What sensitive data is managed and how
 ///
 /// See https://engineering.ops.babylontech.co.uk/docs/principles-security/
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct DataHandling {
     /// Where and how data is stored
@@ -24,7 +24,7 @@ impl DataHandling {
 }
 
 /// Data storage information and encryption information
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct DataStore {
     /// Storage type (one of "MySQL", "DynamoDB", "S3", "File", "Kafka")
@@ -83,7 +83,7 @@ impl DataStore {
 ///
 /// This is to indicate the canonical data type, not the actual field names.
 /// TODO: into Config!
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub enum DataFieldType {
     FullName,
     HomeAddress,
@@ -136,7 +136,7 @@ impl DataFieldType {
 
 
 /// Data storage information and encryption information
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct DataField {
     /// Canonical name of the data field
@@ -158,7 +158,7 @@ pub struct DataField {
 }
 
 /// Data storage information and encryption information
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct DataProcess {
     /// Canonical field name
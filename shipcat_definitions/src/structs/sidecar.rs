@@ -2,7 +2,7 @@ use super::{Resources};
 use super::env::EnvVars;
 use super::{Result};
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields, rename_all = "lowercase")]
 pub struct Sidecar {
   pub name: String,
@@ -1,4 +1,4 @@
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct VaultOpts {
     /// If Vault name differs from service name
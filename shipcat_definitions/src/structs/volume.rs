@@ -5,7 +5,7 @@ use std::collections::BTreeMap;
 // TODO: cross reference better with
 // https://kubernetes.io/docs/concepts/storage/volumes/
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct VolumeSecretItem {
     #[serde(default = "volume_key")]
     pub key: String,
@@ -16,35 +16,35 @@ pub struct VolumeSecretItem {
 fn volume_key() -> String { "value".into() }
 fn volume_default_mode() -> u32 { 420 } // 0644
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct VolumeSecretDetail {
     pub secretName: String,
     pub items: Vec<VolumeSecretItem>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct ProjectedVolumeSecretSourceDetail {
     pub name: String,
     pub items: Vec<VolumeSecretItem>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct ProjectedVolumeSecretSource {
     pub secret: ProjectedVolumeSecretSourceDetail,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct ProjectedVolumeSecret {
     pub sources: Vec<ProjectedVolumeSecretSource>,
     // pub default_mode: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct DownwardApiWrapper {
     pub items: Vec<DownwardApiItem>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct DownwardApiItem {
     /// Kube path to string
     pub path: String,
@@ -52,7 +52,7 @@ pub struct DownwardApiItem {
     pub resourceFieldRef: DownWardApiResource,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct DownWardApiResource {
     /// Name of container TODO: default to service name
     pub containerName: String,
@@ -63,7 +63,7 @@ pub struct DownWardApiResource {
     pub divisor: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct Volume {
     pub name: String,
     /// A projection combines multiple volume items
@@ -88,7 +88,7 @@ impl Volume {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct VolumeMount {
     pub name: String,
     pub mountPath: String,
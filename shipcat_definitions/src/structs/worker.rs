@@ -8,7 +8,7 @@ use super::Result;
 ///
 /// Essentially a side-car like object that can scale resources separately to the main pods.
 /// Useful for services that have one single side service that polls or does some work.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct Worker {
     /// Name of the worker
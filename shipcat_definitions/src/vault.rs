@@ -2,8 +2,11 @@ use std::collections::BTreeMap;
 use std::env;
 use std::io::Read;
 
+use serde::de::DeserializeOwned;
+
 use super::{Result, ErrorKind, ResultExt, Error};
 use crate::region::{VaultConfig};
+use crate::secrets::SecretVersion;
 
 fn default_addr() -> Result<String> {
     env::var("VAULT_ADDR").map_err(|_| ErrorKind::MissingVaultAddr.into())
@@ -70,12 +73,42 @@ struct Secret {
     lease_duration: u64,
 }
 
+/// Secret data retrieved from a KV v2 engine
+///
+/// The data is nested one level deeper, next to version metadata.
+#[derive(Debug, Deserialize)]
+struct SecretV2 {
+    data: VersionedSecret,
+}
+
+#[derive(Debug, Deserialize)]
+struct VersionedSecret {
+    data: BTreeMap<String, SecretValue>,
+    metadata: SecretMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+struct SecretMetadata {
+    version: u32,
+}
+
 /// List data retrieved from Vault when listing available secrets
 #[derive(Debug, Deserialize)]
 struct ListSecrets {
     data: BTreeMap<String, Vec<String>>
 }
 
+/// Response from a vault login
+#[derive(Debug, Deserialize)]
+struct Login {
+    auth: LoginAuth,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginAuth {
+    client_token: String,
+}
+
 /// Vault client with cached data
 pub struct Vault {
     /// Our HTTP client.  This can be configured to mock out the network.
@@ -86,8 +119,33 @@ pub struct Vault {
     token: String,
     /// Vault operation mode
     mode: Mode,
+    /// Version of the KV engine mounted at secret/
+    kv: KvVersion,
+}
+
+/// Version of the vault KV secret engine
+///
+/// KV v2 keeps a history of every secret, and moves secrets under
+/// `secret/data/` (values) and `secret/metadata/` (listing) in the HTTP api.
+/// Paths in shipcat are the same for both versions.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KvVersion {
+    V1,
+    V2,
 }
 
+impl Default for KvVersion {
+    fn default() -> KvVersion {
+        KvVersion::V1
+    }
+}
+
+/// Arbitrary base64 encoded value returned for mocked secrets
+///
+/// Base64 so that it is compatible with everything (including secretFiles).
+pub const MOCKED_SECRET: &str = "aGVsbG8gd29ybGQ=";
+
 /// Vault usage mode
 #[derive(PartialEq, Debug, Clone)]
 pub enum Mode {
@@ -100,33 +158,73 @@ pub enum Mode {
 impl Vault {
     /// Initialize using the same evars or token files that the `vault` CLI uses
     pub fn from_evars() -> Result<Vault> {
-        Vault::new(reqwest::Client::new(), &default_addr()?, default_token()?, Mode::Standard)
+        Vault::new(reqwest::Client::new(), &default_addr()?, default_token()?, Mode::Standard, KvVersion::V1)
     }
 
     /// Initialize using VAULT_TOKEN evar + addr in shipcat.conf
     pub fn regional(vc: &VaultConfig) -> Result<Vault> {
-        Vault::new(reqwest::Client::new(), &vc.url, default_token()?, Mode::Standard)
+        Vault::new(reqwest::Client::new(), &vc.url, default_token()?, Mode::Standard, vc.kvVersion.clone())
     }
 
     /// Initialize using dummy values and return garbage
     pub fn mocked(vc: &VaultConfig) -> Result<Vault> {
-        Vault::new(reqwest::Client::new(), &vc.url, default_token()?, Mode::Mocked)
+        Vault::new(reqwest::Client::new(), &vc.url, default_token()?, Mode::Mocked, vc.kvVersion.clone())
+    }
+
+    /// Log in to vault with a kubernetes service account token
+    ///
+    /// Uses the kubernetes auth method mounted at `auth/kubernetes`,
+    /// and returns a token with the policies vault binds to `role`.
+    pub fn kubernetes_login(vc: &VaultConfig, role: &str, jwt: &str) -> Result<String> {
+        let url = reqwest::Url::parse(&vc.url)?.join("v1/auth/kubernetes/login")?;
+        debug!("POST {}", url);
+
+        let mkerr = || ErrorKind::Url(url.clone());
+        let body = serde_json::json!({ "role": role, "jwt": jwt });
+        let mut res = reqwest::Client::new().post(url.clone())
+            .json(&body)
+            .send()
+            .chain_err(&mkerr)?;
+        if !res.status().is_success() {
+            let status = res.status().to_owned();
+            let err: Error = ErrorKind::UnexpectedHttpStatus(status).into();
+            return Err(err).chain_err(&mkerr);
+        }
+        let login : Login = res.json().chain_err(&mkerr)?;
+        Ok(login.auth.client_token)
     }
 
-    fn new<U, S>(client: reqwest::Client, addr: U, token: S, mode: Mode) -> Result<Vault>
+    fn new<U, S>(client: reqwest::Client, addr: U, token: S, mode: Mode, kv: KvVersion) -> Result<Vault>
         where U: reqwest::IntoUrl,
               S: Into<String>
     {
         let addr = addr.into_url()?;
-        Ok(Vault { client, addr, mode, token: token.into() })
+        Ok(Vault { client, addr, mode, kv, token: token.into() })
     }
 
     pub fn mode(&self) -> Mode {
         self.mode.clone()
     }
 
+    /// Api path of the value of a secret
+    fn data_path(&self, key: &str, version: &SecretVersion) -> String {
+        match (&self.kv, version) {
+            (KvVersion::V1, _) => format!("secret/{}", key),
+            (KvVersion::V2, SecretVersion::Latest) => format!("secret/data/{}", key),
+            (KvVersion::V2, SecretVersion::Pinned(n)) => format!("secret/data/{}?version={}", key, n),
+        }
+    }
+
+    /// Api path for listing a folder
+    fn metadata_path(&self, path: &str) -> String {
+        match self.kv {
+            KvVersion::V1 => format!("secret/{}", path),
+            KvVersion::V2 => format!("secret/metadata/{}", path),
+        }
+    }
+
     // The actual HTTP GET logic
-    fn get_secret(&self, path: &str) -> Result<Secret> {
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
         let url = self.addr.join(&format!("v1/{}", path))?;
         debug!("GET {}", url);
 
@@ -153,67 +251,105 @@ impl Vault {
     ///
     /// Does a HTTP LIST on the folder a service is in and returns the keys
     pub fn list(&self, path: &str) -> Result<Vec<String>> {
-        let url = self.addr.join(&format!("v1/secret/{}?list=true", path))?;
-        debug!("LIST {}", url);
-
-        let mkerr = || ErrorKind::Url(url.clone());
-        let mut res = self.client.get(url.clone())
-            .header("X-Vault-Token", self.token.clone())
-            .send()
-            .chain_err(&mkerr)?;
-
-        // Generate informative errors for HTTP failures, because these can
-        // be caused by everything from bad URLs to overly restrictive vault policies
-        if !res.status().is_success() {
-            let status = res.status().to_owned();
-            let err: Error = ErrorKind::UnexpectedHttpStatus(status).into();
-            return Err(err).chain_err(&mkerr);
-        }
-
-        let mut body = String::new();
-        res.read_to_string(&mut body)?;
-        let lsec : ListSecrets = serde_json::from_str(&body)?;
-        if !lsec.data.contains_key("keys") {
-            bail!("secret list {} does not contain keys list from vault api!?: {}", url, body);
-        }
-        let res = lsec.data["keys"].iter()
+        let res = self.list_entries(path)?.into_iter()
             .filter(|e| !e.ends_with('/')) // skip sub folders
-            .map(|e| e.to_string())
             .collect::<Vec<String>>();
         Ok(res)
     }
 
+    /// List sub folders
+    ///
+    /// Does a HTTP LIST on a folder and returns the folder names without trailing slashes
+    pub fn list_folders(&self, path: &str) -> Result<Vec<String>> {
+        let res = self.list_entries(path)?.into_iter()
+            .filter_map(|e| e.strip_suffix('/').map(String::from))
+            .collect::<Vec<String>>();
+        Ok(res)
+    }
+
+    fn list_entries(&self, path: &str) -> Result<Vec<String>> {
+        let pth = format!("{}?list=true", self.metadata_path(path));
+        let mut lsec : ListSecrets = self.get(&pth)?;
+        match lsec.data.remove("keys") {
+            Some(keys) => Ok(keys),
+            None => bail!("secret list {} does not contain keys list from vault api!?", pth),
+        }
+    }
+
 
     /// Read secret from a Vault via an authenticated HTTP GET (or memory cache)
     pub fn read(&self, key: &str) -> Result<String> {
-        let pth = format!("secret/{}", key);
+        Ok(self.read_version(key, &SecretVersion::Latest)?.0)
+    }
+
+    /// Read a secret at a version
+    ///
+    /// Returns the version that was read when using KV v2.
+    /// Pinned versions are not available in KV v1.
+    pub fn read_version(&self, key: &str, version: &SecretVersion) -> Result<(String, Option<u32>)> {
+        if let (KvVersion::V1, SecretVersion::Pinned(n)) = (&self.kv, version) {
+            bail!("{} is pinned to version {}, but vault KV v1 does not keep versions", key, n);
+        }
         if self.mode == Mode::Mocked {
-            // arbitrary base64 encoded value so it's compatible with everything
-            return Ok("aGVsbG8gd29ybGQ=".into());
+            let pinned = match version {
+                SecretVersion::Pinned(n) => Some(*n),
+                SecretVersion::Latest => None,
+            };
+            return Ok((MOCKED_SECRET.into(), pinned));
         }
 
-        let secret = self.get_secret(&pth).chain_err(|| ErrorKind::SecretNotAccessible(pth.clone()))?;
+        let pth = self.data_path(key, version);
+        let (data, read_version) = match self.kv {
+            KvVersion::V1 => {
+                let secret : Secret = self.get(&pth).chain_err(|| ErrorKind::SecretNotAccessible(pth.clone()))?;
+                (secret.data, None)
+            },
+            KvVersion::V2 => {
+                let secret : SecretV2 = self.get(&pth).chain_err(|| ErrorKind::SecretNotAccessible(pth.clone()))?;
+                (secret.data.data, Some(secret.data.metadata.version))
+            },
+        };
 
         // NB: Currently assume each path in vault has a single `value`
         // Read the value key (which should exist)
-        secret.data
-            .get("value")
-            .ok_or_else(|| { ErrorKind::InvalidSecretForm(pth).into() })
-            .map(|v| {
-                v.clone().into()
-            })
+        let value = data.get("value")
+            .ok_or_else(|| -> Error { ErrorKind::InvalidSecretForm(pth).into() })?;
+        Ok((value.clone().into(), read_version))
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::Vault;
+    use super::{Vault, KvVersion};
+    use crate::region::VaultConfig;
+    use crate::secrets::SecretVersion;
+    use crate::mockvault::{MockVault, MOCK_TOKEN};
+    use std::path::Path;
     use base64;
 
+    #[test]
+    fn kv_paths() {
+        let mut client = Vault::new(reqwest::Client::new(), "http://localhost:8200", "", super::Mode::Standard, KvVersion::V1).unwrap();
+        assert_eq!(client.data_path("dev-uk/svc/KEY", &SecretVersion::Latest), "secret/dev-uk/svc/KEY");
+        assert_eq!(client.metadata_path("dev-uk/svc"), "secret/dev-uk/svc");
+        assert!(client.read_version("dev-uk/svc/KEY", &SecretVersion::Pinned(2)).is_err());
+
+        client.kv = KvVersion::V2;
+        assert_eq!(client.data_path("dev-uk/svc/KEY", &SecretVersion::Latest), "secret/data/dev-uk/svc/KEY");
+        assert_eq!(client.data_path("dev-uk/svc/KEY", &SecretVersion::Pinned(2)), "secret/data/dev-uk/svc/KEY?version=2");
+        assert_eq!(client.metadata_path("dev-uk/svc"), "secret/metadata/dev-uk/svc");
+    }
+
+    fn dev_vault() -> (MockVault, Vault) {
+        let mock = MockVault::from_file(Path::new("../tests/secrets/dev-uk.yml"), KvVersion::V1).unwrap();
+        let client = Vault::new(reqwest::Client::new(), &mock.url(), "test-token", super::Mode::Standard, KvVersion::V1).unwrap();
+        (mock, client)
+    }
+
     #[test]
     fn get_dev_secret() {
-        let client = Vault::from_evars().unwrap();
+        let (_mock, client) = dev_vault();
         let secret = client.read("dev-uk/test-shipcat/FAKE_SECRET").unwrap();
         assert_eq!(secret, "hello");
 
@@ -230,17 +366,30 @@ mod tests {
         } else {
             assert!(false, "fake-file {} in vault is not base64 encoded", secretfile);
         }
+        assert!(client.read("dev-uk/test-shipcat/MISSING").is_err());
     }
 
     #[test]
     fn list_dev_secrets() {
-        let client = Vault::from_evars().unwrap();
+        let (_mock, client) = dev_vault();
         let mut secrets = client.list("dev-uk/test-shipcat").unwrap();
         secrets.sort_unstable(); // ignore key order
         assert_eq!(secrets, vec![
             "FAKE_NUMBER".to_string(),
             "FAKE_SECRET".to_string(),
+            "UNUSED".to_string(),
             "fake-file".to_string()
         ]);
+        let folders = client.list_folders("dev-uk").unwrap();
+        assert!(folders.contains(&"test-shipcat".to_string()));
+    }
+
+    #[test]
+    fn kubernetes_login() {
+        let (mock, _) = dev_vault();
+        let vc = VaultConfig { url: mock.url(), ..VaultConfig::default() };
+        let token = Vault::kubernetes_login(&vc, "shipcat-operator", "service-account-jwt").unwrap();
+        assert_eq!(token, MOCK_TOKEN);
+        assert!(Vault::kubernetes_login(&vc, "shipcat-operator", "").is_err());
     }
 }
@@ -0,0 +1,201 @@
+#![allow(non_snake_case)]
+#![warn(rust_2018_idioms)]
+
+#[macro_use] extern crate log;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+
+pub use shipcat::{Result, ResultExt, Error, ErrorKind};
+use shipcat::{Config, Manifest, Region};
+use shipcat::helm::{parallel, UpgradeMode};
+use shipcat::kube::{self, Client, Reflector};
+use shipcat_definitions::{Crd, SecretSource, UpgradeState, Vault};
+
+/// Token of the service account the operator runs as
+const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// Pause before listing again after a failed list or watch
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Whether a manifest changed since the operator last reconciled it
+///
+/// Only spec changes bump the generation, so status writes do not trigger reconciles.
+/// Failed reconciles are recorded as observed, and are not retried until the manifest changes.
+pub fn needs_reconcile(obj: &Crd<Manifest>) -> bool {
+    let observed = obj.status.as_ref().and_then(|s| s.observedGeneration);
+    observed != obj.metadata.generation
+}
+
+/// Reconciliation controller for the ShipcatManifests of a region
+///
+/// Keeps a reflector of all manifests, shared with raftcat.
+/// Changed manifests are completed with secrets and upgraded with helm, one at a time,
+/// and the outcome is written to their status.
+pub struct Operator {
+    client: Client,
+    /// Region the operator manages, and the name of its ShipcatConfig
+    region: String,
+    /// Namespace of the ShipcatManifests
+    namespace: String,
+    /// Vault role bound to the operator's service account
+    vaultRole: String,
+}
+
+impl Operator {
+    /// Configure an operator from the environment
+    ///
+    /// Needs `REGION_NAME` and `ENV_NAME` (the namespace) like raftcat,
+    /// and optionally `VAULT_ROLE` (defaulting to `shipcat-operator`).
+    pub fn from_env() -> Result<Operator> {
+        let region = env::var("REGION_NAME").chain_err(|| "Need REGION_NAME evar (kube context)")?;
+        let namespace = env::var("ENV_NAME").chain_err(|| "Need ENV_NAME evar (namespace)")?;
+        let vaultRole = env::var("VAULT_ROLE").unwrap_or_else(|_| "shipcat-operator".into());
+        Ok(Operator { client: Client::new()?, region, namespace, vaultRole })
+    }
+
+    /// Config and region from the region's ShipcatConfig
+    ///
+    /// Read for every reconcile so that config changes apply without a restart.
+    fn config(&self) -> Result<(Config, Region)> {
+        let pth = format!("{}/{}", kube::shipcatconfigs_path(&self.namespace), self.region);
+        let crd : Crd<Config> = self.client.get(&pth, &[])?;
+        let region = crd.spec.get_region(&self.region)?;
+        Ok((crd.spec, region))
+    }
+
+    /// Log in to the region's vault as the operator's service account
+    ///
+    /// `Manifest::complete` reads `VAULT_TOKEN`, so the token is exported to the process.
+    fn vault_login(&self, region: &Region) -> Result<()> {
+        if region.vault.backend != SecretSource::Vault {
+            return Ok(());
+        }
+        let jwt = fs::read_to_string(SERVICE_ACCOUNT_TOKEN)?;
+        let token = Vault::kubernetes_login(&region.vault, &self.vaultRole, jwt.trim())
+            .chain_err(|| format!("Could not log in to vault as {}", self.vaultRole))?;
+        env::set_var("VAULT_TOKEN", token);
+        Ok(())
+    }
+
+    /// Upgrade a service if its manifest changed, and record the outcome in its status
+    ///
+    /// The upgrade itself is recorded in the deployment history by the reconcile worker.
+    pub fn reconcile(&self, obj: Crd<Manifest>) -> Result<()> {
+        let name = obj.metadata.name.clone();
+        if !needs_reconcile(&obj) {
+            debug!("{} is up to date at generation {:?}", name, obj.metadata.generation);
+            return Ok(());
+        }
+        info!("Reconciling {} at generation {:?}", name, obj.metadata.generation);
+        let (conf, mut region) = self.config()?;
+        self.vault_login(&region)?;
+        // kong consumer and webhook secrets, as the cli does for a filtered config
+        region.secrets()?;
+        let res = parallel::reconcile_worker(obj.spec.into_base(), UpgradeMode::UpgradeInstallWait, conf, region, None);
+
+        // re-read what the worker recorded
+        let mut status = match kube::get_manifest_status(&self.client, &self.namespace, &name) {
+            Ok(Some(s)) => s,
+            Ok(None) => {
+                info!("{} was removed while reconciling", name);
+                return res.map(|_| ());
+            },
+            Err(e) => {
+                // the reconcile still has to be recorded, or it would be retried
+                warn!("Failed to read the status of {}: {}", name, e);
+                let mut s = obj.status.clone().unwrap_or_default();
+                if let Ok(Some(ud)) = &res {
+                    s.record(&ud.version, UpgradeState::Completed, &Utc::now().to_rfc3339());
+                }
+                s
+            }
+        };
+        status.observedGeneration = obj.metadata.generation;
+        status.lastReconcile = Some(Utc::now().to_rfc3339());
+        status.error = None;
+        match &res {
+            Ok(Some(ud)) => info!("Reconciled {} at {}", name, ud.version),
+            Ok(None) => debug!("Nothing to reconcile for {}", name),
+            Err(e) => {
+                let chain = e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                warn!("Failed to reconcile {}: {}", name, chain.join(": "));
+                status.state = Some(UpgradeState::Failed);
+                status.error = Some(chain.join(": "));
+            }
+        }
+        kube::update_manifest_status(&self.client, &self.namespace, &name, &status)?;
+        res.map(|_| ())
+    }
+
+    /// Reconcile manifests forever
+    ///
+    /// Reconciles everything outdated on startup, then every change seen by the reflector.
+    pub fn run(&self) {
+        let pth = kube::shipcatmanifests_path(&self.namespace);
+        let mut r = loop {
+            match Reflector::<Manifest>::init(&self.client, &pth) {
+                Ok(r) => break r,
+                Err(e) => {
+                    error!("Failed to list manifests in {}: {}", self.namespace, e);
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        };
+        // generations reconciled before the watch has seen their status
+        let mut handled = BTreeMap::new();
+        loop {
+            for obj in r.data.values() {
+                let name = &obj.metadata.name;
+                if !needs_reconcile(obj) || handled.get(name) == Some(&obj.metadata.generation) {
+                    continue;
+                }
+                handled.insert(name.clone(), obj.metadata.generation);
+                if let Err(e) = self.reconcile(obj.clone()) {
+                    warn!("Failed to reconcile {}: {}", name, e);
+                }
+            }
+            handled.retain(|n, g| r.data.get(n).map_or(false, |o| needs_reconcile(o) && o.metadata.generation == *g));
+            // releases of removed manifests are left for a human to purge
+            loop {
+                match r.poll(&self.client) {
+                    Ok(true) => break,
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warn!("Watch of manifests in {} failed: {}", self.namespace, e);
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::needs_reconcile;
+    use shipcat_definitions::{Crd, Manifest};
+
+    fn object(generation: i64, observed: Option<i64>) -> Crd<Manifest> {
+        let status = observed.map(|g| serde_json::json!({ "observedGeneration": g }));
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "babylontech.co.uk/v1",
+            "kind": "ShipcatManifest",
+            "metadata": { "name": "fake-ask", "generation": generation },
+            "spec": { "name": "fake-ask" },
+            "status": status,
+        })).unwrap()
+    }
+
+    #[test]
+    fn reconcile_on_spec_changes() {
+        assert!(needs_reconcile(&object(1, None)));
+        assert!(needs_reconcile(&object(2, Some(1))));
+        assert!(!needs_reconcile(&object(2, Some(2))));
+    }
+}
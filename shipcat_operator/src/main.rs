@@ -0,0 +1,19 @@
+use log::error;
+use std::process;
+
+use shipcat_operator::Operator;
+
+fn main() {
+    env_logger::init();
+    if let Err(e) = shipcat::init() {
+        error!("Failed to initialise: {}", e);
+        process::exit(1);
+    }
+    match Operator::from_env() {
+        Ok(op) => op.run(),
+        Err(e) => {
+            error!("Failed to configure the operator: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,6 @@
+{{/* Labels shared by all objects */}}
+{{- define "base.labels" -}}
+app: {{ .Values.name }}
+chart: {{ .Chart.Name }}-{{ .Chart.Version }}
+heritage: {{ .Release.Service }}
+{{- end -}}